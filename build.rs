@@ -0,0 +1,27 @@
+// Expose the exact versions of the Revault libraries we were built with, for `--version`.
+use std::{env, fs, path::PathBuf};
+
+fn locked_version(lockfile: &str, package: &str) -> Option<String> {
+    let name_line = format!("name = \"{}\"", package);
+    let mut lines = lockfile.lines().skip_while(|l| *l != name_line).skip(1);
+    let version = lines
+        .next()?
+        .strip_prefix("version = \"")?
+        .strip_suffix('"')?;
+    Some(version.to_string())
+}
+
+fn main() {
+    let mut lockfile_path = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap());
+    lockfile_path.push("Cargo.lock");
+    println!("cargo:rerun-if-changed={}", lockfile_path.display());
+    let lockfile = fs::read_to_string(&lockfile_path).unwrap_or_default();
+
+    for (package, var) in &[
+        ("revault_tx", "REVAULT_TX_VERSION"),
+        ("revault_net", "REVAULT_NET_VERSION"),
+    ] {
+        let version = locked_version(&lockfile, package).unwrap_or_else(|| "unknown".to_string());
+        println!("cargo:rustc-env={}={}", var, version);
+    }
+}
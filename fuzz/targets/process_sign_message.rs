@@ -1,6 +1,7 @@
 use cosignerd::{
     self,
     database::db_signed_outpoint,
+    processing::{SignDecision, SignRefusal},
     revault_net::message::cosigner::SignRequest,
     revault_tx::transactions::{RevaultTransaction, SpendTransaction},
 };
@@ -9,9 +10,19 @@ use honggfuzz::fuzz;
 fn main() {
     let builder = cosignerd::tests::builder::CosignerTestBuilder::new(10);
     let db_path = builder.config.db_file();
+    let our_pubkey = *builder.keys.bitcoin_pubkey();
+    // Whether we share our known signatures is up to the first byte of the input
+    let mut known_sigs_config = builder.config.clone();
+    known_sigs_config.return_known_sigs = true;
+    let configs = [&builder.config, &known_sigs_config];
 
     loop {
         fuzz!(|data: &[u8]| {
+            let (flags, data) = match data.split_first() {
+                Some(split) => split,
+                None => return,
+            };
+            let config = configs[(flags & 1) as usize];
             if let Ok(tx) = SpendTransaction::from_psbt_serialized(data) {
                 let prevouts: Vec<_> = tx
                     .tx()
@@ -30,35 +41,59 @@ fn main() {
                 let is_finalized = tx.is_finalized();
 
                 let msg = SignRequest { tx };
-                let resp = match cosignerd::processing::process_sign_message(
-                    &builder.config,
+                let decision = match cosignerd::processing::process_sign_request(
+                    config,
                     msg,
-                    &builder.bitcoin_privkey,
-                    &builder.secp,
+                    &builder.keys,
                 ) {
-                    Ok(res) => res,
+                    Ok(decision) => decision,
                     Err(cosignerd::processing::SignProcessingError::Database(e)) => panic!("{}", e),
                     Err(_) => return,
                 };
+                let is_signed = |prevout| db_signed_outpoint(&db_path, prevout).unwrap().is_some();
 
-                if let Some(resp_tx) = resp.tx {
-                    let psbt = resp_tx.psbt();
+                match decision {
+                    SignDecision::Signed(resp_tx) => {
+                        let psbt = resp_tx.psbt();
 
-                    for (i, sigs) in sigs_list.into_iter().enumerate() {
-                        assert!(psbt.inputs[i].partial_sigs.len() == sigs.len() + 1);
-                    }
+                        for (i, sigs) in sigs_list.into_iter().enumerate() {
+                            assert!(psbt.inputs[i].partial_sigs.len() == sigs.len() + 1);
+                        }
 
-                    for txin in psbt.global.unsigned_tx.input.iter() {
-                        assert!(db_signed_outpoint(&db_path, &txin.previous_output)
-                            .unwrap()
-                            .is_some());
+                        for txin in psbt.global.unsigned_tx.input.iter() {
+                            assert!(is_signed(&txin.previous_output));
+                        }
+                    }
+                    // Exactly the outpoints it names were signed already, and not all of them
+                    SignDecision::Refused(SignRefusal::AlreadySigned(signed)) => {
+                        assert!(!signed.is_empty() && signed.len() < prevouts.len());
+                        assert!(signed.iter().all(|o| prevouts.contains(o)));
+                        let n_signed = prevouts.iter().filter(|o| is_signed(o)).count();
+                        assert_eq!(n_signed, signed.len());
+                    }
+                    // Same, but we shared our signatures for them as configured to
+                    SignDecision::KnownSignatures(resp_tx, signed) => {
+                        assert!(config.return_known_sigs);
+                        assert!(!signed.is_empty() && signed.len() < prevouts.len());
+                        for (psbtin, txin) in resp_tx
+                            .psbt()
+                            .inputs
+                            .iter()
+                            .zip(resp_tx.tx().input.iter())
+                        {
+                            assert_eq!(
+                                psbtin.partial_sigs.contains_key(&our_pubkey),
+                                signed.contains(&txin.previous_output)
+                            );
+                        }
+                    }
+                    // All of them were, but for another Spend
+                    SignDecision::Refused(SignRefusal::ModifiedSpend(signed)) => {
+                        assert_eq!(signed.len(), prevouts.len());
+                        assert!(prevouts.iter().all(|o| is_signed(o)));
                     }
-                } else if !is_finalized {
-                    let n_signed = prevouts
-                        .iter()
-                        .filter_map(|prevout| db_signed_outpoint(&db_path, &prevout).unwrap())
-                        .count();
-                    assert!(n_signed > 0 && n_signed < sigs_list.len());
+                    SignDecision::Refused(SignRefusal::Finalized) => assert!(is_finalized),
+                    SignDecision::Refused(_) => {}
                 }
             }
         });
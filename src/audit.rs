@@ -0,0 +1,237 @@
+//! The audit log is an append-only file recording the security-relevant events (what we signed,
+//! for whom, and when) as one JSON object per line. Unlike the operational log it's never
+//! silenced by the log level.
+
+use crate::processing::SignRefusal;
+
+use revault_net::noise::PublicKey as NoisePubkey;
+use revault_tx::bitcoin::{hashes::hex::ToHex, OutPoint};
+
+use serde::Serialize;
+use std::{
+    fs,
+    io::{self, Write},
+    os::unix::fs::OpenOptionsExt,
+    path::Path,
+    time,
+};
+
+/// A security-relevant event
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum AuditEvent {
+    /// We shared signatures for these outpoints with this manager
+    Signed {
+        manager: String,
+        outpoints: Vec<String>,
+    },
+    /// We refused to share signatures for these outpoints with this manager. The reason is one of
+    /// [SignRefusal::code], and the offending outpoints are empty if it's not specific to some.
+    Refused {
+        manager: String,
+        outpoints: Vec<String>,
+        reason: &'static str,
+        offending_outpoints: Vec<String>,
+    },
+    /// The record of this outpoint was removed from the anti-replay database by the operator
+    Forgotten { outpoint: String },
+}
+
+impl AuditEvent {
+    pub fn signed(manager: &NoisePubkey, outpoints: &[OutPoint]) -> Self {
+        Self::Signed {
+            manager: manager.0.to_hex(),
+            outpoints: outpoints.iter().map(|o| o.to_string()).collect(),
+        }
+    }
+
+    pub fn forgotten(outpoint: &OutPoint) -> Self {
+        Self::Forgotten {
+            outpoint: outpoint.to_string(),
+        }
+    }
+
+    pub fn refused(manager: &NoisePubkey, outpoints: &[OutPoint], refusal: &SignRefusal) -> Self {
+        Self::Refused {
+            manager: manager.0.to_hex(),
+            outpoints: outpoints.iter().map(|o| o.to_string()).collect(),
+            reason: refusal.code(),
+            offending_outpoints: refusal
+                .offending_outpoints()
+                .iter()
+                .map(|o| o.to_string())
+                .collect(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct AuditEntry<'a> {
+    timestamp: u64,
+    #[serde(flatten)]
+    event: &'a AuditEvent,
+}
+
+/// Append this event to the audit log at this path, creating it if needed.
+pub fn write_audit_event(audit_log: &Path, event: &AuditEvent) -> Result<(), io::Error> {
+    let timestamp = time::SystemTime::now()
+        .duration_since(time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let mut line = serde_json::to_vec(&AuditEntry { timestamp, event })?;
+    line.push(b'\n');
+
+    let mut file = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .mode(0o600)
+        .open(audit_log)?;
+    file.write_all(&line)?;
+    file.sync_data()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::builder::CosignerTestBuilder;
+    use std::str::FromStr;
+
+    #[test]
+    fn audit_log_format() {
+        let test_framework = CosignerTestBuilder::new(1);
+        let audit_log = test_framework.config.data_dir.join("audit.log");
+        let manager = test_framework.config.managers[0].noise_key;
+        let outpoint = OutPoint::from_str(
+            "2b8930127e9dfd1bcdf35df2bc7f3b8cdbec083b1ae693f36b6305fccd1425da:0",
+        )
+        .unwrap();
+
+        write_audit_event(&audit_log, &AuditEvent::signed(&manager, &[outpoint])).unwrap();
+        write_audit_event(
+            &audit_log,
+            &AuditEvent::refused(&manager, &[outpoint], &SignRefusal::NoRbf),
+        )
+        .unwrap();
+
+        let content = fs::read_to_string(&audit_log).unwrap();
+        let lines: Vec<serde_json::Value> = content
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["event"], "signed");
+        assert_eq!(lines[1]["event"], "refused");
+        for line in lines.iter() {
+            assert!(line["timestamp"].as_u64().unwrap() > 0);
+            assert_eq!(line["manager"], manager.0.to_hex());
+            assert_eq!(line["outpoints"], serde_json::json!([outpoint.to_string()]));
+        }
+        assert!(lines[0].get("reason").is_none());
+        assert_eq!(lines[1]["reason"], "no_rbf");
+        assert_eq!(lines[1]["offending_outpoints"], serde_json::json!([]));
+
+        write_audit_event(&audit_log, &AuditEvent::forgotten(&outpoint)).unwrap();
+        let content = fs::read_to_string(&audit_log).unwrap();
+        let line: serde_json::Value =
+            serde_json::from_str(content.lines().last().unwrap()).unwrap();
+        assert_eq!(line["event"], "forgotten");
+        assert_eq!(line["outpoint"], outpoint.to_string());
+    }
+
+    #[test]
+    fn audit_log_refusal_reasons() {
+        let test_framework = CosignerTestBuilder::new(1);
+        let audit_log = test_framework.config.data_dir.join("audit.log");
+        let manager = test_framework.config.managers[0].noise_key;
+        let (outpoint_a, outpoint_b) = (
+            OutPoint::from_str(
+                "2b8930127e9dfd1bcdf35df2bc7f3b8cdbec083b1ae693f36b6305fccd1425da:0",
+            )
+            .unwrap(),
+            OutPoint::from_str(
+                "ceca4de398c63b29543f8346c09fd7522fd8661ce8bdc0e454e8d6ed8ad46a0d:1",
+            )
+            .unwrap(),
+        );
+
+        let refusals = vec![
+            (SignRefusal::Finalized, "finalized"),
+            (
+                SignRefusal::BlacklistedOutpoints(vec![outpoint_b]),
+                "blacklisted_outpoints",
+            ),
+            (
+                SignRefusal::NotAllowedOutpoints(vec![outpoint_a]),
+                "not_allowed_outpoints",
+            ),
+            (
+                SignRefusal::NotAllowedDestinations,
+                "not_allowed_destinations",
+            ),
+            (SignRefusal::CsvMismatch(vec![outpoint_b]), "csv_mismatch"),
+            (SignRefusal::StaleLocktime, "stale_locktime"),
+            (SignRefusal::NoRbf, "no_rbf"),
+            (SignRefusal::FeerateTooLow, "feerate_too_low"),
+            (SignRefusal::FeerateTooHigh, "feerate_too_high"),
+            (SignRefusal::MissingManagerSigs, "missing_manager_sigs"),
+            (SignRefusal::MissingSenderSig, "missing_sender_sig"),
+            (
+                SignRefusal::ModifiedSpend(vec![outpoint_a, outpoint_b]),
+                "modified_spend",
+            ),
+            (
+                SignRefusal::AlreadySigned(vec![outpoint_a]),
+                "already_signed",
+            ),
+            (
+                SignRefusal::ConcurrentlySigned(vec![outpoint_a, outpoint_b]),
+                "concurrently_signed",
+            ),
+            (SignRefusal::ReadOnly, "read_only"),
+            (SignRefusal::ProcessingError, "processing_error"),
+        ];
+        for (refusal, _) in refusals.iter() {
+            write_audit_event(
+                &audit_log,
+                &AuditEvent::refused(&manager, &[outpoint_a, outpoint_b], refusal),
+            )
+            .unwrap();
+        }
+
+        let content = fs::read_to_string(&audit_log).unwrap();
+        let lines: Vec<serde_json::Value> = content
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+        assert_eq!(lines.len(), refusals.len());
+        for (line, (refusal, code)) in lines.iter().zip(refusals.iter()) {
+            let mut keys: Vec<&String> = line.as_object().unwrap().keys().collect();
+            keys.sort();
+            assert_eq!(
+                keys,
+                vec![
+                    "event",
+                    "manager",
+                    "offending_outpoints",
+                    "outpoints",
+                    "reason",
+                    "timestamp"
+                ]
+            );
+            assert_eq!(line["event"], "refused");
+            assert!(line["timestamp"].as_u64().unwrap() > 0);
+            assert_eq!(line["manager"], manager.0.to_hex());
+            assert_eq!(
+                line["outpoints"],
+                serde_json::json!([outpoint_a.to_string(), outpoint_b.to_string()])
+            );
+            assert_eq!(line["reason"], *code);
+            let offending: Vec<String> = refusal
+                .offending_outpoints()
+                .iter()
+                .map(|o| o.to_string())
+                .collect();
+            assert_eq!(line["offending_outpoints"], serde_json::json!(offending));
+        }
+    }
+}
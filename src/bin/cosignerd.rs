@@ -1,45 +1,380 @@
 use cosignerd::{
-    config::Config,
-    daemonize::daemonize,
-    database::setup_db,
-    keys::{read_bitcoin_privkey, read_or_create_noise_key},
-    processing::process_sign_message,
+    audit::{write_audit_event, AuditEvent},
+    config::{config_template, Config, ConfigError, LogTimestampFormat},
+    daemon::{bind_listener, serve, setup_reload_signal, ShutdownReason},
+    daemonize::{daemonize, RotatingLog},
+    database::{
+        db_all_signed_outpoints, db_backup, db_diff, db_forget_outpoint, db_reserved_outpoints,
+        db_set_busy_retries, db_set_busy_timeout, db_set_read_only, db_set_wal, db_warm_cache,
+        setup_db, DatabaseError,
+    },
+    error::Error,
+    health::start_health_endpoint,
+    keys::{
+        check_permissions, encrypt_bitcoin_privkey, read_bitcoin_privkey, read_key_passphrase,
+        secp_ctx, Keys, DATADIR_MODE, KEY_FILE_MODE,
+    },
+    metrics::start_metrics_endpoint,
+    processing::{check_signatures, SignatureCheck},
+    selftest::{selftest, selftest_offline},
 };
-use revault_net::{
-    bitcoin::PrivateKey,
-    message::{RequestParams, ResponseResult},
-    noise::{PublicKey as NoisePubkey, SecretKey as NoisePrivkey},
-    sodiumoxide::crypto::scalarmult::curve25519,
+use revault_net::noise::PublicKey as NoisePubkey;
+use revault_tx::{
+    bitcoin::{hashes::hex::ToHex, OutPoint, PublicKey as BitcoinPubkey},
+    transactions::SpendTransaction,
 };
-use revault_tx::bitcoin::{hashes::hex::ToHex, secp256k1};
-use std::{env, fs, net::TcpListener, os::unix::fs::DirBuilderExt, path::PathBuf, process, time};
+use std::{
+    env, fs,
+    io::{self, BufRead, Write},
+    net::TcpListener,
+    os::unix::fs::{DirBuilderExt, OpenOptionsExt},
+    path::{Path, PathBuf},
+    process,
+    str::FromStr,
+    sync::{atomic::AtomicBool, Arc},
+    time,
+};
+
+/// What we were asked to do on the command line
+#[derive(Debug, PartialEq)]
+enum Command {
+    /// Run the daemon, with an optional custom configuration file path
+    Daemon(Option<PathBuf>),
+    /// Compare the signed outpoints of two databases
+    DbDiff(PathBuf, PathBuf),
+    /// Check the signing pipeline works on this host, with an optional custom configuration file
+    /// path. Only in-process, without touching the data directory nor the network, if set.
+    Selftest(Option<PathBuf>, bool),
+    /// Check the signatures of a cosigner in a Spend PSBT file
+    VerifySigned(PathBuf, BitcoinPubkey),
+    /// Encrypt a raw Bitcoin key file under the passphrase in a file, to an output file
+    EncryptKey(PathBuf, PathBuf, PathBuf),
+    /// Print a configuration file template
+    GenerateConfig,
+    /// Print our public keys, with an optional custom configuration file path
+    ShowKeys(Option<PathBuf>),
+    /// Check the configuration, with an optional custom configuration file path
+    ValidateConfig(Option<PathBuf>),
+    /// Print our version and the one of the Revault libraries
+    Version,
+    /// Copy the database to a new file, with an optional custom configuration file path
+    Backup(Option<PathBuf>, PathBuf),
+    /// Print the outpoints in the database, with an optional custom configuration file path
+    DbList(Option<PathBuf>),
+    /// Remove an outpoint from the database, with an optional custom configuration file path
+    DbForget(Option<PathBuf>, OutPoint),
+}
+
+const USAGE: &str = "Usage:
+    cosignerd [--conf <configuration file path>] [--datadir <data directory path>]
+    cosignerd [--conf <configuration file path>] [--datadir <data directory path>] selftest [--offline]
+    cosignerd [--conf <configuration file path>] [--datadir <data directory path>] validate-config
+    cosignerd [--conf <configuration file path>] [--datadir <data directory path>] show-keys
+    cosignerd [--conf <configuration file path>] [--datadir <data directory path>] backup --out <backup path>
+    cosignerd [--conf <configuration file path>] [--datadir <data directory path>] db list
+    cosignerd [--conf <configuration file path>] [--datadir <data directory path>] db forget <txid:vout>
+    cosignerd generate-config
+    cosignerd --version
+    cosignerd db-diff --a <database path> --b <database path>
+    cosignerd verify-signed --psbt <PSBT file path> --cosigner-pubkey <hex public key>
+    cosignerd encrypt-key --key <key path> --passphrase-file <passphrase path> --out <output path>";
 
-fn parse_args(args: Vec<String>) -> Option<PathBuf> {
-    if args.len() == 1 {
-        return None;
+fn version() -> String {
+    format!(
+        "cosignerd {} (revault_tx {}, revault_net {})",
+        env!("CARGO_PKG_VERSION"),
+        env!("REVAULT_TX_VERSION"),
+        env!("REVAULT_NET_VERSION"),
+    )
+}
+
+fn parse_db_diff_args(args: &[String]) -> Option<Command> {
+    let (mut a, mut b) = (None, None);
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--a" => a = Some(PathBuf::from(args.next()?)),
+            "--b" => b = Some(PathBuf::from(args.next()?)),
+            _ => return None,
+        }
     }
 
-    if args.len() != 3 {
-        eprintln!("Unknown arguments '{:?}'.", args);
-        eprintln!("Only '--conf <configuration file path>' is supported.");
-        process::exit(1);
+    Some(Command::DbDiff(a?, b?))
+}
+
+fn parse_verify_signed_args(args: &[String]) -> Option<Command> {
+    let (mut psbt, mut pubkey) = (None, None);
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--psbt" => psbt = Some(PathBuf::from(args.next()?)),
+            "--cosigner-pubkey" => pubkey = Some(BitcoinPubkey::from_str(args.next()?).ok()?),
+            _ => return None,
+        }
+    }
+
+    Some(Command::VerifySigned(psbt?, pubkey?))
+}
+
+fn parse_encrypt_key_args(args: &[String]) -> Option<Command> {
+    let (mut key, mut passphrase, mut out) = (None, None, None);
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--key" => key = Some(PathBuf::from(args.next()?)),
+            "--passphrase-file" => passphrase = Some(PathBuf::from(args.next()?)),
+            "--out" => out = Some(PathBuf::from(args.next()?)),
+            _ => return None,
+        }
+    }
+
+    Some(Command::EncryptKey(key?, passphrase?, out?))
+}
+
+fn parse_db_args(conf_file: Option<PathBuf>, args: &[String]) -> Option<Command> {
+    match args {
+        [list] if list == "list" => Some(Command::DbList(conf_file)),
+        [forget, outpoint] if forget == "forget" => Some(Command::DbForget(
+            conf_file,
+            OutPoint::from_str(outpoint).ok()?,
+        )),
+        _ => None,
+    }
+}
+
+impl Command {
+    // Whether it reads the configuration, and may be given a data directory to use instead of
+    // the configured one
+    fn reads_config(&self) -> bool {
+        matches!(
+            self,
+            Self::Daemon(_)
+                | Self::Selftest(..)
+                | Self::ValidateConfig(_)
+                | Self::ShowKeys(_)
+                | Self::Backup(..)
+                | Self::DbList(_)
+                | Self::DbForget(..)
+        )
+    }
+}
+
+// Remove the `--datadir <path>` option from the arguments, if given. Like `--conf`, it must come
+// before the command, and may come before or after `--conf`.
+fn take_datadir_arg(args: &mut Vec<String>) -> Result<Option<PathBuf>, String> {
+    let position = match args.iter().position(|a| a == "--datadir") {
+        Some(position) => position,
+        None => return Ok(None),
+    };
+    let leading = position == 1 || (position == 3 && args[1] == "--conf");
+    if !leading || position + 1 >= args.len() {
+        return Err(format!("Invalid '--datadir' argument.\n{}", USAGE));
+    }
+
+    let data_dir = PathBuf::from(args.remove(position + 1));
+    args.remove(position);
+    Ok(Some(data_dir))
+}
+
+// Read the configuration, using this data directory if given on the command line. It takes
+// precedence over the configured one, which takes precedence over the default one.
+fn load_config(
+    conf_file: Option<PathBuf>,
+    data_dir: Option<PathBuf>,
+) -> Result<Config, ConfigError> {
+    let mut config = Config::from_file(conf_file)?;
+    if let Some(data_dir) = data_dir {
+        config.override_data_dir(data_dir);
     }
 
-    Some(PathBuf::from(args[2].to_owned()))
+    Ok(config)
+}
+
+fn parse_args(args: Vec<String>) -> Result<Command, String> {
+    let command = match args.get(1).map(|a| a.as_str()) {
+        None => Some(Command::Daemon(None)),
+        Some("--conf") if args.len() == 3 => Some(Command::Daemon(Some(PathBuf::from(&args[2])))),
+        Some("--conf") if args.len() == 4 && args[3] == "selftest" => {
+            Some(Command::Selftest(Some(PathBuf::from(&args[2])), false))
+        }
+        Some("--conf") if args.len() == 5 && args[3] == "selftest" && args[4] == "--offline" => {
+            Some(Command::Selftest(Some(PathBuf::from(&args[2])), true))
+        }
+        Some("--conf") if args.len() == 4 && args[3] == "validate-config" => {
+            Some(Command::ValidateConfig(Some(PathBuf::from(&args[2]))))
+        }
+        Some("--conf") if args.len() == 4 && args[3] == "show-keys" => {
+            Some(Command::ShowKeys(Some(PathBuf::from(&args[2]))))
+        }
+        Some("--conf") if args.len() == 6 && args[3] == "backup" && args[4] == "--out" => Some(
+            Command::Backup(Some(PathBuf::from(&args[2])), PathBuf::from(&args[5])),
+        ),
+        Some("--conf") if args.len() > 4 && args[3] == "db" => {
+            parse_db_args(Some(PathBuf::from(&args[2])), &args[4..])
+        }
+        Some("db") => parse_db_args(None, &args[2..]),
+        Some("backup") if args.len() == 4 && args[2] == "--out" => {
+            Some(Command::Backup(None, PathBuf::from(&args[3])))
+        }
+        Some("selftest") if args.len() == 2 => Some(Command::Selftest(None, false)),
+        Some("selftest") if args.len() == 3 && args[2] == "--offline" => {
+            Some(Command::Selftest(None, true))
+        }
+        Some("show-keys") if args.len() == 2 => Some(Command::ShowKeys(None)),
+        Some("validate-config") if args.len() == 2 => Some(Command::ValidateConfig(None)),
+        Some("generate-config") if args.len() == 2 => Some(Command::GenerateConfig),
+        Some("--version") if args.len() == 2 => Some(Command::Version),
+        Some("db-diff") => parse_db_diff_args(&args[2..]),
+        Some("verify-signed") => parse_verify_signed_args(&args[2..]),
+        Some("encrypt-key") => parse_encrypt_key_args(&args[2..]),
+        _ => None,
+    };
+
+    command.ok_or_else(|| format!("Unknown arguments '{:?}'.\n{}", &args[1..], USAGE))
+}
+
+// Compare the databases and report their differences. Returns false if they differ.
+fn db_diff_main(db_a: &PathBuf, db_b: &PathBuf) -> Result<bool, Error> {
+    for db_path in &[db_a, db_b] {
+        if !db_path.exists() {
+            return Err(DatabaseError(format!("No database at '{}'", db_path.display())).into());
+        }
+    }
+    let diff = db_diff(db_a, db_b)?;
+
+    for outpoint in diff.only_in_a.iter() {
+        println!("Only in '{}': {}", db_a.display(), outpoint);
+    }
+    for outpoint in diff.only_in_b.iter() {
+        println!("Only in '{}': {}", db_b.display(), outpoint);
+    }
+    for outpoint in diff.different_signatures.iter() {
+        println!(
+            "ALERT: '{}' was signed with different signatures in each database!",
+            outpoint
+        );
+    }
+
+    Ok(diff.is_empty())
+}
+
+// Check the signature of this cosigner for each input of the Spend. Returns false if any is
+// missing or invalid.
+fn verify_signed_main(psbt_path: &PathBuf, pubkey: &BitcoinPubkey) -> Result<bool, Error> {
+    let psbt_str = fs::read_to_string(psbt_path).map_err(|e| Error::Io(psbt_path.clone(), e))?;
+    let spend_tx = SpendTransaction::from_str(psbt_str.trim())?;
+    let checks = check_signatures(&spend_tx, pubkey, secp_ctx())?;
+
+    for (i, check) in checks.iter().enumerate() {
+        match check {
+            SignatureCheck::Valid => println!("Input {}: PASS", i),
+            SignatureCheck::Invalid => println!("Input {}: FAIL (invalid signature)", i),
+            SignatureCheck::Missing => println!("Input {}: FAIL (missing signature)", i),
+        }
+    }
+
+    Ok(checks.iter().all(|c| *c == SignatureCheck::Valid))
+}
+
+// Encrypt the Bitcoin key (raw or WIF, for any network) under the passphrase and write it to a
+// new file
+fn encrypt_key_main(
+    key_path: &Path,
+    passphrase_path: &PathBuf,
+    out_path: &PathBuf,
+) -> Result<(), Error> {
+    let privkey = read_bitcoin_privkey(key_path, None, None)?;
+    let passphrase = read_key_passphrase(Some(passphrase_path))?.expect("We passed a file");
+    let encrypted = encrypt_bitcoin_privkey(&privkey, &passphrase)?;
+
+    fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(KEY_FILE_MODE)
+        .open(out_path)
+        .and_then(|mut fd| fd.write_all(&encrypted))
+        .map_err(|e| Error::Io(out_path.clone(), e))
 }
 
-fn setup_logger(log_level: log::LevelFilter) -> Result<(), fern::InitError> {
+// Make sure we could create files in this directory, or in the closest existing parent if it
+// doesn't exist yet (we'd create it at startup).
+fn check_datadir_writable(data_dir: &Path) -> Result<(), Error> {
+    let mut dir = data_dir;
+    while !dir.exists() {
+        dir = dir.parent().unwrap_or_else(|| Path::new("/"));
+    }
+
+    let probe_path = dir.join(format!(".cosignerd-probe-{}", process::id()));
+    fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&probe_path)
+        .and_then(|_| fs::remove_file(&probe_path))
+        .map_err(|e| Error::Io(dir.to_path_buf(), e))
+}
+
+// Check the configuration as thoroughly as we can without binding nor touching the database
+fn validate_config_main(
+    conf_file: Option<PathBuf>,
+    data_dir: Option<PathBuf>,
+) -> Result<(), Error> {
+    let config = load_config(conf_file, data_dir)?;
+    check_datadir_writable(&config.data_dir)
+}
+
+// The UTC date and time at this many seconds since the epoch, in the RFC3339 format
+fn rfc3339_timestamp(secs: u64) -> String {
+    let (days, secs_of_day) = (secs / 86400, secs % 86400);
+
+    // Howard Hinnant's civil_from_days, for days since the epoch (so a positive era)
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        secs_of_day / 3600,
+        secs_of_day % 3600 / 60,
+        secs_of_day % 60
+    )
+}
+
+// The timestamp of a log line written at this time
+fn log_timestamp(format: LogTimestampFormat, now: time::SystemTime) -> String {
+    let secs = now
+        .duration_since(time::UNIX_EPOCH)
+        .unwrap_or_else(|e| {
+            println!("Can't get time since epoch: '{}'. Using a dummy value.", e);
+            time::Duration::from_secs(0)
+        })
+        .as_secs();
+
+    match format {
+        LogTimestampFormat::Epoch => secs.to_string(),
+        LogTimestampFormat::Rfc3339 => rfc3339_timestamp(secs),
+    }
+}
+
+// Log to stdout, through the rotation of the log file if any
+fn setup_logger(
+    log_level: log::LevelFilter,
+    timestamp_format: LogTimestampFormat,
+    log_rotation: Option<RotatingLog>,
+) -> Result<(), fern::InitError> {
     let dispatcher = fern::Dispatch::new()
-        .format(|out, message, record| {
+        .format(move |out, message, record| {
             out.finish(format_args!(
                 "{}[{}][{}] {}",
-                time::SystemTime::now()
-                    .duration_since(time::UNIX_EPOCH)
-                    .unwrap_or_else(|e| {
-                        println!("Can't get time since epoch: '{}'. Using a dummy value.", e);
-                        time::Duration::from_secs(0)
-                    })
-                    .as_secs(),
+                log_timestamp(timestamp_format, time::SystemTime::now()),
                 record.target(),
                 record.level(),
                 message
@@ -47,144 +382,355 @@ fn setup_logger(log_level: log::LevelFilter) -> Result<(), fern::InitError> {
         })
         .level(log_level);
 
-    dispatcher.chain(std::io::stdout()).apply()?;
+    match log_rotation {
+        Some(log_rotation) => dispatcher.chain(Box::new(log_rotation) as Box<dyn Write + Send>),
+        None => dispatcher.chain(std::io::stdout()),
+    }
+    .apply()?;
 
     Ok(())
 }
 
-fn process_message(
-    secp_ctx: &secp256k1::Secp256k1<secp256k1::All>,
-    config: &Config,
-    bitcoin_privkey: &secp256k1::SecretKey,
-    message: RequestParams,
-) -> Option<revault_net::message::ResponseResult> {
-    match message {
-        RequestParams::Sign(sign_req) => {
-            log::trace!("Decoded request: {:#?}", sign_req);
-
-            let res = match process_sign_message(&config, sign_req, bitcoin_privkey, &secp_ctx) {
-                Ok(res) => res,
-                Err(e) => {
-                    log::error!("Error when processing 'sign' message: '{}'", e);
-                    return None;
-                }
-            };
-            log::trace!("Decoded response: {:#?}", res);
-
-            Some(ResponseResult::SignResult(res))
-        }
-        _ => {
-            // FIXME: This should probably be fatal, they are violating the protocol
-            log::error!("Unexpected message: '{:?}'", message);
-            None
-        }
-    }
-}
-
-// Wait for connections from managers on the configured interface and process `sign` messages.
-fn daemon_main(
-    config: Config,
-    noise_privkey: &NoisePrivkey,
-    bitcoin_privkey: &secp256k1::SecretKey,
-) {
-    let host = config.listen;
-    let listener = TcpListener::bind(host).unwrap_or_else(|e| {
-        log::error!("Error binding on '{}': '{}'", host, e);
-        process::exit(1);
-    });
-    let managers_noise_pubkeys: Vec<NoisePubkey> =
-        config.managers.iter().map(|m| m.noise_key).collect();
-    let secp_ctx = secp256k1::Secp256k1::new();
-
-    // We expect a single connection once in a while, there is *no need* for complexity here so
-    // just treat incoming connections sequentially.
-    loop {
-        let (connection, _) = match listener.accept() {
-            Ok(c) => c,
-            Err(e) => {
-                log::error!("Accepting new connection: '{}'", e);
-                continue;
-            }
-        };
-        let mut kk_stream = match revault_net::transport::KKTransport::accept(
-            connection,
-            noise_privkey,
-            &managers_noise_pubkeys,
-        ) {
-            Ok(s) => s,
-            Err(e) => {
-                log::error!("Error during handshake: '{}'", e);
-                continue;
-            }
-        };
+// Wait for connections from managers on the configured interface and process `sign` messages,
+// until we reach the maximum uptime if any.
+fn daemon_main(config: Config, keys: &Keys) {
+    let listeners = config
+        .listen
+        .iter()
+        .map(|host| {
+            bind_listener(host, &config.listen).unwrap_or_else(|e| {
+                log::error!("Error binding on '{}': '{}'", host, e);
+                process::exit(1);
+            })
+        })
+        .collect();
 
-        // Process all messages from this connection.
-        loop {
-            if let Err(e) =
-                kk_stream.read_req(|msg| process_message(&secp_ctx, &config, &bitcoin_privkey, msg))
-            {
-                log::error!(
-                    "Error handling request from stream '{:?}': '{}'. Dropping connection.",
-                    kk_stream,
-                    e
-                );
-                break;
-            }
-        }
+    if let Some(health_listen) = config.health_listen {
+        let listener = TcpListener::bind(health_listen).unwrap_or_else(|e| {
+            log::error!("Error binding on '{}': '{}'", health_listen, e);
+            process::exit(1);
+        });
+        start_health_endpoint(
+            listener,
+            config.db_file(),
+            config.capabilities(),
+            time::Duration::from_secs(config.connection_timeout.max(1)),
+        );
+    }
+
+    if let Some(metrics_listen) = config.metrics_listen {
+        let listener = TcpListener::bind(metrics_listen).unwrap_or_else(|e| {
+            log::error!("Error binding on '{}': '{}'", metrics_listen, e);
+            process::exit(1);
+        });
+        start_metrics_endpoint(
+            listener,
+            config.db_file(),
+            time::Duration::from_secs(config.connection_timeout.max(1)),
+        );
+    }
+
+    // We never get shutdown from here, we only exit on signals or after the maximum uptime.
+    let reason = serve(listeners, &config, keys, &AtomicBool::new(false));
+    if reason == ShutdownReason::MaxUptime {
+        process::exit(reason.exit_code());
     }
 }
 
 fn create_datadir(datadir_path: &PathBuf) -> Result<(), std::io::Error> {
     let mut builder = fs::DirBuilder::new();
-    builder.mode(0o700).recursive(true).create(datadir_path)
+    builder
+        .mode(DATADIR_MODE)
+        .recursive(true)
+        .create(datadir_path)
 }
 
-fn main() {
-    let args = env::args().collect();
-    let conf_file = parse_args(args);
-
-    let mut config = Config::from_file(conf_file).unwrap_or_else(|e| {
-        eprintln!("Error parsing config: {}", e);
-        process::exit(1);
-    });
-    let log_level = config.log_level;
-    setup_logger(log_level).unwrap_or_else(|e| {
-        eprintln!("Error setting up logger: {}", e);
-        process::exit(1);
-    });
-
+// Create the data directory if it doesn't exist yet, and make its path absolute
+fn setup_datadir(config: &mut Config) -> Result<(), Error> {
     if !config.data_dir.as_path().exists() {
-        create_datadir(&config.data_dir).unwrap_or_else(|e| {
-            eprintln!("Error creating datadir: '{}'", e);
-            process::exit(1);
-        })
+        create_datadir(&config.data_dir).map_err(|e| Error::Io(config.data_dir.clone(), e))?;
     }
-    config.data_dir = fs::canonicalize(config.data_dir).unwrap_or_else(|e| {
-        eprintln!("Error creating datadir: '{}'", e);
-        process::exit(1);
-    });
+    config.data_dir =
+        fs::canonicalize(&config.data_dir).map_err(|e| Error::Io(config.data_dir.clone(), e))?;
 
+    Ok(())
+}
+
+// Read our keys from the data directory, creating the Noise one if necessary
+fn read_keys(config: &Config) -> Result<Keys, Error> {
     let mut noise_key_path = config.data_dir.clone();
     noise_key_path.push("noise_secret");
-    let noise_privkey = read_or_create_noise_key(&noise_key_path).unwrap_or_else(|e| {
-        eprintln!("Error reading Noise key: '{}'", e);
-        process::exit(1);
-    });
-
     let mut bitcoin_key_path = config.data_dir.clone();
     bitcoin_key_path.push("bitcoin_secret");
-    let bitcoin_privkey = read_bitcoin_privkey(&bitcoin_key_path).unwrap_or_else(|e| {
-        eprintln!("Error reading Bitcoin key: '{}'", e);
+    let passphrase = read_key_passphrase(config.bitcoin_key_passphrase_file.as_ref())?;
+
+    Ok(Keys::from_files(
+        &noise_key_path,
+        &bitcoin_key_path,
+        passphrase.as_deref(),
+        Some(config.network),
+    )?)
+}
+
+// Create the database if it doesn't exist yet and check it, returning its path
+fn setup_database(config: &Config) -> Result<PathBuf, Error> {
+    let db_path = config.db_file();
+    db_set_busy_timeout(config.db_busy_timeout_ms);
+    db_set_busy_retries(config.db_busy_retries);
+    if config.read_only {
+        db_set_read_only(&db_path);
+    }
+    setup_db(&db_path, config.db_integrity_check)?;
+    // The journal mode is the primary's business
+    if !config.read_only {
+        db_set_wal(&db_path, config.db_wal)?;
+    }
+
+    Ok(db_path)
+}
+
+// Report this error and exit, or return the result
+fn exit_on_error<T>(res: Result<T, Error>) -> T {
+    res.unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(1);
+    })
+}
+
+fn pubkeys(keys: &Keys) -> (NoisePubkey, BitcoinPubkey) {
+    (keys.noise_pubkey(), *keys.bitcoin_pubkey())
+}
+
+// The lines printed by show-keys
+fn show_keys_output(noise_pubkey: &NoisePubkey, bitcoin_pubkey: &BitcoinPubkey) -> String {
+    format!(
+        "noise_pubkey {}\nbitcoin_pubkey {}",
+        noise_pubkey.0.to_hex(),
+        bitcoin_pubkey
+    )
+}
+
+// Print the public keys the managers need for the setup, without starting the daemon
+fn show_keys_main(conf_file: Option<PathBuf>, data_dir: Option<PathBuf>) -> Result<(), Error> {
+    let mut config = load_config(conf_file, data_dir)?;
+    setup_datadir(&mut config)?;
+    let keys = read_keys(&config)?;
+
+    let (noise_pubkey, bitcoin_pubkey) = pubkeys(&keys);
+    println!("{}", show_keys_output(&noise_pubkey, &bitcoin_pubkey));
+
+    Ok(())
+}
+
+// Copy the database of the configured data directory to this new file
+fn backup_main(
+    conf_file: Option<PathBuf>,
+    data_dir: Option<PathBuf>,
+    backup_path: &PathBuf,
+) -> Result<(), Error> {
+    let config = load_config(conf_file, data_dir)?;
+    let db_path = existing_db_file(&config)?;
+    db_backup(&db_path, backup_path)?;
+
+    Ok(())
+}
+
+// The path to the database of the configured data directory, which must exist already
+fn existing_db_file(config: &Config) -> Result<PathBuf, Error> {
+    let db_path = config.db_file();
+    if !db_path.exists() {
+        return Err(DatabaseError(format!("No database at '{}'", db_path.display())).into());
+    }
+
+    Ok(db_path)
+}
+
+// The lines printed by db list: the signed outpoints with the time they were signed at (0 if
+// unknown), then the reserved ones.
+fn db_list_output(signed: &[(OutPoint, u64)], reserved: &[OutPoint]) -> String {
+    signed
+        .iter()
+        .map(|(outpoint, signed_at)| format!("{} signed_at={}\n", outpoint, signed_at))
+        .chain(
+            reserved
+                .iter()
+                .map(|outpoint| format!("{} reserved\n", outpoint)),
+        )
+        .collect()
+}
+
+// Print the outpoints recorded in the database of the configured data directory
+fn db_list_main(conf_file: Option<PathBuf>, data_dir: Option<PathBuf>) -> Result<(), Error> {
+    let config = load_config(conf_file, data_dir)?;
+    let db_path = existing_db_file(&config)?;
+    let signed: Vec<(OutPoint, u64)> = db_all_signed_outpoints(&db_path)?
+        .into_iter()
+        .map(|row| (row.outpoint, row.signed_at))
+        .collect();
+    let reserved = db_reserved_outpoints(&db_path)?;
+    print!("{}", db_list_output(&signed, &reserved));
+
+    Ok(())
+}
+
+// Remove this outpoint from the database of the configured data directory, once the operator
+// confirmed it on `input`. Returns false if it was not removed.
+fn db_forget_main(
+    conf_file: Option<PathBuf>,
+    data_dir: Option<PathBuf>,
+    outpoint: &OutPoint,
+    input: &mut dyn BufRead,
+) -> Result<bool, Error> {
+    let config = load_config(conf_file, data_dir)?;
+    let db_path = existing_db_file(&config)?;
+
+    eprintln!(
+        "WARNING: this removes the anti-replay record of '{}', which could then be signed again \
+         for a different Spend. Only do this with the daemon stopped.",
+        outpoint
+    );
+    eprint!("Type 'forget' to confirm: ");
+    let mut answer = String::new();
+    if input.read_line(&mut answer).is_err() || answer.trim() != "forget" {
+        eprintln!("Aborted.");
+        return Ok(false);
+    }
+
+    if !db_forget_outpoint(&db_path, outpoint)? {
+        eprintln!("No record of '{}' in the database.", outpoint);
+        return Ok(false);
+    }
+    eprintln!(
+        "WARNING: forgot '{}', it is not protected against replay anymore.",
+        outpoint
+    );
+    if let Some(ref audit_log) = config.audit_log {
+        if let Err(e) = write_audit_event(audit_log, &AuditEvent::forgotten(outpoint)) {
+            eprintln!("Error writing to the audit log: '{}'", e);
+        }
+    }
+
+    Ok(true)
+}
+
+fn main() {
+    let mut args = env::args().collect();
+    let data_dir = take_datadir_arg(&mut args).unwrap_or_else(|e| {
+        eprintln!("{}", e);
         process::exit(1);
     });
+    let command = parse_args(args);
+    if data_dir.is_some() && !matches!(command, Ok(ref c) if c.reads_config()) {
+        eprintln!(
+            "'--datadir' is only valid with a command reading the configuration.\n{}",
+            USAGE
+        );
+        process::exit(1);
+    }
+    // Whether to run the selftest instead of the daemon, and if so only in-process
+    let (conf_file, run_selftest) = match command {
+        Ok(Command::Daemon(conf_file)) => (conf_file, None),
+        Ok(Command::Selftest(conf_file, offline)) => (conf_file, Some(offline)),
+        Ok(Command::DbDiff(db_a, db_b)) => {
+            let identical = exit_on_error(db_diff_main(&db_a, &db_b));
+            process::exit(if identical { 0 } else { 1 });
+        }
+        Ok(Command::VerifySigned(psbt_path, pubkey)) => {
+            let valid = exit_on_error(verify_signed_main(&psbt_path, &pubkey));
+            process::exit(if valid { 0 } else { 1 });
+        }
+        Ok(Command::Version) => {
+            println!("{}", version());
+            process::exit(0);
+        }
+        Ok(Command::ValidateConfig(conf_file)) => {
+            exit_on_error(validate_config_main(conf_file, data_dir));
+            println!("OK");
+            process::exit(0);
+        }
+        Ok(Command::ShowKeys(conf_file)) => {
+            exit_on_error(show_keys_main(conf_file, data_dir));
+            process::exit(0);
+        }
+        Ok(Command::Backup(conf_file, backup_path)) => {
+            exit_on_error(backup_main(conf_file, data_dir, &backup_path));
+            process::exit(0);
+        }
+        Ok(Command::DbList(conf_file)) => {
+            exit_on_error(db_list_main(conf_file, data_dir));
+            process::exit(0);
+        }
+        Ok(Command::DbForget(conf_file, outpoint)) => {
+            let forgotten = exit_on_error(db_forget_main(
+                conf_file,
+                data_dir,
+                &outpoint,
+                &mut io::stdin().lock(),
+            ));
+            process::exit(if forgotten { 0 } else { 1 });
+        }
+        Ok(Command::GenerateConfig) => {
+            print!("{}", config_template());
+            process::exit(0);
+        }
+        Ok(Command::EncryptKey(key_path, passphrase_path, out_path)) => {
+            exit_on_error(encrypt_key_main(&key_path, &passphrase_path, &out_path));
+            process::exit(0);
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    };
 
-    let mut db_path = config.data_dir.clone();
-    db_path.push("cosignerd.sqlite3");
-    setup_db(&db_path).unwrap_or_else(|e| {
-        eprintln!("Error setting up database: '{}'", e);
+    let mut config = exit_on_error(load_config(conf_file, data_dir).map_err(Error::from));
+    // Before setting up the logger, as the log file path must not be relative to the current
+    // directory once daemonized.
+    exit_on_error(setup_datadir(&mut config));
+    let log_rotation = config
+        .log_max_size
+        .filter(|_| config.daemon)
+        .map(|max_size| RotatingLog::new(config.log_file(), max_size, config.log_backups));
+    setup_logger(config.log_level, config.log_timestamp_format, log_rotation).unwrap_or_else(|e| {
+        eprintln!("Error setting up logger: {}", e);
         process::exit(1);
     });
 
+    exit_on_error(
+        check_permissions(&config.data_dir, config.strict_permissions).map_err(Error::from),
+    );
+    // Read before daemonizing, so that a wrong passphrase is reported on the terminal
+    let mut keys = exit_on_error(read_keys(&config));
+
+    // The selftests use their own scratch database, leave ours alone.
+    if let Some(offline) = run_selftest {
+        let keys = Arc::new(keys);
+        let res = if offline {
+            selftest_offline(&config, &keys)
+        } else {
+            selftest(&config, &keys)
+        };
+        match res {
+            Ok(()) => {
+                println!("PASS");
+                process::exit(0);
+            }
+            Err(e) => {
+                println!("FAIL: {}", e);
+                process::exit(1);
+            }
+        }
+    }
+
+    let db_path = exit_on_error(setup_database(&config));
+    if config.warm_db_cache {
+        match db_warm_cache(&db_path) {
+            Ok(size) => log::info!("Read {} bytes of database into the page cache", size),
+            // Not critical, we'd just be a bit slower.
+            Err(e) => log::warn!("Error warming up the database cache: '{}'", e),
+        }
+    }
+
     if config.daemon {
         unsafe {
             daemonize(&config.data_dir, &config.pid_file(), &config.log_file()).unwrap_or_else(
@@ -196,20 +742,545 @@ fn main() {
                 },
             );
         }
+        // The locks on the secrets were left to the parent
+        keys.relock_memory();
     }
-    let noise_pubkey =
-        NoisePubkey(curve25519::scalarmult_base(&curve25519::Scalar(noise_privkey.0)).0);
-    let bit_pubkey = PrivateKey {
-        compressed: true,
-        network: revault_tx::bitcoin::Network::Bitcoin,
-        key: bitcoin_privkey,
-    }
-    .public_key(&secp256k1::Secp256k1::signing_only());
+    let (noise_pubkey, bit_pubkey) = pubkeys(&keys);
     log::info!(
         "Started cosignerd daemon with Noise pubkey '{}' and Bitcoin pubkey '{}'",
         noise_pubkey.0.to_hex(),
         bit_pubkey
     );
+    log::info!("Signing policy: {}", config.policy());
+    if config.dry_run {
+        log::warn!(
+            "DRY RUN MODE: the outpoints we sign for are not recorded and may be signed again. \
+             This must never be used in production!"
+        );
+    }
+    if config.unvault_descriptor.is_none() {
+        log::warn!(
+            "No 'unvault_descriptor' configured: we don't check the Spends inputs pay to our \
+             vaults."
+        );
+    }
+    if config.read_only {
+        log::info!("Running as a read-only standby: we won't sign anything until promoted.");
+    }
+    if let Err(e) = setup_reload_signal() {
+        log::error!(
+            "Error setting up the SIGHUP handler, can't reload managers: '{}'",
+            e
+        );
+    }
+
+    daemon_main(config, &keys);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        config_template, db_forget_main, db_list_output, load_config, log_timestamp, parse_args,
+        pubkeys, show_keys_output, take_datadir_arg, validate_config_main, version, Command,
+    };
+    use cosignerd::{
+        config::LogTimestampFormat,
+        database::{
+            db_insert_signed_outpoint, db_reserve_outpoints, db_reserved_outpoints,
+            db_signed_outpoint, setup_db,
+        },
+        error::Error,
+        keys::Keys,
+    };
+    use revault_tx::bitcoin::{
+        hashes::hex::FromHex, secp256k1::Signature, Network, OutPoint, PublicKey as BitcoinPubkey,
+    };
+    use std::{fs, path::PathBuf, str::FromStr, time};
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|a| a.to_string()).collect()
+    }
+
+    #[test]
+    fn args_parsing() {
+        assert_eq!(
+            parse_args(args(&["cosignerd"])).unwrap(),
+            Command::Daemon(None)
+        );
+        assert_eq!(
+            parse_args(args(&["cosignerd", "--conf", "/a/config.toml"])).unwrap(),
+            Command::Daemon(Some(PathBuf::from("/a/config.toml")))
+        );
+        parse_args(args(&["cosignerd", "--conf"])).unwrap_err();
+        parse_args(args(&["cosignerd", "--cnof", "/a/config.toml"])).unwrap_err();
 
-    daemon_main(config, &noise_privkey, &bitcoin_privkey);
+        assert_eq!(
+            parse_args(args(&["cosignerd", "selftest"])).unwrap(),
+            Command::Selftest(None, false)
+        );
+        assert_eq!(
+            parse_args(args(&["cosignerd", "--conf", "/a/config.toml", "selftest"])).unwrap(),
+            Command::Selftest(Some(PathBuf::from("/a/config.toml")), false)
+        );
+        assert_eq!(
+            parse_args(args(&["cosignerd", "selftest", "--offline"])).unwrap(),
+            Command::Selftest(None, true)
+        );
+        assert_eq!(
+            parse_args(args(&[
+                "cosignerd",
+                "--conf",
+                "/a/config.toml",
+                "selftest",
+                "--offline"
+            ]))
+            .unwrap(),
+            Command::Selftest(Some(PathBuf::from("/a/config.toml")), true)
+        );
+        parse_args(args(&["cosignerd", "selftest", "--ofline"])).unwrap_err();
+        parse_args(args(&["cosignerd", "--conf", "/a/config.toml", "selftes"])).unwrap_err();
+
+        assert_eq!(
+            parse_args(args(&["cosignerd", "validate-config"])).unwrap(),
+            Command::ValidateConfig(None)
+        );
+        assert_eq!(
+            parse_args(args(&[
+                "cosignerd",
+                "--conf",
+                "/a/config.toml",
+                "validate-config"
+            ]))
+            .unwrap(),
+            Command::ValidateConfig(Some(PathBuf::from("/a/config.toml")))
+        );
+        assert_eq!(
+            parse_args(args(&["cosignerd", "show-keys"])).unwrap(),
+            Command::ShowKeys(None)
+        );
+        assert_eq!(
+            parse_args(args(&[
+                "cosignerd",
+                "--conf",
+                "/a/config.toml",
+                "show-keys"
+            ]))
+            .unwrap(),
+            Command::ShowKeys(Some(PathBuf::from("/a/config.toml")))
+        );
+        assert_eq!(
+            parse_args(args(&["cosignerd", "backup", "--out", "/a/backup.sqlite3"])).unwrap(),
+            Command::Backup(None, PathBuf::from("/a/backup.sqlite3"))
+        );
+        assert_eq!(
+            parse_args(args(&[
+                "cosignerd",
+                "--conf",
+                "/a/config.toml",
+                "backup",
+                "--out",
+                "/a/backup.sqlite3"
+            ]))
+            .unwrap(),
+            Command::Backup(
+                Some(PathBuf::from("/a/config.toml")),
+                PathBuf::from("/a/backup.sqlite3")
+            )
+        );
+        parse_args(args(&["cosignerd", "backup"])).unwrap_err();
+        parse_args(args(&["cosignerd", "backup", "/a/backup.sqlite3"])).unwrap_err();
+
+        let outpoint = OutPoint::from_str(
+            "2b8930127e9dfd1bcdf35df2bc7f3b8cdbec083b1ae693f36b6305fccd1425da:0",
+        )
+        .unwrap();
+        assert_eq!(
+            parse_args(args(&["cosignerd", "db", "list"])).unwrap(),
+            Command::DbList(None)
+        );
+        assert_eq!(
+            parse_args(args(&[
+                "cosignerd",
+                "--conf",
+                "/a/config.toml",
+                "db",
+                "list"
+            ]))
+            .unwrap(),
+            Command::DbList(Some(PathBuf::from("/a/config.toml")))
+        );
+        assert_eq!(
+            parse_args(args(&["cosignerd", "db", "forget", &outpoint.to_string()])).unwrap(),
+            Command::DbForget(None, outpoint)
+        );
+        assert_eq!(
+            parse_args(args(&[
+                "cosignerd",
+                "--conf",
+                "/a/config.toml",
+                "db",
+                "forget",
+                &outpoint.to_string()
+            ]))
+            .unwrap(),
+            Command::DbForget(Some(PathBuf::from("/a/config.toml")), outpoint)
+        );
+        parse_args(args(&["cosignerd", "db"])).unwrap_err();
+        parse_args(args(&["cosignerd", "db", "forget"])).unwrap_err();
+        parse_args(args(&["cosignerd", "db", "forget", "2b8930127e9dfd1b"])).unwrap_err();
+
+        assert_eq!(
+            parse_args(args(&[
+                "cosignerd",
+                "db-diff",
+                "--a",
+                "/a.sqlite3",
+                "--b",
+                "/b.sqlite3"
+            ]))
+            .unwrap(),
+            Command::DbDiff(PathBuf::from("/a.sqlite3"), PathBuf::from("/b.sqlite3"))
+        );
+        assert_eq!(
+            parse_args(args(&[
+                "cosignerd",
+                "db-diff",
+                "--b",
+                "/b.sqlite3",
+                "--a",
+                "/a.sqlite3"
+            ]))
+            .unwrap(),
+            Command::DbDiff(PathBuf::from("/a.sqlite3"), PathBuf::from("/b.sqlite3"))
+        );
+        parse_args(args(&["cosignerd", "db-diff", "--a", "/a.sqlite3"])).unwrap_err();
+        parse_args(args(&["cosignerd", "db-diff", "--a", "/a.sqlite3", "--b"])).unwrap_err();
+
+        let pubkey = "0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798";
+        assert_eq!(
+            parse_args(args(&[
+                "cosignerd",
+                "verify-signed",
+                "--psbt",
+                "/spend.psbt",
+                "--cosigner-pubkey",
+                pubkey
+            ]))
+            .unwrap(),
+            Command::VerifySigned(
+                PathBuf::from("/spend.psbt"),
+                BitcoinPubkey::from_str(pubkey).unwrap()
+            )
+        );
+        parse_args(args(&[
+            "cosignerd",
+            "verify-signed",
+            "--psbt",
+            "/spend.psbt",
+            "--cosigner-pubkey",
+            "02a1",
+        ]))
+        .unwrap_err();
+        parse_args(args(&[
+            "cosignerd",
+            "verify-signed",
+            "--psbt",
+            "/spend.psbt",
+        ]))
+        .unwrap_err();
+
+        assert_eq!(
+            parse_args(args(&["cosignerd", "--version"])).unwrap(),
+            Command::Version
+        );
+        parse_args(args(&["cosignerd", "--version", "--conf", "/c.toml"])).unwrap_err();
+        assert!(version().starts_with(&format!("cosignerd {} (", env!("CARGO_PKG_VERSION"))));
+        assert!(version().contains(&format!("revault_tx {}", env!("REVAULT_TX_VERSION"))));
+
+        assert_eq!(
+            parse_args(args(&["cosignerd", "generate-config"])).unwrap(),
+            Command::GenerateConfig
+        );
+        parse_args(args(&["cosignerd", "generate-config", "--conf", "/c.toml"])).unwrap_err();
+        let template = config_template();
+        for line in &[
+            "daemon = ",
+            "log_level = ",
+            "data_dir = ",
+            "listen = ",
+            "[[managers]]",
+        ] {
+            assert!(template.lines().any(|l| l.starts_with(line)), "{}", line);
+        }
+
+        assert_eq!(
+            parse_args(args(&[
+                "cosignerd",
+                "encrypt-key",
+                "--key",
+                "/bitcoin_secret",
+                "--passphrase-file",
+                "/passphrase",
+                "--out",
+                "/encrypted_secret"
+            ]))
+            .unwrap(),
+            Command::EncryptKey(
+                PathBuf::from("/bitcoin_secret"),
+                PathBuf::from("/passphrase"),
+                PathBuf::from("/encrypted_secret")
+            )
+        );
+        parse_args(args(&[
+            "cosignerd",
+            "encrypt-key",
+            "--key",
+            "/bitcoin_secret",
+            "--out",
+            "/encrypted_secret",
+        ]))
+        .unwrap_err();
+    }
+
+    #[test]
+    fn config_validation() {
+        let dir = std::env::temp_dir().join(format!("cosignerd-validate-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let conf_path = dir.join("config.toml");
+        let valid = format!(
+            "data_dir = \"{}\"\n\
+             [[managers]]\n\
+             noise_key = \"b28cf2091bbbecf347d29420f884a936713e7b2e86fe4f6653d7e12356d26114\"\n",
+            dir.join("datadir").display()
+        );
+
+        // A malformed Noise key is reported
+        fs::write(&conf_path, valid.replace("b28c", "zz8c")).unwrap();
+        assert!(matches!(
+            validate_config_main(Some(conf_path.clone()), None),
+            Err(Error::Config(..))
+        ));
+
+        // The data directory need not exist yet, we'd create it
+        fs::write(&conf_path, &valid).unwrap();
+        validate_config_main(Some(conf_path.clone()), None).unwrap();
+        assert!(!dir.join("datadir").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn datadir_precedence() {
+        // It's taken out of the arguments, before or after --conf
+        let mut a = args(&["cosignerd", "--datadir", "/d", "show-keys"]);
+        assert_eq!(take_datadir_arg(&mut a).unwrap(), Some(PathBuf::from("/d")));
+        assert_eq!(parse_args(a).unwrap(), Command::ShowKeys(None));
+        let mut a = args(&["cosignerd", "--conf", "/c.toml", "--datadir", "/d"]);
+        assert_eq!(take_datadir_arg(&mut a).unwrap(), Some(PathBuf::from("/d")));
+        assert_eq!(
+            parse_args(a).unwrap(),
+            Command::Daemon(Some(PathBuf::from("/c.toml")))
+        );
+        let mut a = args(&[
+            "cosignerd",
+            "--datadir",
+            "/d",
+            "--conf",
+            "/c.toml",
+            "db",
+            "list",
+        ]);
+        assert_eq!(take_datadir_arg(&mut a).unwrap(), Some(PathBuf::from("/d")));
+        assert_eq!(
+            parse_args(a).unwrap(),
+            Command::DbList(Some(PathBuf::from("/c.toml")))
+        );
+        let mut a = args(&["cosignerd", "show-keys"]);
+        assert_eq!(take_datadir_arg(&mut a).unwrap(), None);
+        assert_eq!(a, args(&["cosignerd", "show-keys"]));
+        // But not after the command, nor without a path
+        take_datadir_arg(&mut args(&["cosignerd", "show-keys", "--datadir", "/d"])).unwrap_err();
+        take_datadir_arg(&mut args(&["cosignerd", "--datadir"])).unwrap_err();
+        // And only with the commands reading the configuration
+        assert!(Command::Backup(None, PathBuf::from("/b")).reads_config());
+        assert!(!Command::GenerateConfig.reads_config());
+
+        // The command line takes precedence over the configuration file, which takes precedence
+        // over the default
+        let dir = std::env::temp_dir().join(format!("cosignerd-datadir-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let conf_path = dir.join("config.toml");
+        let managers = "[[managers]]\n\
+                        noise_key = \"b28cf2091bbbecf347d29420f884a936713e7b2e86fe4f6653d7e12356d26114\"\n";
+        fs::write(
+            &conf_path,
+            format!("data_dir = \"/configured\"\n{}", managers),
+        )
+        .unwrap();
+        let config = load_config(Some(conf_path.clone()), Some(PathBuf::from("/cli"))).unwrap();
+        assert_eq!(config.data_dir, PathBuf::from("/cli"));
+        assert!(config.data_dir_overridden);
+        let config = load_config(Some(conf_path.clone()), None).unwrap();
+        assert_eq!(config.data_dir, PathBuf::from("/configured"));
+        assert!(!config.data_dir_overridden);
+        fs::write(&conf_path, managers).unwrap();
+        let config = load_config(Some(conf_path.clone()), None).unwrap();
+        assert!(config.data_dir.ends_with(".cosignerd"));
+        let config = load_config(Some(conf_path), Some(PathBuf::from("/cli"))).unwrap();
+        assert_eq!(config.data_dir, PathBuf::from("/cli"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn keys_display() {
+        let dir = std::env::temp_dir().join(format!("cosignerd-show-keys-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let (noise_path, bitcoin_path) = (dir.join("noise_secret"), dir.join("bitcoin_secret"));
+        fs::write(&bitcoin_path, [0x42; 32]).unwrap();
+        let keys =
+            Keys::from_files(&noise_path, &bitcoin_path, None, Some(Network::Bitcoin)).unwrap();
+
+        let (noise_pubkey, bitcoin_pubkey) = pubkeys(&keys);
+        let output = show_keys_output(&noise_pubkey, &bitcoin_pubkey);
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(lines.len(), 2);
+        let noise_hex = lines[0].strip_prefix("noise_pubkey ").unwrap();
+        assert_eq!(
+            Vec::<u8>::from_hex(noise_hex).unwrap(),
+            noise_pubkey.0.to_vec()
+        );
+        let bitcoin_hex = lines[1].strip_prefix("bitcoin_pubkey ").unwrap();
+        assert_eq!(
+            BitcoinPubkey::from_str(bitcoin_hex).unwrap(),
+            bitcoin_pubkey
+        );
+
+        // The Noise key is created once and for all
+        let (same_noise_pubkey, _) =
+            pubkeys(&Keys::from_files(&noise_path, &bitcoin_path, None, None).unwrap());
+        assert_eq!(same_noise_pubkey, noise_pubkey);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn db_maintenance() {
+        let dir = std::env::temp_dir().join(format!("cosignerd-db-maint-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let conf_path = dir.join("config.toml");
+        let audit_log = dir.join("audit.log");
+        fs::write(
+            &conf_path,
+            format!(
+                "data_dir = \"{}\"\naudit_log = \"{}\"\n\
+                 [[managers]]\n\
+                 noise_key = \"b28cf2091bbbecf347d29420f884a936713e7b2e86fe4f6653d7e12356d26114\"\n",
+                dir.display(),
+                audit_log.display()
+            ),
+        )
+        .unwrap();
+        let db_path = dir.join("cosignerd.sqlite3");
+        setup_db(&db_path, true).unwrap();
+        let (signed, reserved) = (
+            OutPoint::from_str(
+                "2b8930127e9dfd1bcdf35df2bc7f3b8cdbec083b1ae693f36b6305fccd1425da:0",
+            )
+            .unwrap(),
+            OutPoint::from_str(
+                "ceca4de398c63b29543f8346c09fd7522fd8661ce8bdc0e454e8d6ed8ad46a0d:1",
+            )
+            .unwrap(),
+        );
+        let sig = Signature::from_str(
+            "3045022100bd287d1cc62223e344a4eea99801e15dab6484365d2b4f981\
+             fa7febc0b29cea40220579ec2071c1e5e2dab8a468849214c6cfed2342bb\
+             ffa572327621bad2d894961",
+        )
+        .unwrap();
+        db_insert_signed_outpoint(&db_path, &signed, &sig).unwrap();
+        db_reserve_outpoints(&db_path, &[reserved]).unwrap();
+
+        // Both are listed
+        let signed_at = db_signed_outpoint(&db_path, &signed)
+            .unwrap()
+            .unwrap()
+            .signed_at;
+        assert_eq!(
+            db_list_output(&[(signed, signed_at)], &[reserved]),
+            format!(
+                "{} signed_at={}\n{} reserved\n",
+                signed, signed_at, reserved
+            )
+        );
+
+        // Nothing is forgotten without confirmation
+        let forgotten = db_forget_main(
+            Some(conf_path.clone()),
+            None,
+            &signed,
+            &mut "yes\n".as_bytes(),
+        )
+        .unwrap();
+        assert!(!forgotten);
+        assert!(db_signed_outpoint(&db_path, &signed).unwrap().is_some());
+
+        // Once confirmed it's gone, and the other one is untouched
+        let forgotten = db_forget_main(
+            Some(conf_path.clone()),
+            None,
+            &signed,
+            &mut "forget\n".as_bytes(),
+        )
+        .unwrap();
+        assert!(forgotten);
+        assert!(db_signed_outpoint(&db_path, &signed).unwrap().is_none());
+        assert_eq!(db_reserved_outpoints(&db_path).unwrap(), vec![reserved]);
+        let audit_line: serde_json::Value =
+            serde_json::from_str(fs::read_to_string(&audit_log).unwrap().trim()).unwrap();
+        assert_eq!(audit_line["event"], "forgotten");
+        assert_eq!(audit_line["outpoint"], signed.to_string());
+
+        // Reservations can be forgotten too, but nothing can be twice
+        assert!(db_forget_main(
+            Some(conf_path.clone()),
+            None,
+            &reserved,
+            &mut "forget\n".as_bytes()
+        )
+        .unwrap());
+        assert!(!db_forget_main(
+            Some(conf_path.clone()),
+            None,
+            &reserved,
+            &mut "forget\n".as_bytes()
+        )
+        .unwrap());
+        assert!(db_reserved_outpoints(&db_path).unwrap().is_empty());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn log_timestamps() {
+        let at = |secs| time::UNIX_EPOCH + time::Duration::from_secs(secs);
+
+        assert_eq!(
+            log_timestamp(LogTimestampFormat::Epoch, at(1622548800)),
+            "1622548800"
+        );
+        for (secs, timestamp) in &[
+            (0, "1970-01-01T00:00:00Z"),
+            (951782400, "2000-02-29T00:00:00Z"),
+            (1622548800, "2021-06-01T12:00:00Z"),
+            (1704067199, "2023-12-31T23:59:59Z"),
+        ] {
+            assert_eq!(
+                log_timestamp(LogTimestampFormat::Rfc3339, at(*secs)),
+                *timestamp
+            );
+        }
+    }
 }
@@ -3,10 +3,29 @@
 //! with wallet clients (to set-up noise_KK channels) and bitcoin DescriptorPublicKeys
 //! for each manager (for signature verification of Spend Transactions).
 
+use crate::{
+    daemon::PROTOCOL_VERSION,
+    database::{DB_BUSY_RETRIES_DEFAULT, DB_BUSY_TIMEOUT_MS_DEFAULT},
+};
 use revault_net::noise::PublicKey as NoisePubkey;
-use revault_tx::bitcoin::hashes::hex::FromHex;
+use revault_tx::{
+    bitcoin::{
+        hashes::hex::{FromHex, ToHex},
+        secp256k1, Address, Network, OutPoint, PublicKey as BitcoinPubkey, Script,
+    },
+    miniscript::descriptor::{ConversionError, DescriptorPublicKey},
+    scripts::UnvaultDescriptor,
+};
 
-use std::{env, net::SocketAddr, path::PathBuf, process, str::FromStr, vec::Vec};
+use std::{
+    collections::HashSet,
+    env,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    process,
+    str::FromStr,
+    vec::Vec,
+};
 
 use serde::{de, Deserialize, Deserializer};
 
@@ -16,10 +35,32 @@ where
 {
     let data = String::deserialize(deserializer)?;
     FromHex::from_hex(&data)
-        .map_err(|e| de::Error::custom(e))
+        .map_err(de::Error::custom)
         .map(NoisePubkey)
 }
 
+fn deserialize_xpub<'de, D>(deserializer: D) -> Result<Option<DescriptorPublicKey>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let xpub_str = String::deserialize(deserializer)?;
+    DescriptorPublicKey::from_str(&xpub_str)
+        .map(Some)
+        .map_err(de::Error::custom)
+}
+
+fn deserialize_unvault_descriptor<'de, D>(
+    deserializer: D,
+) -> Result<Option<UnvaultDescriptor>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let desc_str = String::deserialize(deserializer)?;
+    UnvaultDescriptor::from_str(&desc_str)
+        .map(Some)
+        .map_err(de::Error::custom)
+}
+
 fn deserialize_loglevel<'de, D>(deserializer: D) -> Result<log::LevelFilter, D::Error>
 where
     D: Deserializer<'de>,
@@ -28,8 +69,63 @@ where
     log::LevelFilter::from_str(&level_str).map_err(de::Error::custom)
 }
 
-fn listen_default() -> SocketAddr {
-    SocketAddr::from(([127, 0, 0, 1], 8383))
+// Either a single address or a list of them
+fn deserialize_listen<'de, D>(deserializer: D) -> Result<Vec<SocketAddr>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(SocketAddr),
+        Many(Vec<SocketAddr>),
+    }
+
+    let addresses = match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(addr) => vec![addr],
+        OneOrMany::Many(addresses) => addresses,
+    };
+    if addresses.is_empty() {
+        return Err(de::Error::custom(
+            "'listen' must contain at least one address",
+        ));
+    }
+
+    Ok(addresses)
+}
+
+fn deserialize_network<'de, D>(deserializer: D) -> Result<Network, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let network_str = String::deserialize(deserializer)?;
+    Network::from_str(&network_str).map_err(de::Error::custom)
+}
+
+/// How the timestamps of the log lines are written
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogTimestampFormat {
+    /// Seconds since the epoch
+    Epoch,
+    /// A UTC date and time, as in "2021-06-01T12:00:00Z"
+    Rfc3339,
+}
+
+fn log_timestamp_format_default() -> LogTimestampFormat {
+    LogTimestampFormat::Rfc3339
+}
+
+fn log_backups_default() -> u32 {
+    3
+}
+
+fn network_default() -> Network {
+    Network::Bitcoin
+}
+
+fn listen_default() -> Vec<SocketAddr> {
+    vec![SocketAddr::from(([127, 0, 0, 1], 8383))]
 }
 
 fn loglevel_default() -> log::LevelFilter {
@@ -40,10 +136,55 @@ fn daemon_default() -> bool {
     false
 }
 
+fn tcp_nodelay_default() -> bool {
+    true
+}
+
+fn max_spend_inputs_default() -> usize {
+    128
+}
+
+fn config_version_default() -> u32 {
+    CONFIG_VERSION
+}
+
+fn protocol_version_default() -> u32 {
+    PROTOCOL_VERSION
+}
+
+fn db_integrity_check_default() -> bool {
+    true
+}
+
+fn db_busy_timeout_ms_default() -> u64 {
+    DB_BUSY_TIMEOUT_MS_DEFAULT
+}
+
+fn db_busy_retries_default() -> u32 {
+    DB_BUSY_RETRIES_DEFAULT
+}
+
+fn signature_cache_size_default() -> usize {
+    1024
+}
+
+fn requests_per_minute_default() -> u32 {
+    60
+}
+
+fn connection_timeout_default() -> u64 {
+    30
+}
+
 #[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct ManagerConfig {
     #[serde(deserialize_with = "deserialize_noisepubkey")]
     pub noise_key: NoisePubkey,
+    /// The extended key of this manager in the Unvault descriptor, needed to verify its
+    /// signatures
+    #[serde(default, deserialize_with = "deserialize_xpub")]
+    pub xpub: Option<DescriptorPublicKey>,
 }
 
 fn default_datadir_path() -> PathBuf {
@@ -59,32 +200,254 @@ fn default_datadir_path() -> PathBuf {
         })
 }
 
-/// Static informations we require to operate
-#[derive(Debug, Deserialize)]
+/// Static informations we require to operate. Unknown keys are refused, so that a typo doesn't
+/// silently get us running with a default.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct Config {
+    /// The version of the configuration format this file was written for. We refuse to start on
+    /// one written for a newer format than [CONFIG_VERSION], as we may not understand it as
+    /// intended.
+    #[serde(default = "config_version_default")]
+    pub config_version: u32,
     /// The managers', for which we need the Noise static pubkeys
+    #[serde(default)]
     pub managers: Vec<ManagerConfig>,
+    /// An optional file containing more managers entries, see [read_managers_file]
+    #[serde(default)]
+    pub managers_file: Option<PathBuf>,
+    /// Refuse to start with more than this many managers, as it's likely a configuration mistake
+    #[serde(default)]
+    pub max_managers: Option<usize>,
+    /// The Bitcoin network our keys are for
+    #[serde(deserialize_with = "deserialize_network", default = "network_default")]
+    pub network: Network,
     /// An optional custom data directory
     #[serde(default = "default_datadir_path")]
     pub data_dir: PathBuf,
-    /// What interface to listen on
-    #[serde(default = "listen_default")]
-    pub listen: SocketAddr,
+    /// What interface(s) to listen on
+    #[serde(deserialize_with = "deserialize_listen", default = "listen_default")]
+    pub listen: Vec<SocketAddr>,
     /// Whether to daemonize the process
     #[serde(default = "daemon_default")]
     pub daemon: bool,
+    /// Whether to disable Nagle's algorithm on the managers connections. Our exchanges are small
+    /// and latency-sensitive.
+    #[serde(default = "tcp_nodelay_default")]
+    pub tcp_nodelay: bool,
+    /// Drop a manager connection after this many seconds without being able to read or write,
+    /// for a stalling manager not to block the others
+    #[serde(default = "connection_timeout_default")]
+    pub connection_timeout: u64,
     /// What messages to log
     #[serde(
         deserialize_with = "deserialize_loglevel",
         default = "loglevel_default"
     )]
     pub log_level: log::LevelFilter,
+    /// Once daemonized, rotate the log file when it grows past this many bytes
+    #[serde(default)]
+    pub log_max_size: Option<u64>,
+    /// How many rotated log files to keep, as `log.1` (the most recent) to `log.<log_backups>`
+    #[serde(default = "log_backups_default")]
+    pub log_backups: u32,
+    /// How to write the timestamps of the log lines, "epoch" or "rfc3339"
+    #[serde(default = "log_timestamp_format_default")]
+    pub log_timestamp_format: LogTimestampFormat,
+    /// Refuse to sign Spend PSBTs carrying global or input fields a Revault Spend never has
+    /// (proprietary or unknown fields, hash preimages, ..)
+    #[serde(default)]
+    pub reject_unknown_psbt_fields: bool,
+    /// Only count the signatures of the managers (as per their `xpub`) toward
+    /// `require_manager_sigs`, instead of those of any key in the witness script
+    #[serde(default)]
+    pub verify_manager_sigs: bool,
+    /// Refuse a Spend unless each of our inputs carries a valid signature of the manager which
+    /// sent it (as per the `xpub` of the manager whose Noise key it connected with), for setups
+    /// where each manager connects with its own identity
+    #[serde(default)]
+    pub require_sender_sig: bool,
+    /// Drop the connection of a manager making more requests than this per minute. 0 disables
+    /// the limit.
+    #[serde(default = "requests_per_minute_default")]
+    pub requests_per_minute: u32,
+    /// Raise an alert after this many consecutive refusals for the same manager
+    #[serde(default)]
+    pub refusal_alert_threshold: Option<u32>,
+    /// Refuse Spends with a timestamp nLockTime older than this many seconds
+    #[serde(default)]
+    pub max_locktime_age: Option<u64>,
+    /// Refuse Spends with an input not signaling for replaceability
+    #[serde(default)]
+    pub require_rbf: bool,
+    /// Read the whole database at startup so that it's in the OS page cache. This trades memory
+    /// for latency on the first lookups.
+    #[serde(default)]
+    pub warm_db_cache: bool,
+    /// Use the WAL journal mode for the database, for faster concurrent requests. Commits are not
+    /// synced to disk each time in this mode, so a power loss may lose the last signed outpoints.
+    #[serde(default)]
+    pub db_wal: bool,
+    /// Check the database file is not corrupted at startup. This reads it entirely.
+    #[serde(default = "db_integrity_check_default")]
+    pub db_integrity_check: bool,
+    /// How long to wait for a concurrent connection to release its lock on the database, in
+    /// milliseconds, before failing.
+    #[serde(default = "db_busy_timeout_ms_default")]
+    pub db_busy_timeout_ms: u64,
+    /// How many times to retry, with an exponential backoff, a database operation which failed
+    /// because the database was still busy after the timeout.
+    #[serde(default = "db_busy_retries_default")]
+    pub db_busy_retries: u32,
+    /// How many of the signatures we stored to keep in memory, so that retried requests don't
+    /// hit the database. 0 disables the cache.
+    #[serde(default = "signature_cache_size_default")]
+    pub signature_cache_size: usize,
+    /// Refuse to start if the data directory or our key files are accessible to more than their
+    /// owner, instead of only logging a warning.
+    #[serde(default)]
+    pub strict_permissions: bool,
+    /// A file containing the passphrase the Bitcoin key is encrypted under, if it is. If unset,
+    /// the passphrase is read from the COSIGNERD_BITCOIN_KEY_PASSPHRASE environment variable.
+    #[serde(default)]
+    pub bitcoin_key_passphrase_file: Option<PathBuf>,
+    /// An optional path to an append-only log of the security-relevant events
+    #[serde(default)]
+    pub audit_log: Option<PathBuf>,
+    /// Only apply time-based refusals if the system clock is synchronized (eg by NTP)
+    #[serde(default)]
+    pub require_time_sync: bool,
+    /// Refuse to sign unless each input carries at least this many valid signatures, besides
+    /// ours, from keys of its witness script. Managers must not strip their signatures from the
+    /// Spend they send us for this to be enabled.
+    #[serde(default)]
+    pub require_manager_sigs: Option<usize>,
+    /// Refuse Spends whose feerate, in sats/vbyte, would be below this (eg the network minimum
+    /// relay feerate). It's computed using the maximum satisfaction weight.
+    #[serde(default)]
+    pub min_relay_feerate: Option<u64>,
+    /// Refuse Spends whose feerate, in sats/vbyte, would be above this, as they would burn the
+    /// funds in fees. It's computed using the maximum satisfaction weight.
+    #[serde(default)]
+    pub max_feerate: Option<u64>,
+    /// The relative timelock, in blocks, of the Unvault outputs of our vaults. If set, we refuse
+    /// Spends with an input whose Script or nSequence encodes another one.
+    #[serde(default)]
+    pub unvault_csv: Option<u32>,
+    /// The descriptor of the Unvault outputs of our vaults. If set, we refuse Spends with an
+    /// input whose witness Script isn't the one of this descriptor at the input's derivation
+    /// index. We don't check the inputs Scripts otherwise.
+    #[serde(default, deserialize_with = "deserialize_unvault_descriptor")]
+    pub unvault_descriptor: Option<UnvaultDescriptor>,
+    /// Sign only the inputs of a Spend paying to our Unvault descriptor, instead of refusing the
+    /// whole Spend if any input doesn't. Needs `unvault_descriptor`.
+    #[serde(default)]
+    pub partial_signing: bool,
+    /// When asked to sign a Spend of outpoints we signed only some of already, answer with our
+    /// stored signatures for these instead of refusing with a null signature. We never sign the
+    /// others.
+    #[serde(default)]
+    pub return_known_sigs: bool,
+    /// Sign as usual but don't record the outpoints we sign for, so that they may be signed
+    /// again. This defeats the anti-replay protection: it is only meant for integration testing.
+    #[serde(default)]
+    pub dry_run: bool,
+    /// Run as a standby: open the database read-only and refuse every Spend we would sign,
+    /// while refusing the others as the primary would.
+    #[serde(default)]
+    pub read_only: bool,
+    /// Refuse Spends with more inputs than this, to bound the work a single request can make us do
+    #[serde(default = "max_spend_inputs_default")]
+    pub max_spend_inputs: usize,
+    /// The version of the messages the managers send us, to pin it across upgrades. We refuse to
+    /// start if it's not the one we speak, [crate::daemon::PROTOCOL_VERSION].
+    #[serde(default = "protocol_version_default")]
+    pub protocol_version: u32,
+    /// Outpoints we must never sign a Spend for (eg known-compromised deposits)
+    #[serde(default)]
+    pub blacklisted_outpoints: Vec<OutPoint>,
+    /// An optional file containing more outpoints to never sign for, one per line. It's read at
+    /// startup and upon SIGHUP.
+    #[serde(default)]
+    pub blacklisted_outpoints_file: Option<PathBuf>,
+    /// The outpoints last read from `blacklisted_outpoints_file`, if it's set
+    #[serde(skip)]
+    pub file_blacklisted_outpoints: Vec<OutPoint>,
+    /// An optional file containing the only outpoints we may sign for, one per line. It's read at
+    /// startup and upon SIGHUP.
+    #[serde(default)]
+    pub allowed_outpoints_file: Option<PathBuf>,
+    /// The outpoints last read from `allowed_outpoints_file`, if it's set
+    #[serde(skip)]
+    pub allowed_outpoints: Option<HashSet<OutPoint>>,
+    /// An optional file containing the only addresses a Spend may pay to, one per line. It's read
+    /// at startup and upon SIGHUP.
+    #[serde(default)]
+    pub allowed_destinations_file: Option<PathBuf>,
+    /// The Scripts of the addresses last read from `allowed_destinations_file`, if it's set
+    #[serde(skip)]
+    pub allowed_destinations: Option<HashSet<Script>>,
+    /// An optional interface to answer plaintext health checks, and capabilities requests, on
+    #[serde(default)]
+    pub health_listen: Option<SocketAddr>,
+    /// An optional interface to serve Prometheus metrics on, under `/metrics`
+    #[serde(default)]
+    pub metrics_listen: Option<SocketAddr>,
+    /// An optional statsd collector to send the requests, signed and refused counters to
+    #[serde(default)]
+    pub statsd_addr: Option<SocketAddr>,
+    /// Gracefully shut down after running for this many seconds, exiting with
+    /// [crate::daemon::SCHEDULED_RESTART_EXIT_CODE] for a supervisor to restart us
+    #[serde(default)]
+    pub max_uptime_secs: Option<u64>,
+    /// Forget about the outpoints we signed more than this many seconds ago. Their Spend must be
+    /// confirmed by then for the anti-replay to hold.
+    #[serde(default)]
+    pub prune_after: Option<u64>,
+    /// The file this configuration was read from, if any. This is where we reload it from.
+    #[serde(skip)]
+    pub config_file: Option<PathBuf>,
+    /// Whether `data_dir` was given on the command line instead, see [Config::override_data_dir]
+    #[serde(skip)]
+    pub data_dir_overridden: bool,
+}
+
+// The content of the managers file
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ManagersFile {
+    managers: Vec<ManagerConfig>,
 }
 
 #[derive(Debug)]
 pub enum ConfigError {
     ReadingConfigFile(std::io::Error),
     ParsingConfigFile(toml::de::Error),
+    ReadingManagersFile(std::io::Error),
+    ParsingManagersFile(String),
+    NoManagers,
+    TooManyManagers(usize, usize),
+    DuplicateManagerKey(NoisePubkey),
+    /// `verify_manager_sigs` is set but no threshold is
+    MissingManagerSigsThreshold,
+    /// `verify_manager_sigs`, `require_sender_sig` or `allowed_destinations_file` is set but this
+    /// manager has no xpub
+    MissingManagerXpub(NoisePubkey),
+    /// `min_relay_feerate` is above `max_feerate`
+    InvalidFeerateRange(u64, u64),
+    /// `partial_signing` is set but we can't tell our inputs apart without `unvault_descriptor`
+    MissingUnvaultDescriptor,
+    /// `unvault_csv` isn't the relative timelock of `unvault_descriptor` (`unvault_csv`, the
+    /// descriptor's)
+    UnvaultCsvMismatch(u32, u32),
+    ReadingOutpointsFile(std::io::Error),
+    ParsingOutpointsFile(String),
+    ReadingAddressesFile(std::io::Error),
+    ParsingAddressesFile(String),
+    /// The configured `protocol_version` isn't the one we speak
+    UnsupportedProtocolVersion(u32),
+    /// The configuration was written for a newer format than ours
+    UnsupportedConfigVersion(u32),
 }
 
 impl std::fmt::Display for ConfigError {
@@ -92,12 +455,134 @@ impl std::fmt::Display for ConfigError {
         match self {
             Self::ReadingConfigFile(e) => write!(f, "Error when reading config file: '{}'", e),
             Self::ParsingConfigFile(e) => write!(f, "Error when reading config file: '{}'", e),
+            Self::ReadingManagersFile(e) => write!(f, "Error when reading managers file: '{}'", e),
+            Self::ParsingManagersFile(e) => write!(f, "Error when parsing managers file: '{}'", e),
+            Self::NoManagers => write!(f, "No manager configured"),
+            Self::TooManyManagers(n, max) => write!(
+                f,
+                "{} managers configured, but at most {} are expected (max_managers)",
+                n, max
+            ),
+            Self::DuplicateManagerKey(key) => write!(
+                f,
+                "Noise key '{}' is configured for more than one manager",
+                key.0.to_hex()
+            ),
+            Self::MissingManagerSigsThreshold => write!(
+                f,
+                "'verify_manager_sigs' is set without 'require_manager_sigs'"
+            ),
+            Self::MissingManagerXpub(key) => write!(
+                f,
+                "'verify_manager_sigs', 'require_sender_sig' or 'allowed_destinations_file' is set \
+                 but manager '{}' has no 'xpub'",
+                key.0.to_hex()
+            ),
+            Self::InvalidFeerateRange(min, max) => write!(
+                f,
+                "'min_relay_feerate' ({}) is above 'max_feerate' ({})",
+                min, max
+            ),
+            Self::MissingUnvaultDescriptor => {
+                write!(f, "'partial_signing' is set without 'unvault_descriptor'")
+            }
+            Self::UnvaultCsvMismatch(csv, desc_csv) => write!(
+                f,
+                "'unvault_csv' ({}) isn't the relative timelock of 'unvault_descriptor' ({})",
+                csv, desc_csv
+            ),
+            Self::ReadingOutpointsFile(e) => {
+                write!(f, "Error when reading outpoints file: '{}'", e)
+            }
+            Self::ParsingOutpointsFile(e) => {
+                write!(f, "Error when parsing outpoints file: '{}'", e)
+            }
+            Self::ReadingAddressesFile(e) => {
+                write!(f, "Error when reading addresses file: '{}'", e)
+            }
+            Self::ParsingAddressesFile(e) => {
+                write!(f, "Error when parsing addresses file: '{}'", e)
+            }
+            Self::UnsupportedProtocolVersion(v) => write!(
+                f,
+                "Protocol version {} is not supported, we only speak version {}",
+                v, PROTOCOL_VERSION
+            ),
+            Self::UnsupportedConfigVersion(v) => write!(
+                f,
+                "Configuration version {} is not supported, we only understand up to version {}",
+                v, CONFIG_VERSION
+            ),
         }
     }
 }
 
 impl std::error::Error for ConfigError {}
 
+/// Read the managers entries of this file. It's a TOML file with `[[managers]]` sections, or a
+/// JSON one with a `managers` array of the same entries if its extension is `.json`.
+pub fn read_managers_file(path: &Path) -> Result<Vec<ManagerConfig>, ConfigError> {
+    let content = std::fs::read(path).map_err(ConfigError::ReadingManagersFile)?;
+
+    let ManagersFile { managers } = if path.extension() == Some("json".as_ref()) {
+        serde_json::from_slice(&content)
+            .map_err(|e| ConfigError::ParsingManagersFile(e.to_string()))?
+    } else {
+        toml::from_slice(&content).map_err(|e| ConfigError::ParsingManagersFile(e.to_string()))?
+    };
+    Ok(managers)
+}
+
+/// Read a list of outpoints from a file, one `txid:vout` per line. Empty lines and lines starting
+/// with a '#' are ignored.
+pub fn read_outpoints_file(path: &Path) -> Result<Vec<OutPoint>, ConfigError> {
+    let content = std::fs::read_to_string(path).map_err(ConfigError::ReadingOutpointsFile)?;
+
+    content
+        .lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| {
+            OutPoint::from_str(line).map_err(|e| {
+                ConfigError::ParsingOutpointsFile(format!("invalid outpoint '{}': {}", line, e))
+            })
+        })
+        .collect()
+}
+
+// Whether this address may be used on this network. Testnet, signet and regtest share their
+// base58 prefixes, and testnet and signet their bech32 one.
+fn address_is_for_network(address: &Address, network: Network) -> bool {
+    match (address.network, network) {
+        (Network::Testnet, Network::Signet) | (Network::Testnet, Network::Regtest) => true,
+        (address_network, network) => address_network == network,
+    }
+}
+
+/// Read a list of addresses for this network from a file, one per line, as the Scripts they pay
+/// to. Empty lines and lines starting with a '#' are ignored.
+pub fn read_addresses_file(path: &Path, network: Network) -> Result<Vec<Script>, ConfigError> {
+    let content = std::fs::read_to_string(path).map_err(ConfigError::ReadingAddressesFile)?;
+
+    content
+        .lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| {
+            let address = Address::from_str(line).map_err(|e| {
+                ConfigError::ParsingAddressesFile(format!("invalid address '{}': {}", line, e))
+            })?;
+            if !address_is_for_network(&address, network) {
+                return Err(ConfigError::ParsingAddressesFile(format!(
+                    "address '{}' is not for network '{}'",
+                    line, network
+                )));
+            }
+            Ok(address.script_pubkey())
+        })
+        .collect()
+}
+
 /// Get the default path to our config file, inside the data directory
 pub fn default_config_file_path() -> PathBuf {
     let mut path = default_datadir_path();
@@ -105,24 +590,154 @@ pub fn default_config_file_path() -> PathBuf {
     path
 }
 
+/// The version of the configuration format we understand. Bump it along with changes older
+/// versions would misinterpret.
+pub const CONFIG_VERSION: u32 = 1;
+
+/// The placeholder for the managers Noise keys in the configuration template
+pub const NOISE_KEY_PLACEHOLDER: &str = "<manager Noise key in hex, as given by revaultd>";
+
+/// A commented configuration file to start from, with our defaults. The placeholder managers
+/// entries must be replaced for it to be valid.
+pub fn config_template() -> String {
+    let listen = listen_default()
+        .iter()
+        .map(|addr| format!("\"{}\"", addr))
+        .collect::<Vec<String>>()
+        .join(", ");
+
+    format!(
+        r#"# The version of this configuration format
+config_version = {config_version}
+# Whether to daemonize the process
+daemon = {daemon}
+# The Bitcoin network our keys are for: "bitcoin", "testnet", "signet" or "regtest"
+network = "{network}"
+# One of "off", "error", "warn", "info", "debug" or "trace"
+log_level = "{log_level}"
+# Where we store our keys and database
+data_dir = "{data_dir}"
+# The interface(s) the managers connect to
+listen = [{listen}]
+
+# You'll need a [[managers]] section for each manager you have
+[[managers]]
+noise_key = "{placeholder}"
+
+[[managers]]
+noise_key = "{placeholder}"
+"#,
+        config_version = CONFIG_VERSION,
+        daemon = daemon_default(),
+        network = network_default(),
+        log_level = loglevel_default().to_string().to_lowercase(),
+        data_dir = default_datadir_path().display(),
+        listen = listen,
+        placeholder = NOISE_KEY_PLACEHOLDER,
+    )
+}
+
 impl Config {
     /// Get our static configuration out of a mandatory configuration file.
     ///
     /// We require all settings to be set in the configuration file, and only in the configuration
-    /// file. We don't allow to set them via the command line or environment variables to avoid a
-    /// futile duplication.
+    /// file, to avoid a futile duplication. The only exception is the data directory, which the
+    /// caller may override afterward (see [Config::override_data_dir]), eg for a `--datadir`
+    /// given on the command line.
     pub fn from_file(custom_path: Option<PathBuf>) -> Result<Config, ConfigError> {
-        let config_file = custom_path.unwrap_or_else(|| default_config_file_path());
+        let config_file = custom_path.unwrap_or_else(default_config_file_path);
 
-        let config = std::fs::read(&config_file)
-            .map_err(ConfigError::ReadingConfigFile)
-            .and_then(|file_content| {
-                toml::from_slice::<Config>(&file_content).map_err(ConfigError::ParsingConfigFile)
-            })?;
+        let file_content = std::fs::read(&config_file).map_err(ConfigError::ReadingConfigFile)?;
 
+        let mut config = Config::from_toml(&file_content)?;
+        config.config_file = Some(config_file);
         Ok(config)
     }
 
+    // Parse the configuration file content, and load the managers from the managers file if any.
+    fn from_toml(file_content: &[u8]) -> Result<Config, ConfigError> {
+        let mut config =
+            toml::from_slice::<Config>(file_content).map_err(ConfigError::ParsingConfigFile)?;
+
+        if let Some(ref managers_file) = config.managers_file {
+            let managers = read_managers_file(managers_file)?;
+            config.managers.extend(managers);
+        }
+        config.load_blacklisted_outpoints()?;
+        config.load_allowed_outpoints()?;
+        config.load_allowed_destinations()?;
+
+        if config.managers.is_empty() {
+            return Err(ConfigError::NoManagers);
+        }
+        if let Some(max_managers) = config.max_managers {
+            if config.managers.len() > max_managers {
+                return Err(ConfigError::TooManyManagers(
+                    config.managers.len(),
+                    max_managers,
+                ));
+            }
+        }
+        // We couldn't tell them apart
+        let mut noise_keys = HashSet::with_capacity(config.managers.len());
+        if let Some(manager) = config
+            .managers
+            .iter()
+            .find(|m| !noise_keys.insert(m.noise_key.0))
+        {
+            return Err(ConfigError::DuplicateManagerKey(manager.noise_key));
+        }
+        if config.verify_manager_sigs && config.require_manager_sigs.is_none() {
+            return Err(ConfigError::MissingManagerSigsThreshold);
+        }
+
+        // We need them to check the managers' signatures, and to tell the CPFP output apart from
+        // the destinations
+        if config.verify_manager_sigs
+            || config.require_sender_sig
+            || config.allowed_destinations_file.is_some()
+        {
+            if let Some(manager) = config.managers.iter().find(|m| m.xpub.is_none()) {
+                return Err(ConfigError::MissingManagerXpub(manager.noise_key));
+            }
+        }
+
+        if let (Some(min), Some(max)) = (config.min_relay_feerate, config.max_feerate) {
+            if min > max {
+                return Err(ConfigError::InvalidFeerateRange(min, max));
+            }
+        }
+
+        // We'd have no way to tell which inputs are ours
+        if config.partial_signing && config.unvault_descriptor.is_none() {
+            return Err(ConfigError::MissingUnvaultDescriptor);
+        }
+        if let (Some(csv), Some(desc)) = (config.unvault_csv, &config.unvault_descriptor) {
+            if csv != desc.csv_value() {
+                return Err(ConfigError::UnvaultCsvMismatch(csv, desc.csv_value()));
+            }
+        }
+
+        if config.config_version > CONFIG_VERSION {
+            return Err(ConfigError::UnsupportedConfigVersion(config.config_version));
+        }
+
+        if config.protocol_version != PROTOCOL_VERSION {
+            return Err(ConfigError::UnsupportedProtocolVersion(
+                config.protocol_version,
+            ));
+        }
+
+        Ok(config)
+    }
+
+    /// Use this data directory instead of the configured one (eg as given on the command line).
+    /// The configured one is then ignored upon reload too.
+    pub fn override_data_dir(&mut self, data_dir: PathBuf) {
+        self.data_dir = data_dir;
+        self.data_dir_overridden = true;
+    }
+
     fn file_from_datadir(&self, file_name: &str) -> PathBuf {
         let data_dir_str = self
             .data_dir
@@ -143,11 +758,266 @@ impl Config {
     pub fn db_file(&self) -> PathBuf {
         self.file_from_datadir("cosignerd.sqlite3")
     }
+
+    /// All the outpoints we must never sign for, from the configuration and as last read from the
+    /// deny-list file.
+    pub fn blacklisted_outpoints(&self) -> Vec<OutPoint> {
+        self.blacklisted_outpoints
+            .iter()
+            .chain(self.file_blacklisted_outpoints.iter())
+            .copied()
+            .collect()
+    }
+
+    /// Read the blacklisted outpoints from `blacklisted_outpoints_file` again, if it's set. They
+    /// are left untouched on error.
+    pub fn load_blacklisted_outpoints(&mut self) -> Result<(), ConfigError> {
+        self.file_blacklisted_outpoints = match self.blacklisted_outpoints_file {
+            Some(ref path) => read_outpoints_file(path)?,
+            None => Vec::new(),
+        };
+
+        Ok(())
+    }
+
+    /// Read the allowed outpoints from `allowed_outpoints_file` again, if it's set. They are left
+    /// untouched on error.
+    pub fn load_allowed_outpoints(&mut self) -> Result<(), ConfigError> {
+        self.allowed_outpoints = self
+            .allowed_outpoints_file
+            .as_ref()
+            .map(|path| read_outpoints_file(path).map(|outpoints| outpoints.into_iter().collect()))
+            .transpose()?;
+
+        Ok(())
+    }
+
+    /// Read the allowed destinations from `allowed_destinations_file` again, if it's set. They are
+    /// left untouched on error.
+    pub fn load_allowed_destinations(&mut self) -> Result<(), ConfigError> {
+        let network = self.network;
+        self.allowed_destinations = self
+            .allowed_destinations_file
+            .as_ref()
+            .map(|path| {
+                read_addresses_file(path, network).map(|scripts| scripts.into_iter().collect())
+            })
+            .transpose()?;
+
+        Ok(())
+    }
+
+    /// The extended keys of the managers which have one configured, in order
+    pub fn manager_xpubs(&self) -> Vec<DescriptorPublicKey> {
+        self.managers
+            .iter()
+            .filter_map(|m| m.xpub.clone())
+            .collect()
+    }
+
+    /// The public keys of the managers at this derivation index (for the wildcard ones)
+    pub fn derived_manager_pubkeys(
+        &self,
+        index: u32,
+        secp: &secp256k1::Secp256k1<secp256k1::All>,
+    ) -> Result<Vec<BitcoinPubkey>, ConversionError> {
+        self.manager_xpubs()
+            .into_iter()
+            .map(|xpub| xpub.derive(index).derive_public_key(secp))
+            .collect()
+    }
+
+    /// The signing policies in effect, and their limits, as a JSON object. This lets a manager
+    /// know which Spends we would refuse without wasting a round trip: it's served to the
+    /// `CAPABILITIES` request of the health endpoint. The outpoints and destinations lists are
+    /// only reported as being in effect or not.
+    pub fn capabilities(&self) -> serde_json::Value {
+        serde_json::json!({
+            "read_only": self.read_only,
+            "blacklisted_outpoints": !self.blacklisted_outpoints.is_empty()
+                || self.blacklisted_outpoints_file.is_some(),
+            "allowed_outpoints": self.allowed_outpoints.is_some(),
+            "allowed_destinations": self.allowed_destinations.is_some(),
+            "reject_unknown_psbt_fields": self.reject_unknown_psbt_fields,
+            "max_locktime_age": self.max_locktime_age,
+            "require_rbf": self.require_rbf,
+            "require_time_sync": self.require_time_sync,
+            "require_manager_sigs": self.require_manager_sigs,
+            "verify_manager_sigs": self.verify_manager_sigs,
+            "require_sender_sig": self.require_sender_sig,
+            "min_relay_feerate": self.min_relay_feerate,
+            "max_feerate": self.max_feerate,
+            "unvault_csv": self.unvault_csv,
+            "unvault_descriptor": self.unvault_descriptor.as_ref().map(|d| d.to_string()),
+            "partial_signing": self.partial_signing,
+            "return_known_sigs": self.return_known_sigs,
+            "max_spend_inputs": self.max_spend_inputs,
+            "protocol_version": self.protocol_version,
+        })
+    }
+
+    /// Every signing-relevant setting with its resolved value, as a JSON object. This is what we
+    /// log at startup, and a superset of the capabilities we advertise (with the sizes of the
+    /// lists instead).
+    pub fn policy(&self) -> serde_json::Value {
+        let mut policy = self.capabilities();
+        let extra = serde_json::json!({
+            "blacklisted_outpoints": self.blacklisted_outpoints.len(),
+            "blacklisted_outpoints_file": self.blacklisted_outpoints_file,
+            "allowed_outpoints": self.allowed_outpoints.as_ref().map(|o| o.len()),
+            "allowed_destinations": self.allowed_destinations.as_ref().map(|d| d.len()),
+            "refusal_alert_threshold": self.refusal_alert_threshold,
+            "prune_after": self.prune_after,
+            "managers": self.managers.len(),
+            "max_managers": self.max_managers,
+            "requests_per_minute": self.requests_per_minute,
+            "dry_run": self.dry_run,
+        });
+        if let (Some(policy), serde_json::Value::Object(extra)) = (policy.as_object_mut(), extra) {
+            policy.extend(extra);
+        }
+
+        policy
+    }
+
+    /// The names of the settings which differ in this other configuration, for those a reload
+    /// doesn't apply: all of them but the managers, and the ones checked or reloaded on their
+    /// own.
+    pub fn changed_settings(&self, other: &Config) -> Vec<&'static str> {
+        // Destructured so that a new setting can't be forgotten here
+        let Config {
+            config_version,
+            max_managers,
+            network,
+            daemon,
+            tcp_nodelay,
+            connection_timeout,
+            log_level,
+            log_max_size,
+            log_backups,
+            log_timestamp_format,
+            reject_unknown_psbt_fields,
+            verify_manager_sigs,
+            require_sender_sig,
+            requests_per_minute,
+            refusal_alert_threshold,
+            max_locktime_age,
+            require_rbf,
+            warm_db_cache,
+            db_wal,
+            db_integrity_check,
+            db_busy_timeout_ms,
+            db_busy_retries,
+            signature_cache_size,
+            strict_permissions,
+            bitcoin_key_passphrase_file,
+            audit_log,
+            require_time_sync,
+            require_manager_sigs,
+            min_relay_feerate,
+            max_feerate,
+            unvault_csv,
+            unvault_descriptor,
+            partial_signing,
+            return_known_sigs,
+            dry_run,
+            read_only,
+            max_spend_inputs,
+            protocol_version,
+            blacklisted_outpoints,
+            blacklisted_outpoints_file,
+            allowed_outpoints_file,
+            allowed_destinations_file,
+            health_listen,
+            metrics_listen,
+            statsd_addr,
+            max_uptime_secs,
+            prune_after,
+            managers: _,
+            managers_file: _,
+            data_dir: _,
+            listen: _,
+            file_blacklisted_outpoints: _,
+            allowed_outpoints: _,
+            allowed_destinations: _,
+            config_file: _,
+            data_dir_overridden: _,
+        } = other;
+
+        let mut changed = Vec::new();
+        macro_rules! compare {
+            ($($field:ident),*) => {
+                $(if format!("{:?}", self.$field) != format!("{:?}", $field) {
+                    changed.push(stringify!($field));
+                })*
+            };
+        }
+        compare!(
+            config_version,
+            max_managers,
+            network,
+            daemon,
+            tcp_nodelay,
+            connection_timeout,
+            log_level,
+            log_max_size,
+            log_backups,
+            log_timestamp_format,
+            reject_unknown_psbt_fields,
+            verify_manager_sigs,
+            require_sender_sig,
+            requests_per_minute,
+            refusal_alert_threshold,
+            max_locktime_age,
+            require_rbf,
+            warm_db_cache,
+            db_wal,
+            db_integrity_check,
+            db_busy_timeout_ms,
+            db_busy_retries,
+            signature_cache_size,
+            strict_permissions,
+            bitcoin_key_passphrase_file,
+            audit_log,
+            require_time_sync,
+            require_manager_sigs,
+            min_relay_feerate,
+            max_feerate,
+            unvault_csv,
+            unvault_descriptor,
+            partial_signing,
+            return_known_sigs,
+            dry_run,
+            read_only,
+            max_spend_inputs,
+            protocol_version,
+            blacklisted_outpoints,
+            blacklisted_outpoints_file,
+            allowed_outpoints_file,
+            allowed_destinations_file,
+            health_listen,
+            metrics_listen,
+            statsd_addr,
+            max_uptime_secs,
+            prune_after
+        );
+
+        changed
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{default_config_file_path, Config};
+    use super::{
+        config_template, default_config_file_path, read_addresses_file, Config, ConfigError,
+        LogTimestampFormat, CONFIG_VERSION, NOISE_KEY_PLACEHOLDER,
+    };
+    use crate::tests::builder::CosignerTestBuilder;
+    use revault_tx::{
+        bitcoin::{hashes::hex::ToHex, secp256k1, util::bip32, Address, Network, OutPoint},
+        miniscript::descriptor::DescriptorPublicKey,
+    };
+    use std::{net::SocketAddr, str::FromStr};
 
     // Test the format of the configuration file
     #[test]
@@ -168,15 +1038,24 @@ mod tests {
             xpub = "xpub6BHATNyFVsBD8MRygTsv2q9WFTJzEB3o6CgJK7sjopcB286bmWFkNYm6kK5fzVe2gk4mJrSK5isFSFommNDST3RYJWSzrAe9V4bEzboHqnA"
             noise_key = "653bf272f7b691a0fa58fd9736693fbc09f18fc8648a66be6341ef7f3b1254f7"
         "#;
-        let _config: Config = toml::from_str(toml_str).expect("Deserializing toml_str");
+        let config: Config = toml::from_str(toml_str).expect("Deserializing toml_str");
+        let xpubs = config.manager_xpubs();
+        assert_eq!(xpubs.len(), 3);
+        assert_eq!(xpubs[0].to_string(), "xpub6AtVcKWPpZ9t3Aa3VvzWid1dzJFeXPfNntPbkGsYjNrp7uhXpzSL5QVMCmaHqUzbVUGENEwbBbzF9E8emTxQeP3AzbMjfzvwSDkwUrxg2G4");
+
+        // A malformed xpub is rejected
+        let invalid = toml_str.replace("xpub6AtVcKWPpZ9t3Aa3VvzW", "xpub6AtVcKWPpZ9t3Aa3VvzX");
+        assert!(matches!(
+            Config::from_toml(invalid.as_bytes()),
+            Err(ConfigError::ParsingConfigFile(..))
+        ));
 
         // Missing field "managers", will result in error
         let toml_str = r#"
             [cosigner_keys]
             pubkey = "02644cf9e2b78feb0a751e50502f530a4cbd0bbda3020779605391e71654dd66c2" 
         "#;
-        let config_res: Result<Config, toml::de::Error> = toml::from_str(toml_str);
-        config_res.expect_err("Deserializing an invalid toml_str");
+        Config::from_toml(toml_str.as_bytes()).expect_err("Deserializing an invalid toml_str");
     }
 
     #[test]
@@ -186,4 +1065,765 @@ mod tests {
         assert!(filepath.as_path().starts_with("/home/"));
         assert!(filepath.as_path().ends_with(".cosignerd/config.toml"));
     }
+
+    #[test]
+    fn capabilities_reflect_config() {
+        let toml_str = r#"
+            reject_unknown_psbt_fields = true
+
+            [[managers]]
+            noise_key = "91526407c80aa457ce89e8faef1bef2e7c7e303ae2f578e5e4f33465cbb9d0a9"
+        "#;
+        let config: Config = toml::from_str(toml_str).expect("Deserializing toml_str");
+        assert_eq!(
+            config.capabilities(),
+            serde_json::json!({
+                "read_only": false,
+                "blacklisted_outpoints": false,
+                "allowed_outpoints": false,
+                "allowed_destinations": false,
+                "reject_unknown_psbt_fields": true,
+                "max_locktime_age": null,
+                "require_rbf": false,
+                "require_time_sync": false,
+                "require_manager_sigs": null,
+                "verify_manager_sigs": false,
+                "require_sender_sig": false,
+                "min_relay_feerate": null,
+                "max_feerate": null,
+                "unvault_csv": null,
+                "unvault_descriptor": null,
+                "partial_signing": false,
+                "return_known_sigs": false,
+                "max_spend_inputs": 128,
+                "protocol_version": 0,
+            })
+        );
+
+        let toml_str = r#"
+            [[managers]]
+            noise_key = "91526407c80aa457ce89e8faef1bef2e7c7e303ae2f578e5e4f33465cbb9d0a9"
+        "#;
+        let config: Config = toml::from_str(toml_str).expect("Deserializing toml_str");
+        assert_eq!(
+            config.capabilities()["reject_unknown_psbt_fields"],
+            serde_json::json!(false)
+        );
+    }
+
+    #[test]
+    fn blacklisted_outpoints() {
+        let toml_str = r#"
+            blacklisted_outpoints = ["2b8930127e9dfd1bcdf35df2bc7f3b8cdbec083b1ae693f36b6305fccd1425da:0"]
+
+            [[managers]]
+            noise_key = "91526407c80aa457ce89e8faef1bef2e7c7e303ae2f578e5e4f33465cbb9d0a9"
+        "#;
+        let config: Config = toml::from_str(toml_str).expect("Deserializing toml_str");
+        assert_eq!(
+            config.blacklisted_outpoints(),
+            vec![OutPoint::from_str(
+                "2b8930127e9dfd1bcdf35df2bc7f3b8cdbec083b1ae693f36b6305fccd1425da:0"
+            )
+            .unwrap()]
+        );
+
+        let toml_str = r#"
+            blacklisted_outpoints = ["2b8930127e9dfd1bcdf35df2bc7f3b8cdbec083b1ae693f36b6305fccd1425da"]
+
+            [[managers]]
+            noise_key = "91526407c80aa457ce89e8faef1bef2e7c7e303ae2f578e5e4f33465cbb9d0a9"
+        "#;
+        toml::from_str::<Config>(toml_str).expect_err("Missing vout");
+    }
+
+    #[test]
+    fn blacklisted_outpoints_file() {
+        let data_dir =
+            std::env::temp_dir().join(format!("cosignerd-blacklist-{}", std::process::id()));
+        std::fs::create_dir_all(&data_dir).unwrap();
+        let blacklist_file = data_dir.join("blacklist");
+        let toml_str = format!(
+            r#"
+            blacklisted_outpoints = ["2b8930127e9dfd1bcdf35df2bc7f3b8cdbec083b1ae693f36b6305fccd1425da:0"]
+            blacklisted_outpoints_file = {:?}
+
+            [[managers]]
+            noise_key = "91526407c80aa457ce89e8faef1bef2e7c7e303ae2f578e5e4f33465cbb9d0a9"
+        "#,
+            blacklist_file
+        );
+
+        // A missing or invalid file fails at startup, not when signing
+        assert!(matches!(
+            Config::from_toml(toml_str.as_bytes()),
+            Err(ConfigError::ReadingOutpointsFile(_))
+        ));
+        std::fs::write(&blacklist_file, "not an outpoint\n").unwrap();
+        assert!(matches!(
+            Config::from_toml(toml_str.as_bytes()),
+            Err(ConfigError::ParsingOutpointsFile(_))
+        ));
+
+        // Otherwise it's loaded along with the inline ones
+        std::fs::write(
+            &blacklist_file,
+            "# Compromised\nceca4de398c63b29543f8346c09fd7522fd8661ce8bdc0e454e8d6ed8ad46a0d:1\n",
+        )
+        .unwrap();
+        let mut config = Config::from_toml(toml_str.as_bytes()).unwrap();
+        assert_eq!(config.blacklisted_outpoints().len(), 2);
+
+        // And reloaded on demand, keeping the last ones if the file got broken
+        std::fs::write(&blacklist_file, "").unwrap();
+        config.load_blacklisted_outpoints().unwrap();
+        assert_eq!(config.blacklisted_outpoints().len(), 1);
+        std::fs::remove_file(&blacklist_file).unwrap();
+        config.load_blacklisted_outpoints().unwrap_err();
+        assert_eq!(config.blacklisted_outpoints().len(), 1);
+
+        std::fs::remove_dir_all(&data_dir).unwrap();
+    }
+
+    #[test]
+    fn allowed_destinations_file() {
+        let data_dir =
+            std::env::temp_dir().join(format!("cosignerd-destinations-{}", std::process::id()));
+        std::fs::create_dir_all(&data_dir).unwrap();
+        let destinations_file = data_dir.join("destinations");
+        std::fs::write(
+            &destinations_file,
+            "# Cold storage\nbc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4\n\n",
+        )
+        .unwrap();
+        let toml_str = format!(
+            r#"
+            allowed_destinations_file = {:?}
+
+            [[managers]]
+            noise_key = "91526407c80aa457ce89e8faef1bef2e7c7e303ae2f578e5e4f33465cbb9d0a9"
+            xpub = "xpub6AtVcKWPpZ9t3Aa3VvzWid1dzJFeXPfNntPbkGsYjNrp7uhXpzSL5QVMCmaHqUzbVUGENEwbBbzF9E8emTxQeP3AzbMjfzvwSDkwUrxg2G4"
+        "#,
+            destinations_file
+        );
+        let config = Config::from_toml(toml_str.as_bytes()).unwrap();
+        let destinations = config.allowed_destinations.unwrap();
+        assert_eq!(destinations.len(), 1);
+        assert!(destinations.contains(
+            &Address::from_str("bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4")
+                .unwrap()
+                .script_pubkey()
+        ));
+
+        // A testnet address is fine on signet, but not on mainnet
+        std::fs::write(
+            &destinations_file,
+            "tb1qw508d6qejxtdg4y5r3zarvary0c5xw7kxpjzsx\n",
+        )
+        .unwrap();
+        assert!(matches!(
+            Config::from_toml(toml_str.as_bytes()),
+            Err(ConfigError::ParsingAddressesFile(_))
+        ));
+        assert_eq!(
+            read_addresses_file(&destinations_file, Network::Signet)
+                .unwrap()
+                .len(),
+            1
+        );
+
+        // Nor is garbage
+        std::fs::write(&destinations_file, "bc1qw508d6qejxtdg4\n").unwrap();
+        assert!(matches!(
+            Config::from_toml(toml_str.as_bytes()),
+            Err(ConfigError::ParsingAddressesFile(_))
+        ));
+
+        // We can't tell the CPFP output apart without the managers' xpubs
+        std::fs::write(
+            &destinations_file,
+            "bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4\n",
+        )
+        .unwrap();
+        let toml_str = format!(
+            r#"
+            allowed_destinations_file = {:?}
+
+            [[managers]]
+            noise_key = "91526407c80aa457ce89e8faef1bef2e7c7e303ae2f578e5e4f33465cbb9d0a9"
+        "#,
+            destinations_file
+        );
+        assert!(matches!(
+            Config::from_toml(toml_str.as_bytes()),
+            Err(ConfigError::MissingManagerXpub(_))
+        ));
+
+        std::fs::remove_dir_all(&data_dir).unwrap();
+    }
+
+    #[test]
+    fn managers_file() {
+        let data_dir =
+            std::env::temp_dir().join(format!("cosignerd-managers-{}", std::process::id()));
+        std::fs::create_dir_all(&data_dir).unwrap();
+        let managers_file = data_dir.join("managers.toml");
+        std::fs::write(
+            &managers_file,
+            r#"
+            [[managers]]
+            noise_key = "72c9be5363932b1aeaf1d8fa4bf0047b4e03c6e7e2f8db4c64876dc176b986cf"
+            [[managers]]
+            noise_key = "653bf272f7b691a0fa58fd9736693fbc09f18fc8648a66be6341ef7f3b1254f7"
+        "#,
+        )
+        .unwrap();
+
+        // Only from the file
+        let toml_str = format!("managers_file = {:?}", managers_file);
+        let config = Config::from_toml(toml_str.as_bytes()).unwrap();
+        assert_eq!(config.managers.len(), 2);
+
+        // In addition to the inline ones
+        let toml_str = format!(
+            r#"
+            managers_file = {:?}
+
+            [[managers]]
+            noise_key = "91526407c80aa457ce89e8faef1bef2e7c7e303ae2f578e5e4f33465cbb9d0a9"
+        "#,
+            managers_file
+        );
+        let config = Config::from_toml(toml_str.as_bytes()).unwrap();
+        assert_eq!(
+            config
+                .managers
+                .iter()
+                .map(|m| m.noise_key.0.to_hex())
+                .collect::<Vec<String>>(),
+            vec![
+                "91526407c80aa457ce89e8faef1bef2e7c7e303ae2f578e5e4f33465cbb9d0a9",
+                "72c9be5363932b1aeaf1d8fa4bf0047b4e03c6e7e2f8db4c64876dc176b986cf",
+                "653bf272f7b691a0fa58fd9736693fbc09f18fc8648a66be6341ef7f3b1254f7",
+            ]
+        );
+
+        // An invalid managers file
+        std::fs::write(
+            &managers_file,
+            r#"
+            [[managers]]
+            noise_key = "72c9be5363"
+        "#,
+        )
+        .unwrap();
+        let toml_str = format!("managers_file = {:?}", managers_file);
+        assert!(matches!(
+            Config::from_toml(toml_str.as_bytes()),
+            Err(ConfigError::ParsingManagersFile(_))
+        ));
+
+        // A manager can't be in both
+        std::fs::write(
+            &managers_file,
+            r#"
+            [[managers]]
+            noise_key = "91526407c80aa457ce89e8faef1bef2e7c7e303ae2f578e5e4f33465cbb9d0a9"
+        "#,
+        )
+        .unwrap();
+        let toml_str = format!(
+            r#"
+            managers_file = {:?}
+
+            [[managers]]
+            noise_key = "91526407c80aa457ce89e8faef1bef2e7c7e303ae2f578e5e4f33465cbb9d0a9"
+        "#,
+            managers_file
+        );
+        assert!(matches!(
+            Config::from_toml(toml_str.as_bytes()),
+            Err(ConfigError::DuplicateManagerKey(key))
+                if key.0.to_hex() == "91526407c80aa457ce89e8faef1bef2e7c7e303ae2f578e5e4f33465cbb9d0a9"
+        ));
+
+        // A JSON one
+        let json_file = data_dir.join("managers.json");
+        std::fs::write(
+            &json_file,
+            r#"{"managers": [
+                {"noise_key": "72c9be5363932b1aeaf1d8fa4bf0047b4e03c6e7e2f8db4c64876dc176b986cf"}
+            ]}"#,
+        )
+        .unwrap();
+        let toml_str = format!(
+            r#"
+            managers_file = {:?}
+
+            [[managers]]
+            noise_key = "91526407c80aa457ce89e8faef1bef2e7c7e303ae2f578e5e4f33465cbb9d0a9"
+        "#,
+            json_file
+        );
+        let config = Config::from_toml(toml_str.as_bytes()).unwrap();
+        assert_eq!(
+            config.managers[1].noise_key.0.to_hex(),
+            "72c9be5363932b1aeaf1d8fa4bf0047b4e03c6e7e2f8db4c64876dc176b986cf"
+        );
+        // It's not read as TOML
+        std::fs::write(&json_file, "[[managers]]\nnoise_key = \"72c9be\"\n").unwrap();
+        assert!(matches!(
+            Config::from_toml(toml_str.as_bytes()),
+            Err(ConfigError::ParsingManagersFile(_))
+        ));
+
+        // A missing one
+        std::fs::remove_dir_all(&data_dir).unwrap();
+        assert!(matches!(
+            Config::from_toml(toml_str.as_bytes()),
+            Err(ConfigError::ReadingManagersFile(_))
+        ));
+    }
+
+    #[test]
+    fn policy_reflects_config() {
+        let toml_str = r#"
+            require_rbf = true
+            max_locktime_age = 86400
+            require_manager_sigs = 2
+            prune_after = 31536000
+            max_managers = 3
+            blacklisted_outpoints = ["2b8930127e9dfd1bcdf35df2bc7f3b8cdbec083b1ae693f36b6305fccd1425da:0"]
+            blacklisted_outpoints_file = "/blacklist"
+
+            [[managers]]
+            noise_key = "91526407c80aa457ce89e8faef1bef2e7c7e303ae2f578e5e4f33465cbb9d0a9"
+        "#;
+        let config: Config = toml::from_str(toml_str).expect("Deserializing toml_str");
+        assert_eq!(
+            config.policy(),
+            serde_json::json!({
+                "reject_unknown_psbt_fields": false,
+                "max_locktime_age": 86400,
+                "require_rbf": true,
+                "require_time_sync": false,
+                "require_manager_sigs": 2,
+                "verify_manager_sigs": false,
+                "require_sender_sig": false,
+                "min_relay_feerate": null,
+                "max_feerate": null,
+                "unvault_csv": null,
+                "unvault_descriptor": null,
+                "partial_signing": false,
+                "return_known_sigs": false,
+                "max_spend_inputs": 128,
+                "protocol_version": 0,
+                "blacklisted_outpoints": 1,
+                "blacklisted_outpoints_file": "/blacklist",
+                "allowed_outpoints": null,
+                "allowed_destinations": null,
+                "refusal_alert_threshold": null,
+                "prune_after": 31536000,
+                "managers": 1,
+                "max_managers": 3,
+                "requests_per_minute": 60,
+                "dry_run": false,
+                "read_only": false,
+            })
+        );
+    }
+
+    #[test]
+    fn max_managers() {
+        let toml_str = r#"
+            max_managers = 2
+
+            [[managers]]
+            noise_key = "91526407c80aa457ce89e8faef1bef2e7c7e303ae2f578e5e4f33465cbb9d0a9"
+            [[managers]]
+            noise_key = "72c9be5363932b1aeaf1d8fa4bf0047b4e03c6e7e2f8db4c64876dc176b986cf"
+        "#;
+        Config::from_toml(toml_str.as_bytes()).unwrap();
+
+        let toml_str = r#"
+            max_managers = 2
+
+            [[managers]]
+            noise_key = "91526407c80aa457ce89e8faef1bef2e7c7e303ae2f578e5e4f33465cbb9d0a9"
+            [[managers]]
+            noise_key = "72c9be5363932b1aeaf1d8fa4bf0047b4e03c6e7e2f8db4c64876dc176b986cf"
+            [[managers]]
+            noise_key = "653bf272f7b691a0fa58fd9736693fbc09f18fc8648a66be6341ef7f3b1254f7"
+        "#;
+        let err = Config::from_toml(toml_str.as_bytes()).unwrap_err();
+        assert!(matches!(err, ConfigError::TooManyManagers(3, 2)));
+        assert_eq!(
+            err.to_string(),
+            "3 managers configured, but at most 2 are expected (max_managers)"
+        );
+    }
+
+    #[test]
+    fn listen_addresses() {
+        let managers = r#"
+            [[managers]]
+            noise_key = "91526407c80aa457ce89e8faef1bef2e7c7e303ae2f578e5e4f33465cbb9d0a9"
+        "#;
+
+        let config: Config = toml::from_str(managers).unwrap();
+        assert_eq!(
+            config.listen,
+            vec![SocketAddr::from_str("127.0.0.1:8383").unwrap()]
+        );
+
+        let config: Config =
+            toml::from_str(&format!("listen = \"0.0.0.0:20001\"\n{}", managers)).unwrap();
+        assert_eq!(
+            config.listen,
+            vec![SocketAddr::from_str("0.0.0.0:20001").unwrap()]
+        );
+
+        let config: Config = toml::from_str(&format!(
+            "listen = [\"127.0.0.1:20001\", \"192.168.1.2:20001\"]\n{}",
+            managers
+        ))
+        .unwrap();
+        assert_eq!(
+            config.listen,
+            vec![
+                SocketAddr::from_str("127.0.0.1:20001").unwrap(),
+                SocketAddr::from_str("192.168.1.2:20001").unwrap()
+            ]
+        );
+
+        toml::from_str::<Config>(&format!("listen = []\n{}", managers)).unwrap_err();
+        toml::from_str::<Config>(&format!("listen = \"127.0.0.1\"\n{}", managers)).unwrap_err();
+    }
+
+    #[test]
+    fn managers_validation() {
+        // No manager at all, be it inline or in an empty list
+        for toml_str in &["data_dir = \"tests/\"", "managers = []"] {
+            assert!(matches!(
+                Config::from_toml(toml_str.as_bytes()),
+                Err(ConfigError::NoManagers)
+            ));
+        }
+
+        let toml_str = r#"
+            [[managers]]
+            noise_key = "91526407c80aa457ce89e8faef1bef2e7c7e303ae2f578e5e4f33465cbb9d0a9"
+            [[managers]]
+            noise_key = "72c9be5363932b1aeaf1d8fa4bf0047b4e03c6e7e2f8db4c64876dc176b986cf"
+            [[managers]]
+            noise_key = "91526407c80aa457ce89e8faef1bef2e7c7e303ae2f578e5e4f33465cbb9d0a9"
+        "#;
+        let err = Config::from_toml(toml_str.as_bytes()).unwrap_err();
+        assert!(matches!(
+            err,
+            ConfigError::DuplicateManagerKey(key)
+                if key.0.to_hex() == "91526407c80aa457ce89e8faef1bef2e7c7e303ae2f578e5e4f33465cbb9d0a9"
+        ));
+        assert_eq!(
+            err.to_string(),
+            "Noise key '91526407c80aa457ce89e8faef1bef2e7c7e303ae2f578e5e4f33465cbb9d0a9' is \
+             configured for more than one manager"
+        );
+    }
+
+    #[test]
+    fn network() {
+        let managers = r#"
+            [[managers]]
+            noise_key = "91526407c80aa457ce89e8faef1bef2e7c7e303ae2f578e5e4f33465cbb9d0a9"
+        "#;
+
+        let config: Config = toml::from_str(managers).unwrap();
+        assert_eq!(config.network, Network::Bitcoin);
+
+        for (network_str, network) in &[
+            ("bitcoin", Network::Bitcoin),
+            ("testnet", Network::Testnet),
+            ("signet", Network::Signet),
+            ("regtest", Network::Regtest),
+        ] {
+            let config: Config =
+                toml::from_str(&format!("network = \"{}\"\n{}", network_str, managers)).unwrap();
+            assert_eq!(config.network, *network);
+        }
+
+        toml::from_str::<Config>(&format!("network = \"litecoin\"\n{}", managers)).unwrap_err();
+    }
+
+    #[test]
+    fn template() {
+        let template = config_template();
+
+        // It must not be usable as is
+        Config::from_toml(template.as_bytes()).unwrap_err();
+
+        // But it's valid once the placeholders are replaced
+        let mut keys = [
+            "b28cf2091bbbecf347d29420f884a936713e7b2e86fe4f6653d7e12356d26114",
+            "4c43a5c01c4a7bc5ba3c668a419fe941e57b6b1eb5bd5cb7a45c2b6e0e4bf866",
+        ]
+        .iter();
+        let filled = template
+            .split(NOISE_KEY_PLACEHOLDER)
+            .enumerate()
+            .map(|(i, part)| {
+                if i == 0 {
+                    part.to_string()
+                } else {
+                    format!("{}{}", keys.next().unwrap(), part)
+                }
+            })
+            .collect::<String>();
+        let config = Config::from_toml(filled.as_bytes()).unwrap();
+        assert_eq!(config.managers.len(), 2);
+        assert_eq!(config.listen, super::listen_default());
+        assert_eq!(config.log_level, super::loglevel_default());
+        assert_eq!(config.network, Network::Bitcoin);
+    }
+
+    #[test]
+    fn verify_manager_sigs() {
+        let toml_str = r#"
+            verify_manager_sigs = true
+            require_manager_sigs = 1
+
+            [[managers]]
+            xpub = "xpub6AtVcKWPpZ9t3Aa3VvzWid1dzJFeXPfNntPbkGsYjNrp7uhXpzSL5QVMCmaHqUzbVUGENEwbBbzF9E8emTxQeP3AzbMjfzvwSDkwUrxg2G4/*"
+            noise_key = "91526407c80aa457ce89e8faef1bef2e7c7e303ae2f578e5e4f33465cbb9d0a9"
+        "#;
+        Config::from_toml(toml_str.as_bytes()).unwrap();
+
+        // It needs a threshold
+        let no_threshold = toml_str.replace("require_manager_sigs = 1", "");
+        assert!(matches!(
+            Config::from_toml(no_threshold.as_bytes()),
+            Err(ConfigError::MissingManagerSigsThreshold)
+        ));
+
+        // And the xpubs of all the managers
+        let toml_str = format!(
+            "{}\n[[managers]]\nnoise_key = \"72c9be5363932b1aeaf1d8fa4bf0047b4e03c6e7e2f8db4c64876dc176b986cf\"\n",
+            toml_str
+        );
+        assert!(matches!(
+            Config::from_toml(toml_str.as_bytes()),
+            Err(ConfigError::MissingManagerXpub(..))
+        ));
+
+        // So does checking the sender's signature
+        let toml_str = toml_str
+            .replace("verify_manager_sigs = true", "require_sender_sig = true")
+            .replace("require_manager_sigs = 1", "");
+        assert!(matches!(
+            Config::from_toml(toml_str.as_bytes()),
+            Err(ConfigError::MissingManagerXpub(..))
+        ));
+        let single_manager = toml_str
+            .split("\n[[managers]]\nnoise_key = \"72c9be")
+            .next()
+            .unwrap();
+        assert!(
+            Config::from_toml(single_manager.as_bytes())
+                .unwrap()
+                .require_sender_sig
+        );
+    }
+
+    #[test]
+    fn feerate_range() {
+        let toml_str = r#"
+            min_relay_feerate = 2
+            max_feerate = 2
+
+            [[managers]]
+            noise_key = "91526407c80aa457ce89e8faef1bef2e7c7e303ae2f578e5e4f33465cbb9d0a9"
+        "#;
+        Config::from_toml(toml_str.as_bytes()).unwrap();
+
+        let toml_str = toml_str.replace("min_relay_feerate = 2", "min_relay_feerate = 3");
+        assert!(matches!(
+            Config::from_toml(toml_str.as_bytes()),
+            Err(ConfigError::InvalidFeerateRange(3, 2))
+        ));
+    }
+
+    #[test]
+    fn unvault_descriptor() {
+        let descriptor = CosignerTestBuilder::new(2).unvault_descriptor.clone();
+        let toml_str = format!(
+            r#"
+            unvault_descriptor = "{}"
+            partial_signing = true
+            unvault_csv = 12
+
+            [[managers]]
+            noise_key = "91526407c80aa457ce89e8faef1bef2e7c7e303ae2f578e5e4f33465cbb9d0a9"
+        "#,
+            descriptor
+        );
+        let config = Config::from_toml(toml_str.as_bytes()).unwrap();
+        assert_eq!(config.unvault_descriptor, Some(descriptor.clone()));
+
+        // It must be one of an Unvault
+        let invalid = toml_str.replace(&descriptor.to_string(), "wsh(pk(02e5ff))");
+        assert!(matches!(
+            Config::from_toml(invalid.as_bytes()),
+            Err(ConfigError::ParsingConfigFile(..))
+        ));
+        // Agree with the configured CSV
+        let other_csv = toml_str.replace("unvault_csv = 12", "unvault_csv = 18");
+        assert!(matches!(
+            Config::from_toml(other_csv.as_bytes()),
+            Err(ConfigError::UnvaultCsvMismatch(18, 12))
+        ));
+        // And we can't tell our inputs apart without it
+        let missing = toml_str.replace(&format!("unvault_descriptor = \"{}\"", descriptor), "");
+        assert!(matches!(
+            Config::from_toml(missing.as_bytes()),
+            Err(ConfigError::MissingUnvaultDescriptor)
+        ));
+        let missing = missing.replace("partial_signing = true", "");
+        assert_eq!(
+            Config::from_toml(missing.as_bytes())
+                .unwrap()
+                .unvault_descriptor,
+            None
+        );
+    }
+
+    #[test]
+    fn protocol_version() {
+        let toml_str = r#"
+            [[managers]]
+            noise_key = "91526407c80aa457ce89e8faef1bef2e7c7e303ae2f578e5e4f33465cbb9d0a9"
+        "#;
+        assert_eq!(
+            Config::from_toml(toml_str.as_bytes())
+                .unwrap()
+                .protocol_version,
+            0
+        );
+
+        let pinned = format!("protocol_version = 0\n{}", toml_str);
+        Config::from_toml(pinned.as_bytes()).unwrap();
+
+        let future = format!("protocol_version = 1\n{}", toml_str);
+        let err = Config::from_toml(future.as_bytes()).unwrap_err();
+        assert!(matches!(err, ConfigError::UnsupportedProtocolVersion(1)));
+        assert_eq!(
+            err.to_string(),
+            "Protocol version 1 is not supported, we only speak version 0"
+        );
+    }
+
+    #[test]
+    fn unknown_fields() {
+        let toml_str = r#"
+            [[managers]]
+            noise_key = "91526407c80aa457ce89e8faef1bef2e7c7e303ae2f578e5e4f33465cbb9d0a9"
+        "#;
+
+        // A typo is not silently ignored
+        let typo = format!("listenn = \"127.0.0.1:20001\"\n{}", toml_str);
+        let err = Config::from_toml(typo.as_bytes()).unwrap_err();
+        assert!(matches!(err, ConfigError::ParsingConfigFile(..)));
+        assert!(
+            err.to_string().contains("unknown field `listenn`"),
+            "{}",
+            err
+        );
+        // Neither in a manager entry
+        let typo = format!("{}xpubb = \"xpub\"\n", toml_str);
+        let err = Config::from_toml(typo.as_bytes()).unwrap_err();
+        assert!(err.to_string().contains("unknown field `xpubb`"), "{}", err);
+        // Nor can what we set ourselves be set from the file
+        let internal = format!("config_file = \"/a\"\n{}", toml_str);
+        Config::from_toml(internal.as_bytes()).unwrap_err();
+
+        // The configuration version is ours by default, and may be pinned
+        assert_eq!(
+            Config::from_toml(toml_str.as_bytes())
+                .unwrap()
+                .config_version,
+            CONFIG_VERSION
+        );
+        let pinned = format!("config_version = 1\n{}", toml_str);
+        Config::from_toml(pinned.as_bytes()).unwrap();
+        let future = format!("config_version = 2\n{}", toml_str);
+        let err = Config::from_toml(future.as_bytes()).unwrap_err();
+        assert!(matches!(err, ConfigError::UnsupportedConfigVersion(2)));
+        assert_eq!(
+            err.to_string(),
+            "Configuration version 2 is not supported, we only understand up to version 1"
+        );
+    }
+
+    #[test]
+    fn log_timestamp_format() {
+        let toml_str = r#"
+            [[managers]]
+            noise_key = "91526407c80aa457ce89e8faef1bef2e7c7e303ae2f578e5e4f33465cbb9d0a9"
+        "#;
+        assert_eq!(
+            Config::from_toml(toml_str.as_bytes())
+                .unwrap()
+                .log_timestamp_format,
+            LogTimestampFormat::Rfc3339
+        );
+
+        let epoch = format!("log_timestamp_format = \"epoch\"\n{}", toml_str);
+        assert_eq!(
+            Config::from_toml(epoch.as_bytes())
+                .unwrap()
+                .log_timestamp_format,
+            LogTimestampFormat::Epoch
+        );
+
+        let unknown = format!("log_timestamp_format = \"iso8601\"\n{}", toml_str);
+        assert!(matches!(
+            Config::from_toml(unknown.as_bytes()),
+            Err(ConfigError::ParsingConfigFile(_))
+        ));
+    }
+
+    #[test]
+    fn managers_keys_derivation() {
+        let toml_str = r#"
+            [[managers]]
+            xpub = "xpub6AtVcKWPpZ9t3Aa3VvzWid1dzJFeXPfNntPbkGsYjNrp7uhXpzSL5QVMCmaHqUzbVUGENEwbBbzF9E8emTxQeP3AzbMjfzvwSDkwUrxg2G4/*"
+            noise_key = "91526407c80aa457ce89e8faef1bef2e7c7e303ae2f578e5e4f33465cbb9d0a9"
+            [[managers]]
+            noise_key = "72c9be5363932b1aeaf1d8fa4bf0047b4e03c6e7e2f8db4c64876dc176b986cf"
+            [[managers]]
+            xpub = "xpub6BHATNyFVsBD8MRygTsv2q9WFTJzEB3o6CgJK7sjopcB286bmWFkNYm6kK5fzVe2gk4mJrSK5isFSFommNDST3RYJWSzrAe9V4bEzboHqnA"
+            noise_key = "653bf272f7b691a0fa58fd9736693fbc09f18fc8648a66be6341ef7f3b1254f7"
+        "#;
+        let config = Config::from_toml(toml_str.as_bytes()).unwrap();
+        let secp = secp256k1::Secp256k1::new();
+
+        // Only the wildcard key changes with the index
+        let (keys_0, keys_1) = (
+            config.derived_manager_pubkeys(0, &secp).unwrap(),
+            config.derived_manager_pubkeys(1, &secp).unwrap(),
+        );
+        assert_eq!(keys_0.len(), 2);
+        assert_ne!(keys_0[0], keys_1[0]);
+        assert_eq!(keys_0[1], keys_1[1]);
+        let xpub = match &config.manager_xpubs()[0] {
+            DescriptorPublicKey::XPub(xpub) => xpub.xkey,
+            _ => unreachable!(),
+        };
+        assert_eq!(
+            keys_1[0].key,
+            xpub.derive_pub(&secp, &[bip32::ChildNumber::from_normal_idx(1).unwrap()])
+                .unwrap()
+                .public_key
+                .key
+        );
+    }
 }
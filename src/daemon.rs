@@ -0,0 +1,1531 @@
+use crate::{
+    audit::{write_audit_event, AuditEvent},
+    config::{Config, ManagerConfig},
+    database::{db_prune_signed_outpoints, db_vacuum},
+    keys::Keys,
+    metrics::{Metric, StatsdClient, PROMETHEUS_METRICS},
+    processing::{process_manager_sign_request, SignDecision, SignRefusal},
+};
+
+use revault_net::{
+    message::{
+        cosigner::{SignRequest, SignResult},
+        RequestParams, ResponseResult,
+    },
+    noise::PublicKey as NoisePubkey,
+};
+use revault_tx::{
+    bitcoin::{hashes::hex::ToHex, OutPoint},
+    transactions::{RevaultTransaction, SpendTransaction},
+};
+
+use std::{
+    collections::HashMap,
+    fs, io, mem,
+    net::{SocketAddr, SocketAddrV6, TcpListener, TcpStream},
+    os::unix::io::{AsRawFd, FromRawFd},
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        mpsc, Arc,
+    },
+    thread, time,
+};
+
+// Incremented each time we are asked to reload the managers
+static RELOAD_GENERATION: AtomicUsize = AtomicUsize::new(0);
+
+extern "C" fn sighup_handler(_: libc::c_int) {
+    RELOAD_GENERATION.fetch_add(1, Ordering::SeqCst);
+}
+
+/// Reload the managers from the configuration file upon SIGHUP. The reload happens upon the next
+/// connection, before its handshake.
+pub fn setup_reload_signal() -> Result<(), io::Error> {
+    // Safe as the handler only performs an atomic operation, which is async-signal-safe.
+    if unsafe {
+        libc::signal(
+            libc::SIGHUP,
+            sighup_handler as extern "C" fn(libc::c_int) as libc::sighandler_t,
+        )
+    } == libc::SIG_ERR
+    {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+// How often we prune the signed outpoints, if configured to
+const PRUNE_INTERVAL: time::Duration = time::Duration::from_secs(3600);
+
+// How long we keep quiet about the handshake failures of a kind after logging one
+const HANDSHAKE_LOG_WINDOW: time::Duration = time::Duration::from_secs(60);
+
+/// Why a connection failed the Noise handshake
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum HandshakeFailure {
+    /// It couldn't be decrypted with any of our managers' Noise keys: it was made with another
+    /// key (or is garbage of the size of a handshake message)
+    UnknownKey,
+    /// It wasn't a valid handshake, or was interrupted (eg by a port scanner)
+    Malformed,
+}
+
+impl HandshakeFailure {
+    pub fn from_error(error: &revault_net::Error) -> Self {
+        match error {
+            // revault_net doesn't export its NoiseError, so tell it by its name
+            revault_net::Error::Noise(e) if format!("{:?}", e) == "MissingStaticKey" => {
+                Self::UnknownKey
+            }
+            _ => Self::Malformed,
+        }
+    }
+
+    /// A stable identifier for this reason
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::UnknownKey => "unknown_key",
+            Self::Malformed => "malformed",
+        }
+    }
+}
+
+// Logs a handshake failure of each kind at most once every HANDSHAKE_LOG_WINDOW, so that a port
+// scan or a misconfigured manager doesn't flood the logs.
+#[derive(Debug, Default)]
+struct HandshakeFailureLog {
+    // When we last logged a failure of this kind, and how many we didn't since
+    last_logged: HashMap<HandshakeFailure, (time::Instant, u64)>,
+}
+
+impl HandshakeFailureLog {
+    // Whether to log this failure happening at `now`, along with the count of those of the same
+    // kind we suppressed since the last one we logged.
+    fn should_log(&mut self, failure: HandshakeFailure, now: time::Instant) -> Option<u64> {
+        match self.last_logged.get_mut(&failure) {
+            Some((last, suppressed))
+                if now.saturating_duration_since(*last) < HANDSHAKE_LOG_WINDOW =>
+            {
+                *suppressed += 1;
+                None
+            }
+            entry => {
+                let suppressed = entry.map(|(_, suppressed)| *suppressed).unwrap_or(0);
+                self.last_logged.insert(failure, (now, 0));
+                Some(suppressed)
+            }
+        }
+    }
+
+    fn record(&mut self, peer: Option<SocketAddr>, error: &revault_net::Error) {
+        let failure = HandshakeFailure::from_error(error);
+        let suppressed = match self.should_log(failure, time::Instant::now()) {
+            Some(suppressed) => suppressed,
+            None => return,
+        };
+        let peer = peer.map_or_else(|| "unknown peer".to_string(), |p| p.to_string());
+        let suppressed = if suppressed > 0 {
+            format!(
+                " ({} similar failures not logged since the last one)",
+                suppressed
+            )
+        } else {
+            String::new()
+        };
+        match failure {
+            HandshakeFailure::UnknownKey => log::error!(
+                "Handshake failure with '{}': it's not using one of our managers' Noise keys \
+                 ('{}'){}",
+                peer,
+                error,
+                suppressed
+            ),
+            HandshakeFailure::Malformed => log::error!(
+                "Handshake failure with '{}': malformed or incomplete handshake ('{}'){}",
+                peer,
+                error,
+                suppressed
+            ),
+        }
+    }
+}
+
+/// The exit code signaling we shut down for a scheduled restart, as per `max_uptime_secs`
+pub const SCHEDULED_RESTART_EXIT_CODE: i32 = 75;
+
+/// The version of the practical-revault messages we speak. Managers must send us `sign` requests
+/// only: anything else is a protocol violation and gets their connection dropped.
+pub const PROTOCOL_VERSION: u32 = 0;
+
+/// Why the daemon stopped serving
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShutdownReason {
+    /// We were asked to shut down
+    Requested,
+    /// We ran for the configured maximum uptime
+    MaxUptime,
+}
+
+impl ShutdownReason {
+    /// The code the process should exit with
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Self::Requested => 0,
+            Self::MaxUptime => SCHEDULED_RESTART_EXIT_CODE,
+        }
+    }
+}
+
+fn spend_outpoints(spend_tx: &SpendTransaction) -> Vec<OutPoint> {
+    spend_tx
+        .tx()
+        .input
+        .iter()
+        .map(|txin| txin.previous_output)
+        .collect()
+}
+
+// A short identifier of this manager for the logs: the start of its Noise key in hex
+fn manager_id(noise_key: &NoisePubkey) -> String {
+    noise_key.0[..4].to_hex()
+}
+
+// The method of this request, as on the wire
+fn request_method(request: &RequestParams) -> &'static str {
+    match request {
+        RequestParams::WtSigs(_) => "sigs",
+        RequestParams::SetSpendTx(_) => "set_spend_tx",
+        RequestParams::GetSpendTx(_) => "get_spend_tx",
+        RequestParams::CoordSig(_) => "sig",
+        RequestParams::GetSigs(_) => "get_sigs",
+        RequestParams::Sign(_) => "sign",
+    }
+}
+
+// Process this `sign` request from this manager, returning the response if any along with the
+// reason we refused to sign if we didn't.
+fn process_message(
+    config: &Config,
+    keys: &Keys,
+    manager: &NoisePubkey,
+    sign_req: SignRequest,
+) -> (Option<ResponseResult>, Option<SignRefusal>) {
+    log::trace!("Decoded request: {:#?}", sign_req);
+
+    let manager_id = manager_id(manager);
+    let (res, refusal) = match process_manager_sign_request(config, sign_req, keys, manager) {
+        Ok(SignDecision::Signed(spend_tx)) => (SignResult { tx: Some(spend_tx) }, None),
+        // We do share signatures, but that's still a refusal to sign the other outpoints
+        Ok(SignDecision::KnownSignatures(spend_tx, signed)) => (
+            SignResult { tx: Some(spend_tx) },
+            Some(SignRefusal::AlreadySigned(signed)),
+        ),
+        Ok(SignDecision::Refused(refusal)) => (SignResult { tx: None }, Some(refusal)),
+        Err(e) => {
+            log::error!(
+                "Error when processing 'sign' message from manager '{}': '{}'",
+                manager_id,
+                e
+            );
+            return (None, Some(SignRefusal::ProcessingError));
+        }
+    };
+    log::trace!("Decoded response: {:#?}", res);
+    match refusal {
+        Some(ref refusal) => log::info!(
+            "Refused 'sign' request from manager '{}' ({})",
+            manager_id,
+            refusal.code()
+        ),
+        None => log::info!("Signed 'sign' request from manager '{}'", manager_id),
+    }
+
+    (Some(ResponseResult::SignResult(res)), refusal)
+}
+
+/// Process this request from this manager, returning the response to send it if any. We only
+/// answer `sign` requests: the others get none. This is what the daemon does for each request
+/// (but for the rate limiting and the per-connection bookkeeping), the Prometheus counters and the
+/// audit log are updated accordingly.
+pub fn handle_request(
+    config: &Config,
+    keys: &Keys,
+    manager: &NoisePubkey,
+    request: RequestParams,
+) -> Option<ResponseResult> {
+    match request {
+        RequestParams::Sign(sign_req) => handle_sign_request(config, keys, manager, sign_req).0,
+        _ => None,
+    }
+}
+
+// The `sign` part of [handle_request], also returning the reason we refused to sign if we didn't
+fn handle_sign_request(
+    config: &Config,
+    keys: &Keys,
+    manager: &NoisePubkey,
+    sign_req: SignRequest,
+) -> (Option<ResponseResult>, Option<SignRefusal>) {
+    let outpoints = spend_outpoints(&sign_req.tx);
+    let (res, refusal) = process_message(config, keys, manager, sign_req);
+
+    match refusal {
+        Some(ref refusal) => PROMETHEUS_METRICS.increment_refused(refusal.code()),
+        None => PROMETHEUS_METRICS.increment(Metric::Signed),
+    }
+    if let Some(ref audit_log) = config.audit_log {
+        let event = match refusal {
+            Some(ref refusal) => AuditEvent::refused(manager, &outpoints, refusal),
+            None => AuditEvent::signed(manager, &outpoints),
+        };
+        if let Err(e) = write_audit_event(audit_log, &event) {
+            log::error!("Error writing to the audit log: '{}'", e);
+        }
+    }
+
+    (res, refusal)
+}
+
+// Once the maximum uptime elapsed, set `elapsed` and wake up the accept loop listening on `addr`.
+// Dropping the returned sender cancels it.
+fn start_uptime_timer(
+    addr: SocketAddr,
+    max_uptime: time::Duration,
+    elapsed: Arc<AtomicBool>,
+) -> mpsc::Sender<()> {
+    let (sender, receiver) = mpsc::channel::<()>();
+    thread::spawn(move || {
+        if let Err(mpsc::RecvTimeoutError::Timeout) = receiver.recv_timeout(max_uptime) {
+            elapsed.store(true, Ordering::SeqCst);
+            if let Err(e) = TcpStream::connect(addr) {
+                log::error!("Connecting to the daemon to shut it down: '{}'", e);
+            }
+        }
+    });
+
+    sender
+}
+
+fn same_path(a: &Path, b: &Path) -> bool {
+    a == b || matches!((fs::canonicalize(a), fs::canonicalize(b)), (Ok(a), Ok(b)) if a == b)
+}
+
+// Read the managers (their Noise keys and xpubs) from our configuration file again. Only the
+// managers are reloaded: we refuse to if the data directory or the interfaces we listen on
+// changed, and warn about any other setting ignored until the next restart.
+fn reload_managers(config: &Config) -> Result<Vec<ManagerConfig>, String> {
+    let config_file = config
+        .config_file
+        .clone()
+        .ok_or_else(|| "not started from a configuration file".to_string())?;
+    let new_config = Config::from_file(Some(config_file)).map_err(|e| e.to_string())?;
+    if !config.data_dir_overridden && !same_path(&new_config.data_dir, &config.data_dir) {
+        return Err("changing 'data_dir' requires a restart".to_string());
+    }
+    if new_config.listen != config.listen {
+        return Err("changing 'listen' requires a restart".to_string());
+    }
+    for setting in config.changed_settings(&new_config) {
+        log::warn!(
+            "Ignoring the new value of '{}' upon reload: changing it requires a restart.",
+            setting
+        );
+    }
+
+    Ok(new_config.managers)
+}
+
+// Prune the old signed outpoints if configured to and we didn't in the last PRUNE_INTERVAL
+fn maybe_prune(config: &Config, last_prune: &mut Option<time::Instant>) {
+    // A standby's database is the primary's, pruned by it
+    let prune_after = match config.prune_after {
+        Some(secs) if !config.read_only => secs,
+        _ => return,
+    };
+    if matches!(last_prune, Some(last) if last.elapsed() < PRUNE_INTERVAL) {
+        return;
+    }
+    *last_prune = Some(time::Instant::now());
+
+    match db_prune_signed_outpoints(&config.db_file(), prune_after) {
+        Ok(0) => {}
+        Ok(n) => {
+            log::info!(
+                "Pruned {} outpoint(s) signed more than {}s ago",
+                n,
+                prune_after
+            );
+            // Not critical, the file would just keep its size.
+            if let Err(e) = db_vacuum(&config.db_file()) {
+                log::warn!("Error vacuuming the database after pruning: '{}'", e);
+            }
+        }
+        Err(e) => log::error!("Error pruning signed outpoints: '{}'", e),
+    }
+}
+
+// Wait for a connection on any of these listeners, accept it and set it up as configured
+fn accept_connection(listeners: &[TcpListener], config: &Config) -> Result<TcpStream, io::Error> {
+    let mut fds: Vec<libc::pollfd> = listeners
+        .iter()
+        .map(|listener| libc::pollfd {
+            fd: listener.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        })
+        .collect();
+    // Safe as we pass the right length of our (valid) array, and don't use the fds beyond the
+    // listeners' lifetime.
+    while unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, -1) } < 0 {
+        // A signal (such as SIGHUP, for a reload) landed on this thread, keep on waiting.
+        let e = io::Error::last_os_error();
+        if e.kind() != io::ErrorKind::Interrupted {
+            return Err(e);
+        }
+    }
+    let listener = fds
+        .iter()
+        .position(|fd| fd.revents != 0)
+        .map(|i| &listeners[i])
+        .ok_or_else(|| io::Error::other("No listener ready after poll"))?;
+
+    let (connection, _) = listener.accept()?;
+    if let Err(e) = connection.set_nodelay(config.tcp_nodelay) {
+        log::warn!("Setting TCP_NODELAY on connection: '{}'", e);
+    }
+    // A zero Duration is invalid, so treat 0 as the shortest timeout.
+    let timeout = time::Duration::from_secs(config.connection_timeout.max(1));
+    connection.set_read_timeout(Some(timeout))?;
+    connection.set_write_timeout(Some(timeout))?;
+
+    Ok(connection)
+}
+
+// SOMAXCONN on Linux, as used by the standard library
+const LISTEN_BACKLOG: libc::c_int = 128;
+
+// Set this integer socket option
+fn set_socket_option(
+    fd: libc::c_int,
+    level: libc::c_int,
+    option: libc::c_int,
+    value: libc::c_int,
+) -> Result<(), io::Error> {
+    // Safe as we pass a valid pointer to a value of the size we give.
+    if unsafe {
+        libc::setsockopt(
+            fd,
+            level,
+            option,
+            &value as *const libc::c_int as *const libc::c_void,
+            mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    } != 0
+    {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+// The standard library doesn't let us set IPV6_V6ONLY before binding, so do it by hand
+fn bind_v6(addr: &SocketAddrV6, v6only: bool) -> Result<TcpListener, io::Error> {
+    // Safe as we check the returned descriptor, which we then own.
+    let fd = unsafe { libc::socket(libc::AF_INET6, libc::SOCK_STREAM | libc::SOCK_CLOEXEC, 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // Closes it on error
+    let listener = unsafe { TcpListener::from_raw_fd(fd) };
+
+    set_socket_option(fd, libc::SOL_SOCKET, libc::SO_REUSEADDR, 1)?;
+    set_socket_option(
+        fd,
+        libc::IPPROTO_IPV6,
+        libc::IPV6_V6ONLY,
+        v6only as libc::c_int,
+    )?;
+
+    let sockaddr = libc::sockaddr_in6 {
+        sin6_family: libc::AF_INET6 as libc::sa_family_t,
+        sin6_port: addr.port().to_be(),
+        sin6_flowinfo: addr.flowinfo(),
+        sin6_addr: libc::in6_addr {
+            s6_addr: addr.ip().octets(),
+        },
+        sin6_scope_id: addr.scope_id(),
+    };
+    // Safe as we pass a valid pointer to an address of the size we give.
+    if unsafe {
+        libc::bind(
+            fd,
+            &sockaddr as *const libc::sockaddr_in6 as *const libc::sockaddr,
+            mem::size_of::<libc::sockaddr_in6>() as libc::socklen_t,
+        )
+    } != 0
+    {
+        return Err(io::Error::last_os_error());
+    }
+    if unsafe { libc::listen(fd, LISTEN_BACKLOG) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(listener)
+}
+
+/// Bind on this address, one of the `listen` ones. An IPv6 wildcard address is dual-stack (it
+/// accepts IPv4 connections too) unless an IPv4 address on the same port is also configured, in
+/// which case it's IPv6-only so that both can be bound.
+pub fn bind_listener(addr: &SocketAddr, listen: &[SocketAddr]) -> Result<TcpListener, io::Error> {
+    match addr {
+        SocketAddr::V4(_) => TcpListener::bind(addr),
+        SocketAddr::V6(addr_v6) => {
+            let dual_stack = addr_v6.ip().is_unspecified()
+                && !listen
+                    .iter()
+                    .any(|other| other.is_ipv4() && other.port() == addr_v6.port());
+            bind_v6(addr_v6, !dual_stack)
+        }
+    }
+}
+
+/// Keeps track of the number of consecutive refusals per manager. A manager suddenly getting its
+/// requests refused (eg presenting Spends with already signed outpoints) may be buggy or
+/// compromised.
+#[derive(Debug, Default)]
+pub struct RefusalTracker {
+    consecutive_refusals: HashMap<NoisePubkey, u32>,
+}
+
+impl RefusalTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record the outcome of a request from this manager. A successful sign resets its counter.
+    /// Returns true if this refusal made it reach the alert threshold, in which case an alert
+    /// was logged.
+    pub fn record(&mut self, manager: &NoisePubkey, refused: bool, threshold: Option<u32>) -> bool {
+        if !refused {
+            self.consecutive_refusals.remove(manager);
+            return false;
+        }
+
+        let count = self.consecutive_refusals.entry(*manager).or_insert(0);
+        *count += 1;
+        if Some(*count) == threshold {
+            log::error!(
+                "ALERT: manager '{}' got {} sign requests refused in a row. It may be buggy or \
+                 compromised.",
+                manager_id(manager),
+                count
+            );
+            return true;
+        }
+
+        false
+    }
+
+    /// The current number of consecutive refusals for this manager
+    pub fn consecutive_refusals(&self, manager: &NoisePubkey) -> u32 {
+        self.consecutive_refusals.get(manager).copied().unwrap_or(0)
+    }
+}
+
+// A token bucket refilling continuously
+#[derive(Debug, Clone, Copy)]
+struct TokenBucket {
+    tokens: f64,
+    last_refill: time::Instant,
+}
+
+/// Limits the rate of the requests of each manager, as a token bucket holding a minute worth of
+/// requests.
+#[derive(Debug, Default)]
+pub struct RateLimiter {
+    buckets: HashMap<NoisePubkey, TokenBucket>,
+}
+
+impl RateLimiter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Whether this manager may make a request at `now`, given the allowed number of requests per
+    /// minute (0 for no limit). Consumes a token if so.
+    pub fn allow(
+        &mut self,
+        manager: &NoisePubkey,
+        requests_per_minute: u32,
+        now: time::Instant,
+    ) -> bool {
+        if requests_per_minute == 0 {
+            return true;
+        }
+
+        let capacity = requests_per_minute as f64;
+        let bucket = self.buckets.entry(*manager).or_insert(TokenBucket {
+            tokens: capacity,
+            last_refill: now,
+        });
+        let elapsed = now.saturating_duration_since(bucket.last_refill);
+        bucket.tokens = (bucket.tokens + elapsed.as_secs_f64() * capacity / 60.0).min(capacity);
+        bucket.last_refill = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Accept connections from the managers on these listeners and process their `sign` messages,
+/// until `shutdown` is set or the configured maximum uptime is elapsed. It's checked after each
+/// accepted connection, as is whether we were asked to reload the managers
+/// (see [setup_reload_signal]).
+pub fn serve(
+    listeners: Vec<TcpListener>,
+    config: &Config,
+    keys: &Keys,
+    shutdown: &AtomicBool,
+) -> ShutdownReason {
+    // Our own copy, as the allowed outpoints are reloaded along with the managers
+    let mut config = config.clone();
+    let mut managers_noise_pubkeys: Vec<NoisePubkey> =
+        config.managers.iter().map(|m| m.noise_key).collect();
+    let mut reload_generation = RELOAD_GENERATION.load(Ordering::SeqCst);
+    let mut refusal_tracker = RefusalTracker::new();
+    let mut rate_limiter = RateLimiter::new();
+    let mut handshake_failures = HandshakeFailureLog::default();
+    let statsd = config.statsd_addr.and_then(|addr| {
+        StatsdClient::new(addr)
+            .map_err(|e| log::error!("Error setting up the statsd client: '{}'", e))
+            .ok()
+    });
+    let uptime_elapsed = Arc::new(AtomicBool::new(false));
+    let _uptime_timer = config.max_uptime_secs.and_then(|secs| {
+        let addr = listeners
+            .first()?
+            .local_addr()
+            .map_err(|e| log::error!("Error getting the listening address: '{}'", e))
+            .ok()?;
+        Some(start_uptime_timer(
+            addr,
+            time::Duration::from_secs(secs),
+            uptime_elapsed.clone(),
+        ))
+    });
+
+    let mut last_prune = None;
+
+    // We expect a single connection once in a while, there is *no need* for complexity here so
+    // just treat incoming connections sequentially.
+    loop {
+        maybe_prune(&config, &mut last_prune);
+
+        let connection = match accept_connection(&listeners, &config) {
+            Ok(c) => c,
+            Err(e) => {
+                log::error!("Accepting new connection: '{}'", e);
+                continue;
+            }
+        };
+        if shutdown.load(Ordering::SeqCst) {
+            log::info!("Shutting down.");
+            return ShutdownReason::Requested;
+        }
+        if uptime_elapsed.load(Ordering::SeqCst) {
+            log::info!("Reached the maximum uptime, shutting down for a scheduled restart.");
+            return ShutdownReason::MaxUptime;
+        }
+        let generation = RELOAD_GENERATION.load(Ordering::SeqCst);
+        if generation != reload_generation {
+            reload_generation = generation;
+            match reload_managers(&config) {
+                Ok(managers) => {
+                    log::info!("Reloaded the managers, {} are configured.", managers.len());
+                    managers_noise_pubkeys = managers.iter().map(|m| m.noise_key).collect();
+                    config.managers = managers;
+                }
+                Err(e) => log::error!("Not reloading the managers: {}", e),
+            }
+            match config.load_blacklisted_outpoints() {
+                Ok(()) => {
+                    if config.blacklisted_outpoints_file.is_some() {
+                        log::info!(
+                            "Reloaded the blacklisted outpoints, {} are blacklisted.",
+                            config.blacklisted_outpoints().len()
+                        );
+                    }
+                }
+                Err(e) => log::error!("Not reloading the blacklisted outpoints: {}", e),
+            }
+            match config.load_allowed_outpoints() {
+                Ok(()) => {
+                    if let Some(ref allowed_outpoints) = config.allowed_outpoints {
+                        log::info!(
+                            "Reloaded the allowed outpoints, {} are allowed.",
+                            allowed_outpoints.len()
+                        );
+                    }
+                }
+                Err(e) => log::error!("Not reloading the allowed outpoints: {}", e),
+            }
+            match config.load_allowed_destinations() {
+                Ok(()) => {
+                    if let Some(ref allowed_destinations) = config.allowed_destinations {
+                        log::info!(
+                            "Reloaded the allowed destinations, {} are allowed.",
+                            allowed_destinations.len()
+                        );
+                    }
+                }
+                Err(e) => log::error!("Not reloading the allowed destinations: {}", e),
+            }
+        }
+
+        let peer = connection.peer_addr().ok();
+        let mut kk_stream = match revault_net::transport::KKTransport::accept(
+            connection,
+            keys.noise_privkey(),
+            &managers_noise_pubkeys,
+        ) {
+            Ok(s) => s,
+            Err(e) => {
+                handshake_failures.record(peer, &e);
+                PROMETHEUS_METRICS
+                    .increment_handshake_failure(HandshakeFailure::from_error(&e).code());
+                if let Some(ref statsd) = statsd {
+                    statsd.increment(Metric::HandshakeFailure);
+                }
+                continue;
+            }
+        };
+
+        // Process all messages from this connection.
+        let manager = kk_stream.remote_static();
+        let manager_id = manager_id(&manager);
+        log::debug!("Accepted connection from manager '{}'", manager_id);
+        loop {
+            let mut rate_limited = false;
+            let mut unexpected_method = None;
+            if let Err(e) = kk_stream.read_req(|msg| {
+                log::debug!("Got a request from manager '{}'", manager_id);
+                PROMETHEUS_METRICS.increment(Metric::Request);
+                if let Some(ref statsd) = statsd {
+                    statsd.increment(Metric::Request);
+                }
+                if !rate_limiter.allow(&manager, config.requests_per_minute, time::Instant::now()) {
+                    rate_limited = true;
+                    return None;
+                }
+                // We'll drop the connection of a manager not speaking our protocol
+                let sign_req = match msg {
+                    RequestParams::Sign(sign_req) => sign_req,
+                    msg => {
+                        unexpected_method = Some(request_method(&msg));
+                        return None;
+                    }
+                };
+                let (res, refusal) = handle_sign_request(&config, keys, &manager, sign_req);
+                let refused = refusal.is_some();
+                refusal_tracker.record(&manager, refused, config.refusal_alert_threshold);
+                if let Some(ref statsd) = statsd {
+                    statsd.increment(if refused {
+                        Metric::Refused
+                    } else {
+                        Metric::Signed
+                    });
+                }
+
+                res
+            }) {
+                log::error!(
+                    "Error handling request from manager '{}' (stream '{:?}'): '{}'. Dropping \
+                     connection.",
+                    manager_id,
+                    kk_stream,
+                    e
+                );
+                break;
+            }
+            if let Some(method) = unexpected_method {
+                log::error!(
+                    "Protocol violation: manager '{}' sent a '{}' request, but we only accept \
+                     'sign' requests (protocol version {}). Dropping connection.",
+                    manager_id,
+                    method,
+                    PROTOCOL_VERSION
+                );
+                break;
+            }
+            if rate_limited {
+                log::warn!(
+                    "Manager '{}' exceeded {} requests per minute. Dropping connection.",
+                    manager_id,
+                    config.requests_per_minute
+                );
+                break;
+            }
+        }
+    }
+}
+
+/// A handle to a daemon running in its own thread, as started by [start].
+#[derive(Debug)]
+pub struct RunHandle {
+    bound_addrs: Vec<SocketAddr>,
+    shutdown: Arc<AtomicBool>,
+    thread: thread::JoinHandle<ShutdownReason>,
+}
+
+impl RunHandle {
+    /// The (first) address the daemon is listening on
+    pub fn bound_addr(&self) -> SocketAddr {
+        self.bound_addrs[0]
+    }
+
+    /// All the addresses the daemon is listening on, in the configured order
+    pub fn bound_addrs(&self) -> &[SocketAddr] {
+        &self.bound_addrs
+    }
+
+    /// Stop accepting connections and wait for the daemon thread to finish. A manager connected
+    /// at this time is served until it disconnects.
+    pub fn shutdown(self) {
+        self.shutdown.store(true, Ordering::SeqCst);
+        // Wake up the accept loop so that it notices.
+        if let Err(e) = TcpStream::connect(self.bound_addr()) {
+            log::error!("Connecting to the daemon to shut it down: '{}'", e);
+        }
+        if self.thread.join().is_err() {
+            log::error!("The daemon thread panicked");
+        }
+    }
+}
+
+/// Bind on the configured interfaces and start processing requests in a new thread.
+pub fn start(config: Config, keys: Arc<Keys>) -> Result<RunHandle, io::Error> {
+    if config.listen.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "No address to listen on",
+        ));
+    }
+    let listeners = config
+        .listen
+        .iter()
+        .map(|addr| bind_listener(addr, &config.listen))
+        .collect::<Result<Vec<_>, _>>()?;
+    let bound_addrs = listeners
+        .iter()
+        .map(|l| l.local_addr())
+        .collect::<Result<Vec<_>, _>>()?;
+    let shutdown = Arc::new(AtomicBool::new(false));
+
+    let thread = {
+        let shutdown = shutdown.clone();
+        thread::spawn(move || serve(listeners, &config, &keys, &shutdown))
+    };
+
+    Ok(RunHandle {
+        bound_addrs,
+        shutdown,
+        thread,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        accept_connection, bind_listener, handle_request, reload_managers, serve,
+        setup_reload_signal, start, HandshakeFailure, HandshakeFailureLog, RateLimiter,
+        RefusalTracker, RunHandle, ShutdownReason, HANDSHAKE_LOG_WINDOW,
+        SCHEDULED_RESTART_EXIT_CODE,
+    };
+    use crate::{
+        config::Config,
+        metrics::PROMETHEUS_METRICS,
+        tests::{
+            builder::CosignerTestBuilder,
+            logs::{capture_logs, logged},
+        },
+    };
+    use revault_net::{
+        message::{
+            coordinator::{GetSigs, Sigs},
+            cosigner::{SignRequest, SignResult},
+            RequestParams, ResponseResult,
+        },
+        noise::{PublicKey as NoisePubkey, SecretKey as NoisePrivkey, KK_MSG_1_SIZE},
+        sodiumoxide,
+        transport::KKTransport,
+    };
+    use revault_tx::{
+        bitcoin::{hashes::hex::ToHex, OutPoint},
+        transactions::{RevaultTransaction, SpendTransaction},
+    };
+    use std::{
+        fs,
+        io::{Read, Write},
+        net::{SocketAddr, TcpListener, TcpStream, UdpSocket},
+        os::unix::thread::JoinHandleExt,
+        str::FromStr,
+        sync::atomic::AtomicBool,
+        time::{Duration, Instant},
+    };
+
+    // The outpoint spent by the tests needing a single one
+    fn test_outpoint() -> OutPoint {
+        OutPoint::from_str("2b8930127e9dfd1bcdf35df2bc7f3b8cdbec083b1ae693f36b6305fccd1425da:0")
+            .unwrap()
+    }
+
+    // Connect to the daemon listening on this address, as this manager of the test framework
+    fn connect(
+        test_framework: &CosignerTestBuilder,
+        addr: SocketAddr,
+        manager: usize,
+    ) -> Result<KKTransport, revault_net::Error> {
+        KKTransport::connect(
+            addr,
+            &test_framework.managers_noise_privkeys[manager],
+            &test_framework.keys.noise_pubkey(),
+        )
+    }
+
+    // Start the daemon with this configuration, and connect to it as the first manager
+    fn start_connected(
+        test_framework: &CosignerTestBuilder,
+        config: Config,
+    ) -> (RunHandle, KKTransport) {
+        let handle = start(config, test_framework.keys.clone()).unwrap();
+        let transport = connect(test_framework, handle.bound_addr(), 0).unwrap();
+        (handle, transport)
+    }
+
+    // Have the daemon sign this Spend, returning it signed or None if it refused to
+    fn sign(transport: &mut KKTransport, tx: SpendTransaction) -> Option<SpendTransaction> {
+        let SignResult { tx } = transport
+            .send_req(&SignRequest { tx }.into())
+            .expect("Sending sign request");
+        tx
+    }
+
+    #[test]
+    fn run_handle_sanity() {
+        capture_logs();
+        let mut test_framework = CosignerTestBuilder::new(2);
+        test_framework.config.listen = vec![SocketAddr::from_str("127.0.0.1:0").unwrap()];
+        let audit_log = test_framework.config.data_dir.join("audit.log");
+        test_framework.config.audit_log = Some(audit_log.clone());
+        let tx = test_framework.generate_spend_tx(&[test_outpoint()]);
+
+        let handle = start(test_framework.config.clone(), test_framework.keys.clone()).unwrap();
+        assert_ne!(handle.bound_addr().port(), 0);
+
+        {
+            let mut transport = connect(&test_framework, handle.bound_addr(), 1).unwrap();
+            let tx = sign(&mut transport, tx).unwrap();
+            assert_eq!(tx.psbt().inputs[0].partial_sigs.len(), 1);
+        }
+
+        handle.shutdown();
+
+        // The manager is identified in the logs
+        let manager_id = test_framework.config.managers[1].noise_key.0[..4].to_hex();
+        assert!(logged(&[
+            "Got a request from manager",
+            &format!("'{}'", manager_id)
+        ]));
+        assert!(logged(&[
+            "Signed 'sign' request from manager",
+            &format!("'{}'", manager_id)
+        ]));
+
+        // The sign was recorded in the audit log
+        let audit_line: serde_json::Value =
+            serde_json::from_str(fs::read_to_string(&audit_log).unwrap().trim()).unwrap();
+        assert_eq!(audit_line["event"], "signed");
+        assert_eq!(
+            audit_line["manager"],
+            test_framework.config.managers[1].noise_key.0.to_hex()
+        );
+        assert_eq!(
+            audit_line["outpoints"],
+            serde_json::json!([test_outpoint().to_string()])
+        );
+    }
+
+    #[test]
+    fn accepted_connections_nodelay() {
+        let mut test_framework = CosignerTestBuilder::new(1);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        for nodelay in &[true, false] {
+            test_framework.config.tcp_nodelay = *nodelay;
+            let _client = TcpStream::connect(addr).unwrap();
+            let connection =
+                accept_connection(&[listener.try_clone().unwrap()], &test_framework.config)
+                    .unwrap();
+            assert_eq!(connection.nodelay().unwrap(), *nodelay);
+            assert_eq!(
+                connection.read_timeout().unwrap(),
+                Some(Duration::from_secs(30))
+            );
+            assert_eq!(
+                connection.write_timeout().unwrap(),
+                Some(Duration::from_secs(30))
+            );
+        }
+    }
+
+    #[test]
+    fn statsd_metrics() {
+        let mut test_framework = CosignerTestBuilder::new(1);
+        test_framework.config.listen = vec![SocketAddr::from_str("127.0.0.1:0").unwrap()];
+        let collector = UdpSocket::bind("127.0.0.1:0").unwrap();
+        collector
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        test_framework.config.statsd_addr = Some(collector.local_addr().unwrap());
+        let blacklisted = OutPoint::from_str(
+            "ceca4de398c63b29543f8346c09fd7522fd8661ce8bdc0e454e8d6ed8ad46a0d:1",
+        )
+        .unwrap();
+        test_framework.config.blacklisted_outpoints = vec![blacklisted];
+        let signed_tx = test_framework.generate_spend_tx(&[test_outpoint()]);
+        let refused_tx = test_framework.generate_spend_tx(&[blacklisted]);
+
+        let (handle, mut transport) =
+            start_connected(&test_framework, test_framework.config.clone());
+        assert!(sign(&mut transport, signed_tx).is_some());
+        assert!(sign(&mut transport, refused_tx).is_none());
+        drop(transport);
+        handle.shutdown();
+
+        let mut buf = [0; 64];
+        let packets: Vec<String> = (0..4)
+            .map(|_| {
+                let size = collector.recv(&mut buf).unwrap();
+                String::from_utf8(buf[..size].to_vec()).unwrap()
+            })
+            .collect();
+        assert_eq!(
+            packets,
+            vec![
+                "cosignerd.requests:1|c",
+                "cosignerd.signed:1|c",
+                "cosignerd.requests:1|c",
+                "cosignerd.refused:1|c"
+            ]
+        );
+
+        // The Prometheus counters were updated too
+        let rendered = PROMETHEUS_METRICS.render(None);
+        assert!(
+            rendered.contains("cosignerd_spends_refused_total{reason=\"blacklisted_outpoints\"} "),
+            "{}",
+            rendered
+        );
+    }
+
+    #[test]
+    fn max_uptime_shutdown() {
+        let mut test_framework = CosignerTestBuilder::new(1);
+        test_framework.config.max_uptime_secs = Some(1);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+
+        let start = Instant::now();
+        let reason = serve(
+            vec![listener],
+            &test_framework.config,
+            &test_framework.keys,
+            &AtomicBool::new(false),
+        );
+        assert_eq!(reason, ShutdownReason::MaxUptime);
+        assert_eq!(reason.exit_code(), SCHEDULED_RESTART_EXIT_CODE);
+        assert!(start.elapsed() >= Duration::from_secs(1));
+        assert_eq!(ShutdownReason::Requested.exit_code(), 0);
+    }
+
+    #[test]
+    fn multiple_listeners() {
+        let mut test_framework = CosignerTestBuilder::new(1);
+        test_framework.config.listen = vec![
+            SocketAddr::from_str("127.0.0.1:0").unwrap(),
+            SocketAddr::from_str("127.0.0.1:0").unwrap(),
+        ];
+        let outpoints = [
+            test_outpoint(),
+            OutPoint::from_str(
+                "ceca4de398c63b29543f8346c09fd7522fd8661ce8bdc0e454e8d6ed8ad46a0d:1",
+            )
+            .unwrap(),
+        ];
+
+        let handle = start(test_framework.config.clone(), test_framework.keys.clone()).unwrap();
+        assert_eq!(handle.bound_addrs().len(), 2);
+        assert_ne!(handle.bound_addrs()[0], handle.bound_addrs()[1]);
+
+        // We can be served on either of them, starting with the last one
+        for (addr, outpoint) in handle.bound_addrs().iter().rev().zip(outpoints.iter()) {
+            let tx = test_framework.generate_spend_tx(&[*outpoint]);
+            let mut transport = connect(&test_framework, *addr, 0).unwrap();
+            assert!(sign(&mut transport, tx).is_some());
+        }
+
+        handle.shutdown();
+    }
+
+    #[test]
+    fn ipv6_listener() {
+        let mut test_framework = CosignerTestBuilder::new(1);
+        test_framework.config.listen = vec![SocketAddr::from_str("[::1]:0").unwrap()];
+        let tx = test_framework.generate_spend_tx(&[test_outpoint()]);
+
+        let (handle, mut transport) =
+            start_connected(&test_framework, test_framework.config.clone());
+        assert!(handle.bound_addr().is_ipv6());
+        assert!(sign(&mut transport, tx).is_some());
+        drop(transport);
+
+        handle.shutdown();
+    }
+
+    #[test]
+    fn request_handling() {
+        let test_framework = CosignerTestBuilder::new(1);
+        let manager = test_framework.config.managers[0].noise_key;
+        let handle = |request: RequestParams| {
+            handle_request(
+                &test_framework.config,
+                &test_framework.keys,
+                &manager,
+                request,
+            )
+        };
+        let outpoint = test_outpoint();
+
+        // We sign a Spend once, and refuse to sign another one of the same outpoint
+        let tx = test_framework.generate_spend_tx(&[outpoint]);
+        assert!(matches!(
+            handle(RequestParams::Sign(SignRequest { tx })),
+            Some(ResponseResult::SignResult(SignResult { tx: Some(_) }))
+        ));
+        let tx = test_framework.generate_spend_tx(&[outpoint]);
+        assert!(matches!(
+            handle(RequestParams::Sign(SignRequest { tx })),
+            Some(ResponseResult::SignResult(SignResult { tx: None }))
+        ));
+
+        // The other requests are never answered
+        let get_sigs = GetSigs {
+            txid: outpoint.txid,
+        };
+        assert!(handle(RequestParams::GetSigs(get_sigs)).is_none());
+    }
+
+    #[test]
+    fn protocol_violation() {
+        capture_logs();
+        let test_framework = CosignerTestBuilder::new(1);
+        let mut config = test_framework.config.clone();
+        config.listen = vec![SocketAddr::from_str("127.0.0.1:0").unwrap()];
+        let (handle, mut transport) = start_connected(&test_framework, config);
+
+        // A request for another method gets the connection dropped instead of being ignored
+        let get_sigs = GetSigs {
+            txid: test_outpoint().txid,
+        };
+        assert!(transport.send_req::<Sigs>(&get_sigs.into()).is_err());
+        drop(transport);
+        let manager_id = test_framework.config.managers[0].noise_key.0[..4].to_hex();
+        assert!(logged(&[
+            "Protocol violation",
+            &format!("manager '{}'", manager_id),
+            "'get_sigs' request",
+            "protocol version 0",
+        ]));
+
+        // It may still connect and send 'sign' requests afterward
+        {
+            let tx = test_framework.generate_spend_tx(&[OutPoint::from_str(
+                "8a1f1bcd3fd7613dbbebc684b389896e2bbe8ac6beebe1cbe8e6c190d025d60b:3",
+            )
+            .unwrap()]);
+            let mut transport = connect(&test_framework, handle.bound_addr(), 0).unwrap();
+            assert!(sign(&mut transport, tx).is_some());
+        }
+
+        handle.shutdown();
+    }
+
+    #[test]
+    fn handshake_failure_log_window() {
+        let mut failures = HandshakeFailureLog::default();
+        let start = Instant::now();
+
+        assert_eq!(
+            failures.should_log(HandshakeFailure::UnknownKey, start),
+            Some(0)
+        );
+        for secs in 1..4 {
+            assert_eq!(
+                failures.should_log(
+                    HandshakeFailure::UnknownKey,
+                    start + Duration::from_secs(secs)
+                ),
+                None
+            );
+        }
+        // Each kind has its own window
+        assert_eq!(
+            failures.should_log(HandshakeFailure::Malformed, start),
+            Some(0)
+        );
+        // Once it's elapsed, we log again and tell how many we didn't
+        assert_eq!(
+            failures.should_log(HandshakeFailure::UnknownKey, start + HANDSHAKE_LOG_WINDOW),
+            Some(3)
+        );
+        assert_eq!(
+            failures.should_log(
+                HandshakeFailure::UnknownKey,
+                start + HANDSHAKE_LOG_WINDOW + Duration::from_secs(1)
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn handshake_failures() {
+        capture_logs();
+        let test_framework = CosignerTestBuilder::new(1);
+        let mut config = test_framework.config.clone();
+        config.listen = vec![SocketAddr::from_str("127.0.0.1:0").unwrap()];
+        let handle = start(config, test_framework.keys.clone()).unwrap();
+
+        // A Noise key which isn't one of our managers'
+        let (_, unknown_privkey) = sodiumoxide::crypto::box_::gen_keypair();
+        assert!(KKTransport::connect(
+            handle.bound_addr(),
+            &NoisePrivkey(unknown_privkey.0),
+            &test_framework.keys.noise_pubkey()
+        )
+        .is_err());
+        // A truncated handshake
+        {
+            let mut stream = TcpStream::connect(handle.bound_addr()).unwrap();
+            stream.write_all(&[0x42; KK_MSG_1_SIZE / 2]).unwrap();
+            stream.shutdown(std::net::Shutdown::Write).unwrap();
+            let _ = stream.read(&mut [0; 1]);
+        }
+
+        handle.shutdown();
+
+        assert!(logged(&[
+            "Handshake failure with '127.0.0.1:",
+            "not using one of our managers' Noise keys",
+        ]));
+        assert!(logged(&[
+            "Handshake failure with '127.0.0.1:",
+            "malformed or incomplete handshake",
+        ]));
+        let metrics = PROMETHEUS_METRICS.render(None);
+        for reason in &["unknown_key", "malformed"] {
+            let line = format!(
+                "cosignerd_handshake_failures_total{{reason=\"{}\"}} ",
+                reason
+            );
+            assert!(metrics.contains(&line), "{} not in {}", line, metrics);
+        }
+    }
+
+    #[test]
+    fn dual_stack_listeners() {
+        // An IPv6 wildcard alone accepts IPv4 connections too
+        let wildcard = SocketAddr::from_str("[::]:0").unwrap();
+        let listener = bind_listener(&wildcard, &[wildcard]).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        TcpStream::connect(SocketAddr::from(([127, 0, 0, 1], port))).unwrap();
+
+        // Along with an IPv4 one on the same port, it's IPv6-only so that both can be bound
+        let v4_listener = bind_listener(&SocketAddr::from(([0, 0, 0, 0], 0)), &[]).unwrap();
+        let port = v4_listener.local_addr().unwrap().port();
+        let (v4, v6) = (
+            SocketAddr::from(([0, 0, 0, 0], port)),
+            SocketAddr::from(([0u16; 8], port)),
+        );
+        bind_listener(&v6, &[v6]).unwrap_err();
+        let v6_listener = bind_listener(&v6, &[v4, v6]).unwrap();
+        assert_eq!(v6_listener.local_addr().unwrap(), v6);
+    }
+
+    #[test]
+    fn reload_managers_settings() {
+        capture_logs();
+        let test_framework = CosignerTestBuilder::new(2);
+        let config_file = test_framework.config.data_dir.join("reload-settings.toml");
+        let write_config = |xpub: &str, extra: &str| {
+            fs::write(
+                &config_file,
+                format!(
+                    "data_dir = \"{}\"\n{}\n[[managers]]\nnoise_key = \"{}\"\nxpub = \"{}\"\n",
+                    test_framework.config.data_dir.display(),
+                    extra,
+                    test_framework.config.managers[0].noise_key.0.to_hex(),
+                    xpub
+                ),
+            )
+            .unwrap();
+        };
+        let (old_xpub, new_xpub) = (
+            test_framework.config.managers[0].xpub.clone().unwrap(),
+            test_framework.config.managers[1].xpub.clone().unwrap(),
+        );
+        write_config(&old_xpub.to_string(), "");
+        let config = Config::from_file(Some(config_file.clone())).unwrap();
+
+        // The xpubs are reloaded along with the Noise keys, and nothing is ignored
+        write_config(&new_xpub.to_string(), "");
+        let managers = reload_managers(&config).unwrap();
+        assert_eq!(managers[0].xpub, Some(new_xpub.clone()));
+        assert!(!logged(&["Ignoring the new value"]));
+
+        // Another setting isn't applied, and we say so
+        write_config(
+            &new_xpub.to_string(),
+            "require_rbf = true\nmax_feerate = 1000",
+        );
+        reload_managers(&config).unwrap();
+        for setting in &["require_rbf", "max_feerate"] {
+            assert!(logged(&[
+                &format!("Ignoring the new value of '{}' upon reload", setting),
+                "requires a restart"
+            ]));
+        }
+        assert!(!logged(&["Ignoring the new value of 'managers'"]));
+
+        fs::remove_file(&config_file).unwrap();
+    }
+
+    #[test]
+    fn managers_reload() {
+        let test_framework = CosignerTestBuilder::new(1);
+        let (new_manager_pubkey, new_manager_privkey) =
+            revault_net::sodiumoxide::crypto::box_::gen_keypair();
+        let config_file = test_framework.config.data_dir.join("config.toml");
+        let write_config = |listen: &str, noise_keys: &[&NoisePubkey]| {
+            let managers: String = noise_keys
+                .iter()
+                .map(|k| format!("[[managers]]\nnoise_key = \"{}\"\n", k.0.to_hex()))
+                .collect();
+            fs::write(
+                &config_file,
+                format!(
+                    "data_dir = \"{}\"\nlisten = \"{}\"\n{}",
+                    test_framework.config.data_dir.display(),
+                    listen,
+                    managers
+                ),
+            )
+            .unwrap();
+        };
+        let connect = |addr: SocketAddr, privkey| {
+            let tx = test_framework.generate_spend_tx(&[test_outpoint()]);
+            KKTransport::connect(addr, privkey, &test_framework.keys.noise_pubkey())
+                .and_then(|mut t| t.send_req::<SignResult>(&SignRequest { tx }.into()))
+        };
+        let old_manager_pubkey = &test_framework.config.managers[0].noise_key;
+
+        write_config("127.0.0.1:0", &[old_manager_pubkey]);
+        let config = Config::from_file(Some(config_file.clone())).unwrap();
+        setup_reload_signal().unwrap();
+        let handle = start(config, test_framework.keys.clone()).unwrap();
+        let sighup = || assert_eq!(unsafe { libc::raise(libc::SIGHUP) }, 0);
+
+        // Unknown for now
+        connect(handle.bound_addr(), &new_manager_privkey).unwrap_err();
+
+        // A reload changing the interface we listen on is refused
+        write_config("127.0.0.1:1", &[old_manager_pubkey, &new_manager_pubkey]);
+        sighup();
+        connect(handle.bound_addr(), &new_manager_privkey).unwrap_err();
+
+        // Once reloaded, the new manager can connect
+        write_config("127.0.0.1:0", &[old_manager_pubkey, &new_manager_pubkey]);
+        sighup();
+        connect(handle.bound_addr(), &new_manager_privkey).unwrap();
+
+        // A removed one can't anymore
+        write_config("127.0.0.1:0", &[&new_manager_pubkey]);
+        sighup();
+        connect(
+            handle.bound_addr(),
+            &test_framework.managers_noise_privkeys[0],
+        )
+        .unwrap_err();
+
+        handle.shutdown();
+    }
+
+    #[test]
+    fn allowed_outpoints_reload() {
+        let test_framework = CosignerTestBuilder::new(1);
+        let outpoint = test_outpoint();
+        let config_file = test_framework.config.data_dir.join("config.toml");
+        let allowlist_file = test_framework.config.data_dir.join("allowlist");
+        fs::write(
+            &config_file,
+            format!(
+                "data_dir = \"{}\"\nlisten = \"127.0.0.1:0\"\nallowed_outpoints_file = \"{}\"\n\
+                 [[managers]]\nnoise_key = \"{}\"\n",
+                test_framework.config.data_dir.display(),
+                allowlist_file.display(),
+                test_framework.config.managers[0].noise_key.0.to_hex()
+            ),
+        )
+        .unwrap();
+        fs::write(&allowlist_file, "").unwrap();
+        let sign_at = |addr: SocketAddr| {
+            let tx = test_framework.generate_spend_tx(&[outpoint]);
+            sign(&mut connect(&test_framework, addr, 0).unwrap(), tx)
+        };
+
+        let config = Config::from_file(Some(config_file)).unwrap();
+        setup_reload_signal().unwrap();
+        let handle = start(config, test_framework.keys.clone()).unwrap();
+
+        // Not allowed for now
+        assert!(sign_at(handle.bound_addr()).is_none());
+
+        // Once the allowlist is reloaded, it is. The signal may be delivered to the thread
+        // waiting for connections: it must keep on waiting, quietly.
+        capture_logs();
+        fs::write(&allowlist_file, format!("{}\n", outpoint)).unwrap();
+        assert_eq!(unsafe { libc::raise(libc::SIGHUP) }, 0);
+        assert!(sign_at(handle.bound_addr()).is_some());
+        // Let it get back to waiting
+        std::thread::sleep(Duration::from_millis(100));
+        let serving_thread = handle.thread.as_pthread_t();
+        assert_eq!(
+            unsafe { libc::pthread_kill(serving_thread, libc::SIGHUP) },
+            0
+        );
+        // Still served (we already signed this outpoint for another Spend)
+        assert!(sign_at(handle.bound_addr()).is_none());
+        assert!(!logged(&["[ERROR]", "Accepting new connection"]));
+
+        handle.shutdown();
+    }
+
+    #[test]
+    fn stalled_connection_timeout() {
+        let mut test_framework = CosignerTestBuilder::new(1);
+        test_framework.config.listen = vec![SocketAddr::from_str("127.0.0.1:0").unwrap()];
+        test_framework.config.connection_timeout = 1;
+        let tx = test_framework.generate_spend_tx(&[test_outpoint()]);
+
+        let handle = start(test_framework.config.clone(), test_framework.keys.clone()).unwrap();
+
+        // This one connects but never starts the handshake
+        let start = Instant::now();
+        let _stalled = TcpStream::connect(handle.bound_addr()).unwrap();
+
+        // We are still served once it timed out
+        let mut transport = connect(&test_framework, handle.bound_addr(), 0).unwrap();
+        assert!(sign(&mut transport, tx).is_some());
+        assert!(start.elapsed() >= Duration::from_secs(1));
+        drop(transport);
+
+        handle.shutdown();
+    }
+
+    #[test]
+    fn requests_rate_limiting() {
+        let mut test_framework = CosignerTestBuilder::new(1);
+        test_framework.config.listen = vec![SocketAddr::from_str("127.0.0.1:0").unwrap()];
+        test_framework.config.requests_per_minute = 2;
+        let tx = test_framework.generate_spend_tx(&[test_outpoint()]);
+
+        let (handle, mut transport) =
+            start_connected(&test_framework, test_framework.config.clone());
+
+        // The first 2 requests are served, the connection is dropped upon the third one
+        for _ in 0..2 {
+            assert!(sign(&mut transport, tx.clone()).is_some());
+        }
+        transport
+            .send_req::<SignResult>(&SignRequest { tx: tx.clone() }.into())
+            .unwrap_err();
+
+        // Reconnecting doesn't reset the limit
+        connect(&test_framework, handle.bound_addr(), 0)
+            .unwrap()
+            .send_req::<SignResult>(&SignRequest { tx }.into())
+            .unwrap_err();
+
+        handle.shutdown();
+    }
+
+    #[test]
+    fn refusal_alert_threshold() {
+        let manager = sodiumoxide::crypto::box_::gen_keypair().0;
+        let other_manager = sodiumoxide::crypto::box_::gen_keypair().0;
+        let mut tracker = RefusalTracker::new();
+
+        // No alert when it's disabled
+        for _ in 0..10 {
+            assert!(!tracker.record(&manager, true, None));
+        }
+        // A successful sign resets the counter
+        assert!(!tracker.record(&manager, false, None));
+        assert_eq!(tracker.consecutive_refusals(&manager), 0);
+
+        assert!(!tracker.record(&manager, true, Some(3)));
+        assert!(!tracker.record(&manager, true, Some(3)));
+        // Counters are per-manager
+        assert!(!tracker.record(&other_manager, true, Some(3)));
+        assert!(tracker.record(&manager, true, Some(3)));
+        assert_eq!(tracker.consecutive_refusals(&manager), 3);
+        assert_eq!(tracker.consecutive_refusals(&other_manager), 1);
+    }
+
+    #[test]
+    fn rate_limiting() {
+        let manager = sodiumoxide::crypto::box_::gen_keypair().0;
+        let other_manager = sodiumoxide::crypto::box_::gen_keypair().0;
+        let mut limiter = RateLimiter::new();
+        let start = std::time::Instant::now();
+
+        // The first 60 requests within the minute are allowed, not the 61st
+        for _ in 0..60 {
+            assert!(limiter.allow(&manager, 60, start));
+        }
+        assert!(!limiter.allow(&manager, 60, start));
+        // Buckets are per-manager
+        assert!(limiter.allow(&other_manager, 60, start));
+
+        // It refills at the configured rate
+        let later = start + std::time::Duration::from_millis(1_500);
+        assert!(limiter.allow(&manager, 60, later));
+        assert!(!limiter.allow(&manager, 60, later));
+        // But never above a minute worth of requests
+        let much_later = start + std::time::Duration::from_secs(3_600);
+        for _ in 0..60 {
+            assert!(limiter.allow(&manager, 60, much_later));
+        }
+        assert!(!limiter.allow(&manager, 60, much_later));
+
+        // 0 disables it
+        for _ in 0..1_000 {
+            assert!(limiter.allow(&manager, 0, much_later));
+        }
+    }
+}
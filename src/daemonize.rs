@@ -1,17 +1,45 @@
 use std::env::set_current_dir;
-use std::fs::File;
-use std::fs::OpenOptions;
-use std::io::prelude::*;
-use std::os::unix::io::AsRawFd;
-use std::path::PathBuf;
+use std::fs::{self, DirBuilder, File, OpenOptions};
+use std::io::{self, prelude::*};
+use std::mem;
+use std::os::unix::{
+    fs::{DirBuilderExt, MetadataExt},
+    io::{AsRawFd, FromRawFd},
+};
+use std::path::{Path, PathBuf};
+
+// Write our PID to this file, creating its parent directory if needed (eg for a path under
+// /run/cosignerd/).
+fn write_pid_file(pid_file: &Path, pid: libc::pid_t) -> Result<(), &'static str> {
+    if let Some(parent) = pid_file.parent() {
+        if !parent.as_os_str().is_empty() && !parent.exists() {
+            DirBuilder::new()
+                .mode(0o700)
+                .recursive(true)
+                .create(parent)
+                .map_err(|_| "Creating the PID file directory failed")?;
+        }
+    }
+
+    let pid_str = format!("{}", pid);
+    File::create(pid_file)
+        .map_err(|_| "Creating the PID file failed")?
+        .write_all(pid_str.as_bytes())
+        .map_err(|_| "Writing to the PID file failed")
+}
 
 // This code was highly inspired from Frank Denis (@jedisct1) 'daemonize-simple' crate,
 // available at https://github.com/jedisct1/rust-daemonize-simple/blob/master/src/unix.rs .
 // MIT licensed according to https://github.com/jedisct1/rust-daemonize-simple/blob/master/Cargo.toml
+/// Detach from the controlling terminal, redirecting stdout and stderr to `log_file` and writing
+/// our PID to `pid_file`.
+///
+/// # Safety
+/// This forks the process, it must be called before any other thread is spawned.
 pub unsafe fn daemonize(
     chdir: &PathBuf,
-    pid_file: &PathBuf,
-    log_file: &PathBuf,
+    pid_file: &Path,
+    log_file: &Path,
 ) -> Result<(), &'static str> {
     match libc::fork() {
         -1 => return Err("fork() failed"),
@@ -36,34 +64,368 @@ pub unsafe fn daemonize(
     if libc::dup2(fd.as_raw_fd(), 0) == -1 {
         return Err("dup2(stdin) failed");
     }
+    redirect_output(log_file)?;
+
+    let pid = match libc::getpid() {
+        -1 => return Err("getpid() failed"),
+        pid => pid,
+    };
+    write_pid_file(pid_file, pid)?;
+
+    set_current_dir(chdir).map_err(|_| "chdir() failed")?;
+
+    Ok(())
+}
+
+// Point our stdout and stderr to the end of this file, creating it if needed
+fn redirect_output(log_file: &Path) -> Result<(), &'static str> {
     let fd = OpenOptions::new()
         .create(true)
-        .write(true)
+        .append(true)
         .open(log_file)
         .map_err(|_| "Unable to open the stdout file")?;
-    if libc::dup2(fd.as_raw_fd(), 1) == -1 {
+    if unsafe { libc::dup2(fd.as_raw_fd(), 1) } == -1 {
         return Err("dup2(stdout) failed");
     }
     let fd = OpenOptions::new()
         .create(true)
-        .write(true)
+        .append(true)
         .open(log_file)
         .map_err(|_| "Unable to open the stderr file")?;
-    if libc::dup2(fd.as_raw_fd(), 2) == -1 {
+    if unsafe { libc::dup2(fd.as_raw_fd(), 2) } == -1 {
         return Err("dup2(stderr) failed");
     }
 
-    let pid = match libc::getpid() {
-        -1 => return Err("getpid() failed"),
-        pid => pid,
+    Ok(())
+}
+
+// The path of the n-th backup of this log file
+fn log_backup(log_file: &Path, n: u32) -> PathBuf {
+    let mut backup = log_file.as_os_str().to_owned();
+    backup.push(format!(".{}", n));
+    PathBuf::from(backup)
+}
+
+/// Shift the backups of this log file (`log.1` becomes `log.2`, ..), dropping the oldest one,
+/// and make it the first backup. With no backups, it's only removed.
+pub fn rotate_log_files(log_file: &Path, backups: u32) -> io::Result<()> {
+    if backups == 0 {
+        return fs::remove_file(log_file);
+    }
+
+    for n in (1..backups).rev() {
+        let backup = log_backup(log_file, n);
+        if backup.exists() {
+            fs::rename(&backup, log_backup(log_file, n + 1))?;
+        }
+    }
+    fs::rename(log_file, log_backup(log_file, 1))
+}
+
+/// Our log lines once daemonized, with a rotation of the log file past a size. It writes to
+/// stdout: as long as it isn't redirected to the log file (eg before we daemonize) it's never
+/// rotated.
+#[derive(Debug)]
+pub struct RotatingLog {
+    log_file: PathBuf,
+    max_size: u64,
+    backups: u32,
+}
+
+impl RotatingLog {
+    pub fn new(log_file: PathBuf, max_size: u64, backups: u32) -> Self {
+        Self {
+            log_file,
+            max_size,
+            backups,
+        }
+    }
+
+    // The size of the log file, if our stdout is redirected to it
+    fn redirected_size(&self) -> Option<u64> {
+        let log_meta = fs::metadata(&self.log_file).ok()?;
+        // Don't close our stdout when done
+        let stdout = mem::ManuallyDrop::new(unsafe { File::from_raw_fd(1) });
+        let stdout_meta = stdout.metadata().ok()?;
+        if (stdout_meta.dev(), stdout_meta.ino()) != (log_meta.dev(), log_meta.ino()) {
+            return None;
+        }
+
+        Some(log_meta.len())
+    }
+}
+
+impl Write for RotatingLog {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        io::stdout().write(buf)
+    }
+
+    // The logger flushes after each line, so we never split one across files
+    fn flush(&mut self) -> io::Result<()> {
+        io::stdout().flush()?;
+
+        match self.redirected_size() {
+            Some(size) if size >= self.max_size => {
+                rotate_log_files(&self.log_file, self.backups)?;
+                redirect_output(&self.log_file).map_err(io::Error::other)
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::{daemonize, log_backup, rotate_log_files, write_pid_file, RotatingLog};
+    use crate::{keys::Keys, tests::builder::CosignerTestBuilder};
+    use revault_net::noise::SecretKey as NoisePrivKey;
+    use revault_tx::bitcoin::secp256k1::SecretKey as BitcoinPrivKey;
+    use std::{
+        env, fs, io::Write, os::unix::fs::PermissionsExt, path::PathBuf, process, thread, time,
     };
-    let pid_str = format!("{}", pid);
-    File::create(pid_file)
-        .map_err(|_| "Creating the PID file failed")?
-        .write_all(pid_str.as_bytes())
-        .map_err(|_| "Writing to the PID file failed")?;
 
-    set_current_dir(chdir).map_err(|_| "chdir() failed")?;
+    const TEST_DIR_ENV: &str = "COSIGNERD_DAEMONIZE_TEST_DIR";
+    const ROTATION_TEST_DIR_ENV: &str = "COSIGNERD_LOG_ROTATION_TEST_DIR";
 
-    Ok(())
+    // The size of the memory this process locked in RAM, in kB
+    fn locked_memory_kb() -> i32 {
+        fs::read_to_string("/proc/self/status")
+            .unwrap()
+            .lines()
+            .find_map(|l| l.strip_prefix("VmLck:"))
+            .and_then(|l| l.trim().trim_end_matches("kB").trim().parse().ok())
+            .expect("No VmLck line")
+    }
+
+    // Not a test per se: this is run in a child process by `daemonize_sanity` below.
+    #[test]
+    #[ignore]
+    fn daemonize_child() {
+        let dir = match env::var_os(TEST_DIR_ENV) {
+            Some(dir) => PathBuf::from(dir),
+            None => return,
+        };
+        let (pid_file, log_file) = (dir.join("cosignerd.pid"), dir.join("log"));
+        let mut keys = Keys::new(
+            NoisePrivKey([0x42; 32]),
+            BitcoinPrivKey::from_slice(&[0x42; 32]).unwrap(),
+        );
+        assert!(locked_memory_kb() > 0);
+
+        unsafe {
+            daemonize(&dir, &pid_file, &log_file).unwrap();
+
+            // We are in the grandchild now. The intermediate child created our session, wait for
+            // it to exit so that we get reparented.
+            let session = libc::getsid(0);
+            for _ in 0..500 {
+                if libc::getppid() != session {
+                    break;
+                }
+                thread::sleep(time::Duration::from_millis(10));
+            }
+
+            println!("stdout is redirected");
+            eprintln!("stderr is redirected");
+            std::io::stdout().flush().unwrap();
+
+            // The locks didn't survive the forks
+            let locked_before = locked_memory_kb();
+            keys.relock_memory();
+            let locked_after = locked_memory_kb();
+
+            let report = format!(
+                "{} {} {} {} {}",
+                libc::getpid(),
+                libc::getppid(),
+                session,
+                locked_before,
+                locked_after
+            );
+            fs::write(dir.join("report.tmp"), report).unwrap();
+            fs::rename(dir.join("report.tmp"), dir.join("report")).unwrap();
+
+            // Don't let the test harness continue in the detached process
+            libc::_exit(0);
+        }
+    }
+
+    #[test]
+    fn daemonize_sanity() {
+        let test_framework = CosignerTestBuilder::new(1);
+        let dir = test_framework.config.data_dir.clone();
+
+        let mut child = process::Command::new(env::current_exe().unwrap())
+            .args([
+                "--exact",
+                "daemonize::tests::daemonize_child",
+                "--ignored",
+                "--nocapture",
+                "--test-threads=1",
+            ])
+            .env(TEST_DIR_ENV, &dir)
+            .stdout(process::Stdio::null())
+            .stderr(process::Stdio::null())
+            .spawn()
+            .unwrap();
+        let child_pid = child.id() as i32;
+        // The process we spawned exits right away, leaving the daemon running
+        assert!(child.wait().unwrap().success());
+
+        let report_path = dir.join("report");
+        for _ in 0..1_000 {
+            if report_path.exists() {
+                break;
+            }
+            thread::sleep(time::Duration::from_millis(10));
+        }
+        let report: Vec<i32> = fs::read_to_string(&report_path)
+            .expect("The daemon never reported")
+            .split(' ')
+            .map(|n| n.parse().unwrap())
+            .collect();
+        let (daemon_pid, daemon_ppid, daemon_session) = (report[0], report[1], report[2]);
+        let (locked_before, locked_after) = (report[3], report[4]);
+
+        // It detached from both the process we spawned and the intermediate session leader, and
+        // got reparented to init (or to the nearest subreaper).
+        assert_ne!(daemon_pid, child_pid);
+        assert_ne!(daemon_ppid, child_pid);
+        assert_ne!(daemon_ppid, daemon_session);
+        // It's in a new session, but isn't its leader so it can't acquire a controlling terminal.
+        assert_ne!(daemon_session, unsafe { libc::getsid(0) });
+        assert_ne!(daemon_session, daemon_pid);
+
+        // Our secrets are locked in the daemon's memory once we relocked them
+        assert_eq!(locked_before, 0);
+        assert!(locked_after > 0);
+
+        // The pid file is the daemon's
+        let pid_file_content = fs::read_to_string(dir.join("cosignerd.pid")).unwrap();
+        assert_eq!(pid_file_content, daemon_pid.to_string());
+
+        // Both stdout and stderr end up in the log file
+        let log = fs::read_to_string(dir.join("log")).unwrap();
+        assert!(log.contains("stdout is redirected"), "{}", log);
+        assert!(log.contains("stderr is redirected"), "{}", log);
+    }
+
+    #[test]
+    fn log_files_rotation() {
+        let test_framework = CosignerTestBuilder::new(1);
+        let log_file = test_framework.config.data_dir.join("log");
+
+        for i in 0..4 {
+            fs::write(&log_file, i.to_string()).unwrap();
+            rotate_log_files(&log_file, 2).unwrap();
+        }
+        // The oldest ones were dropped
+        assert!(!log_file.exists());
+        assert_eq!(fs::read_to_string(log_backup(&log_file, 1)).unwrap(), "3");
+        assert_eq!(fs::read_to_string(log_backup(&log_file, 2)).unwrap(), "2");
+        assert!(!log_backup(&log_file, 3).exists());
+
+        fs::write(&log_file, "4").unwrap();
+        rotate_log_files(&log_file, 0).unwrap();
+        assert!(!log_file.exists());
+        assert_eq!(fs::read_to_string(log_backup(&log_file, 1)).unwrap(), "3");
+    }
+
+    // Not a test per se: this is run in a child process, whose stdout is the log file, by
+    // `log_rotation_sanity` below.
+    #[test]
+    #[ignore]
+    fn log_rotation_child() {
+        let dir = match env::var_os(ROTATION_TEST_DIR_ENV) {
+            Some(dir) => PathBuf::from(dir),
+            None => return,
+        };
+
+        let mut log = RotatingLog::new(dir.join("log"), 100, 2);
+        for i in 0..10 {
+            writeln!(log, "A log line number {:04}", i).unwrap();
+            log.flush().unwrap();
+        }
+    }
+
+    #[test]
+    fn log_rotation_sanity() {
+        let test_framework = CosignerTestBuilder::new(1);
+        let dir = test_framework.config.data_dir.clone();
+        let log_file = dir.join("log");
+
+        // Not rotating when not writing to the log file
+        let mut log = RotatingLog::new(log_file.clone(), 1, 2);
+        fs::write(&log_file, "Not our stdout\n").unwrap();
+        log.flush().unwrap();
+        assert!(!log_backup(&log_file, 1).exists());
+        fs::remove_file(&log_file).unwrap();
+
+        let status = process::Command::new(env::current_exe().unwrap())
+            .args([
+                "--exact",
+                "daemonize::tests::log_rotation_child",
+                "--ignored",
+                "--nocapture",
+                "--test-threads=1",
+            ])
+            .env(ROTATION_TEST_DIR_ENV, &dir)
+            .stdout(fs::File::create(&log_file).unwrap())
+            .stderr(process::Stdio::null())
+            .status()
+            .unwrap();
+        assert!(status.success());
+
+        // Once past 100 bytes the file is rotated, and no line is split across files
+        let (log_2, log_1) = (
+            fs::read_to_string(log_backup(&log_file, 2)).unwrap(),
+            fs::read_to_string(log_backup(&log_file, 1)).unwrap(),
+        );
+        assert!(!log_backup(&log_file, 3).exists());
+        for rotated in &[&log_2, &log_1] {
+            assert!(rotated.len() >= 100 && rotated.len() < 130, "{}", rotated);
+            assert!(rotated.ends_with("\n"));
+        }
+        // The test harness wrote its report after our last line
+        let log = fs::read_to_string(&log_file).unwrap();
+        let all = format!("{}{}{}", log_2, log_1, log);
+        for i in 0..10 {
+            assert!(
+                all.contains(&format!("A log line number {:04}\n", i)),
+                "{}",
+                all
+            );
+        }
+    }
+
+    #[test]
+    fn pid_file_missing_directory() {
+        let test_framework = CosignerTestBuilder::new(1);
+        let run_dir = test_framework.config.data_dir.join("run").join("cosignerd");
+        let pid_file = run_dir.join("cosignerd.pid");
+        assert!(!run_dir.exists());
+
+        write_pid_file(&pid_file, 42).unwrap();
+        assert_eq!(fs::read_to_string(&pid_file).unwrap(), "42");
+        let mode = fs::metadata(&run_dir).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o700);
+
+        // It's fine if it exists already, and the PID is overwritten
+        write_pid_file(&pid_file, 43).unwrap();
+        assert_eq!(fs::read_to_string(&pid_file).unwrap(), "43");
+
+        // We can't create a directory under a regular file
+        let bad_pid_file = test_framework
+            .config
+            .data_dir
+            .join("run")
+            .join("cosignerd")
+            .join("cosignerd.pid")
+            .join("sub")
+            .join("cosignerd.pid");
+        assert_eq!(
+            write_pid_file(&bad_pid_file, 42).unwrap_err(),
+            "Creating the PID file directory failed"
+        );
+    }
 }
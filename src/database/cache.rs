@@ -0,0 +1,172 @@
+use revault_tx::miniscript::bitcoin::{secp256k1::Signature, OutPoint};
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+// Entries are keyed by database too, as there is nothing preventing a process from using more
+// than one (our tests do).
+type CacheKey = (PathBuf, OutPoint);
+
+#[derive(Debug)]
+struct CacheInner {
+    // The signature and the last time it was used
+    entries: BTreeMap<CacheKey, (Signature, u64)>,
+    by_use: BTreeMap<u64, CacheKey>,
+    clock: u64,
+}
+
+impl CacheInner {
+    fn touch(&mut self, key: &CacheKey) {
+        self.clock += 1;
+        let clock = self.clock;
+        if let Some((_, last_use)) = self.entries.get_mut(key) {
+            self.by_use.remove(last_use);
+            *last_use = clock;
+            self.by_use.insert(clock, key.clone());
+        }
+    }
+}
+
+/// A least-recently-used cache of the signatures stored in the database, so that retried
+/// requests don't hit the disk. It only ever contains outpoints signed for good: it must be
+/// invalidated if they are removed from the database.
+#[derive(Debug)]
+pub struct SignatureCache {
+    inner: Mutex<CacheInner>,
+}
+
+/// The cache of this process. Lookups go through [super::db_signature_cached].
+pub static SIGNATURE_CACHE: SignatureCache = SignatureCache::new();
+
+impl SignatureCache {
+    const fn new() -> Self {
+        Self {
+            inner: Mutex::new(CacheInner {
+                entries: BTreeMap::new(),
+                by_use: BTreeMap::new(),
+                clock: 0,
+            }),
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, CacheInner> {
+        // The state is always consistent between two statements
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// The stored signature for this outpoint, if cached
+    pub fn get(&self, db_path: &Path, outpoint: &OutPoint) -> Option<Signature> {
+        let mut inner = self.lock();
+        let key = (db_path.to_path_buf(), *outpoint);
+        let sig = inner.entries.get(&key).map(|(sig, _)| *sig)?;
+        inner.touch(&key);
+        Some(sig)
+    }
+
+    /// Cache the stored signature for this outpoint, evicting the least recently used entries
+    /// beyond `capacity`.
+    pub fn insert(
+        &self,
+        db_path: &Path,
+        outpoint: &OutPoint,
+        signature: Signature,
+        capacity: usize,
+    ) {
+        if capacity == 0 {
+            return;
+        }
+
+        let mut inner = self.lock();
+        let key = (db_path.to_path_buf(), *outpoint);
+        inner.clock += 1;
+        let clock = inner.clock;
+        if let Some((_, last_use)) = inner.entries.insert(key.clone(), (signature, clock)) {
+            inner.by_use.remove(&last_use);
+        }
+        inner.by_use.insert(clock, key);
+
+        while inner.entries.len() > capacity {
+            let oldest = match inner.by_use.keys().next() {
+                Some(oldest) => *oldest,
+                None => break,
+            };
+            if let Some(key) = inner.by_use.remove(&oldest) {
+                inner.entries.remove(&key);
+            }
+        }
+    }
+
+    /// Forget all the signatures cached for this database
+    pub fn invalidate(&self, db_path: &Path) {
+        let mut inner = self.lock();
+        let CacheInner {
+            entries, by_use, ..
+        } = &mut *inner;
+        entries.retain(|(path, _), (_, last_use)| {
+            if path == db_path {
+                by_use.remove(last_use);
+                false
+            } else {
+                true
+            }
+        });
+    }
+
+    #[cfg(test)]
+    pub fn len(&self, db_path: &Path) -> usize {
+        self.lock()
+            .entries
+            .keys()
+            .filter(|(path, _)| path == db_path)
+            .count()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use revault_tx::miniscript::bitcoin::secp256k1;
+    use std::str::FromStr;
+
+    #[test]
+    fn lru_eviction() {
+        let cache = SignatureCache::new();
+        let (db_a, db_b) = (PathBuf::from("/a"), PathBuf::from("/b"));
+        let secp = secp256k1::Secp256k1::new();
+        let sig = secp.sign(
+            &secp256k1::Message::from_slice(&[0x01; 32]).unwrap(),
+            &secp256k1::SecretKey::from_slice(&[0x02; 32]).unwrap(),
+        );
+        let outpoints: Vec<OutPoint> = (0..3)
+            .map(|vout| {
+                OutPoint::from_str(&format!(
+                    "2b8930127e9dfd1bcdf35df2bc7f3b8cdbec083b1ae693f36b6305fccd1425da:{}",
+                    vout
+                ))
+                .unwrap()
+            })
+            .collect();
+
+        // Nothing is ever cached without room for it
+        cache.insert(&db_a, &outpoints[0], sig, 0);
+        assert!(cache.get(&db_a, &outpoints[0]).is_none());
+
+        cache.insert(&db_a, &outpoints[0], sig, 2);
+        cache.insert(&db_a, &outpoints[1], sig, 2);
+        assert!(cache.get(&db_b, &outpoints[0]).is_none());
+        // Using the first one makes the second one the least recently used
+        assert_eq!(cache.get(&db_a, &outpoints[0]), Some(sig));
+        cache.insert(&db_a, &outpoints[2], sig, 2);
+        assert_eq!(cache.len(&db_a), 2);
+        assert!(cache.get(&db_a, &outpoints[1]).is_none());
+        assert!(cache.get(&db_a, &outpoints[0]).is_some());
+        assert!(cache.get(&db_a, &outpoints[2]).is_some());
+
+        cache.insert(&db_b, &outpoints[1], sig, 3);
+        cache.invalidate(&db_a);
+        assert_eq!(cache.len(&db_a), 0);
+        assert_eq!(cache.get(&db_b, &outpoints[1]), Some(sig));
+    }
+}
@@ -1,16 +1,177 @@
+pub mod cache;
 mod schema;
 
-use revault_tx::miniscript::bitcoin::{self, consensus::encode, secp256k1::Signature, OutPoint};
-use rusqlite::{params, types::FromSqlError, Row, ToSql};
+use cache::SIGNATURE_CACHE;
+use revault_tx::miniscript::bitcoin::{
+    self,
+    consensus::encode,
+    hashes::{sha256, Hash},
+    secp256k1::Signature,
+    OutPoint,
+};
+use rusqlite::{backup, params, types::FromSqlError, OpenFlags, Row, ToSql, TransactionBehavior};
 use schema::{DbSignedOutpoint, SCHEMA};
 use std::{
+    collections::HashMap,
     convert::{TryFrom, TryInto},
-    fs,
+    fs, io,
     os::unix::fs::OpenOptionsExt,
-    path::PathBuf,
+    path::{Path, PathBuf},
+    process,
+    sync::{
+        atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering},
+        Mutex,
+    },
+    thread, time,
 };
 
-pub const DB_VERSION: u32 = 0;
+pub const DB_VERSION: u32 = 2;
+
+/// How long a connection waits for another one to release its lock by default, in milliseconds
+pub const DB_BUSY_TIMEOUT_MS_DEFAULT: u64 = 5000;
+
+// It's for the process as a whole, as are the connections
+static DB_BUSY_TIMEOUT_MS: AtomicU64 = AtomicU64::new(DB_BUSY_TIMEOUT_MS_DEFAULT);
+
+/// Set how long the connections we open wait for a concurrent one to release its lock, instead
+/// of failing with SQLITE_BUSY.
+pub fn db_set_busy_timeout(timeout_ms: u64) {
+    DB_BUSY_TIMEOUT_MS.store(timeout_ms, Ordering::Relaxed);
+}
+
+/// How many times we retry an operation which failed because the database was busy, by default
+pub const DB_BUSY_RETRIES_DEFAULT: u32 = 3;
+
+static DB_BUSY_RETRIES: AtomicU32 = AtomicU32::new(DB_BUSY_RETRIES_DEFAULT);
+
+// The delay before the first retry, doubled at each of the next ones
+const DB_RETRY_BASE_DELAY: time::Duration = time::Duration::from_millis(10);
+
+/// Set how many times we retry a query or a transaction which failed because the database was
+/// busy or locked (even after the busy timeout). Other errors are never retried.
+pub fn db_set_busy_retries(retries: u32) {
+    DB_BUSY_RETRIES.store(retries, Ordering::Relaxed);
+}
+
+// The databases we only ever open read-only. They are tracked by path as nothing prevents a
+// process from using more than one (our tests do).
+static READ_ONLY_DBS: Mutex<Vec<PathBuf>> = Mutex::new(Vec::new());
+
+/// Only ever open this database read-only from now on, so that any write to it fails. This is
+/// for a standby cosigner tracking the anti-replay state of the primary through its backups.
+pub fn db_set_read_only(db_path: &Path) {
+    let mut read_only_dbs = READ_ONLY_DBS.lock().unwrap_or_else(|e| e.into_inner());
+    if !read_only_dbs.iter().any(|path| path == db_path) {
+        read_only_dbs.push(db_path.to_path_buf());
+    }
+}
+
+// The databases kept in memory, by the path they stand in for and the name SQLite knows them by
+static IN_MEMORY_DBS: Mutex<Vec<(PathBuf, String)>> = Mutex::new(Vec::new());
+
+// The URI to open the in-memory database standing in for this path, if there is one
+fn in_memory_uri(db_path: &Path) -> Option<String> {
+    IN_MEMORY_DBS
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .iter()
+        .find(|(path, _)| path == db_path)
+        .map(|(_, name)| format!("file:{}?mode=memory&cache=shared", name))
+}
+
+/// A database living in memory for as long as this isn't dropped, see [db_in_memory]
+pub struct InMemoryDb {
+    path: PathBuf,
+    // SQLite frees an in-memory database along with its last connection
+    _conn: rusqlite::Connection,
+}
+
+impl Drop for InMemoryDb {
+    fn drop(&mut self) {
+        IN_MEMORY_DBS
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .retain(|(path, _)| path != &self.path);
+    }
+}
+
+/// Create a fresh database in memory, which the functions of this module use in place of the one
+/// at `db_path` until the returned handle is dropped. Nothing is ever read from or written to
+/// `db_path`. This is for scratch databases, such as the offline selftest's.
+pub fn db_in_memory(db_path: &Path) -> Result<InMemoryDb, DatabaseError> {
+    static N_IN_MEMORY: AtomicUsize = AtomicUsize::new(0);
+    let name = format!(
+        "cosignerd-{}-{}",
+        process::id(),
+        N_IN_MEMORY.fetch_add(1, Ordering::SeqCst)
+    );
+
+    let conn = rusqlite::Connection::open_with_flags(
+        format!("file:{}?mode=memory&cache=shared", name),
+        OpenFlags::SQLITE_OPEN_READ_WRITE
+            | OpenFlags::SQLITE_OPEN_CREATE
+            | OpenFlags::SQLITE_OPEN_NO_MUTEX
+            | OpenFlags::SQLITE_OPEN_URI,
+    )
+    .map_err(|e| DatabaseError(format!("Opening in-memory database: {}", e)))?;
+    conn.execute_batch(SCHEMA)
+        .map_err(|e| DatabaseError(format!("Creating database: {}", e)))?;
+    conn.execute(
+        "INSERT INTO db_params (version) VALUES (?1)",
+        params![DB_VERSION],
+    )
+    .map_err(|e| DatabaseError(format!("Inserting db_params: {}", e)))?;
+
+    IN_MEMORY_DBS
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .push((db_path.to_path_buf(), name));
+    Ok(InMemoryDb {
+        path: db_path.to_path_buf(),
+        _conn: conn,
+    })
+}
+
+/// Whether this database is only ever opened read-only, see [db_set_read_only]
+pub fn db_is_read_only(db_path: &Path) -> bool {
+    READ_ONLY_DBS
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .iter()
+        .any(|path| path == db_path)
+}
+
+// Whether this error is about another connection holding a lock, and may not happen again
+fn is_transient(error: &rusqlite::Error) -> bool {
+    matches!(
+        error,
+        rusqlite::Error::SqliteFailure(e, _)
+            if e.code == rusqlite::ErrorCode::DatabaseBusy
+                || e.code == rusqlite::ErrorCode::DatabaseLocked
+    )
+}
+
+// Run this operation, retrying it with an exponential backoff if it failed with a transient error
+fn with_retries<T, F>(mut operation: F) -> rusqlite::Result<T>
+where
+    F: FnMut() -> rusqlite::Result<T>,
+{
+    let max_retries = DB_BUSY_RETRIES.load(Ordering::Relaxed);
+    let mut delay = DB_RETRY_BASE_DELAY;
+    let mut retries = 0;
+
+    loop {
+        match operation() {
+            Err(e) if retries < max_retries && is_transient(&e) => {
+                log::debug!("Database is busy ('{}'), retrying in {:?}", e, delay);
+                thread::sleep(delay);
+                delay *= 2;
+                retries += 1;
+            }
+            res => return res,
+        }
+    }
+}
 
 #[derive(PartialEq, Eq, Debug)]
 pub struct DatabaseError(pub String);
@@ -23,49 +184,109 @@ impl std::fmt::Display for DatabaseError {
 
 impl std::error::Error for DatabaseError {}
 
-/// Perform a set of modifications to the database inside a single transaction
-pub fn db_exec<F>(path: &PathBuf, modifications: F) -> Result<(), DatabaseError>
+#[cfg(test)]
+thread_local! {
+    // How many times we opened a database from this thread
+    pub static N_OPENS: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+}
+
+fn open_db(path: &PathBuf) -> rusqlite::Result<rusqlite::Connection> {
+    #[cfg(test)]
+    N_OPENS.with(|n| n.set(n.get() + 1));
+    let conn = if let Some(uri) = in_memory_uri(path) {
+        rusqlite::Connection::open_with_flags(
+            uri,
+            OpenFlags::SQLITE_OPEN_READ_WRITE
+                | OpenFlags::SQLITE_OPEN_NO_MUTEX
+                | OpenFlags::SQLITE_OPEN_URI,
+        )?
+    } else if db_is_read_only(path) {
+        rusqlite::Connection::open_with_flags(
+            path,
+            OpenFlags::SQLITE_OPEN_READ_ONLY
+                | OpenFlags::SQLITE_OPEN_NO_MUTEX
+                | OpenFlags::SQLITE_OPEN_URI,
+        )?
+    } else {
+        rusqlite::Connection::open(path)?
+    };
+    conn.busy_timeout(time::Duration::from_millis(
+        DB_BUSY_TIMEOUT_MS.load(Ordering::Relaxed),
+    ))?;
+
+    // The journal mode is persistent but the synchronous setting is per-connection. See
+    // db_set_wal for the tradeoff.
+    let journal_mode: String =
+        conn.query_row("PRAGMA journal_mode", params![], |row| row.get(0))?;
+    if journal_mode == "wal" {
+        conn.execute_batch("PRAGMA synchronous = NORMAL")?;
+    }
+
+    Ok(conn)
+}
+
+/// Perform a set of modifications to the database inside a single transaction. The write lock is
+/// taken upfront, so the whole transaction is retried if the database is busy. `modifications`
+/// may thus be called more than once, each time after the previous attempt was rolled back: it
+/// must start over whatever it accumulates.
+pub fn db_exec<F>(path: &PathBuf, mut modifications: F) -> Result<(), DatabaseError>
 where
-    F: Fn(&rusqlite::Transaction) -> Result<(), DatabaseError>,
+    F: FnMut(&rusqlite::Transaction) -> Result<(), DatabaseError>,
 {
-    let mut conn = rusqlite::Connection::open(path)
-        .map_err(|e| DatabaseError(format!("Opening database: {}", e.to_string())))?;
-    let tx = conn
-        .transaction()
-        .map_err(|e| DatabaseError(format!("Creating transaction: {}", e.to_string())))?;
+    let mut step = "";
+    let mut modifications_res = Ok(());
+    with_retries(|| {
+        step = "Opening database";
+        let mut conn = open_db(path)?;
+        step = "Creating transaction";
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
 
-    modifications(&tx)?;
-    tx.commit()
-        .map_err(|e| DatabaseError(format!("Comitting transaction: {}", e.to_string())))?;
+        // Dropping the transaction rolls it back
+        modifications_res = modifications(&tx);
+        if modifications_res.is_ok() {
+            step = "Comitting transaction";
+            tx.commit()?;
+        }
+        Ok(())
+    })
+    .map_err(|e| DatabaseError(format!("{}: {}", step, e)))?;
 
-    Ok(())
+    modifications_res
+}
+
+// The current time in seconds since the epoch, as we store it
+fn now() -> i64 {
+    time::SystemTime::now()
+        .duration_since(time::UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0)
 }
 
 // Internal helper for queries boilerplate
-fn db_query<'a, P, F, T>(
+fn db_query<P, F, T>(
     path: &PathBuf,
-    stmt_str: &'a str,
+    stmt_str: &str,
     params: P,
-    f: F,
+    mut f: F,
 ) -> Result<Vec<T>, DatabaseError>
 where
-    P: IntoIterator,
+    P: IntoIterator + Clone,
     P::Item: ToSql,
     F: FnMut(&Row<'_>) -> rusqlite::Result<T>,
 {
-    let conn = rusqlite::Connection::open(path)
-        .map_err(|e| DatabaseError(format!("Opening database for query: {}", e.to_string())))?;
-
-    // rustc says 'borrowed value does not live long enough'
-    let x = conn
-        .prepare(stmt_str)
-        .map_err(|e| DatabaseError(format!("Preparing query: '{}'", e.to_string())))?
-        .query_map(params, f)
-        .map_err(|e| DatabaseError(format!("Mapping query: '{}'", e.to_string())))?
-        .collect::<rusqlite::Result<Vec<T>>>()
-        .map_err(|e| DatabaseError(format!("Executing query: '{}'", e.to_string())));
+    let mut step = "";
+    with_retries(|| {
+        step = "Opening database for query";
+        let conn = open_db(path)?;
 
-    x
+        step = "Preparing query";
+        let mut stmt = conn.prepare(stmt_str)?;
+        step = "Mapping query";
+        let rows = stmt.query_map(params.clone(), &mut f)?;
+        step = "Executing query";
+        rows.collect::<rusqlite::Result<Vec<T>>>()
+    })
+    .map_err(|e| DatabaseError(format!("{}: '{}'", step, e)))
 }
 
 /// Get the database version
@@ -89,12 +310,14 @@ impl TryFrom<&Row<'_>> for DbSignedOutpoint {
             vout: row.get(1)?,
         };
         let signature = row.get::<_, Vec<u8>>(2)?;
-        let signature = Signature::from_der(&signature)
-            .expect("We only ever store valid DER-encoded signatures");
+        let signature =
+            Signature::from_der(&signature).map_err(|e| FromSqlError::Other(Box::new(e)))?;
+        let signed_at = row.get::<_, i64>(3)? as u64;
 
         Ok(DbSignedOutpoint {
             outpoint,
             signature,
+            signed_at,
         })
     }
 }
@@ -102,42 +325,406 @@ impl TryFrom<&Row<'_>> for DbSignedOutpoint {
 /// Check for existence of signed outpoint in the database. If it
 /// doesn't exist, returns Ok(None). Returns Ok(Some(DbSignedOutpoint))
 /// if it does exist.
+/// Outpoints that are reserved but not (yet) signed are not returned.
 pub fn db_signed_outpoint(
     db_path: &PathBuf,
     signed_outpoint: &OutPoint,
 ) -> Result<Option<DbSignedOutpoint>, DatabaseError> {
     db_query(
         db_path,
-        "SELECT * FROM signed_outpoints WHERE txid = (?1) AND vout = (?2)",
+        "SELECT * FROM signed_outpoints WHERE txid = (?1) AND vout = (?2) \
+         AND signature != x''",
         params![signed_outpoint.txid.to_vec(), signed_outpoint.vout],
         |row| row.try_into(),
     )
     .map(|mut rows| rows.pop())
 }
 
+/// Get the signature we stored for this outpoint. Returns Ok(None) if we didn't sign it (yet).
+pub fn db_get_signature(
+    db_path: &PathBuf,
+    outpoint: &OutPoint,
+) -> Result<Option<Signature>, DatabaseError> {
+    db_query(
+        db_path,
+        "SELECT signature FROM signed_outpoints WHERE txid = (?1) AND vout = (?2) \
+         AND signature != x''",
+        params![outpoint.txid.to_vec(), outpoint.vout],
+        |row| {
+            let der = row.get::<_, Vec<u8>>(0)?;
+            Signature::from_der(&der).map_err(|e| FromSqlError::Other(Box::new(e)).into())
+        },
+    )
+    .map(|mut rows| rows.pop())
+}
+
+/// Get the signature we stored for this outpoint, if we signed it, from the cache of the
+/// `cache_size` most recently used ones or from the database on a miss.
+pub fn db_signature_cached(
+    db_path: &PathBuf,
+    outpoint: &OutPoint,
+    cache_size: usize,
+) -> Result<Option<Signature>, DatabaseError> {
+    if cache_size > 0 {
+        if let Some(signature) = SIGNATURE_CACHE.get(db_path, outpoint) {
+            return Ok(Some(signature));
+        }
+    }
+
+    let signature = db_get_signature(db_path, outpoint)?;
+    if let Some(signature) = signature {
+        SIGNATURE_CACHE.insert(db_path, outpoint, signature, cache_size);
+    }
+    Ok(signature)
+}
+
+/// Get the time at which we signed this outpoint, in seconds since the epoch. Returns Ok(None) if
+/// we didn't sign it (yet), and Ok(Some(0)) if it was signed before we recorded the time.
+pub fn db_signed_outpoint_time(
+    db_path: &PathBuf,
+    outpoint: &OutPoint,
+) -> Result<Option<u64>, DatabaseError> {
+    db_query(
+        db_path,
+        "SELECT signed_at FROM signed_outpoints WHERE txid = (?1) AND vout = (?2) \
+         AND signature != x''",
+        params![outpoint.txid.to_vec(), outpoint.vout],
+        |row| row.get::<_, i64>(0).map(|t| t as u64),
+    )
+    .map(|mut rows| rows.pop())
+}
+
+/// Get all the signed outpoints in the database, ordered by txid and vout so that two exports
+/// of the same set compare equal. Reserved but not (yet) signed outpoints are not returned.
+pub fn db_all_signed_outpoints(db_path: &PathBuf) -> Result<Vec<DbSignedOutpoint>, DatabaseError> {
+    db_query(
+        db_path,
+        "SELECT * FROM signed_outpoints WHERE signature != x'' ORDER BY txid, vout",
+        params![],
+        |row| row.try_into(),
+    )
+}
+
+/// Get the outpoints reserved but not (yet) signed, ordered by txid and vout
+pub fn db_reserved_outpoints(db_path: &PathBuf) -> Result<Vec<OutPoint>, DatabaseError> {
+    db_query(
+        db_path,
+        "SELECT txid, vout FROM signed_outpoints WHERE signature = x'' ORDER BY txid, vout",
+        params![],
+        |row| {
+            let txid: bitcoin::Txid = encode::deserialize(&row.get::<_, Vec<u8>>(0)?)
+                .map_err(|e| FromSqlError::Other(Box::new(e)))?;
+            Ok(OutPoint {
+                txid,
+                vout: row.get(1)?,
+            })
+        },
+    )
+}
+
+/// Get the number of signed outpoints in the database, without reading them. Reserved but not
+/// (yet) signed outpoints are not counted.
+pub fn db_count_signed_outpoints(db_path: &PathBuf) -> Result<u64, DatabaseError> {
+    let mut rows = db_query(
+        db_path,
+        "SELECT COUNT(*) FROM signed_outpoints WHERE signature != x''",
+        params![],
+        |row| row.get::<_, i64>(0),
+    )?;
+
+    rows.pop()
+        .map(|count| count as u64)
+        .ok_or_else(|| DatabaseError("No row for COUNT query?".to_string()))
+}
+
+/// Get the hash of the outputs of the first Spend we were asked to sign for the set of outpoints
+/// with this hash, if any.
+pub fn db_first_seen_outputs(
+    db_path: &PathBuf,
+    outpoints_hash: &sha256::Hash,
+) -> Result<Option<sha256::Hash>, DatabaseError> {
+    db_query(
+        db_path,
+        "SELECT outputs_hash FROM first_seen_outputs WHERE outpoints_hash = (?1)",
+        params![outpoints_hash.to_vec()],
+        |row| {
+            let hash = row.get::<_, Vec<u8>>(0)?;
+            sha256::Hash::from_slice(&hash).map_err(|e| FromSqlError::Other(Box::new(e)).into())
+        },
+    )
+    .map(|mut rows| rows.pop())
+}
+
+/// Record the hash of the outputs of a Spend of the set of outpoints with this hash, unless we
+/// already recorded one for this set.
+pub fn db_record_first_seen_outputs(
+    db_path: &PathBuf,
+    outpoints_hash: &sha256::Hash,
+    outputs_hash: &sha256::Hash,
+) -> Result<(), DatabaseError> {
+    db_exec(db_path, |tx| {
+        tx.execute(
+            "INSERT OR IGNORE INTO first_seen_outputs (outpoints_hash, outputs_hash) \
+             VALUES (?1, ?2)",
+            params![outpoints_hash.to_vec(), outputs_hash.to_vec()],
+        )
+        .map_err(|e| DatabaseError(format!("Inserting first seen outputs: {}", e)))?;
+        Ok(())
+    })
+}
+
+/// The differences between the signed outpoints of two databases
+#[derive(Debug, Default, PartialEq)]
+pub struct DbDiff {
+    /// Outpoints signed in the first database only
+    pub only_in_a: Vec<OutPoint>,
+    /// Outpoints signed in the second database only
+    pub only_in_b: Vec<OutPoint>,
+    /// Outpoints present in both databases but with a different signature. This should never
+    /// happen as the signatures are deterministic.
+    pub different_signatures: Vec<OutPoint>,
+}
+
+impl DbDiff {
+    /// Whether both databases signed the very same outpoints with the very same signatures
+    pub fn is_empty(&self) -> bool {
+        self.only_in_a.is_empty()
+            && self.only_in_b.is_empty()
+            && self.different_signatures.is_empty()
+    }
+}
+
+/// Compare the signed outpoints of two databases, for instance those of redundant cosigners.
+pub fn db_diff(db_a: &PathBuf, db_b: &PathBuf) -> Result<DbDiff, DatabaseError> {
+    let (signed_a, signed_b) = (
+        db_all_signed_outpoints(db_a)?,
+        db_all_signed_outpoints(db_b)?,
+    );
+    let sigs_a: HashMap<&OutPoint, &Signature> = signed_a
+        .iter()
+        .map(|s| (&s.outpoint, &s.signature))
+        .collect();
+    let sigs_b: HashMap<&OutPoint, &Signature> = signed_b
+        .iter()
+        .map(|s| (&s.outpoint, &s.signature))
+        .collect();
+    let mut diff = DbDiff::default();
+
+    // Iterate on the ordered exports for the result to be deterministic
+    for signed in signed_a.iter() {
+        match sigs_b.get(&signed.outpoint) {
+            None => diff.only_in_a.push(signed.outpoint),
+            Some(sig) if **sig != signed.signature => {
+                diff.different_signatures.push(signed.outpoint)
+            }
+            Some(_) => {}
+        }
+    }
+    diff.only_in_b = signed_b
+        .iter()
+        .filter(|s| !sigs_a.contains_key(&s.outpoint))
+        .map(|s| s.outpoint)
+        .collect();
+
+    Ok(diff)
+}
+
 /// Insert a signed outpoint into the database.
 pub fn db_insert_signed_outpoint(
     db_path: &PathBuf,
     signed_outpoint: &OutPoint,
     signature: &Signature,
 ) -> Result<(), DatabaseError> {
+    db_insert_signed_outpoints(db_path, &[(*signed_outpoint, *signature)])
+}
+
+/// Insert these signed outpoints into the database in a single transaction: if any of them can't
+/// be inserted, none is.
+pub fn db_insert_signed_outpoints(
+    db_path: &PathBuf,
+    signed_outpoints: &[(OutPoint, Signature)],
+) -> Result<(), DatabaseError> {
+    let mut inserted = Vec::with_capacity(signed_outpoints.len());
     db_exec(db_path, |tx| {
-        tx.execute(
-            "INSERT INTO signed_outpoints (txid, vout, signature) \
-             VALUES (?1, ?2, ?3)",
-            params![
-                signed_outpoint.txid.to_vec(),
-                signed_outpoint.vout,
-                signature.serialize_der().to_vec(),
-            ],
-        )
-        .map_err(|e| {
-            DatabaseError(format!(
-                "Inserting signed outpoint '{}': {}",
-                signed_outpoint,
-                e.to_string()
-            ))
-        })?;
+        inserted.clear();
+        let signed_at = now();
+        for (outpoint, signature) in signed_outpoints {
+            tx.execute(
+                "INSERT INTO signed_outpoints (txid, vout, signature, signed_at) \
+                 VALUES (?1, ?2, ?3, ?4)",
+                params![
+                    outpoint.txid.to_vec(),
+                    outpoint.vout,
+                    signature.serialize_der().to_vec(),
+                    signed_at,
+                ],
+            )
+            .map_err(|e| {
+                DatabaseError(format!("Inserting signed outpoint '{}': {}", outpoint, e))
+            })?;
+            inserted.push(outpoint);
+        }
+
+        Ok(())
+    })?;
+    log::debug!("Recorded signed outpoint(s) '{:?}'", inserted);
+
+    Ok(())
+}
+
+/// Delete the signed outpoints we signed more than `older_than_secs` seconds ago, returning how
+/// many were removed. Those signed before we recorded the time of signing, as well as reserved
+/// ones, are never removed.
+pub fn db_prune_signed_outpoints(
+    db_path: &PathBuf,
+    older_than_secs: u64,
+) -> Result<usize, DatabaseError> {
+    let cutoff = now().saturating_sub(older_than_secs.min(i64::MAX as u64) as i64);
+    let mut n_removed = 0;
+    db_exec(db_path, |tx| {
+        n_removed = tx
+            .execute(
+                "DELETE FROM signed_outpoints WHERE signed_at > 0 AND signed_at < (?1) \
+                 AND signature != x''",
+                params![cutoff],
+            )
+            .map_err(|e| DatabaseError(format!("Pruning signed outpoints: {}", e)))?;
+        Ok(())
+    })?;
+    if n_removed > 0 {
+        SIGNATURE_CACHE.invalidate(db_path);
+    }
+
+    Ok(n_removed)
+}
+
+/// Delete the record of this outpoint, be it signed or only reserved, so that it may be signed
+/// again. This defeats the anti-replay protection for it: it's only meant for incident response,
+/// on the database of a stopped daemon. Returns whether there was one.
+pub fn db_forget_outpoint(db_path: &PathBuf, outpoint: &OutPoint) -> Result<bool, DatabaseError> {
+    let mut n_removed = 0;
+    db_exec(db_path, |tx| {
+        n_removed = tx
+            .execute(
+                "DELETE FROM signed_outpoints WHERE txid = (?1) AND vout = (?2)",
+                params![outpoint.txid.to_vec(), outpoint.vout],
+            )
+            .map_err(|e| DatabaseError(format!("Forgetting outpoint '{}': {}", outpoint, e)))?;
+        Ok(())
+    })?;
+    if n_removed > 0 {
+        log::warn!(
+            "Forgot outpoint '{}', it is not protected against replay anymore",
+            outpoint
+        );
+        SIGNATURE_CACHE.invalidate(db_path);
+    }
+
+    Ok(n_removed > 0)
+}
+
+/// The result of an attempt at reserving a set of outpoints to sign them
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReservationOutcome {
+    /// None of the outpoints were present, they are now all reserved
+    Reserved,
+    /// All the outpoints were already present, nothing was reserved
+    AllAlreadyPresent,
+    /// Some of the outpoints were already present, nothing was reserved
+    PartiallyPresent,
+}
+
+/// Atomically check that none of these outpoints are present in the database and insert a
+/// placeholder (empty signature) row for each of them, to be replaced using
+/// [db_fill_reserved_outpoint].
+///
+/// Nothing is reserved unless all of them were absent. Note that a placeholder which never gets
+/// filled makes its outpoint unsignable, which is the safe side of the anti-replay invariant.
+pub fn db_reserve_outpoints(
+    db_path: &PathBuf,
+    outpoints: &[OutPoint],
+) -> Result<ReservationOutcome, DatabaseError> {
+    // The write lock is taken right away by db_exec, so that a concurrent reservation can't slip
+    // in between our check and our insertion.
+    let mut outcome = ReservationOutcome::Reserved;
+    let res = db_exec(db_path, |tx| {
+        outcome = ReservationOutcome::Reserved;
+
+        let mut n_present = 0;
+        for outpoint in outpoints {
+            n_present += tx
+                .query_row(
+                    "SELECT COUNT(*) FROM signed_outpoints WHERE txid = (?1) AND vout = (?2)",
+                    params![outpoint.txid.to_vec(), outpoint.vout],
+                    |row| row.get::<_, u32>(0),
+                )
+                .map_err(|e| DatabaseError(format!("Querying outpoint '{}': {}", outpoint, e)))?;
+        }
+        if n_present > 0 {
+            outcome = if n_present as usize == outpoints.len() {
+                ReservationOutcome::AllAlreadyPresent
+            } else {
+                ReservationOutcome::PartiallyPresent
+            };
+            // Erroring rolls the transaction back
+            return Err(DatabaseError("Outpoints already present".to_string()));
+        }
+
+        for outpoint in outpoints {
+            tx.execute(
+                "INSERT INTO signed_outpoints (txid, vout, signature, signed_at) \
+                 VALUES (?1, ?2, x'', 0)",
+                params![outpoint.txid.to_vec(), outpoint.vout],
+            )
+            .map_err(|e| DatabaseError(format!("Reserving outpoint '{}': {}", outpoint, e)))?;
+        }
+        Ok(())
+    });
+
+    match res {
+        Err(_) if outcome != ReservationOutcome::Reserved => Ok(outcome),
+        res => res.map(|_| outcome),
+    }
+}
+
+/// Set the signature of an outpoint previously reserved by [db_reserve_outpoints], and record the
+/// time of signing.
+pub fn db_fill_reserved_outpoint(
+    db_path: &PathBuf,
+    outpoint: &OutPoint,
+    signature: &Signature,
+) -> Result<(), DatabaseError> {
+    db_fill_reserved_outpoints(db_path, &[(*outpoint, *signature)])
+}
+
+/// Set the signatures of these outpoints previously reserved by [db_reserve_outpoints] in a single
+/// transaction: if any of them wasn't reserved, none is filled.
+pub fn db_fill_reserved_outpoints(
+    db_path: &PathBuf,
+    signed_outpoints: &[(OutPoint, Signature)],
+) -> Result<(), DatabaseError> {
+    db_exec(db_path, |tx| {
+        let signed_at = now();
+        for (outpoint, signature) in signed_outpoints {
+            let updated = tx
+                .execute(
+                    "UPDATE signed_outpoints SET signature = (?3), signed_at = (?4) \
+                     WHERE txid = (?1) AND vout = (?2) AND signature = x''",
+                    params![
+                        outpoint.txid.to_vec(),
+                        outpoint.vout,
+                        signature.serialize_der().to_vec(),
+                        signed_at,
+                    ],
+                )
+                .map_err(|e| DatabaseError(format!("Filling outpoint '{}': {}", outpoint, e)))?;
+            if updated != 1 {
+                return Err(DatabaseError(format!(
+                    "Outpoint '{}' was not reserved",
+                    outpoint
+                )));
+            }
+        }
 
         Ok(())
     })
@@ -157,28 +744,46 @@ fn create_db_file(db_path: &PathBuf) -> Result<(), std::io::Error> {
 }
 
 // Create the SQLite database. This creates a file with 600 perms and creates the SCHEMA, then
-// initializes the version.
+// initializes the version. It's all done in a temporary file which is only linked at `db_path` once
+// initialized, so that a concurrent setup never sees a half-created database. Fails if there is
+// already a database at `db_path`.
 fn create_db(db_path: &PathBuf) -> Result<(), DatabaseError> {
+    static N_CREATIONS: AtomicUsize = AtomicUsize::new(0);
+    let mut tmp_path = db_path.clone();
+    tmp_path.set_extension(format!(
+        "tmp-{}-{}",
+        process::id(),
+        N_CREATIONS.fetch_add(1, Ordering::SeqCst)
+    ));
+
     // Rusqlite could create it for us, but we want custom permissions
-    create_db_file(db_path)
-        .map_err(|e| DatabaseError(format!("Creating db file: {}", e.to_string())))?;
+    create_db_file(&tmp_path).map_err(|e| DatabaseError(format!("Creating db file: {}", e)))?;
 
-    db_exec(db_path, |tx| {
-        tx.execute_batch(&SCHEMA)
-            .map_err(|e| DatabaseError(format!("Creating database: {}", e.to_string())))?;
+    let res = db_exec(&tmp_path, |tx| {
+        tx.execute_batch(SCHEMA)
+            .map_err(|e| DatabaseError(format!("Creating database: {}", e)))?;
         tx.execute(
             "INSERT INTO db_params (version) VALUES (?1)",
             params![DB_VERSION],
         )
-        .map_err(|e| DatabaseError(format!("Inserting db_params: {}", e.to_string())))?;
+        .map_err(|e| DatabaseError(format!("Inserting db_params: {}", e)))?;
         Ok(())
     })
+    .and_then(|_| {
+        // This fails if it exists already
+        fs::hard_link(&tmp_path, db_path)
+            .map_err(|e| DatabaseError(format!("Linking db file: {}", e)))
+    });
+    if let Err(e) = fs::remove_file(&tmp_path) {
+        log::error!("Removing temporary db file '{:?}': '{}'", tmp_path, e);
+    }
+
+    res
 }
 
 // Called on startup to check database integrity
 fn check_db(db_path: &PathBuf) -> Result<(), DatabaseError> {
-    // Check if their database is not from the future.
-    // We'll eventually do migration here if version < VERSION, but be strict until then.
+    // Check if their database is not from the future. Past ones are migrated beforehand.
     let version = db_version(db_path)?;
     if version != DB_VERSION {
         return Err(DatabaseError(format!(
@@ -190,13 +795,194 @@ fn check_db(db_path: &PathBuf) -> Result<(), DatabaseError> {
     Ok(())
 }
 
-/// This integrity checks the database and creates it if it doesn't exist yet.
-pub fn setup_db(db_path: &PathBuf) -> Result<(), DatabaseError> {
+// A step migrating the database schema from one version to the next
+type Migration = fn(&rusqlite::Transaction) -> Result<(), DatabaseError>;
+
+// Version 1 records the outputs of the first Spend we were asked to sign for a set of outpoints
+fn migrate_0_to_1(tx: &rusqlite::Transaction) -> Result<(), DatabaseError> {
+    tx.execute_batch(
+        "CREATE TABLE first_seen_outputs (
+            outpoints_hash BLOB NOT NULL UNIQUE,
+            outputs_hash BLOB NOT NULL
+        );",
+    )
+    .map_err(|e| DatabaseError(format!("Creating first_seen_outputs table: {}", e)))
+}
+
+// Version 2 records when each outpoint was signed. We don't know for the existing ones.
+fn migrate_1_to_2(tx: &rusqlite::Transaction) -> Result<(), DatabaseError> {
+    tx.execute_batch(
+        "ALTER TABLE signed_outpoints ADD COLUMN signed_at INTEGER NOT NULL DEFAULT 0;",
+    )
+    .map_err(|e| DatabaseError(format!("Adding signed_at column: {}", e)))
+}
+
+// The migrations from each past version to the next one up to DB_VERSION
+const MIGRATIONS: &[Migration] = &[migrate_0_to_1, migrate_1_to_2];
+
+// Migrate the database from version `from` to version `to` using these migrations, the one at
+// index `i` migrating from version `i` to `i + 1`. Each step is applied along with the version
+// bump inside its own transaction, so that a crash midway leaves a consistent version.
+fn run_migrations(
+    db_path: &PathBuf,
+    migrations: &[Migration],
+    from: u32,
+    to: u32,
+) -> Result<(), DatabaseError> {
+    if to as usize > migrations.len() {
+        return Err(DatabaseError(format!(
+            "No migration path from version '{}' to version '{}'",
+            from, to
+        )));
+    }
+
+    for version in from..to {
+        log::info!(
+            "Migrating database from version '{}' to '{}'",
+            version,
+            version + 1
+        );
+        db_exec(db_path, |tx| {
+            migrations[version as usize](tx)?;
+            tx.execute("UPDATE db_params SET version = (?1)", params![version + 1])
+                .map_err(|e| DatabaseError(format!("Updating db version: {}", e)))?;
+            Ok(())
+        })?;
+    }
+
+    Ok(())
+}
+
+/// Migrate the database from version `from` to version `to`, one version at a time. A crash
+/// midway leaves it at the last version it was fully migrated to.
+pub fn migrate_db(db_path: &PathBuf, from: u32, to: u32) -> Result<(), DatabaseError> {
+    run_migrations(db_path, MIGRATIONS, from, to)
+}
+
+/// Read the whole database file once, so that the OS keeps it in its page cache and the first
+/// lookups don't hit the disk. Returns the number of bytes read.
+pub fn db_warm_cache(db_path: &PathBuf) -> Result<u64, DatabaseError> {
+    let mut db_file =
+        fs::File::open(db_path).map_err(|e| DatabaseError(format!("Opening db file: {}", e)))?;
+    io::copy(&mut db_file, &mut io::sink())
+        .map_err(|e| DatabaseError(format!("Reading db file: {}", e)))
+}
+
+/// Switch the database to the WAL journal mode if `wal`, or back to the default rollback journal.
+/// The mode is persistent. In WAL mode connections don't fsync on each commit, which makes
+/// concurrent requests faster but means a power loss may lose the last signed outpoints.
+pub fn db_set_wal(db_path: &PathBuf, wal: bool) -> Result<(), DatabaseError> {
+    let conn = open_db(db_path).map_err(|e| DatabaseError(format!("Opening database: {}", e)))?;
+    let mode = if wal { "wal" } else { "delete" };
+    let new_mode: String = conn
+        .query_row(
+            &format!("PRAGMA journal_mode = {}", mode),
+            params![],
+            |row| row.get(0),
+        )
+        .map_err(|e| DatabaseError(format!("Setting journal mode: {}", e)))?;
+    if new_mode != mode {
+        return Err(DatabaseError(format!(
+            "Could not set journal mode to '{}', it is '{}'",
+            mode, new_mode
+        )));
+    }
+
+    Ok(())
+}
+
+/// Copy the database to a new file at `dst_path`, with the same permissions as the database. The
+/// copy is consistent even if the database is being written to concurrently.
+pub fn db_backup(src_path: &PathBuf, dst_path: &PathBuf) -> Result<(), DatabaseError> {
+    let src = open_db(src_path)
+        .map_err(|e| DatabaseError(format!("Opening database to back up: {}", e)))?;
+    create_db_file(dst_path).map_err(|e| DatabaseError(format!("Creating backup file: {}", e)))?;
+
+    let res = rusqlite::Connection::open(dst_path)
+        .map_err(|e| DatabaseError(format!("Opening backup file: {}", e)))
+        .and_then(|mut dst| {
+            let backup = backup::Backup::new(&src, &mut dst)
+                .map_err(|e| DatabaseError(format!("Starting backup: {}", e)))?;
+            // Copy all pages at once, as a concurrent write would make us start over if we
+            // were to step through them.
+            loop {
+                match backup.step(-1) {
+                    Ok(backup::StepResult::Done) => return Ok(()),
+                    Ok(_) => thread::sleep(time::Duration::from_millis(10)),
+                    Err(e) => return Err(DatabaseError(format!("Backing up: {}", e))),
+                }
+            }
+        });
+    if res.is_err() {
+        // Don't leave a partial copy behind
+        let _ = fs::remove_file(dst_path);
+    }
+    res
+}
+
+/// Rebuild the database file so that the space freed by deletions is given back to the OS.
+pub fn db_vacuum(db_path: &PathBuf) -> Result<(), DatabaseError> {
+    // It can't be run inside a transaction
+    open_db(db_path)
+        .and_then(|conn| conn.execute_batch("VACUUM"))
+        .map_err(|e| DatabaseError(format!("Vacuuming database: {}", e)))
+}
+
+/// Check the database file is not corrupted, which would happen on a power loss at the wrong time
+/// or a faulty disk. This reads it entirely.
+pub fn db_integrity_check(db_path: &PathBuf) -> Result<(), DatabaseError> {
+    let results = db_query(db_path, "PRAGMA integrity_check", params![], |row| {
+        row.get::<_, String>(0)
+    })
+    .map_err(|e| vec![e.to_string()]);
+    match results {
+        Ok(results) if results == ["ok"] => Ok(()),
+        Ok(errors) | Err(errors) => Err(DatabaseError(format!(
+            "Integrity check failed, the database is corrupted and must be restored from a \
+             backup: {}",
+            errors.join(", ")
+        ))),
+    }
+}
+
+/// This integrity checks the database and creates it if it doesn't exist yet. If
+/// `integrity_check`, it also checks the physical integrity of the file with
+/// [db_integrity_check].
+pub fn setup_db(db_path: &PathBuf, integrity_check: bool) -> Result<(), DatabaseError> {
+    let read_only = db_is_read_only(db_path);
+    if read_only && !db_path.exists() {
+        return Err(DatabaseError(format!(
+            "No database at {:?}, and it can't be created read-only. Restore a backup of the \
+             primary there.",
+            db_path
+        )));
+    }
     if !db_path.exists() {
         log::info!("No database at {:?}, creating a new one.", db_path);
-        create_db(db_path)?;
+        if let Err(e) = create_db(db_path) {
+            // If someone else created it in the meantime, it's a fully initialized one.
+            if !db_path.exists() {
+                return Err(e);
+            }
+            log::debug!("Database was created concurrently.");
+        }
+    }
+
+    if integrity_check {
+        db_integrity_check(db_path)?;
     }
 
+    let version = db_version(db_path)?;
+    if version < DB_VERSION {
+        if read_only {
+            return Err(DatabaseError(format!(
+                "Database is at version {} and needs migrating to version {}, which can't be \
+                 done read-only",
+                version, DB_VERSION
+            )));
+        }
+        migrate_db(db_path, version, DB_VERSION)?;
+    }
     check_db(db_path)?;
 
     Ok(())
@@ -206,7 +992,7 @@ pub fn setup_db(db_path: &PathBuf) -> Result<(), DatabaseError> {
 mod test {
     use super::*;
     use crate::tests::builder::CosignerTestBuilder;
-    use std::str::FromStr;
+    use std::{os::unix::fs::PermissionsExt, str::FromStr};
 
     #[test]
     fn db_creation_sanity() {
@@ -256,4 +1042,938 @@ mod test {
             sig
         );
     }
+
+    #[test]
+    fn outpoints_reservation() {
+        let test_framework = CosignerTestBuilder::new(3);
+        let db_path = test_framework.config.db_file();
+        let outpoints: Vec<OutPoint> = [
+            "e69a8de68c69b2f19249437004b65e82e2615c61c8d852fd36965c032a117d00:120",
+            "ceca4de398c63b29543f8346c09fd7522fd8661ce8bdc0e454e8d6ed8ad46a0d:1",
+            "0b38682347207cd79de33edf8897a75abe7d8799b194439150306773b6aef55a:189",
+        ]
+        .iter()
+        .map(|o| OutPoint::from_str(o).unwrap())
+        .collect();
+        let sig = Signature::from_str(
+            "3045022100bd287d1cc62223e344a4eea99801e15dab6484365d2b4f981\
+                                      fa7febc0b29cea40220579ec2071c1e5e2dab8a468849214c6cfed2342bb\
+                                      ffa572327621bad2d894961",
+        )
+        .unwrap();
+
+        assert_eq!(
+            db_reserve_outpoints(&db_path, &outpoints[..2]).unwrap(),
+            ReservationOutcome::Reserved
+        );
+        // A reserved outpoint isn't signed, and can't be reserved again
+        assert!(db_signed_outpoint(&db_path, &outpoints[0])
+            .unwrap()
+            .is_none());
+        assert_eq!(
+            db_reserve_outpoints(&db_path, &outpoints[..2]).unwrap(),
+            ReservationOutcome::AllAlreadyPresent
+        );
+        assert_eq!(
+            db_reserve_outpoints(&db_path, &outpoints).unwrap(),
+            ReservationOutcome::PartiallyPresent
+        );
+        // Nothing was reserved on failure
+        assert_eq!(
+            db_reserve_outpoints(&db_path, &outpoints[2..]).unwrap(),
+            ReservationOutcome::Reserved
+        );
+
+        // Once filled it's a regular signed outpoint, and can't be filled twice
+        db_fill_reserved_outpoint(&db_path, &outpoints[0], &sig).unwrap();
+        assert_eq!(
+            db_signed_outpoint(&db_path, &outpoints[0])
+                .unwrap()
+                .unwrap()
+                .signature,
+            sig
+        );
+        db_fill_reserved_outpoint(&db_path, &outpoints[0], &sig).unwrap_err();
+    }
+
+    #[test]
+    fn outpoints_reservation_race() {
+        let test_framework = CosignerTestBuilder::new(3);
+        let db_path = test_framework.config.db_file();
+        let outpoints = vec![
+            OutPoint::from_str(
+                "e69a8de68c69b2f19249437004b65e82e2615c61c8d852fd36965c032a117d00:120",
+            )
+            .unwrap(),
+            OutPoint::from_str(
+                "ceca4de398c63b29543f8346c09fd7522fd8661ce8bdc0e454e8d6ed8ad46a0d:1",
+            )
+            .unwrap(),
+        ];
+
+        let handles: Vec<_> = (0..8)
+            .map(|_| {
+                let (db_path, outpoints) = (db_path.clone(), outpoints.clone());
+                std::thread::spawn(move || db_reserve_outpoints(&db_path, &outpoints).unwrap())
+            })
+            .collect();
+        let outcomes: Vec<_> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+
+        assert_eq!(
+            outcomes
+                .iter()
+                .filter(|o| **o == ReservationOutcome::Reserved)
+                .count(),
+            1
+        );
+        assert!(outcomes
+            .iter()
+            .all(|o| *o != ReservationOutcome::PartiallyPresent));
+    }
+
+    #[test]
+    fn signed_outpoints_diff() {
+        let (framework_a, framework_b) = (CosignerTestBuilder::new(3), CosignerTestBuilder::new(3));
+        let (db_a, db_b) = (framework_a.config.db_file(), framework_b.config.db_file());
+        let outpoints: Vec<OutPoint> = [
+            "e69a8de68c69b2f19249437004b65e82e2615c61c8d852fd36965c032a117d00:120",
+            "ceca4de398c63b29543f8346c09fd7522fd8661ce8bdc0e454e8d6ed8ad46a0d:1",
+            "0b38682347207cd79de33edf8897a75abe7d8799b194439150306773b6aef55a:189",
+            "2b8930127e9dfd1bcdf35df2bc7f3b8cdbec083b1ae693f36b6305fccd1425da:0",
+        ]
+        .iter()
+        .map(|o| OutPoint::from_str(o).unwrap())
+        .collect();
+        let sig = Signature::from_str(
+            "3045022100bd287d1cc62223e344a4eea99801e15dab6484365d2b4f981\
+                                      fa7febc0b29cea40220579ec2071c1e5e2dab8a468849214c6cfed2342bb\
+                                      ffa572327621bad2d894961",
+        )
+        .unwrap();
+        let other_sig = Signature::from_str(
+            "3045022100dc4dc264a9fef17a3f253449cf8c397ab6f16fb3d63d86940b5586823dfd02ae02203b461bb4\
+             336b5ecbaefd6627aa922efc048fec0c881c10c4c9428fca69c132a2",
+        )
+        .unwrap();
+
+        assert!(db_diff(&db_a, &db_b).unwrap().is_empty());
+
+        // Overlapping rows
+        db_insert_signed_outpoint(&db_a, &outpoints[0], &sig).unwrap();
+        db_insert_signed_outpoint(&db_b, &outpoints[0], &sig).unwrap();
+        // Rows in a single db
+        db_insert_signed_outpoint(&db_a, &outpoints[1], &sig).unwrap();
+        db_insert_signed_outpoint(&db_b, &outpoints[2], &sig).unwrap();
+        // Divergent rows
+        db_insert_signed_outpoint(&db_a, &outpoints[3], &sig).unwrap();
+        db_insert_signed_outpoint(&db_b, &outpoints[3], &other_sig).unwrap();
+
+        assert_eq!(
+            db_diff(&db_a, &db_b).unwrap(),
+            DbDiff {
+                only_in_a: vec![outpoints[1]],
+                only_in_b: vec![outpoints[2]],
+                different_signatures: vec![outpoints[3]],
+            }
+        );
+        assert!(db_diff(&db_a, &db_a).unwrap().is_empty());
+    }
+
+    #[test]
+    fn db_cache_warming() {
+        let test_framework = CosignerTestBuilder::new(3);
+        let db_path = test_framework.config.db_file();
+        let outpoint = OutPoint::from_str(
+            "e69a8de68c69b2f19249437004b65e82e2615c61c8d852fd36965c032a117d00:120",
+        )
+        .unwrap();
+        let sig = Signature::from_str(
+            "3045022100bd287d1cc62223e344a4eea99801e15dab6484365d2b4f981\
+                                      fa7febc0b29cea40220579ec2071c1e5e2dab8a468849214c6cfed2342bb\
+                                      ffa572327621bad2d894961",
+        )
+        .unwrap();
+        db_insert_signed_outpoint(&db_path, &outpoint, &sig).unwrap();
+
+        let read = db_warm_cache(&db_path).unwrap();
+        assert_eq!(read, fs::metadata(&db_path).unwrap().len());
+        assert_eq!(
+            db_signed_outpoint(&db_path, &outpoint)
+                .unwrap()
+                .unwrap()
+                .signature,
+            sig
+        );
+    }
+
+    #[test]
+    fn signature_retrieval() {
+        let test_framework = CosignerTestBuilder::new(1);
+        let db_path = test_framework.config.db_file();
+        let (signed, reserved, unknown) = (
+            OutPoint::from_str(
+                "2b8930127e9dfd1bcdf35df2bc7f3b8cdbec083b1ae693f36b6305fccd1425da:0",
+            )
+            .unwrap(),
+            OutPoint::from_str(
+                "ceca4de398c63b29543f8346c09fd7522fd8661ce8bdc0e454e8d6ed8ad46a0d:1",
+            )
+            .unwrap(),
+            OutPoint::from_str(
+                "0b38682347207cd79de33edf8897a75abe7d8799b194439150306773b6aef55a:189",
+            )
+            .unwrap(),
+        );
+        let sig = Signature::from_str(
+            "3045022100bd287d1cc62223e344a4eea99801e15dab6484365d2b4f981\
+                                      fa7febc0b29cea40220579ec2071c1e5e2dab8a468849214c6cfed2342bb\
+                                      ffa572327621bad2d894961",
+        )
+        .unwrap();
+        db_insert_signed_outpoint(&db_path, &signed, &sig).unwrap();
+        db_reserve_outpoints(&db_path, &[reserved]).unwrap();
+
+        assert_eq!(db_get_signature(&db_path, &signed).unwrap(), Some(sig));
+        assert_eq!(db_get_signature(&db_path, &reserved).unwrap(), None);
+        assert_eq!(db_get_signature(&db_path, &unknown).unwrap(), None);
+
+        // A garbage signature is an error, not a panic
+        db_exec(&db_path, |tx| {
+            tx.execute(
+                "UPDATE signed_outpoints SET signature = x'00' WHERE vout = 0",
+                params![],
+            )
+            .unwrap();
+            Ok(())
+        })
+        .unwrap();
+        db_get_signature(&db_path, &signed).unwrap_err();
+        db_signed_outpoint(&db_path, &signed).unwrap_err();
+        db_all_signed_outpoints(&db_path).unwrap_err();
+    }
+
+    #[test]
+    fn in_memory_db() {
+        let test_framework = CosignerTestBuilder::new(1);
+        let (db_path, other_db_path) = (
+            test_framework.config.data_dir.join("memory.sqlite3"),
+            test_framework.config.data_dir.join("other_memory.sqlite3"),
+        );
+        let outpoint = OutPoint::from_str(
+            "2b8930127e9dfd1bcdf35df2bc7f3b8cdbec083b1ae693f36b6305fccd1425da:0",
+        )
+        .unwrap();
+        let sig = Signature::from_str(
+            "3045022100bd287d1cc62223e344a4eea99801e15dab6484365d2b4f981\
+                                      fa7febc0b29cea40220579ec2071c1e5e2dab8a468849214c6cfed2342bb\
+                                      ffa572327621bad2d894961",
+        )
+        .unwrap();
+
+        let memory_db = db_in_memory(&db_path).unwrap();
+        let other_memory_db = db_in_memory(&other_db_path).unwrap();
+        assert_eq!(db_version(&db_path).unwrap(), DB_VERSION);
+        db_insert_signed_outpoint(&db_path, &outpoint, &sig).unwrap();
+        assert_eq!(
+            db_signed_outpoint(&db_path, &outpoint)
+                .unwrap()
+                .unwrap()
+                .signature,
+            sig
+        );
+        // They are distinct, and not on disk
+        assert!(db_signed_outpoint(&other_db_path, &outpoint)
+            .unwrap()
+            .is_none());
+        assert!(!db_path.exists() && !other_db_path.exists());
+        drop((memory_db, other_memory_db));
+        assert!(in_memory_uri(&db_path).is_none());
+    }
+
+    #[test]
+    fn db_wal_mode() {
+        let test_framework = CosignerTestBuilder::new(1);
+        let db_path = test_framework.config.db_file();
+        let modes = || {
+            let conn = open_db(&db_path).unwrap();
+            let journal_mode: String = conn
+                .query_row("PRAGMA journal_mode", params![], |row| row.get(0))
+                .unwrap();
+            let synchronous: u32 = conn
+                .query_row("PRAGMA synchronous", params![], |row| row.get(0))
+                .unwrap();
+            (journal_mode, synchronous)
+        };
+
+        // Off by default, with full syncs
+        assert_eq!(modes(), ("delete".to_string(), 2));
+
+        // Once enabled, it's kept across setups and connections relax the syncs
+        db_set_wal(&db_path, true).unwrap();
+        setup_db(&db_path, true).unwrap();
+        assert_eq!(modes(), ("wal".to_string(), 1));
+        let outpoint = OutPoint::from_str(
+            "e69a8de68c69b2f19249437004b65e82e2615c61c8d852fd36965c032a117d00:120",
+        )
+        .unwrap();
+        let sig = Signature::from_str(
+            "3045022100bd287d1cc62223e344a4eea99801e15dab6484365d2b4f981\
+                                      fa7febc0b29cea40220579ec2071c1e5e2dab8a468849214c6cfed2342bb\
+                                      ffa572327621bad2d894961",
+        )
+        .unwrap();
+        db_insert_signed_outpoint(&db_path, &outpoint, &sig).unwrap();
+        assert!(db_signed_outpoint(&db_path, &outpoint).unwrap().is_some());
+
+        db_set_wal(&db_path, false).unwrap();
+        assert_eq!(modes(), ("delete".to_string(), 2));
+        assert!(db_signed_outpoint(&db_path, &outpoint).unwrap().is_some());
+    }
+
+    #[test]
+    fn db_backup_copy() {
+        let test_framework = CosignerTestBuilder::new(1);
+        let db_path = test_framework.config.db_file();
+        let sig = Signature::from_str(
+            "3045022100bd287d1cc62223e344a4eea99801e15dab6484365d2b4f981\
+                                      fa7febc0b29cea40220579ec2071c1e5e2dab8a468849214c6cfed2342bb\
+                                      ffa572327621bad2d894961",
+        )
+        .unwrap();
+        let signed_outpoints: Vec<(OutPoint, Signature)> = [
+            "2b8930127e9dfd1bcdf35df2bc7f3b8cdbec083b1ae693f36b6305fccd1425da:0",
+            "ceca4de398c63b29543f8346c09fd7522fd8661ce8bdc0e454e8d6ed8ad46a0d:1",
+            "0b38682347207cd79de33edf8897a75abe7d8799b194439150306773b6aef55a:189",
+        ]
+        .iter()
+        .map(|o| (OutPoint::from_str(o).unwrap(), sig))
+        .collect();
+        db_insert_signed_outpoints(&db_path, &signed_outpoints).unwrap();
+
+        let mut backup_path = db_path.clone();
+        backup_path.set_extension("backup");
+        db_backup(&db_path, &backup_path).unwrap();
+        assert_eq!(
+            fs::metadata(&backup_path).unwrap().permissions().mode() & 0o777,
+            0o600
+        );
+        check_db(&backup_path).unwrap();
+        assert_eq!(db_all_signed_outpoints(&backup_path).unwrap().len(), 3);
+        assert!(db_diff(&db_path, &backup_path).unwrap().is_empty());
+
+        // We never overwrite an existing file
+        assert!(db_backup(&db_path, &backup_path).is_err());
+        assert!(db_diff(&db_path, &backup_path).unwrap().is_empty());
+    }
+
+    #[test]
+    fn db_corruption_detection() {
+        let test_framework = CosignerTestBuilder::new(1);
+        let db_path = test_framework.config.db_file();
+        let sig = Signature::from_str(
+            "3045022100bd287d1cc62223e344a4eea99801e15dab6484365d2b4f981\
+                                      fa7febc0b29cea40220579ec2071c1e5e2dab8a468849214c6cfed2342bb\
+                                      ffa572327621bad2d894961",
+        )
+        .unwrap();
+        let signed_outpoints: Vec<(OutPoint, Signature)> = (0..500)
+            .map(|vout| {
+                let outpoint = OutPoint::from_str(&format!(
+                    "e69a8de68c69b2f19249437004b65e82e2615c61c8d852fd36965c032a117d00:{}",
+                    vout
+                ))
+                .unwrap();
+                (outpoint, sig)
+            })
+            .collect();
+        db_insert_signed_outpoints(&db_path, &signed_outpoints).unwrap();
+        db_integrity_check(&db_path).unwrap();
+        setup_db(&db_path, true).unwrap();
+
+        // Scribble over a page in the middle of the file, keeping the header intact
+        let mut content = fs::read(&db_path).unwrap();
+        let middle = content.len() / 2;
+        for byte in content[middle..middle + 512].iter_mut() {
+            *byte = 0x42;
+        }
+        fs::write(&db_path, &content).unwrap();
+
+        let err = setup_db(&db_path, true).unwrap_err();
+        assert!(err.0.contains("restored from a backup"), "{}", err);
+        assert!(db_integrity_check(&db_path).is_err());
+    }
+
+    #[test]
+    fn db_vacuuming() {
+        let test_framework = CosignerTestBuilder::new(1);
+        let db_path = test_framework.config.db_file();
+        let sig = Signature::from_str(
+            "3045022100bd287d1cc62223e344a4eea99801e15dab6484365d2b4f981\
+                                      fa7febc0b29cea40220579ec2071c1e5e2dab8a468849214c6cfed2342bb\
+                                      ffa572327621bad2d894961",
+        )
+        .unwrap();
+        let signed_outpoints: Vec<(OutPoint, Signature)> = (0..2_000)
+            .map(|vout| {
+                let outpoint = OutPoint::from_str(&format!(
+                    "e69a8de68c69b2f19249437004b65e82e2615c61c8d852fd36965c032a117d00:{}",
+                    vout
+                ))
+                .unwrap();
+                (outpoint, sig)
+            })
+            .collect();
+        db_insert_signed_outpoints(&db_path, &signed_outpoints).unwrap();
+        db_exec(&db_path, |tx| {
+            tx.execute("DELETE FROM signed_outpoints WHERE vout > 0", params![])
+                .unwrap();
+            Ok(())
+        })
+        .unwrap();
+
+        // The deleted rows still take space until we vacuum
+        let size = fs::metadata(&db_path).unwrap().len();
+        db_vacuum(&db_path).unwrap();
+        assert!(fs::metadata(&db_path).unwrap().len() < size);
+        assert!(db_signed_outpoint(&db_path, &signed_outpoints[0].0)
+            .unwrap()
+            .is_some());
+        check_db(&db_path).unwrap();
+    }
+
+    // Create a database as the first version of cosignerd would have
+    fn create_v0_db(db_path: &PathBuf) {
+        db_exec(db_path, |tx| {
+            tx.execute_batch(
+                "CREATE TABLE db_params (
+                    version INTEGER NOT NULL
+                );
+                CREATE TABLE signed_outpoints (
+                    txid BLOB NOT NULL,
+                    vout INTEGER NOT NULL,
+                    signature BLOB NOT NULL,
+                    UNIQUE(txid, vout)
+                );
+                INSERT INTO db_params (version) VALUES (0);",
+            )
+            .map_err(|e| DatabaseError(e.to_string()))
+        })
+        .unwrap();
+    }
+
+    // Insert a signed outpoint in a database created by create_v0_db
+    fn insert_v0_signed_outpoint(db_path: &PathBuf, outpoint: &OutPoint, signature: &Signature) {
+        db_exec(db_path, |tx| {
+            tx.execute(
+                "INSERT INTO signed_outpoints (txid, vout, signature) VALUES (?1, ?2, ?3)",
+                params![
+                    outpoint.txid.to_vec(),
+                    outpoint.vout,
+                    signature.serialize_der().to_vec()
+                ],
+            )
+            .map_err(|e| DatabaseError(e.to_string()))?;
+            Ok(())
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn db_migration_0_to_1() {
+        let test_framework = CosignerTestBuilder::new(3);
+        let db_path = &test_framework.config.data_dir.join("v0.sqlite3");
+        create_v0_db(db_path);
+        let outpoint = OutPoint::from_str(
+            "2b8930127e9dfd1bcdf35df2bc7f3b8cdbec083b1ae693f36b6305fccd1425da:0",
+        )
+        .unwrap();
+        let signature = Signature::from_str(
+            "3045022100bd287d1cc62223e344a4eea99801e15dab6484365d2b4f981\
+                                      fa7febc0b29cea40220579ec2071c1e5e2dab8a468849214c6cfed2342bb\
+                                      ffa572327621bad2d894961",
+        )
+        .unwrap();
+        insert_v0_signed_outpoint(db_path, &outpoint, &signature);
+
+        // Setting it up migrates it, and we can use the first seen outputs
+        setup_db(db_path, true).unwrap();
+        assert_eq!(db_version(db_path).unwrap(), DB_VERSION);
+        assert!(db_signed_outpoint(db_path, &outpoint).unwrap().is_some());
+        let (outpoints_hash, outputs_hash) = (sha256::Hash::hash(&[0]), sha256::Hash::hash(&[1]));
+        assert_eq!(
+            db_first_seen_outputs(db_path, &outpoints_hash).unwrap(),
+            None
+        );
+        db_record_first_seen_outputs(db_path, &outpoints_hash, &outputs_hash).unwrap();
+        db_record_first_seen_outputs(db_path, &outpoints_hash, &sha256::Hash::hash(&[2])).unwrap();
+        assert_eq!(
+            db_first_seen_outputs(db_path, &outpoints_hash).unwrap(),
+            Some(outputs_hash)
+        );
+    }
+
+    #[test]
+    fn db_migration_column_addition() {
+        fn add_first_seen(tx: &rusqlite::Transaction) -> Result<(), DatabaseError> {
+            tx.execute_batch(
+                "ALTER TABLE signed_outpoints ADD COLUMN first_seen INTEGER;
+                 UPDATE signed_outpoints SET first_seen = 21;",
+            )
+            .map_err(|e| DatabaseError(format!("Adding first_seen: {}", e)))
+        }
+
+        let test_framework = CosignerTestBuilder::new(3);
+        let db_path = &test_framework.config.data_dir.join("v0.sqlite3");
+        create_v0_db(db_path);
+        assert_eq!(db_version(db_path).unwrap(), 0);
+
+        let outpoint = OutPoint::from_str(
+            "2b8930127e9dfd1bcdf35df2bc7f3b8cdbec083b1ae693f36b6305fccd1425da:0",
+        )
+        .unwrap();
+        let signature = Signature::from_str(
+            "3045022100bd287d1cc62223e344a4eea99801e15dab6484365d2b4f981\
+                                      fa7febc0b29cea40220579ec2071c1e5e2dab8a468849214c6cfed2342bb\
+                                      ffa572327621bad2d894961",
+        )
+        .unwrap();
+        insert_v0_signed_outpoint(db_path, &outpoint, &signature);
+
+        // We can't migrate further than we know how to
+        run_migrations(db_path, &[add_first_seen], 0, 2).unwrap_err();
+        assert_eq!(db_version(db_path).unwrap(), 0);
+
+        run_migrations(db_path, &[add_first_seen], 0, 1).unwrap();
+        assert_eq!(db_version(db_path).unwrap(), 1);
+
+        // The row is still there, with the backfilled value
+        let signed_op = db_signed_outpoint(db_path, &outpoint).unwrap().unwrap();
+        assert_eq!(signed_op.signature, signature);
+        let first_seen = db_query(
+            db_path,
+            "SELECT first_seen FROM signed_outpoints",
+            params![],
+            |row| row.get::<_, u32>(0),
+        )
+        .unwrap();
+        assert_eq!(first_seen, vec![21]);
+
+        // A failing migration step leaves the database untouched
+        fn failing(tx: &rusqlite::Transaction) -> Result<(), DatabaseError> {
+            tx.execute_batch("DROP TABLE signed_outpoints; SELECT * FROM nonexistent;")
+                .map_err(|e| DatabaseError(format!("Failing: {}", e)))
+        }
+        run_migrations(db_path, &[add_first_seen, failing], 1, 2).unwrap_err();
+        assert_eq!(db_version(db_path).unwrap(), 1);
+        assert!(db_signed_outpoint(db_path, &outpoint).unwrap().is_some());
+    }
+
+    #[test]
+    fn concurrent_db_setup() {
+        let test_framework = CosignerTestBuilder::new(3);
+        let data_dir = &test_framework.config.data_dir;
+
+        for i in 0..20 {
+            let db_path = data_dir.join(format!("race-{}.sqlite3", i));
+            let barrier = std::sync::Arc::new(std::sync::Barrier::new(2));
+            let threads: Vec<_> = (0..2)
+                .map(|_| {
+                    let (db_path, barrier) = (db_path.clone(), barrier.clone());
+                    std::thread::spawn(move || {
+                        barrier.wait();
+                        setup_db(&db_path, true)
+                    })
+                })
+                .collect();
+            for thread in threads {
+                thread.join().unwrap().unwrap();
+            }
+            assert_eq!(db_version(&db_path).unwrap(), DB_VERSION);
+        }
+
+        // No leftover temporary file
+        let n_files = fs::read_dir(data_dir).unwrap().count();
+        assert_eq!(n_files, 21);
+    }
+
+    #[test]
+    fn db_migration_sanity() {
+        let test_framework = CosignerTestBuilder::new(3);
+        let db_path = &test_framework.config.data_dir.join("v0.sqlite3");
+        create_v0_db(db_path);
+
+        // It's strict about the version, so it needs to be migrated first
+        check_db(db_path).unwrap_err();
+        // We don't know about the future
+        migrate_db(db_path, 0, DB_VERSION + 1).unwrap_err();
+        assert_eq!(db_version(db_path).unwrap(), 0);
+
+        migrate_db(db_path, 0, 1).unwrap();
+        assert_eq!(db_version(db_path).unwrap(), 1);
+        check_db(db_path).unwrap_err();
+        migrate_db(db_path, 1, DB_VERSION).unwrap();
+        assert_eq!(db_version(db_path).unwrap(), DB_VERSION);
+        check_db(db_path).unwrap();
+        // Migrating to the same version is a no-op
+        migrate_db(db_path, DB_VERSION, DB_VERSION).unwrap();
+        assert_eq!(db_version(db_path).unwrap(), DB_VERSION);
+    }
+
+    #[test]
+    fn db_migration_1_to_2() {
+        let test_framework = CosignerTestBuilder::new(3);
+        let db_path = &test_framework.config.data_dir.join("v0.sqlite3");
+        create_v0_db(db_path);
+        migrate_db(db_path, 0, 1).unwrap();
+        let outpoint = OutPoint::from_str(
+            "2b8930127e9dfd1bcdf35df2bc7f3b8cdbec083b1ae693f36b6305fccd1425da:0",
+        )
+        .unwrap();
+        let signature = Signature::from_str(
+            "3045022100bd287d1cc62223e344a4eea99801e15dab6484365d2b4f981\
+                                      fa7febc0b29cea40220579ec2071c1e5e2dab8a468849214c6cfed2342bb\
+                                      ffa572327621bad2d894961",
+        )
+        .unwrap();
+        insert_v0_signed_outpoint(db_path, &outpoint, &signature);
+
+        // Existing rows have an unknown signing time
+        setup_db(db_path, true).unwrap();
+        assert_eq!(db_version(db_path).unwrap(), 2);
+        let signed_op = db_signed_outpoint(db_path, &outpoint).unwrap().unwrap();
+        assert_eq!(signed_op.signature, signature);
+        assert_eq!(signed_op.signed_at, 0);
+        assert_eq!(
+            db_signed_outpoint_time(db_path, &outpoint).unwrap(),
+            Some(0)
+        );
+    }
+
+    #[test]
+    fn signed_outpoints_time() {
+        let test_framework = CosignerTestBuilder::new(3);
+        let db_path = test_framework.config.db_file();
+        let (inserted, reserved) = (
+            OutPoint::from_str(
+                "2b8930127e9dfd1bcdf35df2bc7f3b8cdbec083b1ae693f36b6305fccd1425da:0",
+            )
+            .unwrap(),
+            OutPoint::from_str(
+                "ceca4de398c63b29543f8346c09fd7522fd8661ce8bdc0e454e8d6ed8ad46a0d:1",
+            )
+            .unwrap(),
+        );
+        let signature = Signature::from_str(
+            "3045022100bd287d1cc62223e344a4eea99801e15dab6484365d2b4f981\
+                                      fa7febc0b29cea40220579ec2071c1e5e2dab8a468849214c6cfed2342bb\
+                                      ffa572327621bad2d894961",
+        )
+        .unwrap();
+        let is_recent = |t: u64| (now() as u64).saturating_sub(t) < 5;
+
+        assert_eq!(db_signed_outpoint_time(&db_path, &inserted).unwrap(), None);
+        db_insert_signed_outpoint(&db_path, &inserted, &signature).unwrap();
+        let signed_at = db_signed_outpoint_time(&db_path, &inserted)
+            .unwrap()
+            .unwrap();
+        assert!(is_recent(signed_at));
+        assert_eq!(
+            db_signed_outpoint(&db_path, &inserted)
+                .unwrap()
+                .unwrap()
+                .signed_at,
+            signed_at
+        );
+
+        // A reserved outpoint isn't signed until it's filled
+        db_reserve_outpoints(&db_path, &[reserved]).unwrap();
+        assert_eq!(db_signed_outpoint_time(&db_path, &reserved).unwrap(), None);
+        db_fill_reserved_outpoint(&db_path, &reserved, &signature).unwrap();
+        assert!(is_recent(
+            db_signed_outpoint_time(&db_path, &reserved)
+                .unwrap()
+                .unwrap()
+        ));
+    }
+
+    #[test]
+    fn signed_outpoints_count() {
+        let test_framework = CosignerTestBuilder::new(3);
+        let db_path = test_framework.config.db_file();
+        let signature = Signature::from_str(
+            "3045022100bd287d1cc62223e344a4eea99801e15dab6484365d2b4f981\
+                                      fa7febc0b29cea40220579ec2071c1e5e2dab8a468849214c6cfed2342bb\
+                                      ffa572327621bad2d894961",
+        )
+        .unwrap();
+
+        assert_eq!(db_count_signed_outpoints(&db_path).unwrap(), 0);
+        for outpoint in &[
+            "2b8930127e9dfd1bcdf35df2bc7f3b8cdbec083b1ae693f36b6305fccd1425da:0",
+            "ceca4de398c63b29543f8346c09fd7522fd8661ce8bdc0e454e8d6ed8ad46a0d:1",
+            "0b38682347207cd79de33edf8897a75abe7d8799b194439150306773b6aef55a:189",
+        ] {
+            let outpoint = OutPoint::from_str(outpoint).unwrap();
+            db_insert_signed_outpoint(&db_path, &outpoint, &signature).unwrap();
+        }
+        assert_eq!(db_count_signed_outpoints(&db_path).unwrap(), 3);
+
+        // Reservations aren't signed outpoints
+        let reserved = OutPoint::from_str(
+            "e69a8de68c69b2f19249437004b65e82e2615c61c8d852fd36965c032a117d00:120",
+        )
+        .unwrap();
+        db_reserve_outpoints(&db_path, &[reserved]).unwrap();
+        assert_eq!(db_count_signed_outpoints(&db_path).unwrap(), 3);
+    }
+
+    #[test]
+    fn signed_outpoints_batch_atomicity() {
+        let test_framework = CosignerTestBuilder::new(3);
+        let db_path = test_framework.config.db_file();
+        let signature = Signature::from_str(
+            "3045022100bd287d1cc62223e344a4eea99801e15dab6484365d2b4f981\
+                                      fa7febc0b29cea40220579ec2071c1e5e2dab8a468849214c6cfed2342bb\
+                                      ffa572327621bad2d894961",
+        )
+        .unwrap();
+        let outpoints: Vec<OutPoint> = [
+            "2b8930127e9dfd1bcdf35df2bc7f3b8cdbec083b1ae693f36b6305fccd1425da:0",
+            "ceca4de398c63b29543f8346c09fd7522fd8661ce8bdc0e454e8d6ed8ad46a0d:1",
+            "0b38682347207cd79de33edf8897a75abe7d8799b194439150306773b6aef55a:189",
+        ]
+        .iter()
+        .map(|o| OutPoint::from_str(o).unwrap())
+        .collect();
+        let signed: Vec<(OutPoint, Signature)> =
+            outpoints.iter().map(|o| (*o, signature)).collect();
+
+        // The last insertion fails as it's present already, so none of the others are inserted
+        db_insert_signed_outpoint(&db_path, &outpoints[2], &signature).unwrap();
+        db_insert_signed_outpoints(&db_path, &signed).unwrap_err();
+        assert_eq!(db_count_signed_outpoints(&db_path).unwrap(), 1);
+        assert!(db_signed_outpoint(&db_path, &outpoints[0])
+            .unwrap()
+            .is_none());
+        assert!(db_signed_outpoint(&db_path, &outpoints[1])
+            .unwrap()
+            .is_none());
+
+        db_insert_signed_outpoints(&db_path, &signed[..2]).unwrap();
+        assert_eq!(db_count_signed_outpoints(&db_path).unwrap(), 3);
+
+        // Same for filling reservations: the last one wasn't reserved, so none is filled
+        let other_db_path = test_framework.config.data_dir.join("other.sqlite3");
+        setup_db(&other_db_path, true).unwrap();
+        db_reserve_outpoints(&other_db_path, &outpoints[..2]).unwrap();
+        db_fill_reserved_outpoints(&other_db_path, &signed).unwrap_err();
+        assert_eq!(db_count_signed_outpoints(&other_db_path).unwrap(), 0);
+        db_fill_reserved_outpoints(&other_db_path, &signed[..2]).unwrap();
+        assert_eq!(db_count_signed_outpoints(&other_db_path).unwrap(), 2);
+    }
+
+    #[test]
+    fn signed_outpoints_pruning() {
+        let test_framework = CosignerTestBuilder::new(3);
+        let db_path = test_framework.config.db_file();
+        let signature = Signature::from_str(
+            "3045022100bd287d1cc62223e344a4eea99801e15dab6484365d2b4f981\
+                                      fa7febc0b29cea40220579ec2071c1e5e2dab8a468849214c6cfed2342bb\
+                                      ffa572327621bad2d894961",
+        )
+        .unwrap();
+        let (stale, fresh, unknown, reserved) = (
+            OutPoint::from_str(
+                "2b8930127e9dfd1bcdf35df2bc7f3b8cdbec083b1ae693f36b6305fccd1425da:0",
+            )
+            .unwrap(),
+            OutPoint::from_str(
+                "ceca4de398c63b29543f8346c09fd7522fd8661ce8bdc0e454e8d6ed8ad46a0d:1",
+            )
+            .unwrap(),
+            OutPoint::from_str(
+                "0b38682347207cd79de33edf8897a75abe7d8799b194439150306773b6aef55a:189",
+            )
+            .unwrap(),
+            OutPoint::from_str(
+                "e69a8de68c69b2f19249437004b65e82e2615c61c8d852fd36965c032a117d00:120",
+            )
+            .unwrap(),
+        );
+        db_insert_signed_outpoints(
+            &db_path,
+            &[(stale, signature), (fresh, signature), (unknown, signature)],
+        )
+        .unwrap();
+        db_reserve_outpoints(&db_path, &[reserved]).unwrap();
+        db_exec(&db_path, |tx| {
+            tx.execute(
+                "UPDATE signed_outpoints SET signed_at = (?1) WHERE vout = 0",
+                params![now() - 10 * 24 * 3600],
+            )
+            .unwrap();
+            tx.execute(
+                "UPDATE signed_outpoints SET signed_at = 0 WHERE vout = 189",
+                params![],
+            )
+            .unwrap();
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(
+            db_signature_cached(&db_path, &stale, 8).unwrap(),
+            Some(signature)
+        );
+
+        // Only the one signed 10 days ago is older than a week
+        assert_eq!(
+            db_prune_signed_outpoints(&db_path, 7 * 24 * 3600).unwrap(),
+            1
+        );
+        assert!(db_signed_outpoint(&db_path, &stale).unwrap().is_none());
+        assert!(db_signature_cached(&db_path, &stale, 8).unwrap().is_none());
+        assert!(db_signed_outpoint(&db_path, &fresh).unwrap().is_some());
+        assert!(db_signed_outpoint(&db_path, &unknown).unwrap().is_some());
+        assert_eq!(
+            db_reserve_outpoints(&db_path, &[reserved]).unwrap(),
+            ReservationOutcome::AllAlreadyPresent
+        );
+        assert_eq!(
+            db_prune_signed_outpoints(&db_path, 7 * 24 * 3600).unwrap(),
+            0
+        );
+    }
+
+    #[test]
+    fn db_exec_mutable_state() {
+        let test_framework = CosignerTestBuilder::new(3);
+        let db_path = test_framework.config.db_file();
+
+        // The modifications can build up a result, and are only run once if not busy
+        let (mut n_calls, mut hashes) = (0, Vec::new());
+        db_exec(&db_path, |tx| {
+            n_calls += 1;
+            hashes.clear();
+            for byte in 0x01..0x04 {
+                tx.execute(
+                    "INSERT INTO first_seen_outputs (outpoints_hash, outputs_hash) \
+                     VALUES (?1, ?2)",
+                    params![vec![byte; 32], vec![byte; 32]],
+                )
+                .map_err(|e| DatabaseError(format!("Inserting: {}", e)))?;
+                hashes.push(sha256::Hash::from_slice(&[byte; 32]).unwrap());
+            }
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(n_calls, 1);
+        assert_eq!(hashes.len(), 3);
+        for hash in hashes.iter() {
+            assert!(db_first_seen_outputs(&db_path, hash).unwrap().is_some());
+        }
+
+        // A failed attempt isn't retried, and what it did is rolled back
+        let res = db_exec(&db_path, |tx| {
+            n_calls += 1;
+            tx.execute("DELETE FROM first_seen_outputs", params![])
+                .map_err(|e| DatabaseError(format!("Deleting: {}", e)))?;
+            Err(DatabaseError("Nope".to_string()))
+        });
+        assert_eq!(res, Err(DatabaseError("Nope".to_string())));
+        assert_eq!(n_calls, 2);
+        assert!(db_first_seen_outputs(&db_path, &hashes[0])
+            .unwrap()
+            .is_some());
+    }
+
+    #[test]
+    fn db_busy_timeout() {
+        let test_framework = CosignerTestBuilder::new(1);
+        let db_path = test_framework.config.db_file();
+        db_set_busy_timeout(DB_BUSY_TIMEOUT_MS_DEFAULT);
+
+        // The connection applies it
+        let conn = open_db(&db_path).unwrap();
+        let timeout: i64 = conn
+            .query_row("PRAGMA busy_timeout", params![], |row| row.get(0))
+            .unwrap();
+        assert_eq!(timeout as u64, DB_BUSY_TIMEOUT_MS_DEFAULT);
+
+        // A writer holding the lock for a while doesn't make a concurrent one fail, it waits
+        let writer = |byte: u8, hold: time::Duration| {
+            let db_path = db_path.clone();
+            thread::spawn(move || {
+                db_exec(&db_path, |tx| {
+                    tx.execute(
+                        "INSERT INTO first_seen_outputs (outpoints_hash, outputs_hash) \
+                         VALUES (?1, ?2)",
+                        params![vec![byte; 32], vec![byte; 32]],
+                    )
+                    .map_err(|e| DatabaseError(format!("Inserting: {}", e)))?;
+                    thread::sleep(hold);
+                    Ok(())
+                })
+            })
+        };
+        let first = writer(0x01, time::Duration::from_millis(500));
+        thread::sleep(time::Duration::from_millis(100));
+        let second = writer(0x02, time::Duration::from_millis(0));
+        first.join().unwrap().unwrap();
+        second.join().unwrap().unwrap();
+        for byte in &[0x01, 0x02] {
+            let hash = sha256::Hash::from_slice(&[*byte; 32]).unwrap();
+            assert!(db_first_seen_outputs(&db_path, &hash).unwrap().is_some());
+        }
+    }
+
+    #[test]
+    fn db_busy_retries() {
+        let busy = || {
+            rusqlite::Error::SqliteFailure(
+                rusqlite::ffi::Error::new(rusqlite::ffi::SQLITE_BUSY),
+                None,
+            )
+        };
+
+        // A database busy for a couple of attempts eventually lets us through
+        let mut attempts = 0;
+        let res = with_retries(|| {
+            attempts += 1;
+            if attempts < 3 {
+                Err(busy())
+            } else {
+                Ok(attempts)
+            }
+        });
+        assert_eq!(res.unwrap(), 3);
+
+        // But we don't wait forever
+        let mut attempts = 0;
+        let res: rusqlite::Result<()> = with_retries(|| {
+            attempts += 1;
+            Err(busy())
+        });
+        assert!(is_transient(&res.unwrap_err()));
+        assert_eq!(attempts, DB_BUSY_RETRIES_DEFAULT + 1);
+
+        // And we never retry actual errors
+        let test_framework = CosignerTestBuilder::new(1);
+        let db_path = test_framework.config.db_file();
+        let outpoint = OutPoint::from_str(
+            "e69a8de68c69b2f19249437004b65e82e2615c61c8d852fd36965c032a117d00:120",
+        )
+        .unwrap();
+        db_reserve_outpoints(&db_path, &[outpoint]).unwrap();
+        let mut attempts = 0;
+        with_retries(|| {
+            attempts += 1;
+            open_db(&db_path)?.execute(
+                "INSERT INTO signed_outpoints (txid, vout, signature, signed_at) \
+                 VALUES (?1, ?2, x'', 0)",
+                params![outpoint.txid.to_vec(), outpoint.vout],
+            )
+        })
+        .unwrap_err();
+        assert_eq!(attempts, 1);
+    }
 }
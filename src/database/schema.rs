@@ -9,9 +9,15 @@ CREATE TABLE signed_outpoints (
     txid BLOB NOT NULL,
     vout INTEGER NOT NULL,
     signature BLOB NOT NULL,
+    signed_at INTEGER NOT NULL,
     UNIQUE(txid, vout)
 );
 
+CREATE TABLE first_seen_outputs (
+    outpoints_hash BLOB NOT NULL UNIQUE,
+    outputs_hash BLOB NOT NULL
+);
+
 ";
 
 /// A row in the "signed_outpoints" table
@@ -21,4 +27,6 @@ pub struct DbSignedOutpoint {
     // We don't even take care of parsing it as a Signature, as we only input it with
     // to_der() and use it to insert in partial_sigs (which takes raw bytes)
     pub signature: Signature,
+    /// When we signed it, in seconds since the epoch. 0 if it was signed before we recorded it.
+    pub signed_at: u64,
 }
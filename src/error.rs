@@ -0,0 +1,127 @@
+use crate::{
+    config::ConfigError, database::DatabaseError, keys::KeyError, processing::SignProcessingError,
+};
+
+use revault_tx::error::TransactionSerialisationError;
+use std::{io, path::PathBuf};
+
+/// Any error of the cosigning server, for the callers which handle them all the same way (eg the
+/// binary, which reports them and exits).
+#[derive(Debug)]
+pub enum Error {
+    Config(ConfigError),
+    Database(DatabaseError),
+    Key(KeyError),
+    SignProcessing(SignProcessingError),
+    /// Reading or writing this file (or directory) failed
+    Io(PathBuf, io::Error),
+    /// The PSBT we were given is not a valid Spend transaction
+    Transaction(Box<TransactionSerialisationError>),
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Config(e) => write!(f, "Configuration error: {}", e),
+            Self::Database(e) => write!(f, "{}", e),
+            Self::Key(e) => write!(f, "{}", e),
+            Self::SignProcessing(e) => write!(f, "Sign request processing error: {}", e),
+            Self::Io(path, e) => write!(f, "I/O error on '{}': '{}'", path.display(), e),
+            Self::Transaction(e) => write!(f, "Error parsing Spend transaction: '{}'", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<ConfigError> for Error {
+    fn from(e: ConfigError) -> Self {
+        Self::Config(e)
+    }
+}
+
+impl From<DatabaseError> for Error {
+    fn from(e: DatabaseError) -> Self {
+        Self::Database(e)
+    }
+}
+
+impl From<KeyError> for Error {
+    fn from(e: KeyError) -> Self {
+        Self::Key(e)
+    }
+}
+
+impl From<TransactionSerialisationError> for Error {
+    fn from(e: TransactionSerialisationError) -> Self {
+        Self::Transaction(Box::new(e))
+    }
+}
+
+impl From<SignProcessingError> for Error {
+    fn from(e: SignProcessingError) -> Self {
+        match e {
+            // Let the callers tell a database failure apart, whatever the operation
+            SignProcessingError::Database(e) => Self::Database(e),
+            e => Self::SignProcessing(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn error_display() {
+        assert_eq!(
+            Error::Config(ConfigError::MissingManagerSigsThreshold).to_string(),
+            "Configuration error: 'verify_manager_sigs' is set without 'require_manager_sigs'"
+        );
+        assert_eq!(
+            Error::Database(DatabaseError("Opening database: no".to_string())).to_string(),
+            "Database error: Opening database: no"
+        );
+        assert_eq!(
+            Error::Key(KeyError::BitcoinDecrypt).to_string(),
+            "Bitcoin key decryption error: wrong passphrase or corrupted key file"
+        );
+        assert_eq!(
+            Error::SignProcessing(SignProcessingError::TooManyInputs(3, 2)).to_string(),
+            "Sign request processing error: Spend transaction has 3 inputs, but we accept at \
+             most 2"
+        );
+        assert_eq!(
+            Error::Io(
+                PathBuf::from("/a/b"),
+                io::Error::new(io::ErrorKind::PermissionDenied, "no")
+            )
+            .to_string(),
+            "I/O error on '/a/b': 'no'"
+        );
+    }
+
+    #[test]
+    fn error_conversions() {
+        assert!(matches!(
+            Error::from(ConfigError::InvalidFeerateRange(3, 2)),
+            Error::Config(ConfigError::InvalidFeerateRange(3, 2))
+        ));
+        assert!(matches!(
+            Error::from(DatabaseError("a".to_string())),
+            Error::Database(DatabaseError(s)) if s == "a"
+        ));
+        assert!(matches!(
+            Error::from(KeyError::MissingPassphrase),
+            Error::Key(KeyError::MissingPassphrase)
+        ));
+        assert!(matches!(
+            Error::from(SignProcessingError::ForeignScript(1)),
+            Error::SignProcessing(SignProcessingError::ForeignScript(1))
+        ));
+        assert!(matches!(
+            Error::from(SignProcessingError::Database(DatabaseError("b".to_string()))),
+            Error::Database(DatabaseError(s)) if s == "b"
+        ));
+    }
+}
@@ -0,0 +1,130 @@
+//! A plaintext health check for orchestrators, answering `PING` with `PONG` and our database
+//! version. It also answers `CAPABILITIES` with our signing policies (see
+//! [crate::config::Config::capabilities]) as a JSON line, for the managers. It never touches our
+//! keys.
+
+use crate::database::db_version;
+
+use std::{
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{TcpListener, TcpStream},
+    path::PathBuf,
+    thread, time,
+};
+
+// Answer a single health check on this connection, then close it
+fn answer_health_check(
+    connection: TcpStream,
+    db_path: &PathBuf,
+    capabilities: &str,
+    timeout: time::Duration,
+) -> Result<(), io::Error> {
+    connection.set_read_timeout(Some(timeout))?;
+    connection.set_write_timeout(Some(timeout))?;
+
+    let mut line = String::new();
+    // Don't let a client make us buffer an unbounded line.
+    BufReader::new((&connection).take(64)).read_line(&mut line)?;
+
+    let response = match line.trim_end() {
+        "PING" => match db_version(db_path) {
+            Ok(version) => format!("PONG\ndb_version {}\n", version),
+            Err(e) => format!("ERROR {}\n", e),
+        },
+        "CAPABILITIES" => format!("{}\n", capabilities),
+        _ => "ERROR unknown command\n".to_string(),
+    };
+    (&connection).write_all(response.as_bytes())
+}
+
+/// Answer health checks and capabilities requests on this listener, in a new thread. The
+/// connections are dropped after `timeout` of inactivity.
+pub fn start_health_endpoint(
+    listener: TcpListener,
+    db_path: PathBuf,
+    capabilities: serde_json::Value,
+    timeout: time::Duration,
+) -> thread::JoinHandle<()> {
+    let capabilities = capabilities.to_string();
+    thread::spawn(move || {
+        for connection in listener.incoming() {
+            match connection {
+                Ok(connection) => {
+                    if let Err(e) =
+                        answer_health_check(connection, &db_path, &capabilities, timeout)
+                    {
+                        log::debug!("Error answering health check: '{}'", e);
+                    }
+                }
+                Err(e) => log::error!("Accepting health check connection: '{}'", e),
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{database::DB_VERSION, tests::builder::CosignerTestBuilder};
+
+    fn health_check(addr: std::net::SocketAddr, request: &[u8]) -> String {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(request).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn health_endpoint() {
+        let test_framework = CosignerTestBuilder::new(1);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        start_health_endpoint(
+            listener,
+            test_framework.config.db_file(),
+            test_framework.config.capabilities(),
+            time::Duration::from_secs(5),
+        );
+
+        assert_eq!(
+            health_check(addr, b"PING\n"),
+            format!("PONG\ndb_version {}\n", DB_VERSION)
+        );
+        // It keeps on answering
+        assert_eq!(
+            health_check(addr, b"PING\r\n"),
+            format!("PONG\ndb_version {}\n", DB_VERSION)
+        );
+        assert_eq!(health_check(addr, b"SIGN\n"), "ERROR unknown command\n");
+    }
+
+    #[test]
+    fn capabilities_request() {
+        let mut test_framework = CosignerTestBuilder::new(1);
+        test_framework.config.require_rbf = true;
+        test_framework.config.max_feerate = Some(1_000);
+        test_framework.config.allowed_outpoints = Some(Default::default());
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        start_health_endpoint(
+            listener,
+            test_framework.config.db_file(),
+            test_framework.config.capabilities(),
+            time::Duration::from_secs(5),
+        );
+
+        let response = health_check(addr, b"CAPABILITIES\n");
+        assert!(response.ends_with('\n'));
+        let capabilities: serde_json::Value = serde_json::from_str(&response).unwrap();
+        assert_eq!(capabilities["require_rbf"], serde_json::json!(true));
+        assert_eq!(capabilities["max_feerate"], serde_json::json!(1_000));
+        assert_eq!(capabilities["allowed_outpoints"], serde_json::json!(true));
+        assert_eq!(
+            capabilities["allowed_destinations"],
+            serde_json::json!(false)
+        );
+        assert_eq!(capabilities["read_only"], serde_json::json!(false));
+        assert_eq!(capabilities, test_framework.config.capabilities());
+    }
+}
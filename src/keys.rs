@@ -1,13 +1,55 @@
-use revault_net::{noise::SecretKey as NoisePrivKey, sodiumoxide};
-use revault_tx::bitcoin::secp256k1::{Error as SecpError, SecretKey as BitcoinPrivKey};
+use revault_net::{
+    noise::{PublicKey as NoisePubKey, SecretKey as NoisePrivKey},
+    sodiumoxide::{
+        self,
+        crypto::{
+            aead::xchacha20poly1305_ietf as aead, pwhash::argon2id13, scalarmult::curve25519,
+        },
+    },
+};
+use revault_tx::bitcoin::{
+    secp256k1::{self, Error as SecpError, SecretKey as BitcoinPrivKey},
+    util::key::Error as WifError,
+    Network, PrivateKey as WifPrivKey, PublicKey as BitcoinPubKey,
+};
 
 use std::{
+    env,
+    ffi::CString,
     fs,
     io::{self, Read, Write},
-    os::unix::fs::OpenOptionsExt,
-    path::PathBuf,
+    os::unix::fs::{OpenOptionsExt, PermissionsExt},
+    path::{Path, PathBuf},
+    slice,
+    sync::OnceLock,
 };
 
+/// The permissions of the data directory we create
+pub const DATADIR_MODE: u32 = 0o700;
+
+/// The permissions of the key files we write
+pub const KEY_FILE_MODE: u32 = 0o400;
+
+/// The header of an encrypted Bitcoin key file. It is followed by the Argon2id limits (the
+/// operations then the memory, as 8 bytes little-endian each), the Argon2id salt, the
+/// XChaCha20-Poly1305 nonce and the encrypted key.
+pub const ENCRYPTED_KEY_MAGIC: &[u8] = b"cosignerd-encrypted-key-v1\n";
+
+// The Argon2id limits we encrypt new key files with. Those of existing files are read from them,
+// so they can be raised later on.
+const KEY_OPSLIMIT: argon2id13::OpsLimit = argon2id13::OPSLIMIT_MODERATE;
+const KEY_MEMLIMIT: argon2id13::MemLimit = argon2id13::MEMLIMIT_MODERATE;
+
+// The largest key file we read: an encrypted key, or a WIF string with some trailing whitespace
+const MAX_KEY_FILE_SIZE: usize = 256;
+
+// The lengths of WIF strings, for uncompressed and compressed keys, and their alphabet
+const WIF_LENGTHS: &[usize] = &[51, 52];
+const BASE58_CHARS: &[u8] = b"123456789ABCDEFGHJKLMNPQRSTUVWXYZabcdefghijkmnopqrstuvwxyz";
+
+/// The environment variable the passphrase is read from if no passphrase file is configured
+pub const PASSPHRASE_ENV_VAR: &str = "COSIGNERD_BITCOIN_KEY_PASSPHRASE";
+
 /// An error occuring during the handling of our keys
 #[derive(Debug)]
 pub enum KeyError {
@@ -17,6 +59,22 @@ pub enum KeyError {
     // not hold for Bitcoin's secp256k1.
     /// Returned if the file does not contain a valid Secp256k1 private key
     BitcoinVerify(SecpError),
+    /// The Bitcoin key file is encrypted but no passphrase was provided
+    MissingPassphrase,
+    Passphrase(io::Error),
+    /// Wrong passphrase or corrupted key file
+    BitcoinDecrypt,
+    /// The file contains an invalid WIF string
+    BitcoinWif(WifError),
+    /// The WIF key is for this network, but we expected a key for this other one
+    BitcoinNetwork(Network, Network),
+    /// The WIF key is for an uncompressed public key, which our Scripts never use
+    BitcoinUncompressed,
+    /// There is no Bitcoin key file at this path. We never create it as it must be the one from
+    /// the Ceremony.
+    MissingBitcoinKey(PathBuf),
+    /// This file has these permissions, more open than the expected ones
+    LoosePermissions(PathBuf, u32, u32),
 }
 
 impl std::fmt::Display for KeyError {
@@ -25,54 +83,948 @@ impl std::fmt::Display for KeyError {
             Self::Noise(e) => write!(f, "Noise key initialization error: '{}'", e),
             Self::BitcoinRead(e) => write!(f, "Bitcoin key read error: '{}'", e),
             Self::BitcoinVerify(e) => write!(f, "Bitcoin key verification error: '{}'", e),
+            Self::MissingPassphrase => write!(
+                f,
+                "Bitcoin key is encrypted but no passphrase was configured (set \
+                 'bitcoin_key_passphrase_file' or the {} environment variable)",
+                PASSPHRASE_ENV_VAR
+            ),
+            Self::Passphrase(e) => write!(f, "Bitcoin key passphrase read error: '{}'", e),
+            Self::BitcoinDecrypt => write!(
+                f,
+                "Bitcoin key decryption error: wrong passphrase or corrupted key file"
+            ),
+            Self::BitcoinWif(e) => write!(f, "Bitcoin key WIF decoding error: '{}'", e),
+            Self::BitcoinNetwork(key_network, network) => write!(
+                f,
+                "Bitcoin key is for network '{}' but we are running on '{}'",
+                key_network, network
+            ),
+            Self::BitcoinUncompressed => write!(
+                f,
+                "Bitcoin key is for an uncompressed public key, but the setup Ceremony only uses \
+                 compressed ones"
+            ),
+            Self::LoosePermissions(path, mode, expected) => write!(
+                f,
+                "'{}' has permissions {:o}, but at most {:o} are expected",
+                path.display(),
+                mode,
+                expected
+            ),
+            Self::MissingBitcoinKey(path) => write!(
+                f,
+                "No Bitcoin key file at '{}': place the private key generated during the setup \
+                 Ceremony there",
+                path.display()
+            ),
         }
     }
 }
 
 impl std::error::Error for KeyError {}
 
+// Lock this memory in RAM so it does not get swapped out. This is best effort.
+fn lock_memory(mem: &mut [u8]) {
+    if sodiumoxide::utils::mlock(mem).is_err() {
+        log::warn!(
+            "Could not lock secret key memory: '{}'",
+            io::Error::last_os_error()
+        );
+    }
+}
+
+/// A heap-allocated buffer for secret bytes in transit. It is locked in RAM (best effort) so it
+/// does not get swapped out, and is wiped when dropped.
+struct SecretBuf(Box<[u8]>);
+
+impl SecretBuf {
+    fn new(len: usize, fill: u8) -> Self {
+        let mut buf = Self(vec![fill; len].into_boxed_slice());
+        lock_memory(&mut buf.0);
+        buf
+    }
+
+    // Read this whole file (up to the buffer's length) into a new locked buffer, and return it
+    // along with the number of bytes read. No other copy of the content is made along the way.
+    fn read_file(path: &Path, len: usize) -> io::Result<(Self, usize)> {
+        let mut file = fs::File::open(path)?;
+        let mut buf = Self::new(len, 0);
+        let mut read = 0;
+        while read < len {
+            match file.read(&mut buf.0[read..]) {
+                Ok(0) => break,
+                Ok(n) => read += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok((buf, read))
+    }
+
+    fn wipe(&mut self) {
+        sodiumoxide::utils::memzero(&mut self.0[..]);
+    }
+}
+
+impl Drop for SecretBuf {
+    fn drop(&mut self) {
+        self.wipe();
+        // Unlocking is best effort, and the memory is about to be freed anyways.
+        let _ = sodiumoxide::utils::munlock(&mut self.0[..]);
+    }
+}
+
 // The communication keys are (for now) hot, so we just create it ourselves on first run.
 pub fn read_or_create_noise_key(secret_file: &PathBuf) -> Result<NoisePrivKey, KeyError> {
     let mut noise_secret = NoisePrivKey([0; 32]);
+    read_or_create_noise_key_into(secret_file, &mut noise_secret)?;
+    Ok(noise_secret)
+}
 
+// Read or create the Noise key directly into this (locked) storage, without any copy of it.
+fn read_or_create_noise_key_into(
+    secret_file: &PathBuf,
+    noise_secret: &mut NoisePrivKey,
+) -> Result<(), KeyError> {
     if !secret_file.as_path().exists() {
         log::info!(
             "No Noise private key at '{:?}', generating a new one",
             secret_file
         );
-        noise_secret = sodiumoxide::crypto::box_::gen_keypair().1;
+        // All 32 bytes strings are valid Curve25519 private keys, this is what gen_keypair()
+        // does minus the copies.
+        sodiumoxide::randombytes::randombytes_into(&mut noise_secret.0);
 
         // We create it in read-only but open it in write only.
         let mut options = fs::OpenOptions::new();
-        options = options.write(true).create_new(true).mode(0o400).clone();
+        options = options
+            .write(true)
+            .create_new(true)
+            .mode(KEY_FILE_MODE)
+            .clone();
 
         let mut fd = options.open(secret_file).map_err(KeyError::Noise)?;
-        fd.write_all(&noise_secret.as_ref())
+        fd.write_all(noise_secret.as_ref())
             .map_err(KeyError::Noise)?;
     } else {
+        // The key is wiped on drop by sodiumoxide, even if we only read part of it.
         let mut noise_secret_fd = fs::File::open(secret_file).map_err(KeyError::Noise)?;
         noise_secret_fd
             .read_exact(&mut noise_secret.0)
             .map_err(KeyError::Noise)?;
     }
 
-    // TODO: have a decent memory management and mlock() the key
-
     assert!(noise_secret.0 != [0; 32]);
-    Ok(noise_secret)
+    Ok(())
+}
+
+/// The longest passphrase we accept, in bytes
+pub const MAX_PASSPHRASE_LEN: usize = 1024;
+
+/// The passphrase of an encrypted Bitcoin key, locked in RAM and wiped when dropped
+pub struct Passphrase {
+    buf: SecretBuf,
+    len: usize,
+}
+
+impl std::ops::Deref for Passphrase {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.buf.0[..self.len]
+    }
+}
+
+// Move the passphrase out of the environment into a new locked buffer, and return it along with
+// its length. It's wiped from there and the variable removed, so it does not leak to our
+// children or through `/proc`.
+fn take_env_passphrase() -> Option<(SecretBuf, usize)> {
+    let name = CString::new(PASSPHRASE_ENV_VAR).expect("No nul byte in the variable name");
+    // Safe as the value is a nul-terminated string we only overwrite in place, before removing
+    // the variable. The keys are read at startup, before we spawn any thread.
+    let passphrase = unsafe {
+        let value = libc::getenv(name.as_ptr());
+        if value.is_null() {
+            return None;
+        }
+        let value = slice::from_raw_parts_mut(value as *mut u8, libc::strlen(value));
+        let mut passphrase = SecretBuf::new(value.len(), 0);
+        passphrase.0.copy_from_slice(value);
+        sodiumoxide::utils::memzero(value);
+        passphrase
+    };
+    env::remove_var(PASSPHRASE_ENV_VAR);
+
+    let len = passphrase.0.len();
+    Some((passphrase, len))
+}
+
+/// Read the passphrase of an encrypted Bitcoin key, from the given file or otherwise from the
+/// environment. A trailing newline is not part of the passphrase. If read from the environment,
+/// it's wiped and removed from there so it does not leak to our children or through `/proc`. It's
+/// never copied outside of locked memory.
+pub fn read_key_passphrase(
+    passphrase_file: Option<&PathBuf>,
+) -> Result<Option<Passphrase>, KeyError> {
+    let (buf, mut len) = match passphrase_file {
+        // One more byte, to tell a passphrase too long apart
+        Some(path) => {
+            SecretBuf::read_file(path, MAX_PASSPHRASE_LEN + 1).map_err(KeyError::Passphrase)?
+        }
+        None => match take_env_passphrase() {
+            Some(passphrase) => passphrase,
+            None => return Ok(None),
+        },
+    };
+    if len > MAX_PASSPHRASE_LEN {
+        return Err(KeyError::Passphrase(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("the passphrase is longer than {} bytes", MAX_PASSPHRASE_LEN),
+        )));
+    }
+
+    while len > 0 && matches!(buf.0[len - 1], b'\n' | b'\r') {
+        len -= 1;
+    }
+    Ok(Some(Passphrase { buf, len }))
+}
+
+// Derive the encryption key of a Bitcoin key file from its passphrase
+fn derive_encryption_key(
+    passphrase: &[u8],
+    salt: &argon2id13::Salt,
+    opslimit: argon2id13::OpsLimit,
+    memlimit: argon2id13::MemLimit,
+) -> Result<aead::Key, KeyError> {
+    let mut key = aead::Key([0; aead::KEYBYTES]);
+    argon2id13::derive_key(&mut key.0, passphrase, salt, opslimit, memlimit).map_err(|_| {
+        KeyError::Passphrase(io::Error::other(
+            "Not enough memory to derive the encryption key",
+        ))
+    })?;
+    Ok(key)
+}
+
+/// Encrypt this Bitcoin private key under this passphrase, in the format expected in the
+/// `bitcoin_secret` file.
+pub fn encrypt_bitcoin_privkey(
+    privkey: &BitcoinPrivKey,
+    passphrase: &[u8],
+) -> Result<Vec<u8>, KeyError> {
+    let salt = argon2id13::gen_salt();
+    let nonce = aead::gen_nonce();
+    let key = derive_encryption_key(passphrase, &salt, KEY_OPSLIMIT, KEY_MEMLIMIT)?;
+
+    // The whole header is authenticated
+    let mut content = ENCRYPTED_KEY_MAGIC.to_vec();
+    content.extend_from_slice(&(KEY_OPSLIMIT.0 as u64).to_le_bytes());
+    content.extend_from_slice(&(KEY_MEMLIMIT.0 as u64).to_le_bytes());
+    content.extend_from_slice(&salt.0);
+    content.extend_from_slice(&nonce.0);
+    let ciphertext = aead::seal(&privkey[..], Some(&content), &nonce, &key);
+    content.extend_from_slice(&ciphertext);
+    Ok(content)
+}
+
+// Read the Argon2id limits from an encrypted key file header. We won't go beyond the most
+// expensive ones libsodium recommends, a corrupted file must not get us to allocate all the RAM.
+fn key_limits(limits: &[u8]) -> Option<(argon2id13::OpsLimit, argon2id13::MemLimit)> {
+    let mut opslimit = [0; 8];
+    opslimit.copy_from_slice(&limits[..8]);
+    let mut memlimit = [0; 8];
+    memlimit.copy_from_slice(&limits[8..16]);
+    let (opslimit, memlimit) = (u64::from_le_bytes(opslimit), u64::from_le_bytes(memlimit));
+    if opslimit > argon2id13::OPSLIMIT_SENSITIVE.0 as u64
+        || memlimit > argon2id13::MEMLIMIT_SENSITIVE.0 as u64
+    {
+        return None;
+    }
+    Some((
+        argon2id13::OpsLimit(opslimit as usize),
+        argon2id13::MemLimit(memlimit as usize),
+    ))
+}
+
+// Decrypt the content of an encrypted key file (header included) into this buffer. The content
+// is decrypted in place, so it must be in locked memory too.
+fn decrypt_bitcoin_privkey(
+    content: &mut [u8],
+    passphrase: &[u8],
+    buf: &mut SecretBuf,
+) -> Result<(), KeyError> {
+    let (magic_len, limits_len) = (ENCRYPTED_KEY_MAGIC.len(), 16);
+    let salt_len = argon2id13::SALTBYTES;
+    let nonce_len = aead::NONCEBYTES;
+    let header_len = magic_len + limits_len + salt_len + nonce_len;
+    if content.len() != header_len + buf.0.len() + aead::TAGBYTES {
+        return Err(KeyError::BitcoinDecrypt);
+    }
+    let (header, encrypted) = content.split_at_mut(header_len);
+    let (opslimit, memlimit) =
+        key_limits(&header[magic_len..magic_len + limits_len]).ok_or(KeyError::BitcoinDecrypt)?;
+    let salt_start = magic_len + limits_len;
+    let salt = argon2id13::Salt::from_slice(&header[salt_start..salt_start + salt_len])
+        .expect("Length checked");
+    let nonce = aead::Nonce::from_slice(&header[salt_start + salt_len..]).expect("Length checked");
+    let key = derive_encryption_key(passphrase, &salt, opslimit, memlimit)?;
+
+    let (ciphertext, tag) = encrypted.split_at_mut(buf.0.len());
+    let tag = aead::Tag::from_slice(tag).expect("Length checked");
+    aead::open_detached(ciphertext, Some(header), &tag, &nonce, &key)
+        .map_err(|_| KeyError::BitcoinDecrypt)?;
+    buf.0.copy_from_slice(ciphertext);
+    Ok(())
+}
+
+// A WIF string, possibly followed by a newline. A raw key is 32 bytes, and the odds of it being
+// followed by enough base58 characters to look like one are negligible.
+fn wif_str(content: &[u8]) -> Option<&str> {
+    let wif = std::str::from_utf8(content).ok()?.trim_end();
+    if WIF_LENGTHS.contains(&wif.len()) && wif.bytes().all(|c| BASE58_CHARS.contains(&c)) {
+        Some(wif)
+    } else {
+        None
+    }
+}
+
+// Decode a WIF key into this buffer, checking it's for this network if any
+fn decode_wif_privkey(
+    wif: &str,
+    network: Option<Network>,
+    buf: &mut SecretBuf,
+) -> Result<(), KeyError> {
+    let privkey = WifPrivKey::from_wif(wif).map_err(KeyError::BitcoinWif)?;
+    if !privkey.compressed {
+        return Err(KeyError::BitcoinUncompressed);
+    }
+    if let Some(network) = network {
+        // Test networks share the same WIF version byte
+        let wif_network = match network {
+            Network::Bitcoin => Network::Bitcoin,
+            _ => Network::Testnet,
+        };
+        if privkey.network != wif_network {
+            return Err(KeyError::BitcoinNetwork(privkey.network, network));
+        }
+    }
+    buf.0.copy_from_slice(&privkey.key[..]);
+    Ok(())
+}
+
+/// Check the data directory and the key files in it are not accessible to more than they should.
+/// Looser permissions than [DATADIR_MODE] and [KEY_FILE_MODE] are logged, or an error if
+/// `strict`. Missing key files are left to the key readers to report.
+pub fn check_permissions(data_dir: &Path, strict: bool) -> Result<(), KeyError> {
+    let files = [
+        (data_dir.to_path_buf(), DATADIR_MODE),
+        (data_dir.join("noise_secret"), KEY_FILE_MODE),
+        (data_dir.join("bitcoin_secret"), KEY_FILE_MODE),
+    ];
+
+    for (path, expected) in files.iter() {
+        let mode = match fs::metadata(path) {
+            Ok(metadata) => metadata.permissions().mode() & 0o777,
+            Err(e) => {
+                if e.kind() != io::ErrorKind::NotFound {
+                    log::warn!(
+                        "Could not check permissions of '{}': '{}'",
+                        path.display(),
+                        e
+                    );
+                }
+                continue;
+            }
+        };
+        if mode & !expected != 0 {
+            let error = KeyError::LoosePermissions(path.clone(), mode, *expected);
+            if strict {
+                return Err(error);
+            }
+            log::warn!("INSECURE PERMISSIONS: {}. Our keys may be exposed!", error);
+        }
+    }
+
+    Ok(())
 }
 
 // The Bitcoin key is hot too (for now) but is part of the onchain Script and is generated
-// during the setup Ceremony.
-pub fn read_bitcoin_privkey(secret_file: &PathBuf) -> Result<BitcoinPrivKey, KeyError> {
+// during the setup Ceremony. It is either stored as 32 raw bytes, as a WIF string (which must be
+// for `network` if given), or encrypted under a passphrase if the file starts with the
+// `ENCRYPTED_KEY_MAGIC` header.
+pub fn read_bitcoin_privkey(
+    secret_file: &Path,
+    passphrase: Option<&[u8]>,
+    network: Option<Network>,
+) -> Result<BitcoinPrivKey, KeyError> {
     // 0xffffff....ffff is not a valid privkey so this ensures we read correctly.
-    let mut buf = [0xff; 32];
+    let mut buf = SecretBuf::new(32, 0xff);
+    read_bitcoin_privkey_into(secret_file, passphrase, network, &mut buf)
+}
+
+/// The public key of our Bitcoin private key, as it appears in the Scripts. The point
+/// multiplication isn't free: derive it once and pass it around rather than per request.
+pub fn bitcoin_pubkey<C: secp256k1::Signing>(
+    secp: &secp256k1::Secp256k1<C>,
+    privkey: &BitcoinPrivKey,
+) -> BitcoinPubKey {
+    BitcoinPubKey {
+        compressed: true,
+        key: secp256k1::PublicKey::from_secret_key(secp, privkey),
+    }
+}
+
+// Read the key through the given buffer, which is wiped before returning whatever the outcome.
+// The file content is read into a locked buffer too, and the key is decrypted there.
+fn read_bitcoin_privkey_into(
+    secret_file: &Path,
+    passphrase: Option<&[u8]>,
+    network: Option<Network>,
+    buf: &mut SecretBuf,
+) -> Result<BitcoinPrivKey, KeyError> {
+    let res = SecretBuf::read_file(secret_file, MAX_KEY_FILE_SIZE)
+        .map_err(|e| match e.kind() {
+            io::ErrorKind::NotFound => KeyError::MissingBitcoinKey(secret_file.to_path_buf()),
+            _ => KeyError::BitcoinRead(e),
+        })
+        .and_then(|(mut file_buf, len)| {
+            let content = &mut file_buf.0[..len];
+            if content.starts_with(ENCRYPTED_KEY_MAGIC) {
+                passphrase
+                    .ok_or(KeyError::MissingPassphrase)
+                    .and_then(|passphrase| decrypt_bitcoin_privkey(content, passphrase, buf))
+            } else if let Some(wif) = wif_str(content) {
+                decode_wif_privkey(wif, network, buf)
+            } else if content.len() >= buf.0.len() {
+                buf.0.copy_from_slice(&content[..32]);
+                Ok(())
+            } else {
+                Err(KeyError::BitcoinRead(io::ErrorKind::UnexpectedEof.into()))
+            }
+        })
+        .and_then(|_| BitcoinPrivKey::from_slice(&buf.0[..]).map_err(KeyError::BitcoinVerify));
+    buf.wipe();
+    res
+}
+
+static SECP_CTX: OnceLock<secp256k1::Secp256k1<secp256k1::All>> = OnceLock::new();
+
+/// The secp context of this process. Creating one is expensive (it precomputes tables for both
+/// signing and verification), so it's created on first use and then borrowed everywhere.
+pub fn secp_ctx() -> &'static secp256k1::Secp256k1<secp256k1::All> {
+    SECP_CTX.get_or_init(secp256k1::Secp256k1::new)
+}
+
+// Our long-lived secrets, kept together in a heap allocation locked in RAM
+struct LockedSecrets {
+    noise_privkey: NoisePrivKey,
+    bitcoin_privkey: BitcoinPrivKey,
+}
+
+impl LockedSecrets {
+    // Placeholder keys, to be overwritten in place
+    fn new() -> Box<Self> {
+        let mut secrets = Box::new(Self {
+            noise_privkey: NoisePrivKey([0; 32]),
+            bitcoin_privkey: secp256k1::key::ONE_KEY,
+        });
+        lock_memory(secrets.as_bytes_mut());
+        secrets
+    }
+
+    fn as_bytes_mut(&mut self) -> &mut [u8] {
+        // Both keys are plain arrays of bytes
+        unsafe {
+            std::slice::from_raw_parts_mut(
+                self as *mut Self as *mut u8,
+                std::mem::size_of::<Self>(),
+            )
+        }
+    }
+}
+
+/// Our private keys, along with what we derive from them. Pass it around rather than the bare
+/// secrets: they are kept in memory locked in RAM, and wiped when it's dropped. It's purposely
+/// not `Clone` so there is a single copy of them, share it by reference (or `Arc` across
+/// threads).
+pub struct Keys {
+    secrets: Box<LockedSecrets>,
+    // Derived on first use, and then kept around
+    bitcoin_pubkey: OnceLock<BitcoinPubKey>,
+}
 
-    let mut bitcoin_secret_fd = fs::File::open(secret_file).map_err(KeyError::BitcoinRead)?;
-    bitcoin_secret_fd
-        .read_exact(&mut buf)
-        .map_err(KeyError::BitcoinRead)?;
+impl Keys {
+    pub fn new(noise_privkey: NoisePrivKey, bitcoin_privkey: BitcoinPrivKey) -> Self {
+        let mut secrets = LockedSecrets::new();
+        secrets.noise_privkey.0 = noise_privkey.0;
+        secrets.bitcoin_privkey = bitcoin_privkey;
+        Self {
+            secrets,
+            bitcoin_pubkey: OnceLock::new(),
+        }
+    }
+
+    /// Read our keys from these files, creating the Noise one if necessary. See
+    /// [read_or_create_noise_key] and [read_bitcoin_privkey].
+    pub fn from_files(
+        noise_secret_file: &PathBuf,
+        bitcoin_secret_file: &Path,
+        passphrase: Option<&[u8]>,
+        network: Option<Network>,
+    ) -> Result<Self, KeyError> {
+        let mut secrets = LockedSecrets::new();
+        read_or_create_noise_key_into(noise_secret_file, &mut secrets.noise_privkey)?;
+        let mut buf = SecretBuf::new(32, 0xff);
+        secrets.bitcoin_privkey =
+            read_bitcoin_privkey_into(bitcoin_secret_file, passphrase, network, &mut buf)?;
+        Ok(Self {
+            secrets,
+            bitcoin_pubkey: OnceLock::new(),
+        })
+    }
+
+    pub fn noise_privkey(&self) -> &NoisePrivKey {
+        &self.secrets.noise_privkey
+    }
+
+    /// The public key of our Noise private key, as the managers know us by
+    pub fn noise_pubkey(&self) -> NoisePubKey {
+        NoisePubKey(
+            curve25519::scalarmult_base(&curve25519::Scalar(self.secrets.noise_privkey.0)).0,
+        )
+    }
+
+    pub fn bitcoin_privkey(&self) -> &BitcoinPrivKey {
+        &self.secrets.bitcoin_privkey
+    }
+
+    /// The secp context to use along with our keys, see [secp_ctx]
+    pub fn secp(&self) -> &secp256k1::Secp256k1<secp256k1::All> {
+        secp_ctx()
+    }
+
+    /// The public key of our Bitcoin private key, see [bitcoin_pubkey]
+    pub fn bitcoin_pubkey(&self) -> &BitcoinPubKey {
+        self.bitcoin_pubkey
+            .get_or_init(|| bitcoin_pubkey(self.secp(), self.bitcoin_privkey()))
+    }
+
+    /// Lock our secrets in RAM again. Memory locks are not inherited across `fork()`, so this
+    /// must be called once daemonized.
+    pub fn relock_memory(&mut self) {
+        lock_memory(self.secrets.as_bytes_mut());
+    }
+
+    fn wipe(&mut self) {
+        sodiumoxide::utils::memzero(self.secrets.as_bytes_mut());
+    }
+}
+
+impl Drop for Keys {
+    fn drop(&mut self) {
+        self.wipe();
+        // Unlocking is best effort, and the memory is about to be freed anyways.
+        let _ = sodiumoxide::utils::munlock(self.secrets.as_bytes_mut());
+    }
+}
+
+// Never print our secrets
+impl std::fmt::Debug for Keys {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("Keys")
+            .field("bitcoin_pubkey", &self.bitcoin_pubkey().to_string())
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::logs::{capture_logs, logged};
+    use std::{os::unix::fs::DirBuilderExt, path::PathBuf};
+
+    fn tmp_key_file(name: &str, content: &[u8]) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("cosignerd-keys-{}-{}", name, std::process::id()));
+        let _ = fs::remove_file(&path);
+        fs::write(&path, content).unwrap();
+        path
+    }
+
+    #[test]
+    fn bitcoin_key_buffer_wiped() {
+        let secret = [0x42; 32];
+        let key_path = tmp_key_file("valid", &secret);
+
+        // The key is read correctly, and the intermediate buffer is wiped
+        let mut buf = SecretBuf::new(32, 0xff);
+        let privkey = read_bitcoin_privkey_into(&key_path, None, None, &mut buf).unwrap();
+        assert_eq!(privkey, BitcoinPrivKey::from_slice(&secret).unwrap());
+        assert_eq!(*buf.0, [0; 32]);
+        assert_eq!(
+            read_bitcoin_privkey(&key_path, None, None).unwrap(),
+            privkey
+        );
+
+        // Same on the error paths: an invalid key..
+        let invalid_path = tmp_key_file("invalid", &[0xff; 32]);
+        let mut buf = SecretBuf::new(32, 0xff);
+        assert!(matches!(
+            read_bitcoin_privkey_into(&invalid_path, None, None, &mut buf),
+            Err(KeyError::BitcoinVerify(..))
+        ));
+        assert_eq!(*buf.0, [0; 32]);
+
+        // .. or a truncated file.
+        let short_path = tmp_key_file("short", &secret[..16]);
+        let mut buf = SecretBuf::new(32, 0xff);
+        assert!(matches!(
+            read_bitcoin_privkey_into(&short_path, None, None, &mut buf),
+            Err(KeyError::BitcoinRead(..))
+        ));
+        assert_eq!(*buf.0, [0; 32]);
+
+        // .. or an encrypted one, which is decrypted in locked memory.
+        let passphrase = b"correct horse battery staple";
+        let encrypted = encrypt_bitcoin_privkey(&privkey, passphrase).unwrap();
+        let enc_path = tmp_key_file("valid-encrypted", &encrypted);
+        let mut buf = SecretBuf::new(32, 0xff);
+        assert_eq!(
+            read_bitcoin_privkey_into(&enc_path, Some(passphrase), None, &mut buf).unwrap(),
+            privkey
+        );
+        assert_eq!(*buf.0, [0; 32]);
+        let (file_buf, len) = SecretBuf::read_file(&enc_path, MAX_KEY_FILE_SIZE).unwrap();
+        assert_eq!(&file_buf.0[..len], &encrypted[..]);
+
+        for path in &[key_path, invalid_path, short_path, enc_path] {
+            fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[test]
+    fn encrypted_bitcoin_key() {
+        let privkey = BitcoinPrivKey::from_slice(&[0x42; 32]).unwrap();
+        let passphrase = b"correct horse battery staple";
+
+        // A plaintext key is still read as-is, with or without passphrase
+        let plain_path = tmp_key_file("plain", &privkey[..]);
+        assert_eq!(
+            read_bitcoin_privkey(&plain_path, None, None).unwrap(),
+            privkey
+        );
+        assert_eq!(
+            read_bitcoin_privkey(&plain_path, Some(passphrase), None).unwrap(),
+            privkey
+        );
+
+        // An encrypted one round-trips with the right passphrase
+        let encrypted = encrypt_bitcoin_privkey(&privkey, passphrase).unwrap();
+        assert!(encrypted.starts_with(ENCRYPTED_KEY_MAGIC));
+        assert!(!encrypted.windows(privkey.len()).any(|w| w == &privkey[..]));
+        let enc_path = tmp_key_file("encrypted", &encrypted);
+        assert_eq!(
+            read_bitcoin_privkey(&enc_path, Some(passphrase), None).unwrap(),
+            privkey
+        );
+
+        // But not without a passphrase, with a wrong one or if it was tampered with
+        assert!(matches!(
+            read_bitcoin_privkey(&enc_path, None, None),
+            Err(KeyError::MissingPassphrase)
+        ));
+        assert!(matches!(
+            read_bitcoin_privkey(&enc_path, Some(b"wrong"), None),
+            Err(KeyError::BitcoinDecrypt)
+        ));
+        let mut tampered = encrypted.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        let tampered_path = tmp_key_file("tampered", &tampered);
+        assert!(matches!(
+            read_bitcoin_privkey(&tampered_path, Some(passphrase), None),
+            Err(KeyError::BitcoinDecrypt)
+        ));
+        let truncated_path = tmp_key_file("truncated", &encrypted[..encrypted.len() - 1]);
+        assert!(matches!(
+            read_bitcoin_privkey(&truncated_path, Some(passphrase), None),
+            Err(KeyError::BitcoinDecrypt)
+        ));
 
-    // FIXME: buf zeroization, mlock of the key, decent mem management
-    BitcoinPrivKey::from_slice(&buf).map_err(KeyError::BitcoinVerify)
+        // The passphrase file may have a trailing newline
+        let passphrase_path = tmp_key_file("passphrase", b"correct horse battery staple\n");
+        let read_passphrase = read_key_passphrase(Some(&passphrase_path))
+            .unwrap()
+            .unwrap();
+        assert_eq!(&read_passphrase[..], passphrase);
+        // But not be absurdly long
+        let long_passphrase_path = tmp_key_file("long-passphrase", &[b'a'; MAX_PASSPHRASE_LEN + 1]);
+        assert!(matches!(
+            read_key_passphrase(Some(&long_passphrase_path)),
+            Err(KeyError::Passphrase(_))
+        ));
+        let max_passphrase_path = tmp_key_file("max-passphrase", &[b'a'; MAX_PASSPHRASE_LEN]);
+        assert_eq!(
+            read_key_passphrase(Some(&max_passphrase_path))
+                .unwrap()
+                .unwrap()
+                .len(),
+            MAX_PASSPHRASE_LEN
+        );
+
+        // The header is authenticated, and we won't use absurd Argon2id limits
+        let mut tampered_limits = encrypted.clone();
+        tampered_limits[ENCRYPTED_KEY_MAGIC.len()] ^= 1;
+        let tampered_limits_path = tmp_key_file("tampered-limits", &tampered_limits);
+        assert!(matches!(
+            read_bitcoin_privkey(&tampered_limits_path, Some(passphrase), None),
+            Err(KeyError::BitcoinDecrypt)
+        ));
+        let opslimit_start = ENCRYPTED_KEY_MAGIC.len();
+        assert_eq!(
+            &encrypted[opslimit_start..opslimit_start + 8],
+            &(KEY_OPSLIMIT.0 as u64).to_le_bytes()
+        );
+        let mut limits = [0; 16];
+        limits[..8].copy_from_slice(&1u64.to_le_bytes());
+        limits[8..].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert!(key_limits(&limits).is_none());
+        limits[8..].copy_from_slice(&(KEY_MEMLIMIT.0 as u64).to_le_bytes());
+        assert!(key_limits(&limits).is_some());
+
+        for path in &[
+            plain_path,
+            enc_path,
+            tampered_path,
+            truncated_path,
+            passphrase_path,
+            long_passphrase_path,
+            max_passphrase_path,
+            tampered_limits_path,
+        ] {
+            fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[test]
+    fn passphrase_from_env() {
+        // Only read from there when there is no passphrase file, and removed once read
+        env::set_var(PASSPHRASE_ENV_VAR, "correct horse battery staple\r\n");
+        let passphrase = read_key_passphrase(None).unwrap().unwrap();
+        assert_eq!(&passphrase[..], b"correct horse battery staple");
+        assert!(env::var_os(PASSPHRASE_ENV_VAR).is_none());
+        assert!(read_key_passphrase(None).unwrap().is_none());
+    }
+
+    #[test]
+    fn wif_bitcoin_key() {
+        let privkey = BitcoinPrivKey::from_slice(&[0x42; 32]).unwrap();
+        let wif_compressed = |network, compressed| {
+            WifPrivKey {
+                compressed,
+                network,
+                key: privkey,
+            }
+            .to_wif()
+        };
+        let wif = |network| wif_compressed(network, true);
+
+        // The raw key is still read as-is
+        let raw_path = tmp_key_file("wif-raw", &privkey[..]);
+        assert_eq!(
+            read_bitcoin_privkey(&raw_path, None, Some(Network::Bitcoin)).unwrap(),
+            privkey
+        );
+
+        // A WIF one, with or without a newline
+        let mainnet_path = tmp_key_file("wif-mainnet", wif(Network::Bitcoin).as_bytes());
+        assert_eq!(
+            read_bitcoin_privkey(&mainnet_path, None, Some(Network::Bitcoin)).unwrap(),
+            privkey
+        );
+        let testnet_path = tmp_key_file(
+            "wif-testnet",
+            format!("{}\n", wif(Network::Testnet)).as_bytes(),
+        );
+        for network in &[Network::Testnet, Network::Regtest, Network::Signet] {
+            assert_eq!(
+                read_bitcoin_privkey(&testnet_path, None, Some(*network)).unwrap(),
+                privkey
+            );
+        }
+
+        // But it must be for our network
+        assert!(matches!(
+            read_bitcoin_privkey(&testnet_path, None, Some(Network::Bitcoin)),
+            Err(KeyError::BitcoinNetwork(Network::Testnet, Network::Bitcoin))
+        ));
+        assert!(matches!(
+            read_bitcoin_privkey(&mainnet_path, None, Some(Network::Regtest)),
+            Err(KeyError::BitcoinNetwork(Network::Bitcoin, Network::Regtest))
+        ));
+        assert_eq!(
+            read_bitcoin_privkey(&testnet_path, None, None).unwrap(),
+            privkey
+        );
+
+        // And a typo isn't silently read as a raw key
+        let mut typo = wif(Network::Bitcoin).into_bytes();
+        typo[10] = if typo[10] == b'A' { b'B' } else { b'A' };
+        let typo_path = tmp_key_file("wif-typo", &typo);
+        assert!(matches!(
+            read_bitcoin_privkey(&typo_path, None, Some(Network::Bitcoin)),
+            Err(KeyError::BitcoinWif(..))
+        ));
+
+        // Nor is a key for an uncompressed public key, which we'd never use in our Scripts
+        let uncompressed_path = tmp_key_file(
+            "wif-uncompressed",
+            wif_compressed(Network::Bitcoin, false).as_bytes(),
+        );
+        assert!(matches!(
+            read_bitcoin_privkey(&uncompressed_path, None, Some(Network::Bitcoin)),
+            Err(KeyError::BitcoinUncompressed)
+        ));
+
+        for path in &[
+            raw_path,
+            mainnet_path,
+            testnet_path,
+            typo_path,
+            uncompressed_path,
+        ] {
+            fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[test]
+    fn bitcoin_pubkey_derivation() {
+        let secp = secp256k1::Secp256k1::new();
+        let privkey = BitcoinPrivKey::from_slice(&[0x42; 32]).unwrap();
+        let fresh = WifPrivKey {
+            compressed: true,
+            network: Network::Bitcoin,
+            key: privkey,
+        }
+        .public_key(&secp);
+
+        assert_eq!(bitcoin_pubkey(&secp, &privkey), fresh);
+        assert_eq!(
+            bitcoin_pubkey(&secp256k1::Secp256k1::signing_only(), &privkey),
+            fresh
+        );
+        assert!(bitcoin_pubkey(&secp, &privkey).compressed);
+    }
+
+    #[test]
+    fn missing_bitcoin_key() {
+        let path = PathBuf::from("/nonexistent/cosignerd/bitcoin_secret");
+        let err = read_bitcoin_privkey(&path, None, None).unwrap_err();
+        assert!(matches!(err, KeyError::MissingBitcoinKey(ref p) if p == &path));
+        assert_eq!(
+            err.to_string(),
+            "No Bitcoin key file at '/nonexistent/cosignerd/bitcoin_secret': place the private key \
+             generated during the setup Ceremony there"
+        );
+    }
+
+    #[test]
+    fn loose_permissions() {
+        capture_logs();
+        let data_dir =
+            std::env::temp_dir().join(format!("cosignerd-permissions-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&data_dir);
+        fs::DirBuilder::new()
+            .mode(DATADIR_MODE)
+            .create(&data_dir)
+            .unwrap();
+        let bitcoin_key_path = data_dir.join("bitcoin_secret");
+        fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(KEY_FILE_MODE)
+            .open(&bitcoin_key_path)
+            .unwrap()
+            .write_all(&[0x42; 32])
+            .unwrap();
+
+        // All good, and a missing Noise key is not our concern
+        check_permissions(&data_dir, true).unwrap();
+
+        // A world-readable key gets a warning, or can't be used at all if strict
+        fs::set_permissions(&bitcoin_key_path, fs::Permissions::from_mode(0o644)).unwrap();
+        check_permissions(&data_dir, false).unwrap();
+        assert!(logged(&[
+            "INSECURE PERMISSIONS",
+            &format!("'{}' has permissions 644", bitcoin_key_path.display())
+        ]));
+        let err = check_permissions(&data_dir, true).unwrap_err();
+        assert!(matches!(
+            err,
+            KeyError::LoosePermissions(ref p, 0o644, KEY_FILE_MODE) if p == &bitcoin_key_path
+        ));
+
+        // Same for the data directory
+        fs::set_permissions(&bitcoin_key_path, fs::Permissions::from_mode(KEY_FILE_MODE)).unwrap();
+        fs::set_permissions(&data_dir, fs::Permissions::from_mode(0o750)).unwrap();
+        let err = check_permissions(&data_dir, true).unwrap_err();
+        assert!(matches!(
+            err,
+            KeyError::LoosePermissions(ref p, 0o750, DATADIR_MODE) if p == &data_dir
+        ));
+
+        fs::remove_dir_all(&data_dir).unwrap();
+    }
+
+    #[test]
+    fn keys_from_files() {
+        let data_dir =
+            std::env::temp_dir().join(format!("cosignerd-keys-struct-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&data_dir);
+        fs::create_dir(&data_dir).unwrap();
+        let (noise_path, bitcoin_path) = (
+            data_dir.join("noise_secret"),
+            data_dir.join("bitcoin_secret"),
+        );
+        let privkey = BitcoinPrivKey::from_slice(&[0x42; 32]).unwrap();
+        let passphrase = b"correct horse battery staple";
+        fs::write(
+            &bitcoin_path,
+            encrypt_bitcoin_privkey(&privkey, passphrase).unwrap(),
+        )
+        .unwrap();
+
+        // The Noise key gets created, and the Bitcoin one read as usual
+        let keys = Keys::from_files(&noise_path, &bitcoin_path, Some(passphrase), None).unwrap();
+        assert_eq!(keys.bitcoin_privkey(), &privkey);
+        assert_eq!(
+            keys.noise_privkey(),
+            &read_or_create_noise_key(&noise_path).unwrap()
+        );
+        assert_eq!(
+            keys.bitcoin_pubkey(),
+            &bitcoin_pubkey(&secp256k1::Secp256k1::new(), &privkey)
+        );
+        // We never print the secrets
+        let debug = format!("{:?}", keys);
+        assert!(debug.contains(&keys.bitcoin_pubkey().to_string()));
+        assert!(!debug.contains(&privkey.to_string()));
+
+        // Errors are those of the key readers
+        assert!(matches!(
+            Keys::from_files(&noise_path, &bitcoin_path, None, None),
+            Err(KeyError::MissingPassphrase)
+        ));
+        assert!(matches!(
+            Keys::from_files(&noise_path, &data_dir.join("nonexistent"), None, None),
+            Err(KeyError::MissingBitcoinKey(..))
+        ));
+
+        // The Bitcoin key is wiped with the keys
+        let mut keys = keys;
+        keys.wipe();
+        assert_eq!(keys.bitcoin_privkey()[..], [0; 32]);
+        assert_eq!(keys.noise_privkey().0, [0; 32]);
+
+        fs::remove_dir_all(&data_dir).unwrap();
+    }
 }
@@ -7,12 +7,30 @@ pub mod database;
 /// Protocol message processing, we only have to handle a single message.
 pub mod processing;
 
+/// The loop accepting connections from the managers, and a handle to run it in a thread
+pub mod daemon;
+
 /// Unix daemon creation routine
 pub mod daemonize;
 
 /// Noise and Bitcoin key files handling
 pub mod keys;
 
+/// A single error type gathering the ones of the modules above
+pub mod error;
+
+/// The append-only log of security-relevant events
+pub mod audit;
+
+/// Counters pushed to a statsd collector or scraped by Prometheus
+pub mod metrics;
+
+/// A plaintext health check endpoint
+pub mod health;
+
+/// An end-to-end check of the signing pipeline on this host
+pub mod selftest;
+
 #[cfg(any(test, feature = "fuzztesting"))]
 pub mod tests;
 
@@ -0,0 +1,311 @@
+//! Counters of the requests we process, pushed to a statsd collector as they happen or scraped
+//! in the Prometheus text format. Metrics are best effort: failing to send one is never fatal.
+
+use crate::database::db_count_signed_outpoints;
+
+use std::{
+    collections::BTreeMap,
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream, UdpSocket},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    thread, time,
+};
+
+/// An event we count
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Metric {
+    /// We received a request from a manager
+    Request,
+    /// We shared signatures for a Spend
+    Signed,
+    /// We refused to share signatures for a Spend
+    Refused,
+    /// A connection failed to complete the Noise handshake
+    HandshakeFailure,
+}
+
+impl Metric {
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Request => "cosignerd.requests",
+            Self::Signed => "cosignerd.signed",
+            Self::Refused => "cosignerd.refused",
+            Self::HandshakeFailure => "cosignerd.handshake_failures",
+        }
+    }
+
+    /// The statsd packet incrementing this counter
+    pub fn statsd_packet(&self) -> String {
+        format!("{}:1|c", self.name())
+    }
+}
+
+/// Sends counters increments to a statsd collector over UDP
+#[derive(Debug)]
+pub struct StatsdClient {
+    socket: UdpSocket,
+}
+
+impl StatsdClient {
+    pub fn new(statsd_addr: SocketAddr) -> Result<Self, io::Error> {
+        let local_addr = if statsd_addr.is_ipv4() {
+            SocketAddr::from(([0, 0, 0, 0], 0))
+        } else {
+            SocketAddr::from(([0u16; 8], 0))
+        };
+        let socket = UdpSocket::bind(local_addr)?;
+        socket.connect(statsd_addr)?;
+
+        Ok(Self { socket })
+    }
+
+    /// Increment this counter. Errors are logged and otherwise ignored.
+    pub fn increment(&self, metric: Metric) {
+        if let Err(e) = self.socket.send(metric.statsd_packet().as_bytes()) {
+            log::warn!(
+                "Error sending '{}' metric to statsd: '{}'",
+                metric.name(),
+                e
+            );
+        }
+    }
+}
+
+/// The counters since we started, as exposed to Prometheus
+#[derive(Debug)]
+pub struct PrometheusMetrics {
+    requests: AtomicU64,
+    signed: AtomicU64,
+    // By handshake failure reason code
+    handshake_failures: Mutex<BTreeMap<&'static str, u64>>,
+    // By refusal reason code
+    refused: Mutex<BTreeMap<&'static str, u64>>,
+}
+
+/// The counters of this process. They are global as they are about the process as a whole.
+pub static PROMETHEUS_METRICS: PrometheusMetrics = PrometheusMetrics::new();
+
+impl PrometheusMetrics {
+    const fn new() -> Self {
+        Self {
+            requests: AtomicU64::new(0),
+            signed: AtomicU64::new(0),
+            handshake_failures: Mutex::new(BTreeMap::new()),
+            refused: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Increment this counter. Refusals and handshake failures are counted by reason with
+    /// [Self::increment_refused] and [Self::increment_handshake_failure].
+    pub fn increment(&self, metric: Metric) {
+        let counter = match metric {
+            Metric::Request => &self.requests,
+            Metric::Signed => &self.signed,
+            Metric::HandshakeFailure => return self.increment_handshake_failure("unknown"),
+            Metric::Refused => return self.increment_refused("unknown"),
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a refusal with this reason code
+    pub fn increment_refused(&self, reason: &'static str) {
+        let mut refused = self.refused.lock().unwrap_or_else(|e| e.into_inner());
+        *refused.entry(reason).or_insert(0) += 1;
+    }
+
+    /// Count a handshake failure with this reason code
+    pub fn increment_handshake_failure(&self, reason: &'static str) {
+        let mut failures = self
+            .handshake_failures
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        *failures.entry(reason).or_insert(0) += 1;
+    }
+
+    // The values of a counter by reason code, as labels
+    fn by_reason(counter: &Mutex<BTreeMap<&'static str, u64>>) -> Vec<(String, u64)> {
+        counter
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .iter()
+            .map(|(reason, count)| (format!("{{reason=\"{}\"}}", reason), *count))
+            .collect()
+    }
+
+    /// The counters in the Prometheus text exposition format, along with the number of signed
+    /// outpoints if known.
+    pub fn render(&self, signed_outpoints: Option<u64>) -> String {
+        let mut res = String::new();
+        let mut counter = |name: &str, help: &str, values: &[(String, u64)]| {
+            res.push_str(&format!(
+                "# HELP {} {}\n# TYPE {} counter\n",
+                name, help, name
+            ));
+            for (labels, value) in values {
+                res.push_str(&format!("{}{} {}\n", name, labels, value));
+            }
+        };
+
+        counter(
+            "cosignerd_requests_total",
+            "Requests received from the managers.",
+            &[(String::new(), self.requests.load(Ordering::Relaxed))],
+        );
+        counter(
+            "cosignerd_spends_signed_total",
+            "Spends we shared our signatures for.",
+            &[(String::new(), self.signed.load(Ordering::Relaxed))],
+        );
+        counter(
+            "cosignerd_spends_refused_total",
+            "Spends we refused to sign, by reason.",
+            &Self::by_reason(&self.refused),
+        );
+        counter(
+            "cosignerd_handshake_failures_total",
+            "Connections which failed the Noise handshake, by reason.",
+            &Self::by_reason(&self.handshake_failures),
+        );
+
+        if let Some(count) = signed_outpoints {
+            res.push_str(&format!(
+                "# HELP cosignerd_signed_outpoints Outpoints in our anti-replay database.\n\
+                 # TYPE cosignerd_signed_outpoints gauge\n\
+                 cosignerd_signed_outpoints {}\n",
+                count
+            ));
+        }
+
+        res
+    }
+}
+
+// Answer a single HTTP request on this connection, then close it
+fn answer_scrape(
+    connection: TcpStream,
+    db_path: &PathBuf,
+    timeout: time::Duration,
+) -> Result<(), io::Error> {
+    connection.set_read_timeout(Some(timeout))?;
+    connection.set_write_timeout(Some(timeout))?;
+
+    // We only care about the request line, but read the headers to not reset the connection on
+    // the client while it's still sending. Don't let it make us buffer unbounded lines though.
+    let mut reader = BufReader::new((&connection).take(8192));
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 0 && !header.trim_end().is_empty() {
+        header.clear();
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => {
+            let signed_outpoints = db_count_signed_outpoints(db_path)
+                .map_err(|e| log::error!("Error counting signed outpoints: '{}'", e))
+                .ok();
+            ("200 OK", PROMETHEUS_METRICS.render(signed_outpoints))
+        }
+        _ => ("404 Not Found", "Not found\n".to_string()),
+    };
+    write!(
+        &connection,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\n\
+         Connection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )
+}
+
+/// Serve `GET /metrics` on this listener, in a new thread. The connections are dropped after
+/// `timeout` of inactivity.
+pub fn start_metrics_endpoint(
+    listener: TcpListener,
+    db_path: PathBuf,
+    timeout: time::Duration,
+) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        for connection in listener.incoming() {
+            match connection {
+                Ok(connection) => {
+                    if let Err(e) = answer_scrape(connection, &db_path, timeout) {
+                        log::debug!("Error answering metrics scrape: '{}'", e);
+                    }
+                }
+                Err(e) => log::error!("Accepting metrics connection: '{}'", e),
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::builder::CosignerTestBuilder;
+    use std::time::Duration;
+
+    fn scrape(addr: SocketAddr, path: &str) -> String {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn prometheus_endpoint() {
+        let test_framework = CosignerTestBuilder::new(1);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        start_metrics_endpoint(
+            listener,
+            test_framework.config.db_file(),
+            Duration::from_secs(5),
+        );
+
+        PROMETHEUS_METRICS.increment(Metric::Request);
+        PROMETHEUS_METRICS.increment_handshake_failure("malformed");
+        PROMETHEUS_METRICS.increment_refused("stale_locktime");
+        let response = scrape(addr, "/metrics");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        for name in &[
+            "# TYPE cosignerd_requests_total counter\ncosignerd_requests_total ",
+            "# TYPE cosignerd_spends_signed_total counter\ncosignerd_spends_signed_total ",
+            "cosignerd_spends_refused_total{reason=\"stale_locktime\"} ",
+            "# TYPE cosignerd_handshake_failures_total counter\n",
+            "cosignerd_handshake_failures_total{reason=\"malformed\"} ",
+            "# TYPE cosignerd_signed_outpoints gauge\ncosignerd_signed_outpoints 0\n",
+        ] {
+            assert!(response.contains(name), "{} not in {}", name, response);
+        }
+
+        assert!(scrape(addr, "/").starts_with("HTTP/1.1 404 Not Found\r\n"));
+    }
+
+    #[test]
+    fn statsd_packets() {
+        let collector = UdpSocket::bind("127.0.0.1:0").unwrap();
+        collector
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let client = StatsdClient::new(collector.local_addr().unwrap()).unwrap();
+
+        let mut buf = [0; 64];
+        for (metric, packet) in &[
+            (Metric::Request, "cosignerd.requests:1|c"),
+            (Metric::Signed, "cosignerd.signed:1|c"),
+            (Metric::Refused, "cosignerd.refused:1|c"),
+            (Metric::HandshakeFailure, "cosignerd.handshake_failures:1|c"),
+        ] {
+            client.increment(*metric);
+            let size = collector.recv(&mut buf).unwrap();
+            assert_eq!(&buf[..size], packet.as_bytes());
+        }
+    }
+}
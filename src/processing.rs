@@ -1,22 +1,64 @@
 use crate::{
     config::Config,
-    database::{db_insert_signed_outpoint, db_signed_outpoint, DatabaseError},
+    database::{
+        cache::SIGNATURE_CACHE, db_fill_reserved_outpoints, db_first_seen_outputs,
+        db_record_first_seen_outputs, db_reserve_outpoints, db_signature_cached,
+        db_signed_outpoint, DatabaseError, ReservationOutcome,
+    },
+    keys::Keys,
 };
 
-use revault_net::message::cosigner::{SignRequest, SignResult};
+use revault_net::{
+    message::cosigner::{SignRequest, SignResult},
+    noise::PublicKey as NoisePubkey,
+};
 use revault_tx::{
-    bitcoin::{secp256k1, util::bip143::SigHashCache, PublicKey as BitcoinPubkey},
+    bitcoin::{
+        blockdata::{
+            opcodes,
+            script::{read_scriptint, Instruction, Script},
+        },
+        consensus::encode,
+        hashes::{sha256, Hash, HashEngine},
+        secp256k1,
+        util::{
+            bip143::SigHashCache,
+            bip32,
+            psbt::{Input as PsbtIn, Output as PsbtOut, PartiallySignedTransaction as Psbt},
+        },
+        OutPoint, PublicKey as BitcoinPubkey, SigHashType, TxOut,
+    },
     error::InputSatisfactionError,
-    transactions::RevaultTransaction,
+    miniscript::{descriptor::DescriptorPublicKey, DescriptorTrait},
+    scripts::{CpfpDescriptor, UnvaultDescriptor},
+    transactions::{CpfpableTransaction, RevaultTransaction, SpendTransaction, TX_VERSION},
 };
 
+use std::{collections::HashSet, convert::TryFrom, path::PathBuf, time};
+
+// Below this value, nLockTime is interpreted as a block height
+const LOCKTIME_THRESHOLD: u32 = 500_000_000;
+
+// An input signals for replaceability if its nSequence is below this value (BIP125)
+const RBF_SEQUENCE_THRESHOLD: u32 = 0xffff_fffe;
+
 #[derive(Debug)]
 pub enum SignProcessingError {
     Database(DatabaseError),
     // They sent us an insane transaction. FIXME: these checks should be part of revault_tx!
     Garbage,
     // FIXME: we should upstream the iteration over inputs as we can safely panic there.
-    InsanePsbtMissingInput(InputSatisfactionError),
+    InsanePsbtMissingInput(Box<InputSatisfactionError>),
+    // We never signed this outpoint
+    NotSigned(OutPoint),
+    // The signature we stored is not valid for this transaction
+    SignatureMismatch,
+    // It has more inputs than we accept (number of inputs, maximum)
+    TooManyInputs(usize, usize),
+    // This input doesn't pay to a Script of our vaults (index of the input)
+    ForeignScript(usize),
+    // This input carries a signature for our key already, that we did not make (index of the input)
+    UnexpectedSignature(usize),
 }
 
 impl std::fmt::Display for SignProcessingError {
@@ -25,116 +67,1022 @@ impl std::fmt::Display for SignProcessingError {
             Self::Database(e) => write!(f, "{}", e),
             Self::Garbage => write!(f, "We were sent an insane Spend transaction"),
             Self::InsanePsbtMissingInput(e) => write!(f, "{}", e),
+            Self::NotSigned(o) => write!(f, "Outpoint '{}' was never signed", o),
+            Self::SignatureMismatch => {
+                write!(f, "Stored signature is not valid for this transaction")
+            }
+            Self::TooManyInputs(n, max) => write!(
+                f,
+                "Spend transaction has {} inputs, but we accept at most {}",
+                n, max
+            ),
+            Self::ForeignScript(i) => write!(
+                f,
+                "Input {} doesn't pay to a Script with our key and the managers' ones",
+                i
+            ),
+            Self::UnexpectedSignature(i) => write!(
+                f,
+                "Input {} already has a signature for our key that we never made",
+                i
+            ),
         }
     }
 }
 
 impl std::error::Error for SignProcessingError {}
 
-fn null_signature() -> SignResult {
-    SignResult { tx: None }
+/// Why we refused to share signatures for a Spend. The `sign` response can't carry it, so the
+/// manager only gets a null signature.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SignRefusal {
+    /// It's finalized already, there is nothing left to sign
+    Finalized,
+    /// It spends these blacklisted outpoints
+    BlacklistedOutpoints(Vec<OutPoint>),
+    /// It spends these outpoints, which are not in the allowlist
+    NotAllowedOutpoints(Vec<OutPoint>),
+    /// It pays to an address which is not in the allowlist
+    NotAllowedDestinations,
+    /// It spends these outpoints with another relative timelock than the one of our vaults
+    CsvMismatch(Vec<OutPoint>),
+    /// Its timestamp nLockTime is too old
+    StaleLocktime,
+    /// An input doesn't signal for replaceability
+    NoRbf,
+    /// Its feerate is below the configured minimum
+    FeerateTooLow,
+    /// Its feerate is above the configured maximum
+    FeerateTooHigh,
+    /// An input doesn't have enough valid managers signatures
+    MissingManagerSigs,
+    /// An input doesn't carry a valid signature of the manager which sent it
+    MissingSenderSig,
+    /// We signed all these outpoints already, but for another Spend
+    ModifiedSpend(Vec<OutPoint>),
+    /// We signed some of these outpoints already
+    AlreadySigned(Vec<OutPoint>),
+    /// These outpoints were signed by a concurrent request
+    ConcurrentlySigned(Vec<OutPoint>),
+    /// We would have signed it, but we are a read-only standby
+    ReadOnly,
+    /// We couldn't process the request at all (insane Spend, database error, ..)
+    ProcessingError,
+}
+
+impl SignRefusal {
+    /// A stable identifier for this reason
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::Finalized => "finalized",
+            Self::BlacklistedOutpoints(_) => "blacklisted_outpoints",
+            Self::NotAllowedOutpoints(_) => "not_allowed_outpoints",
+            Self::NotAllowedDestinations => "not_allowed_destinations",
+            Self::CsvMismatch(_) => "csv_mismatch",
+            Self::StaleLocktime => "stale_locktime",
+            Self::NoRbf => "no_rbf",
+            Self::FeerateTooLow => "feerate_too_low",
+            Self::FeerateTooHigh => "feerate_too_high",
+            Self::MissingManagerSigs => "missing_manager_sigs",
+            Self::MissingSenderSig => "missing_sender_sig",
+            Self::ModifiedSpend(_) => "modified_spend",
+            Self::AlreadySigned(_) => "already_signed",
+            Self::ConcurrentlySigned(_) => "concurrently_signed",
+            Self::ReadOnly => "read_only",
+            Self::ProcessingError => "processing_error",
+        }
+    }
+
+    /// The outpoints that caused the refusal, if it's specific to some of them
+    pub fn offending_outpoints(&self) -> &[OutPoint] {
+        match self {
+            Self::BlacklistedOutpoints(outpoints)
+            | Self::NotAllowedOutpoints(outpoints)
+            | Self::CsvMismatch(outpoints)
+            | Self::ModifiedSpend(outpoints)
+            | Self::AlreadySigned(outpoints)
+            | Self::ConcurrentlySigned(outpoints) => outpoints,
+            _ => &[],
+        }
+    }
+}
+
+/// What we decided to do with a sign request
+#[derive(Debug)]
+pub enum SignDecision {
+    /// We signed (or had signed) all its inputs, here is the Spend with our signatures
+    Signed(SpendTransaction),
+    /// We signed these outpoints already but not all of them, here is the Spend with only our
+    /// stored signatures for them. We refused to sign the others, as per `return_known_sigs`.
+    KnownSignatures(SpendTransaction, Vec<OutPoint>),
+    Refused(SignRefusal),
+}
+
+// Whether this PSBT contains global or input fields outside of what a Revault Spend needs. The
+// revault_tx sanity checks already take care of non_witness_utxo and redeem_script.
+fn has_unexpected_fields(psbt: &Psbt) -> bool {
+    let global = &psbt.global;
+    if !global.xpub.is_empty() || !global.proprietary.is_empty() || !global.unknown.is_empty() {
+        return true;
+    }
+
+    psbt.inputs.iter().any(|psbtin| {
+        psbtin.final_script_sig.is_some()
+            || !psbtin.ripemd160_preimages.is_empty()
+            || !psbtin.sha256_preimages.is_empty()
+            || !psbtin.hash160_preimages.is_empty()
+            || !psbtin.hash256_preimages.is_empty()
+            || !psbtin.proprietary.is_empty()
+            || !psbtin.unknown.is_empty()
+    })
+}
+
+/// Whether this nLockTime is older than `max_age` seconds at time `now`. We don't have a view of
+/// the chain, so only timestamp locktimes are checked: block height ones are never stale.
+pub fn locktime_is_stale(lock_time: u32, max_age: u64, now: u64) -> bool {
+    if lock_time < LOCKTIME_THRESHOLD {
+        return false;
+    }
+
+    now.saturating_sub(lock_time as u64) > max_age
+}
+
+/// How far in the future of our clock a timestamp nLockTime may be, for clocks drift.
+pub const LOCKTIME_FUTURE_TOLERANCE: u64 = 2 * 3_600;
+
+/// Whether this nLockTime could be that of a Spend the managers are about to broadcast at time
+/// `now`. Such a Spend could not be mined before a timestamp nLockTime, so one in the future
+/// can't be. As for staleness, block height ones are never checked.
+pub fn locktime_is_sane(lock_time: u32, now: u64) -> bool {
+    lock_time < LOCKTIME_THRESHOLD || lock_time as u64 <= now + LOCKTIME_FUTURE_TOLERANCE
+}
+
+/// Whether the system clock is synchronized, as reported by the kernel. None if we can't tell.
+pub fn clock_is_synced() -> Option<bool> {
+    #[cfg(target_os = "linux")]
+    unsafe {
+        // With no mode set, this only reads the kernel clock state.
+        let mut timex: libc::timex = std::mem::zeroed();
+        let state = libc::adjtimex(&mut timex);
+        if state == -1 {
+            return None;
+        }
+        Some(state != libc::TIME_ERROR && timex.status & libc::STA_UNSYNC == 0)
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    None
+}
+
+/// Whether we can rely on the clock for time-based refusals. If we are required to check for
+/// the clock synchronization and it isn't, we'd rather allow than wrongly refuse.
+pub fn clock_trusted(require_time_sync: bool, synced: Option<bool>) -> bool {
+    if !require_time_sync || synced == Some(true) {
+        return true;
+    }
+
+    log::warn!(
+        "The system clock is not known to be synchronized (status: '{:?}'). Not applying \
+         time-based refusals.",
+        synced
+    );
+    false
+}
+
+// Whether this key is pushed in this Script
+fn script_has_key(script: &Script, pubkey: &BitcoinPubkey) -> bool {
+    let pubkey = pubkey.to_bytes();
+    script
+        .instructions()
+        .any(|ins| matches!(ins, Ok(Instruction::PushBytes(bytes)) if bytes == &pubkey[..]))
+}
+
+// The relative timelock this Script enforces, if any: the number pushed right before its
+// OP_CHECKSEQUENCEVERIFY.
+fn script_csv(script: &Script) -> Option<u32> {
+    let (pushnum_1, pushnum_16) = (
+        opcodes::all::OP_PUSHNUM_1.into_u8(),
+        opcodes::all::OP_PUSHNUM_16.into_u8(),
+    );
+    let mut last_number = None;
+    for ins in script.instructions() {
+        match ins.ok()? {
+            Instruction::Op(op) if op == opcodes::all::OP_CSV => {
+                return last_number.and_then(|n: i64| u32::try_from(n).ok())
+            }
+            Instruction::Op(op) if (pushnum_1..=pushnum_16).contains(&op.into_u8()) => {
+                last_number = Some((op.into_u8() - pushnum_1 + 1).into())
+            }
+            Instruction::PushBytes(bytes) => last_number = read_scriptint(bytes).ok(),
+            Instruction::Op(_) => last_number = None,
+        }
+    }
+
+    None
+}
+
+// Whether this input pays to our Unvault descriptor: its witness Script must be, byte for byte,
+// the one of the descriptor derived at one of the input's derivation indexes.
+fn pays_to_our_script(
+    psbtin: &PsbtIn,
+    unvault_descriptor: &UnvaultDescriptor,
+    secp: &secp256k1::Secp256k1<secp256k1::All>,
+) -> bool {
+    let witness_script = match psbtin.witness_script {
+        Some(ref script) => script,
+        None => return false,
+    };
+
+    derivation_indexes(psbtin).into_iter().any(|index| {
+        unvault_descriptor
+            .derive(index.into(), secp)
+            .inner()
+            .explicit_script()
+            == *witness_script
+    })
+}
+
+// Whether this output pays to the CPFP Script of the managers, at one of its derivation indexes
+fn is_cpfp_output(
+    psbtout: &PsbtOut,
+    txout: &TxOut,
+    cpfp_descriptor: &CpfpDescriptor,
+    secp: &secp256k1::Secp256k1<secp256k1::All>,
+) -> bool {
+    psbtout
+        .bip32_derivation
+        .values()
+        .filter_map(|(_, path)| path.as_ref().last().copied())
+        .any(|index| {
+            cpfp_descriptor.derive(index, secp).inner().script_pubkey() == txout.script_pubkey
+        })
+}
+
+// The number of valid SIGHASH_ALL signatures on this input by keys of its witness script, not
+// counting ours.
+fn count_valid_signatures(
+    psbtin: &PsbtIn,
+    sighash: &[u8; 32],
+    our_pubkey: &BitcoinPubkey,
+    secp: &secp256k1::Secp256k1<secp256k1::All>,
+) -> usize {
+    let witness_script = match psbtin.witness_script {
+        Some(ref script) => script,
+        None => return 0,
+    };
+    let sighash = secp256k1::Message::from_slice(sighash).expect("Sighash is 32 bytes");
+
+    psbtin
+        .partial_sigs
+        .iter()
+        .filter(|(pubkey, _)| *pubkey != our_pubkey)
+        .filter(|(pubkey, _)| script_has_key(witness_script, pubkey))
+        .filter(|(pubkey, sig)| signature_is_valid(sig, &sighash, pubkey, secp))
+        .count()
+}
+
+// The indexes of the BIP32 derivation paths of this input
+fn derivation_indexes(psbtin: &PsbtIn) -> HashSet<u32> {
+    psbtin
+        .bip32_derivation
+        .values()
+        .filter_map(|(_, path)| match path.as_ref().last() {
+            Some(bip32::ChildNumber::Normal { index }) => Some(*index),
+            _ => None,
+        })
+        .collect()
+}
+
+// The public keys of the managers for this input, derived at the indexes of its BIP32
+// derivation paths.
+fn managers_pubkeys(
+    psbtin: &PsbtIn,
+    config: &Config,
+    secp: &secp256k1::Secp256k1<secp256k1::All>,
+) -> HashSet<BitcoinPubkey> {
+    derivation_indexes(psbtin)
+        .into_iter()
+        .filter_map(|index| config.derived_manager_pubkeys(index, secp).ok())
+        .flatten()
+        .collect()
+}
+
+// Whether this input carries a valid SIGHASH_ALL signature of the key of this manager, derived
+// at the indexes of its BIP32 derivation paths
+fn has_manager_signature(
+    psbtin: &PsbtIn,
+    sighash: &[u8; 32],
+    xpub: &DescriptorPublicKey,
+    secp: &secp256k1::Secp256k1<secp256k1::All>,
+) -> bool {
+    let sighash = secp256k1::Message::from_slice(sighash).expect("Sighash is 32 bytes");
+
+    derivation_indexes(psbtin)
+        .into_iter()
+        .filter_map(|index| xpub.clone().derive(index).derive_public_key(secp).ok())
+        .any(|pubkey| {
+            psbtin
+                .partial_sigs
+                .get(&pubkey)
+                .map(|sig| signature_is_valid(sig, &sighash, &pubkey, secp))
+                .unwrap_or(false)
+        })
+}
+
+// The number of valid SIGHASH_ALL signatures on this input by the managers
+fn count_valid_manager_signatures(
+    psbtin: &PsbtIn,
+    sighash: &[u8; 32],
+    config: &Config,
+    secp: &secp256k1::Secp256k1<secp256k1::All>,
+) -> usize {
+    let managers_pubkeys = managers_pubkeys(psbtin, config, secp);
+    let sighash = secp256k1::Message::from_slice(sighash).expect("Sighash is 32 bytes");
+
+    psbtin
+        .partial_sigs
+        .iter()
+        .filter(|(pubkey, _)| managers_pubkeys.contains(pubkey))
+        .filter(|(pubkey, sig)| signature_is_valid(sig, &sighash, pubkey, secp))
+        .count()
+}
+
+// Whether this PSBT signature (DER-encoded plus sighash type) is a valid SIGHASH_ALL one for this
+// sighash and pubkey.
+fn signature_is_valid(
+    sig: &[u8],
+    sighash: &secp256k1::Message,
+    pubkey: &BitcoinPubkey,
+    secp: &secp256k1::Secp256k1<secp256k1::All>,
+) -> bool {
+    match sig.split_last() {
+        Some((sighash_type, sig)) if *sighash_type == SigHashType::All as u8 => {
+            secp256k1::Signature::from_der(sig)
+                .map(|sig| secp.verify(sighash, &sig, &pubkey.key).is_ok())
+                .unwrap_or(false)
+        }
+        _ => false,
+    }
+}
+
+/// The state of a given pubkey's signature for an input
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SignatureCheck {
+    Valid,
+    Invalid,
+    Missing,
+}
+
+/// Check the signature of this pubkey for each input of this Spend against the sighash we compute
+/// for it. This only needs the Spend, not any key of ours.
+pub fn check_signatures(
+    spend_tx: &SpendTransaction,
+    pubkey: &BitcoinPubkey,
+    secp: &secp256k1::Secp256k1<secp256k1::All>,
+) -> Result<Vec<SignatureCheck>, SignProcessingError> {
+    let sighashes = compute_spend_sighashes(spend_tx)?;
+
+    Ok(spend_tx
+        .psbt()
+        .inputs
+        .iter()
+        .zip(sighashes.iter())
+        .map(|(psbtin, sighash)| {
+            let sighash = secp256k1::Message::from_slice(sighash).expect("Sighash is 32 bytes");
+            match psbtin.partial_sigs.get(pubkey) {
+                None => SignatureCheck::Missing,
+                Some(sig) if signature_is_valid(sig, &sighash, pubkey, secp) => {
+                    SignatureCheck::Valid
+                }
+                Some(_) => SignatureCheck::Invalid,
+            }
+        })
+        .collect())
+}
+
+/// Compute the SIGHASH_ALL signature hash of each input of this Spend transaction, in order.
+pub fn compute_spend_sighashes(
+    spend_tx: &SpendTransaction,
+) -> Result<Vec<[u8; 32]>, SignProcessingError> {
+    let unsigned_tx = spend_tx.tx();
+    let mut sighash_cache = SigHashCache::new(unsigned_tx);
+
+    (0..spend_tx.psbt().inputs.len())
+        .map(|i| {
+            spend_tx
+                .signature_hash_cached(i, &mut sighash_cache)
+                .map(|sighash| sighash.into_inner())
+                .map_err(|e| SignProcessingError::InsanePsbtMissingInput(Box::new(e)))
+        })
+        .collect()
+}
+
+// A hash of this set of outpoints, regardless of their order
+fn outpoints_hash(outpoints: &[OutPoint]) -> sha256::Hash {
+    let mut outpoints = outpoints.to_vec();
+    outpoints.sort();
+
+    let mut engine = sha256::Hash::engine();
+    for outpoint in outpoints {
+        engine.input(&encode::serialize(&outpoint));
+    }
+    sha256::Hash::from_engine(engine)
+}
+
+/// Whether we were previously asked to sign a Spend of the same set of outpoints but with
+/// different outputs, in which case a warning is logged. Otherwise records the outputs of this
+/// one, if it's the first Spend of this set we see.
+pub fn outputs_changed(
+    db_path: &PathBuf,
+    spend_tx: &SpendTransaction,
+) -> Result<bool, SignProcessingError> {
+    let outpoints: Vec<OutPoint> = spend_tx
+        .tx()
+        .input
+        .iter()
+        .map(|txin| txin.previous_output)
+        .collect();
+    let outpoints_hash = outpoints_hash(&outpoints);
+    let outputs_hash = sha256::Hash::hash(&encode::serialize(&spend_tx.tx().output));
+
+    match db_first_seen_outputs(db_path, &outpoints_hash).map_err(SignProcessingError::Database)? {
+        Some(first_seen) if first_seen != outputs_hash => {
+            log::warn!(
+                "Got a request for a Spend of the same outpoints as a previous one but with \
+                 different outputs (first seen outputs hash: '{}', now: '{}'): '{}'",
+                first_seen,
+                outputs_hash,
+                spend_tx
+            );
+            Ok(true)
+        }
+        Some(_) => Ok(false),
+        None => {
+            db_record_first_seen_outputs(db_path, &outpoints_hash, &outputs_hash)
+                .map_err(SignProcessingError::Database)?;
+            Ok(false)
+        }
+    }
+}
+
+// Add the signatures we previously stored for these inputs of this Spend. Returns false if any of
+// them is invalid for it, ie if it's not the Spend we signed.
+fn add_stored_signatures(
+    spend_tx: &mut SpendTransaction,
+    signatures: Vec<(usize, secp256k1::Signature)>,
+    our_pubkey: &BitcoinPubkey,
+    secp: &secp256k1::Secp256k1<secp256k1::All>,
+) -> bool {
+    signatures
+        .into_iter()
+        .all(|(i, sig)| spend_tx.add_signature(i, our_pubkey.key, sig, secp).is_ok())
+}
+
+/// Add our signatures for all this Spend's inputs as stored in the database, without signing
+/// anything. This is what we answer to a replayed sign request. Fails if any outpoint wasn't
+/// signed, or if the stored signatures aren't for this very Spend.
+pub fn reconstruct_signed_psbt(
+    db_path: &PathBuf,
+    mut spend_tx: SpendTransaction,
+    our_pubkey: &BitcoinPubkey,
+    secp: &secp256k1::Secp256k1<secp256k1::All>,
+) -> Result<SpendTransaction, SignProcessingError> {
+    let mut signatures = Vec::with_capacity(spend_tx.tx().input.len());
+    for (i, txin) in spend_tx.tx().input.iter().enumerate() {
+        let signed_op = db_signed_outpoint(db_path, &txin.previous_output)
+            .map_err(SignProcessingError::Database)?
+            .ok_or(SignProcessingError::NotSigned(txin.previous_output))?;
+        signatures.push((i, signed_op.signature));
+    }
+
+    if !add_stored_signatures(&mut spend_tx, signatures, our_pubkey, secp) {
+        return Err(SignProcessingError::SignatureMismatch);
+    }
+
+    Ok(spend_tx)
 }
 
 /// This implements the main logic of the Cosigning Server. Acting as a dead-simple anti-replay
 /// oracle it signs any incoming Spend transaction if all of its outpoints were not signed already.
 /// See https://github.com/revault/practical-revault/blob/master/messages.md#sign
-pub fn process_sign_message(
+///
+/// We don't know which manager sent it, so a Spend is always refused under `require_sender_sig`.
+/// See [process_manager_sign_request].
+pub fn process_sign_request(
     config: &Config,
     sign_msg: SignRequest,
-    bitcoin_privkey: &secp256k1::SecretKey,
-    secp: &secp256k1::Secp256k1<secp256k1::All>,
-) -> Result<SignResult, SignProcessingError> {
+    keys: &Keys,
+) -> Result<SignDecision, SignProcessingError> {
+    process_sign_request_from(config, sign_msg, keys, None)
+}
+
+/// Process a `sign` request as per [process_sign_request], sent by the manager with this Noise
+/// key. It's only used for `require_sender_sig`.
+pub fn process_manager_sign_request(
+    config: &Config,
+    sign_msg: SignRequest,
+    keys: &Keys,
+    sender: &NoisePubkey,
+) -> Result<SignDecision, SignProcessingError> {
+    process_sign_request_from(config, sign_msg, keys, Some(sender))
+}
+
+fn process_sign_request_from(
+    config: &Config,
+    sign_msg: SignRequest,
+    keys: &Keys,
+    sender: Option<&NoisePubkey>,
+) -> Result<SignDecision, SignProcessingError> {
+    let (bitcoin_privkey, our_pubkey, secp) =
+        (keys.bitcoin_privkey(), keys.bitcoin_pubkey(), keys.secp());
     let db_path = config.db_file();
-    let our_pubkey = BitcoinPubkey {
-        compressed: true,
-        key: secp256k1::PublicKey::from_secret_key(&secp, bitcoin_privkey),
-    };
     let mut spend_tx = sign_msg.tx;
     let n_inputs = spend_tx.tx().input.len();
+    // Query the clock synchronization once, so that all the time-based checks agree
+    let clock_trusted = clock_trusted(config.require_time_sync, clock_is_synced());
+
+    // Before doing any per-input work
+    if n_inputs > config.max_spend_inputs {
+        return Err(SignProcessingError::TooManyInputs(
+            n_inputs,
+            config.max_spend_inputs,
+        ));
+    }
 
     // If it's finalized already, we won't be able to compute the sighash
     if spend_tx.is_finalized() {
+        log::error!("Refusing to sign a finalized Spend: '{}'", spend_tx);
+        return Ok(SignDecision::Refused(SignRefusal::Finalized));
+    }
+
+    // In strict mode, don't put our signature on something that could be interpreted differently
+    // downstream.
+    if config.reject_unknown_psbt_fields && has_unexpected_fields(spend_tx.psbt()) {
+        log::error!(
+            "Refusing to sign a Spend with unexpected PSBT fields: '{}'",
+            spend_tx
+        );
         return Err(SignProcessingError::Garbage);
     }
 
-    // Gather what signatures we have for these prevouts
-    let mut signatures = Vec::with_capacity(n_inputs);
-    for txin in spend_tx.tx().input.iter() {
-        if let Some(signed_op) = db_signed_outpoint(&db_path, &txin.previous_output)
-            .map_err(SignProcessingError::Database)?
-        {
-            signatures.push(signed_op.signature)
+    // revault_tx checks the version when parsing, but we'd rather not rely on it for what we
+    // put our signature on.
+    if spend_tx.tx().version != TX_VERSION {
+        log::error!(
+            "Refusing to sign a Spend with version '{}': '{}'",
+            spend_tx.tx().version,
+            spend_tx
+        );
+        return Err(SignProcessingError::Garbage);
+    }
+    if clock_trusted {
+        let now = time::SystemTime::now()
+            .duration_since(time::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let lock_time = spend_tx.tx().lock_time;
+        if !locktime_is_sane(lock_time, now) {
+            log::error!(
+                "Refusing to sign a Spend with an nLockTime in the future ('{}', now is '{}'): \
+                 '{}'",
+                lock_time,
+                now,
+                spend_tx
+            );
+            return Err(SignProcessingError::Garbage);
         }
+    }
+
+    // Don't sign for coins that aren't ours to cosign. In partial signing mode we only sign
+    // (and record) the inputs that are, and leave the others untouched.
+    let (our_inputs, foreign_inputs): (Vec<usize>, Vec<usize>) =
+        (0..n_inputs).partition(|i| match config.unvault_descriptor {
+            Some(ref descriptor) => {
+                pays_to_our_script(&spend_tx.psbt().inputs[*i], descriptor, secp)
+            }
+            None => true,
+        });
+    if our_inputs.is_empty() || (!foreign_inputs.is_empty() && !config.partial_signing) {
+        let i = foreign_inputs.first().copied().unwrap_or(0);
+        log::error!(
+            "Refusing to sign a Spend of input {} which doesn't pay to one of our Scripts: '{}'",
+            i,
+            spend_tx
+        );
+        return Err(SignProcessingError::ForeignScript(i));
+    }
+    if !foreign_inputs.is_empty() {
+        log::info!(
+            "Only signing inputs {:?} of Spend, the others don't pay to one of our Scripts",
+            our_inputs
+        );
+    }
+    let our_outpoints: Vec<OutPoint> = our_inputs
+        .iter()
+        .map(|i| spend_tx.tx().input[*i].previous_output)
+        .collect();
+
+    let blacklisted_outpoints = config.blacklisted_outpoints();
+    let spent_blacklisted: Vec<OutPoint> = spend_tx
+        .tx()
+        .input
+        .iter()
+        .map(|txin| txin.previous_output)
+        .filter(|outpoint| blacklisted_outpoints.contains(outpoint))
+        .collect();
+    if !spent_blacklisted.is_empty() {
+        log::error!(
+            "CRITICAL: got a request to sign a Spend of blacklisted outpoint(s) '{:?}': '{}'",
+            spent_blacklisted,
+            spend_tx
+        );
+        return Ok(SignDecision::Refused(SignRefusal::BlacklistedOutpoints(
+            spent_blacklisted,
+        )));
+    }
 
-        // NOTE: we initially decided to check each manager's signature here, and then we discarded
-        // it. This is still being discussed whether it's fine to drop this check...
-        // We later stripped the managers signatures from the PSBT before sharing it, in order to
-        // not hit the Noise message size limit. Hence the above is unlikely to happen anytime
-        // soon..
+    // If we were given an allowlist, anything else is off limits.
+    if let Some(ref allowed_outpoints) = config.allowed_outpoints {
+        let not_allowed: Vec<OutPoint> = our_outpoints
+            .iter()
+            .filter(|outpoint| !allowed_outpoints.contains(outpoint))
+            .copied()
+            .collect();
+        if !not_allowed.is_empty() {
+            log::error!(
+                "Refusing to sign a Spend of outpoint(s) '{:?}' not in the allowlist: '{}'",
+                not_allowed,
+                spend_tx
+            );
+            return Ok(SignDecision::Refused(SignRefusal::NotAllowedOutpoints(
+                not_allowed,
+            )));
+        }
     }
 
-    // If we had all the signatures for all these outpoints, send them if they are valid.
-    if signatures.len() == n_inputs {
-        for (i, sig) in signatures.into_iter().enumerate() {
-            // Don't let them fool you!
-            if spend_tx
-                .add_signature(i, our_pubkey.key, sig, &secp)
-                .is_err()
-            {
+    // If we were given a list of destinations, only the CPFP output may pay elsewhere.
+    if let Some(ref allowed_destinations) = config.allowed_destinations {
+        let cpfp_descriptor = match CpfpDescriptor::new(config.manager_xpubs()) {
+            Ok(descriptor) => Some(descriptor),
+            Err(e) => {
+                log::error!("Error computing the managers' CPFP descriptor: '{}'", e);
+                None
+            }
+        };
+        let not_allowed: Vec<String> = spend_tx
+            .tx()
+            .output
+            .iter()
+            .zip(spend_tx.psbt().outputs.iter())
+            .filter(|(txout, psbtout)| {
+                !allowed_destinations.contains(&txout.script_pubkey)
+                    && !cpfp_descriptor
+                        .as_ref()
+                        .map(|desc| is_cpfp_output(psbtout, txout, desc, secp))
+                        .unwrap_or(false)
+            })
+            .map(|(txout, _)| txout.script_pubkey.to_string())
+            .collect();
+        if !not_allowed.is_empty() {
+            log::error!(
+                "Refusing to sign a Spend paying to Script(s) '{:?}' not in the allowlist: '{}'",
+                not_allowed,
+                spend_tx
+            );
+            return Ok(SignDecision::Refused(SignRefusal::NotAllowedDestinations));
+        }
+    }
+
+    // Don't sign for an Unvault with timelock parameters other than the ones of our vaults: both
+    // the Script and the input's nSequence must encode the expected CSV.
+    if let Some(unvault_csv) = config.unvault_csv {
+        let mismatched: Vec<OutPoint> = our_inputs
+            .iter()
+            .filter(|i| {
+                let witness_script = spend_tx.psbt().inputs[**i].witness_script.as_ref();
+                witness_script.and_then(script_csv) != Some(unvault_csv)
+                    || spend_tx.tx().input[**i].sequence != unvault_csv
+            })
+            .map(|i| spend_tx.tx().input[*i].previous_output)
+            .collect();
+        if !mismatched.is_empty() {
+            log::error!(
+                "Refusing to sign a Spend of outpoint(s) '{:?}' not locked for {} blocks: '{}'",
+                mismatched,
+                unvault_csv,
+                spend_tx
+            );
+            return Ok(SignDecision::Refused(SignRefusal::CsvMismatch(mismatched)));
+        }
+    }
+
+    // Time-based refusals
+    let max_locktime_age = config.max_locktime_age.filter(|_| clock_trusted);
+    if let Some(max_age) = max_locktime_age {
+        let now = time::SystemTime::now()
+            .duration_since(time::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let lock_time = spend_tx.tx().lock_time;
+        if locktime_is_stale(lock_time, max_age, now) {
+            log::error!(
+                "Refusing to sign a Spend with a stale nLockTime ('{}', now is '{}'): '{}'",
+                lock_time,
+                now,
+                spend_tx
+            );
+            return Ok(SignDecision::Refused(SignRefusal::StaleLocktime));
+        }
+    }
+
+    if config.require_rbf
+        && spend_tx
+            .tx()
+            .input
+            .iter()
+            .any(|txin| txin.sequence >= RBF_SEQUENCE_THRESHOLD)
+    {
+        log::error!(
+            "Refusing to sign a Spend with an input not signaling RBF: '{}'",
+            spend_tx
+        );
+        return Ok(SignDecision::Refused(SignRefusal::NoRbf));
+    }
+
+    let (fees, weight) = (spend_tx.fees().as_sat(), spend_tx.max_weight());
+    if let Some(min_feerate) = config.min_relay_feerate {
+        // fees / (weight / 4) < min_feerate, without rounding
+        if fees.saturating_mul(4) < min_feerate.saturating_mul(weight) {
+            log::error!(
+                "Refusing to sign a Spend below the minimum feerate of {}sat/vb (fees: {}sats, \
+                 weight: {}WU): '{}'",
+                min_feerate,
+                fees,
+                weight,
+                spend_tx
+            );
+            return Ok(SignDecision::Refused(SignRefusal::FeerateTooLow));
+        }
+    }
+    // Nor one burning the funds in fees
+    if let Some(max_feerate) = config.max_feerate {
+        if fees.saturating_mul(4) > max_feerate.saturating_mul(weight) {
+            log::error!(
+                "Refusing to sign a Spend above the maximum feerate of {}sat/vb (fees: {}sats, \
+                 weight: {}WU): '{}'",
+                max_feerate,
+                fees,
+                weight,
+                spend_tx
+            );
+            return Ok(SignDecision::Refused(SignRefusal::FeerateTooHigh));
+        }
+    }
+
+    if let Some(threshold) = config.require_manager_sigs {
+        let sighashes = compute_spend_sighashes(&spend_tx)?;
+        for i in our_inputs.iter().copied() {
+            let (psbtin, sighash) = (&spend_tx.psbt().inputs[i], &sighashes[i]);
+            let n_sigs = if config.verify_manager_sigs {
+                count_valid_manager_signatures(psbtin, sighash, config, secp)
+            } else {
+                count_valid_signatures(psbtin, sighash, our_pubkey, secp)
+            };
+            if n_sigs < threshold {
+                log::error!(
+                    "Refusing to sign a Spend with {} valid signature(s) on input {} (we require \
+                     {}): '{}'",
+                    n_sigs,
+                    i,
+                    threshold,
+                    spend_tx
+                );
+                return Ok(SignDecision::Refused(SignRefusal::MissingManagerSigs));
+            }
+        }
+    }
+
+    // A manager presenting a Spend it didn't sign, eg one another manager did
+    if config.require_sender_sig {
+        let sender_xpub = sender.and_then(|sender| {
+            config
+                .managers
+                .iter()
+                .find(|m| m.noise_key == *sender)
+                .and_then(|m| m.xpub.as_ref())
+        });
+        let sender_xpub = match sender_xpub {
+            Some(xpub) => xpub,
+            None => {
                 log::error!(
-                    "Invalid signature. Got a request for a modified Spend: '{}'",
+                    "Refusing to sign a Spend from a manager we don't have the xpub of: '{}'",
                     spend_tx
                 );
-                return Ok(null_signature());
+                return Ok(SignDecision::Refused(SignRefusal::MissingSenderSig));
             }
+        };
+        let sighashes = compute_spend_sighashes(&spend_tx)?;
+        if let Some(i) = our_inputs.iter().copied().find(|i| {
+            !has_manager_signature(
+                &spend_tx.psbt().inputs[*i],
+                &sighashes[*i],
+                sender_xpub,
+                secp,
+            )
+        }) {
+            log::error!(
+                "Refusing to sign a Spend without a valid signature of the manager which sent it \
+                 on input {}: '{}'",
+                i,
+                spend_tx
+            );
+            return Ok(SignDecision::Refused(SignRefusal::MissingSenderSig));
+        }
+    }
+
+    // The anti-replay is keyed on the outpoints only, but the outputs changing for the same inputs
+    // is worth the operator's attention.
+    if !config.dry_run && !config.read_only {
+        outputs_changed(&db_path, &spend_tx)?;
+    }
+
+    // Gather what signatures we have for the prevouts we would sign
+    let mut signatures = Vec::with_capacity(our_inputs.len());
+    let mut signed_outpoints = Vec::with_capacity(our_inputs.len());
+    for (i, outpoint) in our_inputs.iter().zip(our_outpoints.iter()) {
+        if let Some(signature) =
+            db_signature_cached(&db_path, outpoint, config.signature_cache_size)
+                .map_err(SignProcessingError::Database)?
+        {
+            signatures.push((*i, signature));
+            signed_outpoints.push(*outpoint);
+        }
+    }
+
+    // If we had all the signatures for all these outpoints, send them if they are valid.
+    if signatures.len() == our_inputs.len() {
+        // Don't let them fool you!
+        if !add_stored_signatures(&mut spend_tx, signatures, our_pubkey, secp) {
+            log::error!(
+                "Invalid signature. Got a request for a modified Spend: '{}'",
+                spend_tx
+            );
+            return Ok(SignDecision::Refused(SignRefusal::ModifiedSpend(
+                signed_outpoints,
+            )));
+        }
+        if config.read_only {
+            log::warn!(
+                "Read-only: not sharing our stored signatures for '{}'",
+                spend_tx
+            );
+            return Ok(SignDecision::Refused(SignRefusal::ReadOnly));
         }
-        return Ok(SignResult { tx: Some(spend_tx) });
+        return Ok(SignDecision::Signed(spend_tx));
     }
 
-    // If we already signed some of the outpoints, don't sign anything else!
+    // If we already signed some of the outpoints, don't sign anything else! We may still share
+    // the signatures we have, if they are for this very Spend.
     if !signatures.is_empty() {
-        return Ok(null_signature());
-    }
-
-    // If we signed none of the input, append fresh signatures for each of them to the PSBT.
-    let unsigned_tx = spend_tx.tx().clone();
-    let mut sighash_cache = SigHashCache::new(&unsigned_tx);
-    for i in 0..spend_tx.psbt().inputs.len() {
-        let sighash = spend_tx
-            .signature_hash_cached(i, &mut sighash_cache)
-            .map_err(SignProcessingError::InsanePsbtMissingInput)?;
-        let sighash = secp256k1::Message::from_slice(&sighash).expect("Sighash is 32 bytes");
-
-        let signature = secp.sign(&sighash, bitcoin_privkey);
-        let res = spend_tx
-            .add_signature(i, our_pubkey.key, signature, &secp)
-            .expect("We must provide valid signatures");
-        assert!(
-            res.is_none(),
-            "If there was a signature for our pubkey already and we didn't return \
-             above, we have big problems.."
+        if config.return_known_sigs && !config.read_only {
+            let mut known_sigs_tx = spend_tx.clone();
+            if add_stored_signatures(&mut known_sigs_tx, signatures, our_pubkey, secp) {
+                log::info!(
+                    "Only returning our stored signatures for outpoint(s) '{:?}' of '{}'",
+                    signed_outpoints,
+                    spend_tx
+                );
+                return Ok(SignDecision::KnownSignatures(
+                    known_sigs_tx,
+                    signed_outpoints,
+                ));
+            }
+            log::error!(
+                "Not returning our stored signatures, they are for another Spend than '{}'",
+                spend_tx
+            );
+        }
+        return Ok(SignDecision::Refused(SignRefusal::AlreadySigned(
+            signed_outpoints,
+        )));
+    }
+
+    // A standby must never sign, lest the primary signs a conflicting Spend
+    if config.read_only {
+        log::warn!("Read-only: not signing '{}'", spend_tx);
+        return Ok(SignDecision::Refused(SignRefusal::ReadOnly));
+    }
+
+    // If we signed none of the input, compute fresh signatures for each of them.
+    let sighashes = compute_spend_sighashes(&spend_tx)?;
+    let fresh_signatures: Vec<secp256k1::Signature> = our_inputs
+        .iter()
+        .map(|i| {
+            let sighash =
+                secp256k1::Message::from_slice(&sighashes[*i]).expect("Sighash is 32 bytes");
+            secp.sign(&sighash, bitcoin_privkey)
+        })
+        .collect();
+
+    // Add them before reserving the outpoints, as we must not burn them if we can't.
+    for (i, signature) in our_inputs.iter().copied().zip(fresh_signatures.iter()) {
+        let previous_sig = spend_tx
+            .add_signature(i, our_pubkey.key, *signature, secp)
+            .map_err(|e| SignProcessingError::InsanePsbtMissingInput(Box::new(e)))?;
+        if previous_sig.is_some() {
+            log::error!(
+                "Input {} of Spend already has a signature for our key that we don't know \
+                 of: '{}'",
+                i,
+                spend_tx
+            );
+            return Err(SignProcessingError::UnexpectedSignature(i));
+        }
+    }
+
+    if config.dry_run {
+        log::warn!(
+            "Dry run: not recording outpoints '{:?}', they may be signed again",
+            our_outpoints
         );
+        return Ok(SignDecision::Signed(spend_tx));
+    }
 
-        db_insert_signed_outpoint(
-            &db_path,
-            &spend_tx.tx().input[i].previous_output,
-            &signature,
-        )
+    // Now make sure nobody signed any of them in the meantime before sharing our signatures.
+    let outpoints = our_outpoints;
+    let outcome =
+        db_reserve_outpoints(&db_path, &outpoints).map_err(SignProcessingError::Database)?;
+    if outcome != ReservationOutcome::Reserved {
+        log::error!(
+            "Outpoints were signed by a concurrent request ('{:?}'), not signing '{}'",
+            outcome,
+            spend_tx
+        );
+        return Ok(SignDecision::Refused(SignRefusal::ConcurrentlySigned(
+            outpoints,
+        )));
+    }
+
+    // Record them all at once, so that we never end up with only part of them stored.
+    let signed_outpoints: Vec<(OutPoint, secp256k1::Signature)> =
+        outpoints.into_iter().zip(fresh_signatures).collect();
+    db_fill_reserved_outpoints(&db_path, &signed_outpoints)
         .map_err(SignProcessingError::Database)?;
+    for (outpoint, signature) in signed_outpoints.iter() {
+        SIGNATURE_CACHE.insert(&db_path, outpoint, *signature, config.signature_cache_size);
     }
 
-    // Belt-and-suspender: if it was not empty, we would have signed a prevout twice.
-    assert!(signatures.is_empty());
+    Ok(SignDecision::Signed(spend_tx))
+}
 
-    Ok(SignResult { tx: Some(spend_tx) })
+/// Process a `sign` message as per [process_sign_request], answering with a null signature on
+/// refusal.
+pub fn process_sign_message(
+    config: &Config,
+    sign_msg: SignRequest,
+    keys: &Keys,
+) -> Result<SignResult, SignProcessingError> {
+    Ok(match process_sign_request(config, sign_msg, keys)? {
+        SignDecision::Signed(spend_tx) | SignDecision::KnownSignatures(spend_tx, _) => {
+            SignResult { tx: Some(spend_tx) }
+        }
+        SignDecision::Refused(_) => SignResult { tx: None },
+    })
 }
 
 #[cfg(test)]
 mod test {
-    use crate::{processing::process_sign_message, tests::builder::CosignerTestBuilder};
+    use crate::{
+        database::{
+            cache::SIGNATURE_CACHE, db_count_signed_outpoints, db_forget_outpoint,
+            db_insert_signed_outpoint, db_set_read_only, db_signed_outpoint, N_OPENS,
+        },
+        keys::{bitcoin_pubkey, secp_ctx},
+        processing::{
+            check_signatures, clock_trusted, compute_spend_sighashes, locktime_is_sane,
+            locktime_is_stale, outputs_changed, process_manager_sign_request, process_sign_message,
+            process_sign_request, reconstruct_signed_psbt, script_csv, SignDecision,
+            SignProcessingError, SignRefusal, SignatureCheck,
+        },
+        tests::builder::CosignerTestBuilder,
+    };
     use revault_net::message::cosigner::*;
-    use revault_tx::{bitcoin::OutPoint, transactions::RevaultTransaction};
-    use std::str::FromStr;
+    use revault_net::noise::PublicKey as NoisePubkey;
+    use revault_tx::{
+        bitcoin::{
+            blockdata::{opcodes, script::Builder},
+            consensus::encode,
+            hashes::hex::ToHex,
+            secp256k1,
+            util::{
+                bip32,
+                psbt::{raw::ProprietaryKey, PartiallySignedTransaction as Psbt},
+            },
+            Address, OutPoint, PublicKey as BitcoinPubkey, SigHashType,
+        },
+        transactions::{CpfpableTransaction, RevaultTransaction, SpendTransaction},
+    };
+    use std::{str::FromStr, time};
 
     #[test]
     fn sign_message_processing_sanity_check() {
@@ -165,13 +1113,9 @@ mod test {
             0
         );
         let sign_a = SignRequest { tx };
-        let SignResult { tx } = process_sign_message(
-            &test_framework.config,
-            sign_a.clone(),
-            &test_framework.bitcoin_privkey,
-            &test_framework.secp,
-        )
-        .unwrap();
+        let SignResult { tx } =
+            process_sign_message(&test_framework.config, sign_a.clone(), &test_framework.keys)
+                .unwrap();
         let tx = tx.unwrap();
         assert_eq!(
             tx.psbt()
@@ -183,13 +1127,8 @@ mod test {
         );
 
         // Now if we ask for the same outpoints again, they'll send the very same PSBT
-        let SignResult { tx: second_psbt } = process_sign_message(
-            &test_framework.config,
-            sign_a,
-            &test_framework.bitcoin_privkey,
-            &test_framework.secp,
-        )
-        .unwrap();
+        let SignResult { tx: second_psbt } =
+            process_sign_message(&test_framework.config, sign_a, &test_framework.keys).unwrap();
         assert_eq!(tx, second_psbt.unwrap());
 
         // However, if the set of inputs is different they wont be happy
@@ -205,13 +1144,1508 @@ mod test {
             .unwrap(),
         ]);
         let sign_a = SignRequest { tx };
+        let SignResult { tx } =
+            process_sign_message(&test_framework.config, sign_a, &test_framework.keys).unwrap();
+        assert!(tx.is_none(), "It contains a duplicated outpoint");
+    }
+
+    #[test]
+    fn sign_message_shared_secp() {
+        // Whatever the keys and the number of requests, we always borrow the process' context
+        for vout in 0..3 {
+            let test_framework = CosignerTestBuilder::new(3);
+            assert!(std::ptr::eq(test_framework.keys.secp(), secp_ctx()));
+            assert!(std::ptr::eq(test_framework.secp, secp_ctx()));
+            for txid in &[
+                "2b8930127e9dfd1bcdf35df2bc7f3b8cdbec083b1ae693f36b6305fccd1425da",
+                "ceca4de398c63b29543f8346c09fd7522fd8661ce8bdc0e454e8d6ed8ad46a0d",
+            ] {
+                let tx = test_framework.generate_spend_tx(&[OutPoint::from_str(&format!(
+                    "{}:{}",
+                    txid, vout
+                ))
+                .unwrap()]);
+                let SignResult { tx } = process_sign_message(
+                    &test_framework.config,
+                    SignRequest { tx },
+                    &test_framework.keys,
+                )
+                .unwrap();
+                assert!(tx.is_some());
+            }
+        }
+    }
+
+    #[test]
+    fn sign_message_version_locktime() {
+        let test_framework = CosignerTestBuilder::new(3);
+        let now = time::SystemTime::now()
+            .duration_since(time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as u32;
+        let spend_with = |vout: u32, version: i32, lock_time: u32| {
+            let tx = test_framework.generate_spend_tx(&[OutPoint::from_str(&format!(
+                "2b8930127e9dfd1bcdf35df2bc7f3b8cdbec083b1ae693f36b6305fccd1425da:{}",
+                vout
+            ))
+            .unwrap()]);
+            let mut psbt = tx.into_psbt();
+            psbt.global.unsigned_tx.version = version;
+            psbt.global.unsigned_tx.lock_time = lock_time;
+            SpendTransaction::from_psbt_serialized(&encode::serialize(&psbt)).ok()
+        };
+        let sign = |tx| {
+            process_sign_message(
+                &test_framework.config,
+                SignRequest { tx },
+                &test_framework.keys,
+            )
+        };
+
+        // A Spend with another version can't even make it to us
+        assert!(spend_with(0, 1, 0).is_none());
+        assert!(spend_with(0, 3, 0).is_none());
+
+        // One which couldn't be mined before tomorrow is refused
+        match sign(spend_with(0, 2, now + 86_400).unwrap()) {
+            Err(SignProcessingError::Garbage) => {}
+            res => panic!("Unexpected result: {:?}", res),
+        }
+
+        // But a recent timestamp or any block height is fine
+        sign(spend_with(0, 2, now - 3_600).unwrap()).unwrap();
+        sign(spend_with(1, 2, now + 60).unwrap()).unwrap();
+        sign(spend_with(2, 2, 700_000).unwrap()).unwrap();
+    }
+
+    #[test]
+    fn sane_locktime() {
+        let now: u32 = 1_600_000_000;
+        let sane = |lock_time: u32| locktime_is_sane(lock_time, now as u64);
+
+        assert!(sane(now - 86_400));
+        assert!(sane(now));
+        // We tolerate a bit of clock drift
+        assert!(sane(now + 7_200));
+        assert!(!sane(now + 7_201));
+        // Block heights are never checked
+        assert!(sane(0));
+        assert!(sane(499_999_999));
+    }
+
+    #[test]
+    fn sign_message_unknown_psbt_fields() {
+        let mut test_framework = CosignerTestBuilder::new(3);
+
+        let tx = test_framework.generate_spend_tx(&[OutPoint::from_str(
+            "2b8930127e9dfd1bcdf35df2bc7f3b8cdbec083b1ae693f36b6305fccd1425da:0",
+        )
+        .unwrap()]);
+        let mut psbt = tx.into_psbt();
+        psbt.inputs[0].proprietary.insert(
+            ProprietaryKey {
+                prefix: b"revault".to_vec(),
+                subtype: 0,
+                key: vec![],
+            },
+            vec![0x42],
+        );
+        let tx = SpendTransaction::from_psbt_serialized(&encode::serialize(&psbt)).unwrap();
+        let sign_req = SignRequest { tx };
+
+        // Under strict mode, it's refused
+        test_framework.config.reject_unknown_psbt_fields = true;
+        match process_sign_message(
+            &test_framework.config,
+            sign_req.clone(),
+            &test_framework.keys,
+        ) {
+            Err(SignProcessingError::Garbage) => {}
+            res => panic!("Unexpected result: {:?}", res),
+        }
+
+        // Otherwise we'll happily sign it
+        test_framework.config.reject_unknown_psbt_fields = false;
+        let SignResult { tx } =
+            process_sign_message(&test_framework.config, sign_req, &test_framework.keys).unwrap();
+        assert_eq!(tx.unwrap().psbt().inputs[0].partial_sigs.len(), 1);
+    }
+
+    #[test]
+    fn stale_locktime() {
+        let now: u32 = 1_600_000_000;
+        let stale = |lock_time: u32, max_age| locktime_is_stale(lock_time, max_age, now as u64);
+
+        // A fresh one
+        assert!(!stale(now - 3_600, 86_400));
+        assert!(!stale(now - 86_400, 86_400));
+        // In the future, it's obviously not stale
+        assert!(!stale(now + 3_600, 86_400));
+        // A stale one
+        assert!(stale(now - 86_401, 86_400));
+        // Block heights are never considered stale
+        assert!(!stale(0, 0));
+        assert!(!stale(680_000, 0));
+    }
+
+    #[test]
+    fn sign_message_require_rbf() {
+        let mut test_framework = CosignerTestBuilder::new(3);
+        test_framework.config.require_rbf = true;
+
+        // Unvault inputs have their CSV as nSequence, so they always signal RBF
+        let tx = test_framework.generate_spend_tx(&[OutPoint::from_str(
+            "2b8930127e9dfd1bcdf35df2bc7f3b8cdbec083b1ae693f36b6305fccd1425da:0",
+        )
+        .unwrap()]);
         let SignResult { tx } = process_sign_message(
             &test_framework.config,
-            sign_a,
-            &test_framework.bitcoin_privkey,
-            &test_framework.secp,
+            SignRequest { tx },
+            &test_framework.keys,
         )
         .unwrap();
-        assert!(tx.is_none(), "It contains a duplicated outpoint");
+        assert!(tx.is_some());
+
+        // But not if it was tweaked
+        let tx = test_framework.generate_spend_tx(&[
+            OutPoint::from_str(
+                "ceca4de398c63b29543f8346c09fd7522fd8661ce8bdc0e454e8d6ed8ad46a0d:1",
+            )
+            .unwrap(),
+            OutPoint::from_str(
+                "0b38682347207cd79de33edf8897a75abe7d8799b194439150306773b6aef55a:189",
+            )
+            .unwrap(),
+        ]);
+        let mut psbt = tx.into_psbt();
+        psbt.global.unsigned_tx.input[1].sequence = 0xffff_fffe;
+        let tx = SpendTransaction::from_psbt_serialized(&encode::serialize(&psbt)).unwrap();
+        let sign_req = SignRequest { tx };
+        let SignResult { tx } = process_sign_message(
+            &test_framework.config,
+            sign_req.clone(),
+            &test_framework.keys,
+        )
+        .unwrap();
+        assert!(tx.is_none());
+
+        // It's only enforced if configured
+        test_framework.config.require_rbf = false;
+        let SignResult { tx } =
+            process_sign_message(&test_framework.config, sign_req, &test_framework.keys).unwrap();
+        assert!(tx.is_some());
+    }
+
+    #[test]
+    fn time_sync_fallback() {
+        // If not required, we always trust the clock
+        assert!(clock_trusted(false, None));
+        assert!(clock_trusted(false, Some(false)));
+
+        // Otherwise only if it's known to be synchronized
+        assert!(clock_trusted(true, Some(true)));
+        assert!(!clock_trusted(true, Some(false)));
+        assert!(!clock_trusted(true, None));
+    }
+
+    #[test]
+    fn spend_sighashes() {
+        // A Spend with two inputs, spending from a deployment with a single manager
+        let spend_tx = SpendTransaction::from_str("cHNidP8BAJACAAAAAtolFM38BWNr85PmGjsI7NuMO3+88l3zzRv9nX4SMIkrAAAAAAAMAAAADWrUiu3W6FTkwL3oHGbYL1LXn8BGgz9UKTvGmONNys4BAAAAAAwAAAAC4P0AAAAAAAAiACCYgk1teCO/SF1QFROa4JzNAz0+yqOS84iHF8F8dJCO42A76gsAAAAAAAAAAAAAAQErAOH1BQAAAAAiACAym0/bDsPONBGea3aLnUnki2WP/MalE76a5MKQc1s75gEF/ZgCIQK+KAZddpBbdT4WPuHonHKptx6IvbrjbR91Wd3xBUeG5KxRh2R2qRTbqgoJMteufUD0CLEvSoY93l6Y7Yisa3apFC7xenlAIgxfTaxDS8OQ1qqEJ+NGiKxsk2t2qRQplPEREr0xf8+ajTLsLWbe4nGvyIisbJNrdqkUy+8Cc53u0JIv2CW7HxHUhaIeLf6IrGyTa3apFNP8UJ4XH70l8NCDbiadtyMQv5F+iKxsk2t2qRSESQK1QLUqYIu1XZWAXaJmppmXOIisbJNrdqkU765P73OW2r3ogjHL/Cu8KJJFWySIrGyTa3apFPH4C8L1ce1Sz+/Ddeh4PJ2u0aSqiKxsk2t2qRQ+sQ5le3sbKCdyz5PQC8ggNKQ8NoisbJNrdqkUWYnBWlMNpu0YDKODwlfzGbE55oGIrGyTWodnWiEDPW7yF9LJCa32CrRugn5+8SmjSjb8BqRnfwfjayWX8NAhAvCXL4iqJ1tKQtxOFbSGe5uqO4tvAMImPh56GCoGTTKgIQOy966VhnNj5UYOClzMCFkXz512o/L6/EpcCjZF7ZP8mSEDzw8otJhefEzL1wsXijITLm1oGhrEx8D0REAHgICdt9MhAp/ZUMJprugb1HgfNcOPzq1UHQABN5PVnLMBz+MR3SjyIQMgVNWvZCH7WiS7YYvbLTIPs/fSIQu7s4tp/UQ5j2p4CSEClygU+8bUKCxQIPj08qpMmmg2Zr2cWy9rXD38Ch2AZXIhA7072ShdGUPk68MeovW8wmpzaGF071VDGlEhaljWqestIQNDVLB5joBVWzK5+S6J1g4nmCQ1jBPrW37sAXGrhEK1TiECVyHHO2zc7VR+s2F/MZWM8twKoDB9K6hg4YlEtt+zhwBar1yyaCIGAlchxzts3O1UfrNhfzGVjPLcCqAwfSuoYOGJRLbfs4cACAAAAAAAAAAAIgYChr5OByZ+UlwNoQtKUeOteiEaA6l6InEx03mZfY/TzMwIbEjZngAAAAAiBgKXKBT7xtQoLFAg+PTyqkyaaDZmvZxbL2tcPfwKHYBlcggAAAAAAAAAACIGAp/ZUMJprugb1HgfNcOPzq1UHQABN5PVnLMBz+MR3SjyCAAAAAAAAAAAIgYCvigGXXaQW3U+Fj7h6JxyqbceiL26420fdVnd8QVHhuQId/YtMAAAAAAiBgLLk/g7USw1JyAWhGWyAAsZEqexC2Re8MVAR/A12F7vuAjXwZ3JAAAAACIGAuWP1C7gllz0QtWzMqcAXzhvrRzCVUSokFyz8uc3VqaZCBrYiZcAAAAAIgYC8JcviKonW0pC3E4VtIZ7m6o7i28AwiY+HnoYKgZNMqAIAAAAAAAAAAAiBgMgVNWvZCH7WiS7YYvbLTIPs/fSIQu7s4tp/UQ5j2p4CQgAAAAAAAAAACIGAzLkDYG8prf92378uetbUn54kzJdnSZR7CRrnphLDK4rCPX+ZTMAAAAAIgYDPW7yF9LJCa32CrRugn5+8SmjSjb8BqRnfwfjayWX8NAIAAAAAAAAAAAiBgNDVLB5joBVWzK5+S6J1g4nmCQ1jBPrW37sAXGrhEK1TggAAAAAAAAAACIGA2xcVQ+6kg6Qqk0X6cTRRp4/wvSnS4es8T5ClIX7mEQ/CF9W2g8AAAAAIgYDhfOckvIqxUtuiSPABB5YOS5JtkQxCw9QdQQq4vbkQB4I6X8PZwAAAAAiBgOTbq5X5STknHoy+JETESLnJgkAFLUoKdryLotP+eUR9wiVl8k2AAAAACIGA7L3rpWGc2PlRg4KXMwIWRfPnXaj8vr8SlwKNkXtk/yZCAAAAAAAAAAAIgYDvTvZKF0ZQ+Trwx6i9bzCanNoYXTvVUMaUSFqWNap6y0IAAAAAAAAAAAiBgO/1Uepd38iC14OTKPvF4/y9H60ZLvvrIF5jyjTaffB+wjMwl4MAAAAACIGA8lQA39yR/EEcukW28CZnprUT1YLl0f7aU2280WZj+xCCFJQnb8AAAAAIgYDzw8otJhefEzL1wsXijITLm1oGhrEx8D0REAHgICdt9MIAAAAAAAAAAAiBgPl9+xMWzCTkuceYVzLKC/XeTj/78YWrUvDi+QxruRfDAhNVAJyAAAAAAABASsA4fUFAAAAACIAIDKbT9sOw840EZ5rdoudSeSLZY/8xqUTvprkwpBzWzvmAQX9mAIhAr4oBl12kFt1PhY+4eiccqm3Hoi9uuNtH3VZ3fEFR4bkrFGHZHapFNuqCgky1659QPQIsS9Khj3eXpjtiKxrdqkULvF6eUAiDF9NrENLw5DWqoQn40aIrGyTa3apFCmU8RESvTF/z5qNMuwtZt7ica/IiKxsk2t2qRTL7wJzne7Qki/YJbsfEdSFoh4t/oisbJNrdqkU0/xQnhcfvSXw0INuJp23IxC/kX6IrGyTa3apFIRJArVAtSpgi7VdlYBdomammZc4iKxsk2t2qRTvrk/vc5baveiCMcv8K7wokkVbJIisbJNrdqkU8fgLwvVx7VLP78N16Hg8na7RpKqIrGyTa3apFD6xDmV7exsoJ3LPk9ALyCA0pDw2iKxsk2t2qRRZicFaUw2m7RgMo4PCV/MZsTnmgYisbJNah2daIQM9bvIX0skJrfYKtG6Cfn7xKaNKNvwGpGd/B+NrJZfw0CEC8JcviKonW0pC3E4VtIZ7m6o7i28AwiY+HnoYKgZNMqAhA7L3rpWGc2PlRg4KXMwIWRfPnXaj8vr8SlwKNkXtk/yZIQPPDyi0mF58TMvXCxeKMhMubWgaGsTHwPREQAeAgJ230yECn9lQwmmu6BvUeB81w4/OrVQdAAE3k9WcswHP4xHdKPIhAyBU1a9kIftaJLthi9stMg+z99IhC7uzi2n9RDmPangJIQKXKBT7xtQoLFAg+PTyqkyaaDZmvZxbL2tcPfwKHYBlciEDvTvZKF0ZQ+Trwx6i9bzCanNoYXTvVUMaUSFqWNap6y0hA0NUsHmOgFVbMrn5LonWDieYJDWME+tbfuwBcauEQrVOIQJXIcc7bNztVH6zYX8xlYzy3AqgMH0rqGDhiUS237OHAFqvXLJoIgYCVyHHO2zc7VR+s2F/MZWM8twKoDB9K6hg4YlEtt+zhwAIAAAAAAAAAAAiBgKGvk4HJn5SXA2hC0pR4616IRoDqXoicTHTeZl9j9PMzAhsSNmeAAAAACIGApcoFPvG1CgsUCD49PKqTJpoNma9nFsva1w9/AodgGVyCAAAAAAAAAAAIgYCn9lQwmmu6BvUeB81w4/OrVQdAAE3k9WcswHP4xHdKPIIAAAAAAAAAAAiBgK+KAZddpBbdT4WPuHonHKptx6IvbrjbR91Wd3xBUeG5Ah39i0wAAAAACIGAsuT+DtRLDUnIBaEZbIACxkSp7ELZF7wxUBH8DXYXu+4CNfBnckAAAAAIgYC5Y/ULuCWXPRC1bMypwBfOG+tHMJVRKiQXLPy5zdWppkIGtiJlwAAAAAiBgLwly+IqidbSkLcThW0hnubqjuLbwDCJj4eehgqBk0yoAgAAAAAAAAAACIGAyBU1a9kIftaJLthi9stMg+z99IhC7uzi2n9RDmPangJCAAAAAAAAAAAIgYDMuQNgbymt/3bfvy561tSfniTMl2dJlHsJGuemEsMrisI9f5lMwAAAAAiBgM9bvIX0skJrfYKtG6Cfn7xKaNKNvwGpGd/B+NrJZfw0AgAAAAAAAAAACIGA0NUsHmOgFVbMrn5LonWDieYJDWME+tbfuwBcauEQrVOCAAAAAAAAAAAIgYDbFxVD7qSDpCqTRfpxNFGnj/C9KdLh6zxPkKUhfuYRD8IX1baDwAAAAAiBgOF85yS8irFS26JI8AEHlg5Lkm2RDELD1B1BCri9uRAHgjpfw9nAAAAACIGA5NurlflJOScejL4kRMRIucmCQAUtSgp2vIui0/55RH3CJWXyTYAAAAAIgYDsveulYZzY+VGDgpczAhZF8+ddqPy+vxKXAo2Re2T/JkIAAAAAAAAAAAiBgO9O9koXRlD5OvDHqL1vMJqc2hhdO9VQxpRIWpY1qnrLQgAAAAAAAAAACIGA7/VR6l3fyILXg5Mo+8Xj/L0frRku++sgXmPKNNp98H7CMzCXgwAAAAAIgYDyVADf3JH8QRy6RbbwJmemtRPVguXR/tpTbbzRZmP7EIIUlCdvwAAAAAiBgPPDyi0mF58TMvXCxeKMhMubWgaGsTHwPREQAeAgJ230wgAAAAAAAAAACIGA+X37ExbMJOS5x5hXMsoL9d5OP/vxhatS8OL5DGu5F8MCE1UAnIAAAAAACICAr4oBl12kFt1PhY+4eiccqm3Hoi9uuNtH3VZ3fEFR4bkCHf2LTAAAAAAAAA=").unwrap();
+
+        let sighashes: Vec<String> = compute_spend_sighashes(&spend_tx)
+            .unwrap()
+            .iter()
+            .map(|sighash| sighash.to_hex())
+            .collect();
+        assert_eq!(
+            sighashes,
+            vec![
+                "d0dbcb3f100d626714b430dc41c62e82f99bbc3109287899b44d386ea717c861",
+                "6e5527843a606b8683a6ec3a348fc8c222bf4da0d7785e5842625a4dbb99d441",
+            ]
+        );
+    }
+
+    // Sign this input of the Spend with the key derived at index 0 (as in generate_spend_tx) of
+    // each of these managers.
+    fn add_managers_signatures(
+        test_framework: &CosignerTestBuilder,
+        psbt: &mut Psbt,
+        input_index: usize,
+        sighash: &[u8; 32],
+        managers_privkeys: &[bip32::ExtendedPrivKey],
+    ) {
+        let sighash = secp256k1::Message::from_slice(sighash).unwrap();
+        for xpriv in managers_privkeys {
+            let privkey = xpriv
+                .derive_priv(
+                    test_framework.secp,
+                    &[bip32::ChildNumber::from_normal_idx(0).unwrap()],
+                )
+                .unwrap()
+                .private_key;
+            let pubkey = BitcoinPubkey::from_private_key(test_framework.secp, &privkey);
+            let mut sig = test_framework
+                .secp
+                .sign(&sighash, &privkey.key)
+                .serialize_der()
+                .to_vec();
+            sig.push(SigHashType::All as u8);
+            psbt.inputs[input_index].partial_sigs.insert(pubkey, sig);
+        }
+    }
+
+    #[test]
+    fn sign_message_require_manager_sigs() {
+        let mut test_framework = CosignerTestBuilder::new(3);
+        test_framework.config.require_manager_sigs = Some(2);
+        let managers_privkeys = test_framework.managers_privkeys.clone();
+
+        // Both inputs are signed by two managers, we sign.
+        let tx = test_framework.generate_spend_tx(&[
+            OutPoint::from_str(
+                "2b8930127e9dfd1bcdf35df2bc7f3b8cdbec083b1ae693f36b6305fccd1425da:0",
+            )
+            .unwrap(),
+            OutPoint::from_str(
+                "ceca4de398c63b29543f8346c09fd7522fd8661ce8bdc0e454e8d6ed8ad46a0d:1",
+            )
+            .unwrap(),
+        ]);
+        let sighashes = compute_spend_sighashes(&tx).unwrap();
+        let mut psbt = tx.into_psbt();
+        add_managers_signatures(
+            &test_framework,
+            &mut psbt,
+            0,
+            &sighashes[0],
+            &managers_privkeys[..2],
+        );
+        add_managers_signatures(
+            &test_framework,
+            &mut psbt,
+            1,
+            &sighashes[1],
+            &managers_privkeys[1..],
+        );
+        let tx = SpendTransaction::from_psbt_serialized(&encode::serialize(&psbt)).unwrap();
+        let SignResult { tx } = process_sign_message(
+            &test_framework.config,
+            SignRequest { tx },
+            &test_framework.keys,
+        )
+        .unwrap();
+        assert!(tx.is_some());
+
+        // The second input is only signed by a single manager, we refuse.
+        let tx = test_framework.generate_spend_tx(&[
+            OutPoint::from_str(
+                "0b38682347207cd79de33edf8897a75abe7d8799b194439150306773b6aef55a:189",
+            )
+            .unwrap(),
+            OutPoint::from_str(
+                "d907a6733fba14884d7de578d0536bf32c8fa96ec2dce9d04d2bcf8bddbd540a:1",
+            )
+            .unwrap(),
+        ]);
+        let sighashes = compute_spend_sighashes(&tx).unwrap();
+        let mut psbt = tx.into_psbt();
+        add_managers_signatures(
+            &test_framework,
+            &mut psbt,
+            0,
+            &sighashes[0],
+            &managers_privkeys,
+        );
+        add_managers_signatures(
+            &test_framework,
+            &mut psbt,
+            1,
+            &sighashes[1],
+            &managers_privkeys[..1],
+        );
+        let tx = SpendTransaction::from_psbt_serialized(&encode::serialize(&psbt)).unwrap();
+        let SignResult { tx: signed } = process_sign_message(
+            &test_framework.config,
+            SignRequest { tx: tx.clone() },
+            &test_framework.keys,
+        )
+        .unwrap();
+        assert!(signed.is_none());
+
+        // Signatures for another input's sighash don't count.
+        let mut psbt = tx.into_psbt();
+        add_managers_signatures(
+            &test_framework,
+            &mut psbt,
+            1,
+            &sighashes[0],
+            &managers_privkeys[1..],
+        );
+        let tx = SpendTransaction::from_psbt_serialized(&encode::serialize(&psbt)).unwrap();
+        let SignResult { tx } = process_sign_message(
+            &test_framework.config,
+            SignRequest { tx },
+            &test_framework.keys,
+        )
+        .unwrap();
+        assert!(tx.is_none());
+    }
+
+    #[test]
+    fn sign_message_verify_manager_sigs() {
+        let mut test_framework = CosignerTestBuilder::new(3);
+        test_framework.config.require_manager_sigs = Some(2);
+        test_framework.config.verify_manager_sigs = true;
+        // The third key in the descriptor isn't one of our managers (anymore).
+        test_framework.config.managers.truncate(2);
+        let managers_privkeys = test_framework.managers_privkeys.clone();
+        let mut config = test_framework.config.clone();
+        let signed_by = |outpoint: &str, signers: &[bip32::ExtendedPrivKey]| {
+            let tx = test_framework.generate_spend_tx(&[OutPoint::from_str(outpoint).unwrap()]);
+            let sighashes = compute_spend_sighashes(&tx).unwrap();
+            let mut psbt = tx.into_psbt();
+            add_managers_signatures(&test_framework, &mut psbt, 0, &sighashes[0], signers);
+            SpendTransaction::from_psbt_serialized(&encode::serialize(&psbt)).unwrap()
+        };
+
+        // Signed by two managers, we sign.
+        let tx = signed_by(
+            "2b8930127e9dfd1bcdf35df2bc7f3b8cdbec083b1ae693f36b6305fccd1425da:0",
+            &managers_privkeys[..2],
+        );
+        let SignResult { tx } =
+            process_sign_message(&config, SignRequest { tx }, &test_framework.keys).unwrap();
+        assert!(tx.is_some());
+
+        // Signed by a manager and a key of the Script which isn't one of our managers: it would
+        // pass without verification, but not with.
+        let tx = signed_by(
+            "ceca4de398c63b29543f8346c09fd7522fd8661ce8bdc0e454e8d6ed8ad46a0d:1",
+            &managers_privkeys[1..],
+        );
+        config.verify_manager_sigs = false;
+        assert!(matches!(
+            process_sign_request(
+                &config,
+                SignRequest { tx: tx.clone() },
+                &test_framework.keys,
+            )
+            .unwrap(),
+            SignDecision::Signed(..)
+        ));
+        let tx = signed_by(
+            "0b38682347207cd79de33edf8897a75abe7d8799b194439150306773b6aef55a:189",
+            &managers_privkeys[1..],
+        );
+        config.verify_manager_sigs = true;
+        assert!(matches!(
+            process_sign_request(&config, SignRequest { tx }, &test_framework.keys,).unwrap(),
+            SignDecision::Refused(SignRefusal::MissingManagerSigs)
+        ));
+    }
+
+    #[test]
+    fn sign_message_require_sender_sig() {
+        let mut test_framework = CosignerTestBuilder::new(3);
+        test_framework.config.require_sender_sig = true;
+        let managers_privkeys = test_framework.managers_privkeys.clone();
+        let mut config = test_framework.config.clone();
+        let managers_noise_keys: Vec<NoisePubkey> = test_framework
+            .config
+            .managers
+            .iter()
+            .map(|m| m.noise_key)
+            .collect();
+        let signed_by = |outpoint: &str, signers: &[bip32::ExtendedPrivKey]| {
+            let tx = test_framework.generate_spend_tx(&[OutPoint::from_str(outpoint).unwrap()]);
+            let sighashes = compute_spend_sighashes(&tx).unwrap();
+            let mut psbt = tx.into_psbt();
+            add_managers_signatures(&test_framework, &mut psbt, 0, &sighashes[0], signers);
+            SpendTransaction::from_psbt_serialized(&encode::serialize(&psbt)).unwrap()
+        };
+        let process = |config: &crate::config::Config,
+                       tx: SpendTransaction,
+                       sender: &NoisePubkey| {
+            process_manager_sign_request(config, SignRequest { tx }, &test_framework.keys, sender)
+                .unwrap()
+        };
+
+        // A manager presenting another manager's signed Spend is refused..
+        let tx = signed_by(
+            "2b8930127e9dfd1bcdf35df2bc7f3b8cdbec083b1ae693f36b6305fccd1425da:0",
+            &managers_privkeys[1..2],
+        );
+        assert!(matches!(
+            process(&config, tx.clone(), &managers_noise_keys[0]),
+            SignDecision::Refused(SignRefusal::MissingSenderSig)
+        ));
+        // .. as is an unsigned one, or one we don't know the sender of.
+        let unsigned = signed_by(
+            "ceca4de398c63b29543f8346c09fd7522fd8661ce8bdc0e454e8d6ed8ad46a0d:1",
+            &[],
+        );
+        assert!(matches!(
+            process(&config, unsigned, &managers_noise_keys[1]),
+            SignDecision::Refused(SignRefusal::MissingSenderSig)
+        ));
+        assert!(matches!(
+            process_sign_request(
+                &config,
+                SignRequest { tx: tx.clone() },
+                &test_framework.keys,
+            )
+            .unwrap(),
+            SignDecision::Refused(SignRefusal::MissingSenderSig)
+        ));
+
+        // But the manager which did sign it may present it
+        assert!(matches!(
+            process(&config, tx, &managers_noise_keys[1]),
+            SignDecision::Signed(..)
+        ));
+
+        // The check is off by default
+        config.require_sender_sig = false;
+        let unsigned = signed_by(
+            "0b38682347207cd79de33edf8897a75abe7d8799b194439150306773b6aef55a:189",
+            &[],
+        );
+        assert!(matches!(
+            process(&config, unsigned, &managers_noise_keys[0]),
+            SignDecision::Signed(..)
+        ));
+    }
+
+    #[test]
+    fn sign_message_blacklisted_outpoints() {
+        let mut test_framework = CosignerTestBuilder::new(3);
+        let blacklisted = OutPoint::from_str(
+            "2b8930127e9dfd1bcdf35df2bc7f3b8cdbec083b1ae693f36b6305fccd1425da:0",
+        )
+        .unwrap();
+        let file_blacklisted = OutPoint::from_str(
+            "ceca4de398c63b29543f8346c09fd7522fd8661ce8bdc0e454e8d6ed8ad46a0d:1",
+        )
+        .unwrap();
+        let clean = OutPoint::from_str(
+            "0b38682347207cd79de33edf8897a75abe7d8799b194439150306773b6aef55a:189",
+        )
+        .unwrap();
+
+        let mut blacklist_file = test_framework.config.data_dir.clone();
+        blacklist_file.push("blacklist");
+        std::fs::write(
+            &blacklist_file,
+            format!("# Compromised deposits\n\n{}\n", file_blacklisted),
+        )
+        .unwrap();
+        test_framework.config.blacklisted_outpoints = vec![blacklisted];
+        test_framework.config.blacklisted_outpoints_file = Some(blacklist_file.clone());
+        test_framework.config.load_blacklisted_outpoints().unwrap();
+
+        // A Spend touching a blacklisted outpoint, be it from the config or the file, is refused
+        for outpoints in &[[clean, blacklisted], [file_blacklisted, clean]] {
+            let tx = test_framework.generate_spend_tx(outpoints);
+            let SignResult { tx } = process_sign_message(
+                &test_framework.config,
+                SignRequest { tx },
+                &test_framework.keys,
+            )
+            .unwrap();
+            assert!(tx.is_none());
+        }
+
+        // If we can't read the file anymore, we keep the outpoints we last read from it
+        std::fs::remove_file(&blacklist_file).unwrap();
+        test_framework
+            .config
+            .load_blacklisted_outpoints()
+            .unwrap_err();
+        assert!(test_framework
+            .config
+            .blacklisted_outpoints()
+            .contains(&file_blacklisted));
+
+        // A Spend not touching any is signed (and the refusals above didn't mark any outpoint as
+        // signed)
+        let tx = test_framework.generate_spend_tx(&[clean]);
+        let SignResult { tx } = process_sign_message(
+            &test_framework.config,
+            SignRequest { tx },
+            &test_framework.keys,
+        )
+        .unwrap();
+        assert!(tx.is_some());
+    }
+
+    #[test]
+    fn sign_message_allowed_outpoints() {
+        let mut test_framework = CosignerTestBuilder::new(3);
+        let allowed = OutPoint::from_str(
+            "2b8930127e9dfd1bcdf35df2bc7f3b8cdbec083b1ae693f36b6305fccd1425da:0",
+        )
+        .unwrap();
+        let unknown = OutPoint::from_str(
+            "ceca4de398c63b29543f8346c09fd7522fd8661ce8bdc0e454e8d6ed8ad46a0d:1",
+        )
+        .unwrap();
+
+        let allowlist_file = test_framework.config.data_dir.join("allowlist");
+        std::fs::write(
+            &allowlist_file,
+            format!("# Provisioned deposits\n{}\n", allowed),
+        )
+        .unwrap();
+        test_framework.config.allowed_outpoints_file = Some(allowlist_file);
+        test_framework.config.load_allowed_outpoints().unwrap();
+
+        // A Spend touching an outpoint that isn't allowed is refused
+        for outpoints in &[&[unknown][..], &[allowed, unknown][..]] {
+            let tx = test_framework.generate_spend_tx(outpoints);
+            let decision = process_sign_request(
+                &test_framework.config,
+                SignRequest { tx },
+                &test_framework.keys,
+            )
+            .unwrap();
+            assert!(matches!(
+                decision,
+                SignDecision::Refused(SignRefusal::NotAllowedOutpoints(ref o)) if o == &[unknown]
+            ));
+        }
+
+        // One only spending allowed outpoints is signed
+        let tx = test_framework.generate_spend_tx(&[allowed]);
+        let SignResult { tx } = process_sign_message(
+            &test_framework.config,
+            SignRequest { tx },
+            &test_framework.keys,
+        )
+        .unwrap();
+        assert!(tx.is_some());
+    }
+
+    #[test]
+    fn sign_message_return_known_sigs() {
+        let mut test_framework = CosignerTestBuilder::new(3);
+        test_framework.config.return_known_sigs = true;
+        let db_path = test_framework.config.db_file();
+        let our_pubkey = *test_framework.keys.bitcoin_pubkey();
+        let (outpoint_a, outpoint_b, outpoint_c) = (
+            OutPoint::from_str(
+                "2b8930127e9dfd1bcdf35df2bc7f3b8cdbec083b1ae693f36b6305fccd1425da:0",
+            )
+            .unwrap(),
+            OutPoint::from_str(
+                "ceca4de398c63b29543f8346c09fd7522fd8661ce8bdc0e454e8d6ed8ad46a0d:1",
+            )
+            .unwrap(),
+            OutPoint::from_str(
+                "0b38682347207cd79de33edf8897a75abe7d8799b194439150306773b6aef55a:189",
+            )
+            .unwrap(),
+        );
+        let request = |tx: &SpendTransaction| {
+            process_sign_request(
+                &test_framework.config,
+                SignRequest { tx: tx.clone() },
+                &test_framework.keys,
+            )
+            .unwrap()
+        };
+
+        // Sign a Spend, and forget about one of its outpoints
+        let tx = test_framework.generate_spend_tx(&[outpoint_a, outpoint_b]);
+        assert!(matches!(request(&tx), SignDecision::Signed(_)));
+        assert!(db_forget_outpoint(&db_path, &outpoint_b).unwrap());
+
+        // We only return the signature we still have, and don't sign the other outpoint again
+        match request(&tx) {
+            SignDecision::KnownSignatures(known_sigs_tx, signed) => {
+                assert_eq!(signed, vec![outpoint_a]);
+                let psbt = known_sigs_tx.psbt();
+                assert!(psbt.inputs[0].partial_sigs.contains_key(&our_pubkey));
+                assert!(!psbt.inputs[1].partial_sigs.contains_key(&our_pubkey));
+            }
+            decision => panic!("Unexpected decision: {:?}", decision),
+        }
+        assert!(db_signed_outpoint(&db_path, &outpoint_b).unwrap().is_none());
+
+        // Our signature for the first outpoint is only valid for this Spend
+        let other_tx = test_framework.generate_spend_tx(&[outpoint_a, outpoint_c]);
+        match request(&other_tx) {
+            SignDecision::Refused(refusal) => {
+                assert_eq!(refusal, SignRefusal::AlreadySigned(vec![outpoint_a]))
+            }
+            decision => panic!("Unexpected decision: {:?}", decision),
+        }
+        assert!(db_signed_outpoint(&db_path, &outpoint_c).unwrap().is_none());
+
+        // And without the flag, we just refuse
+        let mut config = test_framework.config.clone();
+        config.return_known_sigs = false;
+        assert!(matches!(
+            process_sign_request(&config, SignRequest { tx }, &test_framework.keys,).unwrap(),
+            SignDecision::Refused(SignRefusal::AlreadySigned(_))
+        ));
+    }
+
+    #[test]
+    fn sign_message_allowed_destinations() {
+        let mut test_framework = CosignerTestBuilder::new(3);
+        let outpoint = OutPoint::from_str(
+            "2b8930127e9dfd1bcdf35df2bc7f3b8cdbec083b1ae693f36b6305fccd1425da:0",
+        )
+        .unwrap();
+        let allowed = Address::from_str("bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4").unwrap();
+        let unknown = Address::from_str("bc1qar0srrr7xfkvy5l643lydnw9re59gtzzwf5mdq").unwrap();
+
+        let destinations_file = test_framework.config.data_dir.join("destinations");
+        std::fs::write(&destinations_file, format!("{}\n", allowed)).unwrap();
+        test_framework.config.allowed_destinations_file = Some(destinations_file);
+        test_framework.config.load_allowed_destinations().unwrap();
+
+        // Paying to an address that isn't allowed is refused
+        let tx = test_framework.generate_spend_tx_to(&[outpoint], unknown.script_pubkey());
+        let decision = process_sign_request(
+            &test_framework.config,
+            SignRequest { tx },
+            &test_framework.keys,
+        )
+        .unwrap();
+        assert!(matches!(
+            decision,
+            SignDecision::Refused(SignRefusal::NotAllowedDestinations)
+        ));
+
+        // Paying to an allowed one is signed, the CPFP output doesn't need to be listed
+        let tx = test_framework.generate_spend_tx_to(&[outpoint], allowed.script_pubkey());
+        assert_eq!(tx.tx().output.len(), 2);
+        let SignResult { tx } = process_sign_message(
+            &test_framework.config,
+            SignRequest { tx },
+            &test_framework.keys,
+        )
+        .unwrap();
+        assert!(tx.is_some());
+    }
+
+    #[test]
+    fn sign_message_unvault_csv() {
+        let mut test_framework = CosignerTestBuilder::new(3);
+        let outpoints = [
+            OutPoint::from_str(
+                "2b8930127e9dfd1bcdf35df2bc7f3b8cdbec083b1ae693f36b6305fccd1425da:0",
+            )
+            .unwrap(),
+            OutPoint::from_str(
+                "ceca4de398c63b29543f8346c09fd7522fd8661ce8bdc0e454e8d6ed8ad46a0d:1",
+            )
+            .unwrap(),
+        ];
+        let tx = test_framework.generate_spend_tx(&outpoints);
+        assert_eq!(
+            script_csv(tx.psbt().inputs[0].witness_script.as_ref().unwrap()),
+            Some(12)
+        );
+
+        // The builder's vaults are locked for 12 blocks, not 18
+        test_framework.config.unvault_csv = Some(18);
+        let decision = process_sign_request(
+            &test_framework.config,
+            SignRequest { tx: tx.clone() },
+            &test_framework.keys,
+        )
+        .unwrap();
+        assert!(matches!(
+            decision,
+            SignDecision::Refused(SignRefusal::CsvMismatch(ref o)) if o == &outpoints
+        ));
+
+        // An input whose nSequence doesn't match its Script's CSV is refused too
+        test_framework.config.unvault_csv = Some(12);
+        let mut psbt = tx.clone().into_psbt();
+        psbt.global.unsigned_tx.input[1].sequence = 6;
+        let tweaked_tx = SpendTransaction::from_psbt_serialized(&encode::serialize(&psbt)).unwrap();
+        let decision = process_sign_request(
+            &test_framework.config,
+            SignRequest { tx: tweaked_tx },
+            &test_framework.keys,
+        )
+        .unwrap();
+        assert!(matches!(
+            decision,
+            SignDecision::Refused(SignRefusal::CsvMismatch(ref o)) if o == &[outpoints[1]]
+        ));
+
+        // The untouched one is signed
+        let SignResult { tx } = process_sign_message(
+            &test_framework.config,
+            SignRequest { tx },
+            &test_framework.keys,
+        )
+        .unwrap();
+        assert!(tx.is_some());
+    }
+
+    #[test]
+    fn signed_psbt_reconstruction() {
+        let test_framework = CosignerTestBuilder::new(3);
+        let db_path = test_framework.config.db_file();
+        let our_pubkey = *test_framework.keys.bitcoin_pubkey();
+        let outpoints = [
+            OutPoint::from_str(
+                "2b8930127e9dfd1bcdf35df2bc7f3b8cdbec083b1ae693f36b6305fccd1425da:0",
+            )
+            .unwrap(),
+            OutPoint::from_str(
+                "ceca4de398c63b29543f8346c09fd7522fd8661ce8bdc0e454e8d6ed8ad46a0d:1",
+            )
+            .unwrap(),
+        ];
+        let unsigned_tx = test_framework.generate_spend_tx(&outpoints);
+
+        // Nothing to reconstruct from yet
+        assert!(matches!(
+            reconstruct_signed_psbt(
+                &db_path,
+                unsigned_tx.clone(),
+                &our_pubkey,
+                test_framework.secp
+            ),
+            Err(SignProcessingError::NotSigned(o)) if o == outpoints[0]
+        ));
+
+        let SignResult { tx: signed_tx } = process_sign_message(
+            &test_framework.config,
+            SignRequest {
+                tx: unsigned_tx.clone(),
+            },
+            &test_framework.keys,
+        )
+        .unwrap();
+        let signed_tx = signed_tx.unwrap();
+
+        // We get the very same PSBT as the one we answered, with valid signatures
+        let reconstructed = reconstruct_signed_psbt(
+            &db_path,
+            unsigned_tx.clone(),
+            &our_pubkey,
+            test_framework.secp,
+        )
+        .unwrap();
+        assert_eq!(reconstructed, signed_tx);
+        let sighashes = compute_spend_sighashes(&unsigned_tx).unwrap();
+        for (psbtin, sighash) in reconstructed.psbt().inputs.iter().zip(sighashes.iter()) {
+            let sig = psbtin.partial_sigs.get(&our_pubkey).unwrap();
+            let sig = secp256k1::Signature::from_der(&sig[..sig.len() - 1]).unwrap();
+            test_framework
+                .secp
+                .verify(
+                    &secp256k1::Message::from_slice(sighash).unwrap(),
+                    &sig,
+                    &our_pubkey.key,
+                )
+                .unwrap();
+        }
+
+        // Another Spend of the same outpoints can't be reconstructed
+        let other_tx = test_framework.generate_spend_tx(&outpoints);
+        assert!(matches!(
+            reconstruct_signed_psbt(&db_path, other_tx, &our_pubkey, test_framework.secp),
+            Err(SignProcessingError::SignatureMismatch)
+        ));
+    }
+
+    #[test]
+    fn sign_message_min_relay_feerate() {
+        let mut test_framework = CosignerTestBuilder::new(3);
+
+        // The Spends of the test framework pay 50k sats of fees per input, that's way above
+        // 1sat/vb..
+        test_framework.config.min_relay_feerate = Some(1);
+        let tx = test_framework.generate_spend_tx(&[OutPoint::from_str(
+            "2b8930127e9dfd1bcdf35df2bc7f3b8cdbec083b1ae693f36b6305fccd1425da:0",
+        )
+        .unwrap()]);
+        let SignResult { tx } = process_sign_message(
+            &test_framework.config,
+            SignRequest { tx },
+            &test_framework.keys,
+        )
+        .unwrap();
+        assert!(tx.is_some());
+
+        // .. but below 1000sat/vb.
+        test_framework.config.min_relay_feerate = Some(1_000);
+        let tx = test_framework.generate_spend_tx(&[OutPoint::from_str(
+            "ceca4de398c63b29543f8346c09fd7522fd8661ce8bdc0e454e8d6ed8ad46a0d:1",
+        )
+        .unwrap()]);
+        let SignResult { tx } = process_sign_message(
+            &test_framework.config,
+            SignRequest { tx },
+            &test_framework.keys,
+        )
+        .unwrap();
+        assert!(tx.is_none());
+    }
+
+    #[test]
+    fn modified_outputs_detection() {
+        let test_framework = CosignerTestBuilder::new(3);
+        let db_path = test_framework.config.db_file();
+        let tx = test_framework.generate_spend_tx(&[
+            OutPoint::from_str(
+                "2b8930127e9dfd1bcdf35df2bc7f3b8cdbec083b1ae693f36b6305fccd1425da:0",
+            )
+            .unwrap(),
+            OutPoint::from_str(
+                "ceca4de398c63b29543f8346c09fd7522fd8661ce8bdc0e454e8d6ed8ad46a0d:1",
+            )
+            .unwrap(),
+        ]);
+        let SignResult { tx: signed } = process_sign_message(
+            &test_framework.config,
+            SignRequest { tx: tx.clone() },
+            &test_framework.keys,
+        )
+        .unwrap();
+        assert!(signed.is_some());
+
+        // Same inputs, same outputs
+        assert!(!outputs_changed(&db_path, &tx).unwrap());
+
+        // Same inputs in another order, same outputs
+        let mut psbt = tx.clone().into_psbt();
+        psbt.global.unsigned_tx.input.swap(0, 1);
+        psbt.inputs.swap(0, 1);
+        let swapped_tx = SpendTransaction::from_psbt_serialized(&encode::serialize(&psbt)).unwrap();
+        assert!(!outputs_changed(&db_path, &swapped_tx).unwrap());
+
+        // Same inputs, different outputs. We don't sign it (the signatures we have are not valid
+        // for it) but we notice it.
+        let mut psbt = tx.into_psbt();
+        psbt.global.unsigned_tx.output[0].value -= 10_000;
+        let modified_tx =
+            SpendTransaction::from_psbt_serialized(&encode::serialize(&psbt)).unwrap();
+        assert!(outputs_changed(&db_path, &modified_tx).unwrap());
+        let SignResult { tx: signed } = process_sign_message(
+            &test_framework.config,
+            SignRequest { tx: modified_tx },
+            &test_framework.keys,
+        )
+        .unwrap();
+        assert!(signed.is_none());
+    }
+
+    #[test]
+    fn signatures_checking() {
+        let test_framework = CosignerTestBuilder::new(3);
+        let our_pubkey = *test_framework.keys.bitcoin_pubkey();
+        let tx = test_framework.generate_spend_tx(&[
+            OutPoint::from_str(
+                "2b8930127e9dfd1bcdf35df2bc7f3b8cdbec083b1ae693f36b6305fccd1425da:0",
+            )
+            .unwrap(),
+            OutPoint::from_str(
+                "ceca4de398c63b29543f8346c09fd7522fd8661ce8bdc0e454e8d6ed8ad46a0d:1",
+            )
+            .unwrap(),
+        ]);
+        assert_eq!(
+            check_signatures(&tx, &our_pubkey, test_framework.secp).unwrap(),
+            vec![SignatureCheck::Missing, SignatureCheck::Missing]
+        );
+
+        let SignResult { tx } = process_sign_message(
+            &test_framework.config,
+            SignRequest { tx },
+            &test_framework.keys,
+        )
+        .unwrap();
+        let tx = tx.unwrap();
+        assert_eq!(
+            check_signatures(&tx, &our_pubkey, test_framework.secp).unwrap(),
+            vec![SignatureCheck::Valid, SignatureCheck::Valid]
+        );
+
+        // Under another pubkey, there is no signature
+        let other_pubkey = bitcoin_pubkey(
+            test_framework.secp,
+            &secp256k1::SecretKey::from_slice(&[1; 32]).unwrap(),
+        );
+        assert_eq!(
+            check_signatures(&tx, &other_pubkey, test_framework.secp).unwrap(),
+            vec![SignatureCheck::Missing, SignatureCheck::Missing]
+        );
+
+        // Tamper with the signature of the second input
+        let mut psbt = tx.into_psbt();
+        let first_sig = psbt.inputs[0].partial_sigs[&our_pubkey].clone();
+        psbt.inputs[1].partial_sigs.insert(our_pubkey, first_sig);
+        let tx = SpendTransaction::from_psbt_serialized(&encode::serialize(&psbt)).unwrap();
+        assert_eq!(
+            check_signatures(&tx, &our_pubkey, test_framework.secp).unwrap(),
+            vec![SignatureCheck::Valid, SignatureCheck::Invalid]
+        );
+
+        // Tamper with the transaction
+        let mut psbt = tx.into_psbt();
+        psbt.global.unsigned_tx.output[0].value -= 1;
+        let tx = SpendTransaction::from_psbt_serialized(&encode::serialize(&psbt)).unwrap();
+        assert_eq!(
+            check_signatures(&tx, &our_pubkey, test_framework.secp).unwrap(),
+            vec![SignatureCheck::Invalid, SignatureCheck::Invalid]
+        );
+    }
+
+    #[test]
+    fn sign_refusal_reasons() {
+        let mut test_framework = CosignerTestBuilder::new(2);
+        let (outpoint_a, outpoint_b, blacklisted) = (
+            OutPoint::from_str(
+                "2b8930127e9dfd1bcdf35df2bc7f3b8cdbec083b1ae693f36b6305fccd1425da:0",
+            )
+            .unwrap(),
+            OutPoint::from_str(
+                "ceca4de398c63b29543f8346c09fd7522fd8661ce8bdc0e454e8d6ed8ad46a0d:1",
+            )
+            .unwrap(),
+            OutPoint::from_str(
+                "0b38682347207cd79de33edf8897a75abe7d8799b194439150306773b6aef55a:189",
+            )
+            .unwrap(),
+        );
+        test_framework.config.blacklisted_outpoints = vec![blacklisted];
+        let process = |tx: SpendTransaction| {
+            process_sign_request(
+                &test_framework.config,
+                SignRequest { tx },
+                &test_framework.keys,
+            )
+            .unwrap()
+        };
+
+        match process(test_framework.generate_spend_tx(&[outpoint_a, blacklisted])) {
+            SignDecision::Refused(SignRefusal::BlacklistedOutpoints(outpoints)) => {
+                assert_eq!(outpoints, vec![blacklisted])
+            }
+            d => panic!("Unexpected decision: {:?}", d),
+        }
+
+        let tx = test_framework.generate_spend_tx(&[outpoint_a]);
+        assert!(matches!(process(tx.clone()), SignDecision::Signed(_)));
+
+        // Some outpoints were signed already
+        match process(test_framework.generate_spend_tx(&[outpoint_b, outpoint_a])) {
+            SignDecision::Refused(refusal) => {
+                assert_eq!(refusal, SignRefusal::AlreadySigned(vec![outpoint_a]));
+                assert_eq!(refusal.code(), "already_signed");
+                assert_eq!(refusal.offending_outpoints(), &[outpoint_a]);
+            }
+            d => panic!("Unexpected decision: {:?}", d),
+        }
+
+        // All were signed already, but for another Spend
+        let mut psbt = tx.clone().into_psbt();
+        psbt.global.unsigned_tx.output[0].value -= 1;
+        let modified_tx =
+            SpendTransaction::from_psbt_serialized(&encode::serialize(&psbt)).unwrap();
+        match process(modified_tx) {
+            SignDecision::Refused(refusal) => {
+                assert_eq!(refusal, SignRefusal::ModifiedSpend(vec![outpoint_a]))
+            }
+            d => panic!("Unexpected decision: {:?}", d),
+        }
+
+        // There is nothing left to sign
+        let mut psbt = tx.into_psbt();
+        psbt.inputs[0].final_script_witness = Some(vec![vec![0x01]]);
+        psbt.inputs[0].witness_script = None;
+        let finalized_tx =
+            SpendTransaction::from_psbt_serialized(&encode::serialize(&psbt)).unwrap();
+        assert!(finalized_tx.is_finalized());
+        match process(finalized_tx) {
+            SignDecision::Refused(refusal) => {
+                assert_eq!(refusal, SignRefusal::Finalized);
+                assert_eq!(refusal.code(), "finalized");
+            }
+            d => panic!("Unexpected decision: {:?}", d),
+        }
+    }
+
+    // The invariants the fuzz target checks: a refusal for signed outpoints names exactly those
+    // we have in database.
+    #[test]
+    fn already_signed_refusal_matches_database() {
+        let test_framework = CosignerTestBuilder::new(2);
+        let db_path = test_framework.config.db_file();
+        let outpoints: Vec<OutPoint> = (0..4)
+            .map(|vout| {
+                OutPoint::from_str(&format!(
+                    "4c6bad9a8a1f7d2e9685ba59ed84a5fe8aee8d3ffd1810ac2fd60d1e5f2f7d4b:{}",
+                    vout
+                ))
+                .unwrap()
+            })
+            .collect();
+        let process = |outpoints: &[OutPoint]| {
+            process_sign_request(
+                &test_framework.config,
+                SignRequest {
+                    tx: test_framework.generate_spend_tx(outpoints),
+                },
+                &test_framework.keys,
+            )
+            .unwrap()
+        };
+        let n_signed = |outpoints: &[OutPoint]| {
+            outpoints
+                .iter()
+                .filter(|o| db_signed_outpoint(&db_path, o).unwrap().is_some())
+                .count()
+        };
+
+        assert!(matches!(process(&outpoints[..2]), SignDecision::Signed(_)));
+        assert_eq!(n_signed(&outpoints), 2);
+
+        // 2 out of 4 were signed: exactly those are reported, and we don't sign the 2 others
+        match process(&outpoints) {
+            SignDecision::Refused(SignRefusal::AlreadySigned(signed)) => {
+                assert_eq!(signed, outpoints[..2].to_vec());
+                assert_eq!(n_signed(&outpoints), signed.len());
+            }
+            d => panic!("Unexpected decision: {:?}", d),
+        }
+
+        // 1 out of 2
+        match process(&outpoints[1..3]) {
+            SignDecision::Refused(SignRefusal::AlreadySigned(signed)) => {
+                assert_eq!(signed, vec![outpoints[1]]);
+                assert_eq!(n_signed(&outpoints[1..3]), 1);
+            }
+            d => panic!("Unexpected decision: {:?}", d),
+        }
+
+        // None of them
+        assert!(matches!(process(&outpoints[2..]), SignDecision::Signed(_)));
+        assert_eq!(n_signed(&outpoints), 4);
+    }
+
+    #[test]
+    fn sign_message_max_spend_inputs() {
+        let mut test_framework = CosignerTestBuilder::new(2);
+        test_framework.config.max_spend_inputs = 2;
+        let outpoints: Vec<OutPoint> = [
+            "2b8930127e9dfd1bcdf35df2bc7f3b8cdbec083b1ae693f36b6305fccd1425da:0",
+            "ceca4de398c63b29543f8346c09fd7522fd8661ce8bdc0e454e8d6ed8ad46a0d:1",
+            "0b38682347207cd79de33edf8897a75abe7d8799b194439150306773b6aef55a:189",
+        ]
+        .iter()
+        .map(|o| OutPoint::from_str(o).unwrap())
+        .collect();
+
+        let tx = test_framework.generate_spend_tx(&outpoints);
+        let err = process_sign_message(
+            &test_framework.config,
+            SignRequest { tx },
+            &test_framework.keys,
+        )
+        .unwrap_err();
+        assert!(matches!(err, SignProcessingError::TooManyInputs(3, 2)));
+        // Nothing was recorded
+        for outpoint in outpoints.iter() {
+            assert!(
+                db_signed_outpoint(&test_framework.config.db_file(), outpoint)
+                    .unwrap()
+                    .is_none()
+            );
+        }
+
+        let tx = test_framework.generate_spend_tx(&outpoints[..2]);
+        let SignResult { tx } = process_sign_message(
+            &test_framework.config,
+            SignRequest { tx },
+            &test_framework.keys,
+        )
+        .unwrap();
+        assert!(tx.is_some());
+    }
+
+    #[test]
+    fn sign_message_foreign_script() {
+        let mut test_framework = CosignerTestBuilder::new(2);
+        let outpoint = OutPoint::from_str(
+            "2b8930127e9dfd1bcdf35df2bc7f3b8cdbec083b1ae693f36b6305fccd1425da:0",
+        )
+        .unwrap();
+        let keys = test_framework.keys.clone();
+        let process = |config: &crate::config::Config, tx: SpendTransaction| {
+            process_sign_message(config, SignRequest { tx }, &keys)
+        };
+
+        // A Spend of a vault another cosigner is part of, but not us
+        let other_cosigner = CosignerTestBuilder::new(2);
+        let tx = other_cosigner.generate_spend_tx(&[outpoint]);
+        let err = process(&test_framework.config, tx.clone()).unwrap_err();
+        assert!(matches!(err, SignProcessingError::ForeignScript(0)));
+
+        // A Spend of a vault we are part of but with other managers and stakeholders
+        let other_vaults = CosignerTestBuilder::with_keys(2, test_framework.keys.clone());
+        let err = process(
+            &test_framework.config,
+            other_vaults.generate_spend_tx(&[outpoint]),
+        )
+        .unwrap_err();
+        assert!(matches!(err, SignProcessingError::ForeignScript(0)));
+
+        // A Script which contains our key, but isn't an Unvault one
+        let mut psbt = test_framework.generate_spend_tx(&[outpoint]).into_psbt();
+        let script = Builder::new()
+            .push_key(test_framework.keys.bitcoin_pubkey())
+            .push_opcode(opcodes::all::OP_CHECKSIG)
+            .into_script();
+        psbt.inputs[0].witness_utxo.as_mut().unwrap().script_pubkey = script.to_v0_p2wsh();
+        psbt.inputs[0].witness_script = Some(script);
+        let p2pk_tx = SpendTransaction::from_psbt_serialized(&encode::serialize(&psbt)).unwrap();
+        let err = process(&test_framework.config, p2pk_tx).unwrap_err();
+        assert!(matches!(err, SignProcessingError::ForeignScript(0)));
+        assert!(
+            db_signed_outpoint(&test_framework.config.db_file(), &outpoint)
+                .unwrap()
+                .is_none()
+        );
+
+        // Without the descriptor, we have no way to tell
+        test_framework.config.unvault_descriptor = None;
+        let SignResult { tx } = process(&test_framework.config, tx).unwrap();
+        assert!(tx.is_some());
+    }
+
+    #[test]
+    fn sign_message_feerate_range() {
+        let test_framework = CosignerTestBuilder::new(2);
+        let outpoints: Vec<OutPoint> = [
+            "2b8930127e9dfd1bcdf35df2bc7f3b8cdbec083b1ae693f36b6305fccd1425da:0",
+            "ceca4de398c63b29543f8346c09fd7522fd8661ce8bdc0e454e8d6ed8ad46a0d:1",
+            "0b38682347207cd79de33edf8897a75abe7d8799b194439150306773b6aef55a:189",
+        ]
+        .iter()
+        .map(|o| OutPoint::from_str(o).unwrap())
+        .collect();
+        let process = |config: &crate::config::Config, tx: SpendTransaction| {
+            process_sign_request(config, SignRequest { tx }, &test_framework.keys).unwrap()
+        };
+
+        // The feerate of the Spends of the test framework, rounded down and up
+        let tx = test_framework.generate_spend_tx(&outpoints[..1]);
+        let (fees, weight) = (tx.fees().as_sat() * 4, tx.max_weight());
+        let (feerate_floor, feerate_ceil) = (fees / weight, fees.div_ceil(weight));
+        let mut config = test_framework.config.clone();
+
+        // Just below the minimum
+        config.min_relay_feerate = Some(feerate_floor + 1);
+        assert!(matches!(
+            process(&config, tx.clone()),
+            SignDecision::Refused(SignRefusal::FeerateTooLow)
+        ));
+        assert!(db_signed_outpoint(&config.db_file(), &outpoints[0])
+            .unwrap()
+            .is_none());
+        // Just above it
+        config.min_relay_feerate = Some(feerate_floor);
+        assert!(matches!(process(&config, tx), SignDecision::Signed(..)));
+
+        // Just above the maximum
+        config.min_relay_feerate = None;
+        config.max_feerate = Some(feerate_ceil - 1);
+        let tx = test_framework.generate_spend_tx(&outpoints[1..2]);
+        assert!(matches!(
+            process(&config, tx.clone()),
+            SignDecision::Refused(SignRefusal::FeerateTooHigh)
+        ));
+        assert!(db_signed_outpoint(&config.db_file(), &outpoints[1])
+            .unwrap()
+            .is_none());
+        // Just below it
+        config.max_feerate = Some(feerate_ceil);
+        assert!(matches!(process(&config, tx), SignDecision::Signed(..)));
+
+        // Exactly on both bounds
+        if feerate_floor == feerate_ceil {
+            config.min_relay_feerate = Some(feerate_floor);
+            let tx = test_framework.generate_spend_tx(&outpoints[2..3]);
+            assert!(matches!(process(&config, tx), SignDecision::Signed(..)));
+        }
+    }
+
+    #[test]
+    fn sign_message_unexpected_signature() {
+        let test_framework = CosignerTestBuilder::new(2);
+        let outpoint = OutPoint::from_str(
+            "2b8930127e9dfd1bcdf35df2bc7f3b8cdbec083b1ae693f36b6305fccd1425da:0",
+        )
+        .unwrap();
+        let our_pubkey = *test_framework.keys.bitcoin_pubkey();
+
+        // A Spend carrying a signature for our key, that we never made. This used to panic.
+        let tx = test_framework.generate_spend_tx(&[outpoint]);
+        let mut psbt = tx.clone().into_psbt();
+        let mut sig = test_framework
+            .secp
+            .sign(
+                &secp256k1::Message::from_slice(&[0x01; 32]).unwrap(),
+                test_framework.keys.bitcoin_privkey(),
+            )
+            .serialize_der()
+            .to_vec();
+        sig.push(SigHashType::All as u8);
+        psbt.inputs[0].partial_sigs.insert(our_pubkey, sig);
+        let crafted_tx = SpendTransaction::from_psbt_serialized(&encode::serialize(&psbt)).unwrap();
+        let err = process_sign_message(
+            &test_framework.config,
+            SignRequest { tx: crafted_tx },
+            &test_framework.keys,
+        )
+        .unwrap_err();
+        assert!(matches!(err, SignProcessingError::UnexpectedSignature(0)));
+
+        // We didn't record, nor reserve, anything
+        assert!(
+            db_signed_outpoint(&test_framework.config.db_file(), &outpoint)
+                .unwrap()
+                .is_none()
+        );
+        let SignResult { tx } = process_sign_message(
+            &test_framework.config,
+            SignRequest { tx },
+            &test_framework.keys,
+        )
+        .unwrap();
+        assert!(tx.is_some());
+    }
+
+    #[test]
+    fn sign_message_signature_cache() {
+        let test_framework = CosignerTestBuilder::new(2);
+        let outpoints: Vec<OutPoint> = [
+            "2b8930127e9dfd1bcdf35df2bc7f3b8cdbec083b1ae693f36b6305fccd1425da:0",
+            "ceca4de398c63b29543f8346c09fd7522fd8661ce8bdc0e454e8d6ed8ad46a0d:1",
+            "0b38682347207cd79de33edf8897a75abe7d8799b194439150306773b6aef55a:189",
+        ]
+        .iter()
+        .map(|o| OutPoint::from_str(o).unwrap())
+        .collect();
+        let tx = test_framework.generate_spend_tx(&outpoints);
+        let sign = |config: &crate::config::Config| {
+            let n_opens = N_OPENS.with(|n| n.get());
+            let SignResult { tx: signed_tx } =
+                process_sign_message(config, SignRequest { tx: tx.clone() }, &test_framework.keys)
+                    .unwrap();
+            (
+                signed_tx.unwrap().as_psbt_serialized(),
+                N_OPENS.with(|n| n.get()) - n_opens,
+            )
+        };
+
+        let (signed, _) = sign(&test_framework.config);
+        assert_eq!(SIGNATURE_CACHE.len(&test_framework.config.db_file()), 3);
+        // A retry is answered from the cache
+        let (cached, cached_opens) = sign(&test_framework.config);
+        assert_eq!(cached, signed);
+        // And the same as what's in the database
+        let mut config = test_framework.config.clone();
+        config.signature_cache_size = 0;
+        let (uncached, uncached_opens) = sign(&config);
+        assert_eq!(uncached, signed);
+        assert_eq!(cached_opens + outpoints.len(), uncached_opens);
+
+        // A miss falls back to the database
+        SIGNATURE_CACHE.invalidate(&test_framework.config.db_file());
+        assert_eq!(
+            sign(&test_framework.config),
+            (signed.clone(), uncached_opens)
+        );
+        assert_eq!(sign(&test_framework.config), (signed, cached_opens));
+    }
+
+    #[test]
+    fn sign_message_partial_signing() {
+        let test_framework = CosignerTestBuilder::new(2);
+        let (ours, theirs) = (
+            OutPoint::from_str(
+                "2b8930127e9dfd1bcdf35df2bc7f3b8cdbec083b1ae693f36b6305fccd1425da:0",
+            )
+            .unwrap(),
+            OutPoint::from_str(
+                "ceca4de398c63b29543f8346c09fd7522fd8661ce8bdc0e454e8d6ed8ad46a0d:1",
+            )
+            .unwrap(),
+        );
+        let our_pubkey = *test_framework.keys.bitcoin_pubkey();
+
+        // A Spend of one of our vaults along with one of another cosigner
+        let mut psbt = test_framework.generate_spend_tx(&[ours]).into_psbt();
+        let foreign_psbt = CosignerTestBuilder::new(2)
+            .generate_spend_tx(&[theirs])
+            .into_psbt();
+        psbt.global
+            .unsigned_tx
+            .input
+            .push(foreign_psbt.global.unsigned_tx.input[0].clone());
+        psbt.inputs.push(foreign_psbt.inputs[0].clone());
+        let mixed_tx = SpendTransaction::from_psbt_serialized(&encode::serialize(&psbt)).unwrap();
+        let process = |config: &crate::config::Config| {
+            process_sign_message(
+                config,
+                SignRequest {
+                    tx: mixed_tx.clone(),
+                },
+                &test_framework.keys,
+            )
+        };
+
+        // All or nothing by default
+        let mut config = test_framework.config.clone();
+        let err = process(&config).unwrap_err();
+        assert!(matches!(err, SignProcessingError::ForeignScript(1)));
+
+        // Otherwise we only sign and record our input
+        config.partial_signing = true;
+        let SignResult { tx } = process(&config).unwrap();
+        let signed_psbt = tx.unwrap().into_psbt();
+        assert!(signed_psbt.inputs[0].partial_sigs.contains_key(&our_pubkey));
+        assert!(!signed_psbt.inputs[1].partial_sigs.contains_key(&our_pubkey));
+        let db_path = config.db_file();
+        assert!(db_signed_outpoint(&db_path, &ours).unwrap().is_some());
+        assert!(db_signed_outpoint(&db_path, &theirs).unwrap().is_none());
+
+        // A retry gets the very same signature
+        let SignResult { tx } = process(&config).unwrap();
+        assert_eq!(tx.unwrap().into_psbt(), signed_psbt);
+
+        // But we never sign a Spend with none of our inputs
+        let foreign_tx =
+            SpendTransaction::from_psbt_serialized(&encode::serialize(&foreign_psbt)).unwrap();
+        let err = process_sign_message(
+            &config,
+            SignRequest { tx: foreign_tx },
+            &test_framework.keys,
+        )
+        .unwrap_err();
+        assert!(matches!(err, SignProcessingError::ForeignScript(0)));
+    }
+
+    #[test]
+    fn sign_message_dry_run() {
+        let mut test_framework = CosignerTestBuilder::new(2);
+        test_framework.config.dry_run = true;
+        let outpoints = [
+            OutPoint::from_str(
+                "2b8930127e9dfd1bcdf35df2bc7f3b8cdbec083b1ae693f36b6305fccd1425da:0",
+            )
+            .unwrap(),
+            OutPoint::from_str(
+                "ceca4de398c63b29543f8346c09fd7522fd8661ce8bdc0e454e8d6ed8ad46a0d:1",
+            )
+            .unwrap(),
+        ];
+        let our_pubkey = *test_framework.keys.bitcoin_pubkey();
+
+        // Two different Spends of the same outpoints
+        let spend_tx = test_framework.generate_spend_tx(&outpoints);
+        let mut psbt = spend_tx.clone().into_psbt();
+        psbt.global.unsigned_tx.output[0].value -= 1_000;
+        let other_spend_tx =
+            SpendTransaction::from_psbt_serialized(&encode::serialize(&psbt)).unwrap();
+        assert_ne!(spend_tx, other_spend_tx);
+
+        // Both get fully signed, as we record nothing
+        for tx in [spend_tx, other_spend_tx] {
+            let SignResult { tx } = process_sign_message(
+                &test_framework.config,
+                SignRequest { tx },
+                &test_framework.keys,
+            )
+            .unwrap();
+            let signed_psbt = tx.expect("Dry run must sign").into_psbt();
+            for psbtin in signed_psbt.inputs.iter() {
+                assert!(psbtin.partial_sigs.contains_key(&our_pubkey));
+            }
+        }
+        let db_path = test_framework.config.db_file();
+        for outpoint in outpoints.iter() {
+            assert!(db_signed_outpoint(&db_path, outpoint).unwrap().is_none());
+        }
+        assert_eq!(SIGNATURE_CACHE.len(&db_path), 0);
+    }
+
+    #[test]
+    fn sign_message_read_only() {
+        let mut test_framework = CosignerTestBuilder::new(2);
+        let db_path = test_framework.config.db_file();
+        let (signed, fresh) = (
+            OutPoint::from_str(
+                "2b8930127e9dfd1bcdf35df2bc7f3b8cdbec083b1ae693f36b6305fccd1425da:0",
+            )
+            .unwrap(),
+            OutPoint::from_str(
+                "ceca4de398c63b29543f8346c09fd7522fd8661ce8bdc0e454e8d6ed8ad46a0d:1",
+            )
+            .unwrap(),
+        );
+        // The primary signed this one before we got its database
+        let signed_tx = test_framework.generate_spend_tx(&[signed]);
+        let SignResult { tx } = process_sign_message(
+            &test_framework.config,
+            SignRequest {
+                tx: signed_tx.clone(),
+            },
+            &test_framework.keys,
+        )
+        .unwrap();
+        assert!(tx.is_some());
+
+        test_framework.config.read_only = true;
+        db_set_read_only(&db_path);
+        let process = |tx: SpendTransaction| {
+            process_sign_request(
+                &test_framework.config,
+                SignRequest { tx },
+                &test_framework.keys,
+            )
+            .unwrap()
+        };
+
+        // We never sign, be it a fresh Spend or one the primary signed already
+        for tx in [test_framework.generate_spend_tx(&[fresh]), signed_tx] {
+            match process(tx) {
+                SignDecision::Refused(refusal) => {
+                    assert_eq!(refusal, SignRefusal::ReadOnly);
+                    assert_eq!(refusal.code(), "read_only");
+                }
+                d => panic!("Unexpected decision: {:?}", d),
+            }
+        }
+        // But refuse the others as the primary would
+        match process(test_framework.generate_spend_tx(&[fresh, signed])) {
+            SignDecision::Refused(refusal) => {
+                assert_eq!(refusal, SignRefusal::AlreadySigned(vec![signed]))
+            }
+            d => panic!("Unexpected decision: {:?}", d),
+        }
+
+        // Nothing was inserted, and nothing could be
+        assert_eq!(db_count_signed_outpoints(&db_path).unwrap(), 1);
+        assert!(db_signed_outpoint(&db_path, &fresh).unwrap().is_none());
+        let sig = db_signed_outpoint(&db_path, &signed)
+            .unwrap()
+            .unwrap()
+            .signature;
+        assert!(db_insert_signed_outpoint(&db_path, &fresh, &sig).is_err());
+        assert_eq!(db_count_signed_outpoints(&db_path).unwrap(), 1);
     }
 }
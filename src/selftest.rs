@@ -0,0 +1,535 @@
+use crate::{
+    config::{Config, ManagerConfig},
+    daemon::start,
+    database::{db_in_memory, db_signed_outpoint, setup_db, DatabaseError},
+    keys::Keys,
+    processing::{
+        compute_spend_sighashes, process_manager_sign_request, SignDecision, SignProcessingError,
+    },
+};
+
+use revault_net::{
+    message::cosigner::{SignRequest, SignResult},
+    noise::SecretKey as NoisePrivkey,
+    sodiumoxide::{self, randombytes::randombytes},
+    transport::KKTransport,
+};
+use revault_tx::{
+    bitcoin::{
+        consensus::encode,
+        hashes::Hash,
+        secp256k1,
+        util::bip32::{self, ChildNumber},
+        Amount, Network, OutPoint, PublicKey as BitcoinPubkey, Script, SigHashType, TxOut, Txid,
+    },
+    miniscript::descriptor::{DescriptorPublicKey, DescriptorSinglePub, DescriptorXKey, Wildcard},
+    scripts::{CpfpDescriptor, UnvaultDescriptor},
+    transactions::{RevaultTransaction, SpendTransaction},
+    txins::UnvaultTxIn,
+    txouts::{SpendTxOut, UnvaultTxOut},
+};
+
+use std::{
+    fs,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    process,
+    sync::Arc,
+};
+
+const UNVAULT_CSV: u32 = 12;
+
+/// An error making the selftest fail
+#[derive(Debug)]
+pub enum SelftestError {
+    /// We could not set up the scratch environment
+    Setup(String),
+    Database(DatabaseError),
+    Network(revault_net::Error),
+    /// Something in the pipeline did not behave as expected
+    Check(String),
+}
+
+impl std::fmt::Display for SelftestError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Setup(e) => write!(f, "Setting up the selftest: '{}'", e),
+            Self::Database(e) => write!(f, "Database error: '{}'", e),
+            Self::Network(e) => write!(f, "Communicating with the daemon: '{}'", e),
+            Self::Check(e) => write!(f, "Check failed: '{}'", e),
+        }
+    }
+}
+
+impl std::error::Error for SelftestError {}
+
+fn random_xpriv(network: Network) -> Result<bip32::ExtendedPrivKey, SelftestError> {
+    bip32::ExtendedPrivKey::new_master(network, &randombytes(64))
+        .map_err(|e| SelftestError::Setup(e.to_string()))
+}
+
+fn random_outpoint() -> OutPoint {
+    let txid = Txid::from_slice(&randombytes(32)).expect("32 bytes");
+    OutPoint { txid, vout: 0 }
+}
+
+fn xpub_key(
+    secp: &secp256k1::Secp256k1<secp256k1::All>,
+    xpriv: &bip32::ExtendedPrivKey,
+) -> DescriptorPublicKey {
+    DescriptorPublicKey::XPub(DescriptorXKey {
+        origin: None,
+        xkey: bip32::ExtendedPubKey::from_private(secp, xpriv),
+        derivation_path: bip32::DerivationPath::from(vec![]),
+        wildcard: Wildcard::Unhardened,
+    })
+}
+
+// The Unvault descriptor of a deployment with a single other stakeholder, whose cosigner is us,
+// and these managers.
+fn synthetic_unvault_descriptor(
+    secp: &secp256k1::Secp256k1<secp256k1::All>,
+    network: Network,
+    our_pubkey: BitcoinPubkey,
+    managers_xprivs: &[bip32::ExtendedPrivKey],
+    csv: u32,
+) -> Result<UnvaultDescriptor, SelftestError> {
+    let stakeholders_keys = vec![xpub_key(secp, &random_xpriv(network)?)];
+    let cosigners_keys = vec![DescriptorPublicKey::SinglePub(DescriptorSinglePub {
+        origin: None,
+        key: our_pubkey,
+    })];
+    let managers_keys: Vec<DescriptorPublicKey> =
+        managers_xprivs.iter().map(|x| xpub_key(secp, x)).collect();
+
+    UnvaultDescriptor::new(
+        stakeholders_keys,
+        managers_keys.clone(),
+        managers_keys.len(),
+        cosigners_keys,
+        csv,
+    )
+    .map_err(|e| SelftestError::Setup(e.to_string()))
+}
+
+// A Spend of these outpoints from the Unvault of this descriptor, signed by all of these managers
+fn synthetic_spend(
+    secp: &secp256k1::Secp256k1<secp256k1::All>,
+    unvault_descriptor: &UnvaultDescriptor,
+    managers_xprivs: &[bip32::ExtendedPrivKey],
+    outpoints: &[OutPoint],
+) -> Result<SpendTransaction, SelftestError> {
+    let managers_keys: Vec<DescriptorPublicKey> =
+        managers_xprivs.iter().map(|x| xpub_key(secp, x)).collect();
+    let cpfp_descriptor =
+        CpfpDescriptor::new(managers_keys).map_err(|e| SelftestError::Setup(e.to_string()))?;
+
+    let unvault_value = Amount::from_sat(100_000_000);
+    let unvault_txins: Vec<UnvaultTxIn> = outpoints
+        .iter()
+        .map(|o| {
+            let unvault_txout =
+                UnvaultTxOut::new(unvault_value, &unvault_descriptor.derive(0.into(), secp));
+            UnvaultTxIn::new(*o, unvault_txout, unvault_descriptor.csv_value())
+        })
+        .collect();
+    let spend_txo = TxOut {
+        value: (unvault_value.as_sat() - 50_000) * outpoints.len() as u64,
+        ..TxOut::default()
+    };
+    let spend_tx = SpendTransaction::new(
+        unvault_txins,
+        vec![SpendTxOut::new(spend_txo)],
+        None,
+        &cpfp_descriptor.derive(0.into(), secp),
+        0,
+        true,
+    )
+    .map_err(|e| SelftestError::Setup(e.to_string()))?;
+
+    // Have the managers sign it, in case we require their signatures.
+    let sighashes = compute_spend_sighashes(&spend_tx).map_err(sign_processing_error)?;
+    let mut psbt = spend_tx.into_psbt();
+    for xpriv in managers_xprivs {
+        let privkey = xpriv
+            .derive_priv(
+                secp,
+                &[ChildNumber::from_normal_idx(0).expect("0 is normal")],
+            )
+            .map_err(|e| SelftestError::Setup(e.to_string()))?
+            .private_key;
+        let pubkey = BitcoinPubkey::from_private_key(secp, &privkey);
+        for (psbtin, sighash) in psbt.inputs.iter_mut().zip(sighashes.iter()) {
+            let sighash = secp256k1::Message::from_slice(sighash).expect("Sighash is 32 bytes");
+            let mut sig = secp.sign(&sighash, &privkey.key).serialize_der().to_vec();
+            sig.push(SigHashType::All as u8);
+            psbtin.partial_sigs.insert(pubkey, sig);
+        }
+    }
+
+    SpendTransaction::from_psbt_serialized(&encode::serialize(&psbt))
+        .map_err(|e| SelftestError::Setup(e.to_string()))
+}
+
+fn sign_processing_error(e: SignProcessingError) -> SelftestError {
+    match e {
+        SignProcessingError::Database(e) => SelftestError::Database(e),
+        e => SelftestError::Check(e.to_string()),
+    }
+}
+
+// Where the daemon records why it refused the synthetic Spend, in the scratch directory
+fn audit_log_path(scratch_dir: &Path) -> PathBuf {
+    scratch_dir.join("audit.log")
+}
+
+// The reason of the last refusal recorded in this audit log, if any
+fn last_refusal(audit_log: &Path) -> Option<String> {
+    let content = fs::read_to_string(audit_log).ok()?;
+    let event: serde_json::Value = serde_json::from_str(content.lines().last()?).ok()?;
+    event["reason"].as_str().map(|r| r.to_string())
+}
+
+// A scratch configuration with synthetic managers, and a synthetic Spend for it
+struct Scenario {
+    config: Config,
+    manager_noise_privkey: NoisePrivkey,
+    outpoints: [OutPoint; 2],
+    spend_tx: SpendTransaction,
+}
+
+// Set up a scenario using the scratch database in this directory, under the signing policies of
+// this configuration. The caller sets up the database.
+fn setup_scenario(
+    mut config: Config,
+    scratch_dir: &Path,
+    keys: &Keys,
+) -> Result<Scenario, SelftestError> {
+    let (manager_noise_pubkey, manager_noise_privkey) = sodiumoxide::crypto::box_::gen_keypair();
+
+    // Only ever touch the scratch database, and don't pollute the audit log.
+    config.data_dir = scratch_dir.to_path_buf();
+    config.listen = vec![SocketAddr::from(([127, 0, 0, 1], 0))];
+    config.audit_log = None;
+    config.health_listen = None;
+    config.metrics_listen = None;
+    config.statsd_addr = None;
+    config.max_uptime_secs = None;
+
+    let n_managers = config.require_manager_sigs.unwrap_or(1).max(1);
+    let managers_xprivs = (0..n_managers)
+        .map(|_| random_xpriv(config.network))
+        .collect::<Result<Vec<_>, _>>()?;
+    // The synthetic managers, only the first one connects to us.
+    config.managers = managers_xprivs
+        .iter()
+        .enumerate()
+        .map(|(i, xpriv)| ManagerConfig {
+            noise_key: if i == 0 {
+                manager_noise_pubkey
+            } else {
+                sodiumoxide::crypto::box_::gen_keypair().0
+            },
+            xpub: Some(xpub_key(keys.secp(), xpriv)),
+        })
+        .collect();
+    // The synthetic vaults are locked for as long as ours
+    let csv = config
+        .unvault_csv
+        .or_else(|| config.unvault_descriptor.as_ref().map(|d| d.csv_value()))
+        .unwrap_or(UNVAULT_CSV);
+    let unvault_descriptor = synthetic_unvault_descriptor(
+        keys.secp(),
+        config.network,
+        *keys.bitcoin_pubkey(),
+        &managers_xprivs,
+        csv,
+    )?;
+    // Our vaults are not the synthetic managers' ones
+    if config.unvault_descriptor.is_some() {
+        config.unvault_descriptor = Some(unvault_descriptor.clone());
+    }
+    let outpoints = [random_outpoint(), random_outpoint()];
+    let spend_tx = synthetic_spend(
+        keys.secp(),
+        &unvault_descriptor,
+        &managers_xprivs,
+        &outpoints,
+    )?;
+
+    // The synthetic outpoints and destination are not in the allowlists, add them. The files
+    // would only be read again upon SIGHUP, which doesn't concern the scratch daemon.
+    config.allowed_outpoints_file = None;
+    if let Some(ref mut allowed_outpoints) = config.allowed_outpoints {
+        allowed_outpoints.extend(outpoints.iter().copied());
+    }
+    config.allowed_destinations_file = None;
+    if let Some(ref mut allowed_destinations) = config.allowed_destinations {
+        allowed_destinations.insert(Script::new());
+    }
+
+    Ok(Scenario {
+        config,
+        manager_noise_privkey,
+        outpoints,
+        spend_tx,
+    })
+}
+
+// Check this Spend we got back carries a valid signature of ours for each input of the
+// scenario's one, and that they were recorded in the scratch database.
+fn check_signed(
+    scenario: &Scenario,
+    keys: &Keys,
+    signed_tx: &SpendTransaction,
+) -> Result<(), SelftestError> {
+    let (secp, our_pubkey) = (keys.secp(), keys.bitcoin_pubkey());
+    let sighashes = compute_spend_sighashes(&scenario.spend_tx).map_err(sign_processing_error)?;
+    let db_path = scenario.config.db_file();
+
+    for (i, (psbtin, sighash)) in signed_tx
+        .psbt()
+        .inputs
+        .iter()
+        .zip(sighashes.iter())
+        .enumerate()
+    {
+        let sig = psbtin
+            .partial_sigs
+            .get(our_pubkey)
+            .and_then(|sig| sig.split_last())
+            .and_then(|(_, sig)| secp256k1::Signature::from_der(sig).ok())
+            .ok_or_else(|| SelftestError::Check(format!("no signature of ours on input {}", i)))?;
+        let sighash = secp256k1::Message::from_slice(sighash).expect("Sighash is 32 bytes");
+        secp.verify(&sighash, &sig, &our_pubkey.key).map_err(|e| {
+            SelftestError::Check(format!("invalid signature on input {}: {}", i, e))
+        })?;
+
+        let outpoint = &scenario.outpoints[i];
+        let signed_op = db_signed_outpoint(&db_path, outpoint)
+            .map_err(SelftestError::Database)?
+            .ok_or_else(|| {
+                SelftestError::Check(format!("outpoint '{}' not in database", outpoint))
+            })?;
+        if signed_op.signature != sig {
+            return Err(SelftestError::Check(format!(
+                "database signature for '{}' differs from the one sent",
+                outpoint
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+// Run the daemon with this scratch database, make it sign a synthetic Spend as a synthetic
+// manager and check the result.
+fn run_pipeline(config: Config, scratch_dir: &Path, keys: &Arc<Keys>) -> Result<(), SelftestError> {
+    let mut scenario = setup_scenario(config, scratch_dir, keys)?;
+    // Keep a scratch audit log to tell why the daemon refused to sign, if it did
+    scenario.config.audit_log = Some(audit_log_path(scratch_dir));
+    setup_db(&scenario.config.db_file(), true).map_err(SelftestError::Database)?;
+
+    let handle = start(scenario.config.clone(), Arc::clone(keys))
+        .map_err(|e| SelftestError::Setup(format!("starting the daemon: {}", e)))?;
+    let sign_res = KKTransport::connect(
+        handle.bound_addr(),
+        &scenario.manager_noise_privkey,
+        &keys.noise_pubkey(),
+    )
+    .and_then(|mut transport| {
+        transport.send_req::<SignResult>(
+            &SignRequest {
+                tx: scenario.spend_tx.clone(),
+            }
+            .into(),
+        )
+    });
+    handle.shutdown();
+    let signed_tx = sign_res
+        .map_err(SelftestError::Network)?
+        .tx
+        .ok_or_else(|| {
+            let reason = last_refusal(&audit_log_path(scratch_dir));
+            SelftestError::Check(format!(
+                "the daemon refused to sign ({})",
+                reason.as_deref().unwrap_or("unknown reason")
+            ))
+        })?;
+
+    check_signed(&scenario, keys, &signed_tx)
+}
+
+// Have the signing logic sign a synthetic Spend directly, with a scratch database in memory
+// standing in for the one of this (never created) directory, and check the result.
+fn run_in_process(config: Config, scratch_dir: &Path, keys: &Keys) -> Result<(), SelftestError> {
+    let scenario = setup_scenario(config, scratch_dir, keys)?;
+    let _scratch_db = db_in_memory(&scenario.config.db_file()).map_err(SelftestError::Database)?;
+
+    let sign_req = SignRequest {
+        tx: scenario.spend_tx.clone(),
+    };
+    let sender = &scenario.config.managers[0].noise_key;
+    let signed_tx = match process_manager_sign_request(&scenario.config, sign_req, keys, sender)
+        .map_err(sign_processing_error)?
+    {
+        SignDecision::Signed(signed_tx) => signed_tx,
+        SignDecision::KnownSignatures(..) => {
+            return Err(SelftestError::Check(
+                "the Spend was answered with known signatures".to_string(),
+            ))
+        }
+        SignDecision::Refused(refusal) => {
+            return Err(SelftestError::Check(format!(
+                "the Spend was refused ({})",
+                refusal.code()
+            )))
+        }
+    };
+
+    check_signed(&scenario, keys, &signed_tx)
+}
+
+// Create this scratch directory, run this in it and remove it whatever the outcome
+fn with_scratch_dir<F>(scratch_dir: &Path, run: F) -> Result<(), SelftestError>
+where
+    F: FnOnce(&Path) -> Result<(), SelftestError>,
+{
+    fs::create_dir(scratch_dir).map_err(|e| {
+        SelftestError::Setup(format!("creating '{}': {}", scratch_dir.display(), e))
+    })?;
+
+    let res = run(scratch_dir);
+
+    if let Err(e) = fs::remove_dir_all(scratch_dir) {
+        log::error!(
+            "Error removing the selftest directory at '{}': '{}'",
+            scratch_dir.display(),
+            e
+        );
+    }
+
+    res
+}
+
+/// Check that the whole signing pipeline works on this host: start the daemon on an ephemeral
+/// loopback port with a scratch database, have a synthetic manager send it a synthetic Spend over
+/// Noise KK, and check the returned signatures and that they were recorded in the database.
+/// The signing policies of this configuration are in effect, the synthetic outpoints and
+/// destination being added to its allowlists. Everything is torn down afterward.
+pub fn selftest(config: &Config, keys: &Arc<Keys>) -> Result<(), SelftestError> {
+    let scratch_dir = config.data_dir.join(format!("selftest-{}", process::id()));
+    with_scratch_dir(&scratch_dir, |dir| run_pipeline(config.clone(), dir, keys))
+}
+
+/// Check that our keys and the signing logic work, without touching the filesystem nor the
+/// network: sign a synthetic Spend in-process against a scratch database in memory, and check
+/// the signatures and that they were recorded. The signing policies of this configuration are in
+/// effect, as for [selftest].
+pub fn selftest_offline(config: &Config, keys: &Keys) -> Result<(), SelftestError> {
+    let scratch_dir = config.data_dir.join(format!("selftest-{}", process::id()));
+    run_in_process(config.clone(), &scratch_dir, keys)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{selftest, selftest_offline};
+    use crate::{database::db_all_signed_outpoints, tests::builder::CosignerTestBuilder};
+    use revault_tx::bitcoin::Address;
+    use std::str::FromStr;
+
+    #[test]
+    fn selftest_offline_sanity() {
+        let mut test_framework = CosignerTestBuilder::new(1);
+        let data_dir = test_framework.config.data_dir.clone();
+        let data_dir_content = || {
+            std::fs::read_dir(&data_dir)
+                .unwrap()
+                .map(|e| e.unwrap().file_name())
+                .collect::<Vec<_>>()
+        };
+        let before = data_dir_content();
+
+        selftest_offline(&test_framework.config, &test_framework.keys).unwrap();
+        test_framework.config.require_manager_sigs = Some(2);
+        selftest_offline(&test_framework.config, &test_framework.keys).unwrap();
+        // And the sender's one
+        test_framework.config.require_sender_sig = true;
+        selftest_offline(&test_framework.config, &test_framework.keys).unwrap();
+        test_framework.config.require_sender_sig = false;
+
+        // The data directory was left alone, no scratch one was even created
+        assert_eq!(data_dir_content(), before);
+        assert!(db_all_signed_outpoints(&test_framework.config.db_file())
+            .unwrap()
+            .is_empty());
+
+        // Any policy making it refuse makes it fail
+        test_framework.config.require_manager_sigs = None;
+        test_framework.config.max_spend_inputs = 1;
+        selftest_offline(&test_framework.config, &test_framework.keys).unwrap_err();
+    }
+
+    #[test]
+    fn selftest_sanity() {
+        let mut test_framework = CosignerTestBuilder::new(1);
+
+        selftest(&test_framework.config, &test_framework.keys).unwrap();
+
+        // Also with the managers signatures policy
+        test_framework.config.require_manager_sigs = Some(2);
+        selftest(&test_framework.config, &test_framework.keys).unwrap();
+        test_framework.config.require_sender_sig = true;
+        selftest(&test_framework.config, &test_framework.keys).unwrap();
+        test_framework.config.require_sender_sig = false;
+
+        // It cleaned up after itself and never touched the actual database
+        assert_eq!(
+            std::fs::read_dir(&test_framework.config.data_dir)
+                .unwrap()
+                .count(),
+            1
+        );
+        assert!(db_all_signed_outpoints(&test_framework.config.db_file())
+            .unwrap()
+            .is_empty());
+
+        // Any policy making it refuse makes it fail
+        test_framework.config.require_manager_sigs = None;
+        test_framework.config.max_spend_inputs = 1;
+        selftest(&test_framework.config, &test_framework.keys).unwrap_err();
+    }
+
+    #[test]
+    fn selftest_deployment_policies() {
+        let mut test_framework = CosignerTestBuilder::new(1);
+        // Our vaults are locked for 18 blocks, and we only sign for these outpoints to this
+        // address
+        test_framework.config.unvault_descriptor = None;
+        test_framework.config.unvault_csv = Some(18);
+        let data_dir = test_framework.config.data_dir.clone();
+        let outpoints_file = data_dir.join("allowed_outpoints");
+        std::fs::write(
+            &outpoints_file,
+            "2b8930127e9dfd1bcdf35df2bc7f3b8cdbec083b1ae693f36b6305fccd1425da:0\n",
+        )
+        .unwrap();
+        test_framework.config.allowed_outpoints_file = Some(outpoints_file);
+        test_framework.config.load_allowed_outpoints().unwrap();
+        let destinations_file = data_dir.join("allowed_destinations");
+        let destination = Address::from_str("bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4").unwrap();
+        std::fs::write(&destinations_file, format!("{}\n", destination)).unwrap();
+        test_framework.config.allowed_destinations_file = Some(destinations_file);
+        test_framework.config.load_allowed_destinations().unwrap();
+
+        selftest_offline(&test_framework.config, &test_framework.keys).unwrap();
+        selftest(&test_framework.config, &test_framework.keys).unwrap();
+
+        // When it fails, it tells which policy refused
+        test_framework.config.read_only = true;
+        let err = selftest_offline(&test_framework.config, &test_framework.keys).unwrap_err();
+        assert!(err.to_string().contains("read_only"), "{}", err);
+        let err = selftest(&test_framework.config, &test_framework.keys).unwrap_err();
+        assert!(err.to_string().contains("read_only"), "{}", err);
+    }
+}
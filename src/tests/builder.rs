@@ -1,4 +1,8 @@
-use crate::{config::Config, config::ManagerConfig, database::setup_db};
+use crate::{
+    config::{Config, LogTimestampFormat, ManagerConfig},
+    database::setup_db,
+    keys::{secp_ctx, Keys},
+};
 use revault_net::{noise::SecretKey as NoisePrivkey, sodiumoxide};
 use revault_tx::{
     miniscript::{
@@ -6,7 +10,7 @@ use revault_tx::{
             secp256k1,
             secp256k1::rand::{rngs::SmallRng, FromEntropy, RngCore},
             util::bip32,
-            Amount, Network, OutPoint, TxOut,
+            Amount, Network, OutPoint, Script, TxOut,
         },
         descriptor::{DescriptorPublicKey, DescriptorSinglePub, DescriptorXKey, Wildcard},
     },
@@ -16,46 +20,68 @@ use revault_tx::{
     txouts::{SpendTxOut, UnvaultTxOut},
 };
 
-use std::{fs, net::SocketAddr, path::PathBuf, str::FromStr};
+use std::{fs, net::SocketAddr, path::PathBuf, str::FromStr, sync::Arc};
 
 use libc;
 
-fn random_privkey(rng: &mut SmallRng) -> bip32::ExtendedPrivKey {
+fn random_privkey(rng: &mut SmallRng, network: Network) -> bip32::ExtendedPrivKey {
     let mut rand_bytes = [0u8; 64];
 
     rng.fill_bytes(&mut rand_bytes);
 
-    bip32::ExtendedPrivKey::new_master(Network::Bitcoin, &rand_bytes)
-        .unwrap_or_else(|_| random_privkey(rng))
+    bip32::ExtendedPrivKey::new_master(network, &rand_bytes)
+        .unwrap_or_else(|_| random_privkey(rng, network))
 }
 
 #[derive(Debug)]
 pub struct CosignerTestBuilder {
     pub config: Config,
-    pub noise_privkey: NoisePrivkey,
-    pub bitcoin_privkey: secp256k1::SecretKey,
+    pub keys: Arc<Keys>,
     pub managers_keys: Vec<DescriptorPublicKey>,
-    pub secp: secp256k1::Secp256k1<secp256k1::All>,
+    pub managers_privkeys: Vec<bip32::ExtendedPrivKey>,
+    pub managers_noise_privkeys: Vec<NoisePrivkey>,
+    /// The descriptor of the Unvault outputs the generated Spends spend, also in the config
+    pub unvault_descriptor: UnvaultDescriptor,
+    pub secp: &'static secp256k1::Secp256k1<secp256k1::All>,
 }
 
 impl CosignerTestBuilder {
     pub fn new(n_man: usize) -> Self {
         let mut rng = SmallRng::from_entropy();
-        let secp = secp256k1::Secp256k1::new();
+        let noise_privkey = sodiumoxide::crypto::box_::gen_keypair().1;
+        let bitcoin_privkey = secp256k1::SecretKey::new(&mut rng);
+
+        Self::with_keys(n_man, Arc::new(Keys::new(noise_privkey, bitcoin_privkey)))
+    }
+
+    /// A builder whose cosigner, in the Unvault descriptor among 9 others, has the given keys
+    pub fn with_keys(n_man: usize, keys: Arc<Keys>) -> Self {
+        let mut rng = SmallRng::from_entropy();
+        let secp = secp_ctx();
+        let network = Network::Bitcoin;
+        let n_stk = 10;
 
         let mut managers = Vec::with_capacity(n_man);
         let mut managers_keys = Vec::with_capacity(n_man);
+        let mut managers_privkeys = Vec::with_capacity(n_man);
+        let mut managers_noise_privkeys = Vec::with_capacity(n_man);
         for _ in 0..n_man {
+            let xpriv = random_privkey(&mut rng, network);
             let xpub = DescriptorPublicKey::XPub(DescriptorXKey {
                 origin: None,
-                xkey: bip32::ExtendedPubKey::from_private(&secp, &random_privkey(&mut rng)),
+                xkey: bip32::ExtendedPubKey::from_private(secp, &xpriv),
                 derivation_path: bip32::DerivationPath::from(vec![]),
                 wildcard: Wildcard::Unhardened,
             });
-            managers_keys.push(xpub);
+            managers_privkeys.push(xpriv);
 
-            let noise_key = sodiumoxide::crypto::box_::gen_keypair().0;
-            managers.push(ManagerConfig { noise_key });
+            let (noise_key, noise_privkey) = sodiumoxide::crypto::box_::gen_keypair();
+            managers.push(ManagerConfig {
+                noise_key,
+                xpub: Some(xpub.clone()),
+            });
+            managers_noise_privkeys.push(noise_privkey);
+            managers_keys.push(xpub);
         }
 
         // Use a scratch directory in /tmp
@@ -63,7 +89,7 @@ impl CosignerTestBuilder {
             let template = std::ffi::CString::new("/tmp/cosignerd-XXXXXX").unwrap();
             let template_ptr = template.into_raw();
 
-            if libc::mkdtemp(template_ptr) == std::ptr::null_mut() {
+            if libc::mkdtemp(template_ptr).is_null() {
                 panic!(
                     "Error creating temp dir: '{}'",
                     std::io::Error::last_os_error(),
@@ -74,62 +100,130 @@ impl CosignerTestBuilder {
                 .unwrap()
         };
         let data_dir = PathBuf::from_str(&data_dir_str).unwrap();
-        let listen = SocketAddr::from_str("127.0.0.1:8383").unwrap();
+        let listen = vec![SocketAddr::from_str("127.0.0.1:8383").unwrap()];
+
+        let mut stakeholders_keys = Vec::with_capacity(n_stk);
+        // We are the first cosigner
+        let mut cosigners_keys = vec![DescriptorPublicKey::SinglePub(DescriptorSinglePub {
+            origin: None,
+            key: *keys.bitcoin_pubkey(),
+        })];
+        for _ in 0..n_stk {
+            stakeholders_keys.push(DescriptorPublicKey::XPub(DescriptorXKey {
+                origin: None,
+                xkey: bip32::ExtendedPubKey::from_private(secp, &random_privkey(&mut rng, network)),
+                derivation_path: bip32::DerivationPath::from(vec![]),
+                wildcard: Wildcard::Unhardened,
+            }));
+        }
+        for _ in 1..n_stk {
+            cosigners_keys.push(DescriptorPublicKey::SinglePub(DescriptorSinglePub {
+                origin: None,
+                key: bip32::ExtendedPubKey::from_private(secp, &random_privkey(&mut rng, network))
+                    .public_key,
+            }));
+        }
+        let unvault_descriptor = UnvaultDescriptor::new(
+            stakeholders_keys,
+            managers_keys.clone(),
+            1,
+            cosigners_keys,
+            12,
+        )
+        .expect("Unvault descriptor generation error");
 
         let mut db_path = data_dir.clone();
         db_path.push("cosignerd.sqlite3");
-        setup_db(&db_path).expect("Setting up db");
+        setup_db(&db_path, true).expect("Setting up db");
 
         let config = Config {
             managers,
+            network,
+            managers_file: None,
+            max_managers: None,
             data_dir,
             listen,
             log_level: log::LevelFilter::Trace,
+            log_timestamp_format: LogTimestampFormat::Rfc3339,
+            log_max_size: None,
+            log_backups: 3,
             daemon: false,
+            tcp_nodelay: true,
+            connection_timeout: 30,
+            reject_unknown_psbt_fields: false,
+            requests_per_minute: 60,
+            refusal_alert_threshold: None,
+            max_locktime_age: None,
+            require_rbf: false,
+            signature_cache_size: 1024,
+            warm_db_cache: false,
+            db_wal: false,
+            db_integrity_check: true,
+            bitcoin_key_passphrase_file: None,
+            audit_log: None,
+            require_time_sync: false,
+            require_manager_sigs: None,
+            verify_manager_sigs: false,
+            require_sender_sig: false,
+            min_relay_feerate: None,
+            partial_signing: false,
+            return_known_sigs: false,
+            dry_run: false,
+            read_only: false,
+            allowed_outpoints_file: None,
+            allowed_outpoints: None,
+            allowed_destinations_file: None,
+            allowed_destinations: None,
+            db_busy_timeout_ms: 5000,
+            db_busy_retries: 3,
+            strict_permissions: false,
+            max_feerate: None,
+            unvault_csv: None,
+            unvault_descriptor: Some(unvault_descriptor.clone()),
+            max_spend_inputs: 128,
+            protocol_version: 0,
+            config_version: 1,
+            blacklisted_outpoints: Vec::new(),
+            blacklisted_outpoints_file: None,
+            file_blacklisted_outpoints: Vec::new(),
+            health_listen: None,
+            metrics_listen: None,
+            statsd_addr: None,
+            max_uptime_secs: None,
+            prune_after: None,
+            config_file: None,
+            data_dir_overridden: false,
         };
 
-        let noise_privkey = sodiumoxide::crypto::box_::gen_keypair().1;
-        let bitcoin_privkey = secp256k1::SecretKey::new(&mut rng);
-
         CosignerTestBuilder {
             config,
-            noise_privkey,
-            bitcoin_privkey,
+            keys,
             managers_keys,
+            managers_privkeys,
+            managers_noise_privkeys,
+            unvault_descriptor,
             secp,
         }
     }
 
     pub fn generate_spend_tx(&self, outpoints: &[OutPoint]) -> SpendTransaction {
-        let mut rng = SmallRng::from_entropy();
-        let secp = secp256k1::Secp256k1::new();
+        self.generate_spend_tx_to(outpoints, Script::new())
+    }
+
+    /// A Spend of these outpoints paying to this Script, besides the CPFP output
+    pub fn generate_spend_tx_to(
+        &self,
+        outpoints: &[OutPoint],
+        script_pubkey: Script,
+    ) -> SpendTransaction {
+        let secp = self.secp;
         let unvault_value = Amount::from_sat(100000000);
-        let n_stk = 10;
-        let csv = 12;
+        let unvault_descriptor = &self.unvault_descriptor;
+        // The vaults are the same, make each Spend of the same outpoints differ with a random
+        // (block height) nLockTime.
+        let lock_time = SmallRng::from_entropy().next_u32() % 500_000;
+        let csv = unvault_descriptor.csv_value();
 
-        let mut stakeholders_keys = Vec::with_capacity(n_stk);
-        let mut cosigners_keys = Vec::with_capacity(n_stk);
-        for _ in 0..n_stk {
-            stakeholders_keys.push(DescriptorPublicKey::XPub(DescriptorXKey {
-                origin: None,
-                xkey: bip32::ExtendedPubKey::from_private(&secp, &random_privkey(&mut rng)),
-                derivation_path: bip32::DerivationPath::from(vec![]),
-                wildcard: Wildcard::Unhardened,
-            }));
-            cosigners_keys.push(DescriptorPublicKey::SinglePub(DescriptorSinglePub {
-                origin: None,
-                key: bip32::ExtendedPubKey::from_private(&secp, &random_privkey(&mut rng))
-                    .public_key,
-            }));
-        }
-        let unvault_descriptor = UnvaultDescriptor::new(
-            stakeholders_keys,
-            self.managers_keys.clone(),
-            1,
-            cosigners_keys,
-            csv,
-        )
-        .expect("Unvault descriptor generation error");
         let cpfp_descriptor =
             CpfpDescriptor::new(self.managers_keys.clone()).expect("CPFP desc generation error");
 
@@ -137,22 +231,22 @@ impl CosignerTestBuilder {
             .iter()
             .map(|o| {
                 let unvault_txout =
-                    UnvaultTxOut::new(unvault_value, &unvault_descriptor.derive(0.into(), &secp));
+                    UnvaultTxOut::new(unvault_value, &unvault_descriptor.derive(0.into(), secp));
                 UnvaultTxIn::new(*o, unvault_txout, csv)
             })
             .collect();
         let spend_txo = TxOut {
             value: unvault_value.as_sat() * unvault_txins.len() as u64
                 - 50_000 * unvault_txins.len() as u64, // FIXME: we could compute the actual price
-            ..TxOut::default()
+            script_pubkey,
         };
 
         SpendTransaction::new(
             unvault_txins,
             vec![SpendTxOut::new(spend_txo.clone())],
             None,
-            &cpfp_descriptor.derive(0.into(), &secp),
-            0,
+            &cpfp_descriptor.derive(0.into(), secp),
+            lock_time,
             true,
         )
         .expect("Creating spend transaction")
@@ -174,11 +268,14 @@ impl Drop for CosignerTestBuilder {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::processing::process_sign_message;
+    use revault_net::message::cosigner::{SignRequest, SignResult};
+    use revault_tx::transactions::RevaultTransaction;
 
     #[test]
     fn test_builder() {
         let test_framework = CosignerTestBuilder::new(5);
-        test_framework.generate_spend_tx(&[
+        let spend_tx = test_framework.generate_spend_tx(&[
             OutPoint::from_str(
                 "2b8930127e9dfd1bcdf35df2bc7f3b8cdbec083b1ae693f36b6305fccd1425da:0",
             )
@@ -192,5 +289,22 @@ mod tests {
             )
             .unwrap(),
         ]);
+
+        // We can sign it using the very same context
+        let SignResult { tx } = process_sign_message(
+            &test_framework.config,
+            SignRequest { tx: spend_tx },
+            &test_framework.keys,
+        )
+        .unwrap();
+        assert_eq!(
+            tx.unwrap()
+                .psbt()
+                .inputs
+                .iter()
+                .map(|i| i.partial_sigs.len())
+                .sum::<usize>(),
+            3
+        );
     }
 }
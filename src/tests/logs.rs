@@ -0,0 +1,48 @@
+//! A logger recording the log lines of all the threads, for tests to check what was logged.
+
+use std::sync::{Mutex, Once};
+
+struct CapturingLogger {
+    lines: Mutex<Vec<String>>,
+}
+
+impl log::Log for CapturingLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= log::Level::Debug
+    }
+
+    fn log(&self, record: &log::Record) {
+        if self.enabled(record.metadata()) {
+            self.lines
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .push(format!("[{}] {}", record.level(), record.args()));
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: CapturingLogger = CapturingLogger {
+    lines: Mutex::new(Vec::new()),
+};
+static INIT: Once = Once::new();
+
+/// Start recording the log lines, if not already. All the tests share the same logger, so only
+/// look for lines specific to yours.
+pub fn capture_logs() {
+    INIT.call_once(|| {
+        log::set_logger(&LOGGER).expect("No other logger is set in tests");
+        log::set_max_level(log::LevelFilter::Debug);
+    });
+}
+
+/// Whether a line containing all these patterns was logged since [capture_logs]
+pub fn logged(patterns: &[&str]) -> bool {
+    LOGGER
+        .lines
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .iter()
+        .any(|line| patterns.iter().all(|p| line.contains(p)))
+}
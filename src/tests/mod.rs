@@ -1 +1,3 @@
 pub mod builder;
+#[cfg(test)]
+pub mod logs;